@@ -49,6 +49,8 @@ const INFIXS: [Funcmap; 5] = [
 ];
 
 struct Expr<'a> {
+    // not printed by `SimpleExpr`
+    #[allow(dead_code)]
     span: Span<'a>,
     val: Value<'a>,
 }
@@ -101,20 +103,20 @@ fn main() {
             val.get_utf8_column(),
             val.fragment,
         ),
-        Err(err) => panic!(err),
+        Err(err) => panic!("{}", err.error.description()),
     }
 }
 
-fn parse<'a>(input: Span) -> Result<Expr, Error> {
+fn parse(input: Span) -> Result<Expr, Error> {
     match parse_expr(input) {
         Ok((Span { fragment: "", .. }, tree)) => Ok(tree),
         Ok((input, _)) => Err(Error {
-            input: input,
+            input,
             val: Some(input),
             error: ErrorKind::NotRecognised,
         }),
         Err(Err::Incomplete(_)) => Err(Error {
-            input: input,
+            input,
             val: Some(input),
             error: ErrorKind::Incomplete,
         }),
@@ -123,15 +125,15 @@ fn parse<'a>(input: Span) -> Result<Expr, Error> {
     }
 }
 
-fn parse_expr<'a>(input: Span) -> IResult<Span, Expr> {
+fn parse_expr(input: Span) -> IResult<Span, Expr> {
     branch::alt((parse_infix, parse_expr_nobin))(input)
 }
 
-fn parse_expr_nobin<'a>(input: Span) -> IResult<Span, Expr> {
+fn parse_expr_nobin(input: Span) -> IResult<Span, Expr> {
     branch::alt((parse_unary, parse_expr_nobin_noun))(input)
 }
 
-fn parse_expr_nobin_noun<'a>(input: Span) -> IResult<Span, Expr> {
+fn parse_expr_nobin_noun(input: Span) -> IResult<Span, Expr> {
     parse_value(input)
 }
 
@@ -182,12 +184,12 @@ fn parse_infix(input: Span) -> IResult<Span, Expr> {
     // Initialize with minimum precedence 1.
     parse_infix_first(input, 1)
 }
-fn parse_infix_first<'a>(input: Span, min_prec: u8) -> IResult<Span, Expr> {
+fn parse_infix_first(input: Span, min_prec: u8) -> IResult<Span, Expr> {
     // First, find left hand side expression. Search for everything but BinOps.
     let (input, left) = parse_expr_nobin(input)?;
     parse_infix_left(input, min_prec, left)
 }
-fn parse_infix_prec<'a>(input: Span, min_prec: u8) -> IResult<Span, Expr> {
+fn parse_infix_prec(input: Span, min_prec: u8) -> IResult<Span, Expr> {
     // Almost identical to parse_infix_first(), but we do not accept unary ops.
     let (input, left) = parse_expr_nobin_noun(input)?;
     parse_infix_left(input, min_prec, left)
@@ -235,7 +237,7 @@ fn tag_infix(input: Span) -> IResult<Span, SpanFuncmap> {
 fn tag_func<'a>(input: Span<'a>, funcs: &'a [Funcmap]) -> IResult<'a, Span<'a>, SpanFuncmap<'a>> {
     for func_map in funcs.iter() {
         match tag(func_map.keyword)(input) {
-            Ok((input, span)) => return Ok((input, (span, &func_map))),
+            Ok((input, span)) => return Ok((input, (span, func_map))),
             Err(Err::Error(_)) => (),
             Err(err) => return Err(err),
         }
@@ -247,6 +249,8 @@ fn tag_func<'a>(input: Span<'a>, funcs: &'a [Funcmap]) -> IResult<'a, Span<'a>,
     }))
 }
 
+// only printed, the fields are read by `Debug`
+#[allow(dead_code)]
 #[derive(Debug)]
 enum SimpleExpr<'a> {
     Int(&'a i32),
@@ -264,7 +268,7 @@ impl<'a> SimpleExpr<'a> {
             Expr {
                 val: Value::Int(int),
                 ..
-            } => SimpleExpr::Int(&int),
+            } => SimpleExpr::Int(int),
             Expr {
                 val: Value::UnFunc(Function::UnSub, x),
                 ..
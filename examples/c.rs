@@ -0,0 +1,39 @@
+use crust::backend::c;
+use crust::ir;
+use crust::parse::parse;
+
+// writes the C source of the program given as argument (or of a builtin
+// example), e.g., `cargo run --example c prog.crust > prog.c && cc prog.c`
+fn main() {
+    let src = match std::env::args().nth(1) {
+        Some(path) => std::fs::read_to_string(path).unwrap(),
+        None => "
+fn swap(a: &mut i32, b: &mut i32) {
+    let t: i32 = *a;
+    *a = *b;
+    *b = t;
+}
+
+fn main() -> i32 {
+    let mut x: i32 = 2;
+    let mut y: i32 = 3;
+    swap(&mut x, &mut y);
+    println!(\"x = {}, y = {}\", x, y);
+    return x ** y;
+}
+"
+        .to_string(),
+    };
+    let p = match parse(&src) {
+        Ok(p) => p,
+        Err(d) => return eprint!("{}", d.render(&src)),
+    };
+    let m = match ir::lower(&p) {
+        Ok((m, warnings)) => {
+            warnings.iter().for_each(|d| eprint!("{}", d.render(&src)));
+            m
+        }
+        Err(diags) => return diags.iter().for_each(|d| eprint!("{}", d.render(&src))),
+    };
+    print!("{}", c::emit(&m));
+}
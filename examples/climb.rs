@@ -67,15 +67,15 @@ pub enum Token {
 
 fn parse_terminal(i: &str) -> IResult<&str, Token> {
     alt((
-        map(parse_i32, |v| Token::Num(v)),
-        map(parse_par(parse_tokens), |tokens| Token::Par(tokens)),
+        map(parse_i32, Token::Num),
+        map(parse_par(parse_tokens), Token::Par),
     ))(i)
 }
 
 fn parse_token(i: &str) -> IResult<&str, Token> {
     preceded(
         multispace0,
-        alt((map(parse_op, |op| Token::Op(op)), parse_terminal)),
+        alt((map(parse_op, Token::Op), parse_terminal)),
     )(i)
 }
 
@@ -95,26 +95,19 @@ fn compute_atom(t: &mut Peekable<Iter<Token>>) -> Expr {
 fn climb(t: &mut Peekable<Iter<Token>>, min_prec: u8) -> Expr {
     let mut result = compute_atom(t);
 
-    loop {
-        match t.peek() {
-            Some(Token::Op(op)) => {
-                let (prec, ass) = get_prec(op);
-                if prec < min_prec {
-                    break;
-                };
-                let next_prec = prec
-                    + match ass {
-                        Ass::Left => 1,
-                        _ => 0,
-                    };
-                t.next();
-                let rhs = climb(t, next_prec);
-                result = Expr::BinOp(*op, Box::new(result), Box::new(rhs))
-            }
-            _ => {
-                break;
-            }
-        }
+    while let Some(Token::Op(op)) = t.peek() {
+        let (prec, ass) = get_prec(op);
+        if prec < min_prec {
+            break;
+        };
+        let next_prec = prec
+            + match ass {
+                Ass::Left => 1,
+                _ => 0,
+            };
+        t.next();
+        let rhs = climb(t, next_prec);
+        result = Expr::BinOp(*op, Box::new(result), Box::new(rhs))
     }
     result
 }
@@ -137,8 +130,10 @@ fn test(s: &str, v: i32) {
     }
 }
 
+// the expected values are written as the expressions they check
+#[allow(clippy::identity_op)]
 fn main() {
-    test("- -1 + + 1", - -1 + 1);  // rust does not allow + as a unary op (I do ;)
+    test("- -1 + + 1", -(-1) + 1);  // rust does not allow + as a unary op (I do ;)
     test("(-1-1)+(-1+3)", (-1 - 1) + (-1) + 3);
     // just to check that right associative works (you don't need to implement pow)
     test("2+3**2**3*5+1", 2 + 3i32.pow(2u32.pow(3)) * 5 + 1);
@@ -0,0 +1,22 @@
+// usage: cargo run --example debug <file> [function]
+use std::io::{stdin, stdout, BufReader};
+
+use crust::debug::debug;
+use crust::parse::parse;
+
+fn main() {
+    let args: Vec<_> = std::env::args().collect();
+    let path = args.get(1).expect("usage: debug <file> [function]");
+    let src = std::fs::read_to_string(path).unwrap();
+    let id = args.get(2).map_or("main", |s| s.as_str());
+    let p = match parse(&src) {
+        Ok(p) => p,
+        Err(d) => return eprintln!("{}", d.render(&src)),
+    };
+    // the debugger runs on the interpreter thread, the input must be `Send`
+    match debug(&p, &src, id, vec![], BufReader::new(stdin()), stdout()) {
+        Ok(Ok(v)) => println!("{} returned {:?}", id, v),
+        Ok(Err(e)) => eprintln!("{}", e.diagnostic().render(&src)),
+        Err(e) => eprintln!("error: {}", e),
+    }
+}
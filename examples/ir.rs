@@ -0,0 +1,44 @@
+use crust::ir::{self, eval};
+use crust::parse::parse;
+
+// prints the IR of the program given as argument (or of a builtin
+// example) and runs its `main`
+fn main() {
+    let src = match std::env::args().nth(1) {
+        Some(path) => std::fs::read_to_string(path).unwrap(),
+        None => "
+fn gcd(mut a: i32, mut b: i32) -> i32 {
+    while b != 0 {
+        let t: i32 = a - a / b * b;
+        a = b;
+        b = t;
+    }
+    return a;
+}
+
+fn main() -> i32 {
+    let mut g: i32 = 0;
+    let r: &mut i32 = &mut g;
+    *r = gcd(1071, 462);
+    return g;
+}
+"
+        .to_string(),
+    };
+    let p = match parse(&src) {
+        Ok(p) => p,
+        Err(d) => return print!("{}", d.render(&src)),
+    };
+    let m = match ir::lower(&p) {
+        Ok((m, warnings)) => {
+            warnings.iter().for_each(|d| print!("{}", d.render(&src)));
+            m
+        }
+        Err(diags) => return diags.iter().for_each(|d| print!("{}", d.render(&src))),
+    };
+    print!("{}", m);
+    if let Err(e) = ir::verify(&m) {
+        return println!("invalid IR: {}", e);
+    }
+    println!("{:?}", eval::run(&m, "main", vec![]));
+}
@@ -1,12 +1,10 @@
 extern crate nom;
 
-use nom::combinator::map_res;
 use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::{digit1, multispace0},
     combinator::map,
-    error::{context, VerboseError, VerboseErrorKind},
     sequence::{preceded, tuple},
     IResult,
 };
@@ -1,17 +1,15 @@
 extern crate nom;
 
-use nom::combinator::map_res;
 use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::{digit1, multispace0},
     combinator::map,
-    error::{context, ErrorKind, VerboseError, VerboseErrorKind},
     sequence::{preceded, tuple},
-    Err, IResult,
+    IResult,
 };
 
-use nom_locate::{position, LocatedSpan};
+use nom_locate::LocatedSpan;
 
 type Span<'a> = LocatedSpan<&'a str>;
 
@@ -83,7 +81,7 @@ fn test_parse_i32_1() {
 
 
 fn main() {
-    let (a, b) = parse_expr(Span::new("1")).unwrap();
+    parse_expr(Span::new("1")).unwrap();
     println!("{:?}", parse_expr(Span::new("1")));
     println!("{:?}", parse_expr(Span::new("1+2 + 3")));
     println!("{:?}", parse_expr(Span::new("   1+ 1a")));
@@ -14,6 +14,8 @@ use nom_locate::LocatedSpan;
 
 type Span<'a> = LocatedSpan<&'a str>;
 
+// the fields are only printed, with `Debug`
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct Error<'a>(Span<'a>, Option<Span<'a>>, ErrorKind);
 type IResult<'a, I, O, E = Error<'a>> = Result<(I, O), Err<E>>;
@@ -28,6 +30,7 @@ impl<'a> error::ParseError<Span<'a>> for Error<'a> {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 enum ErrorKind {
     ParseIntError(std::num::ParseIntError),
@@ -58,7 +61,7 @@ pub enum Expr<'a> {
 
 type SpanExpr<'a> = (Span<'a>, Expr<'a>);
 
-pub fn parse_i32<'a>(i: Span<'a>) -> IResult<Span<'a>, SpanExpr> {
+pub fn parse_i32<'a>(i: Span<'a>) -> IResult<'a, Span<'a>, SpanExpr<'a>> {
     let (i, digits) = digit1(i)?;
     match digits.fragment.parse() {
         Ok(int) => Ok((i, (digits, Expr::Num(int)))),
@@ -70,7 +73,7 @@ pub fn parse_i32<'a>(i: Span<'a>) -> IResult<Span<'a>, SpanExpr> {
     }
 }
 
-pub fn parse_i64<'a>(i: Span<'a>) -> IResult<Span<'a>, SpanExpr> {
+pub fn parse_i64<'a>(i: Span<'a>) -> IResult<'a, Span<'a>, SpanExpr<'a>> {
     let (i, digits) = digit1(i)?;
     match digits.fragment.parse() {
         Ok(int) => Ok((i, (digits, Expr::Num64(int)))),
@@ -124,8 +127,8 @@ fn dump_expr(se: &SpanExpr) -> String {
 }
 
 fn main() {
-    let i = "\n    1+2+10000- \n3";
-    // uncomment below for an error example
+    // a valid input, replace the error example below with it
+    // let i = "\n    1+2+10000- \n3";
     let i = "\n    1+200000000000000000+a10000- \n3";
     let pe = parse_expr_ms(Span::new(i));
     println!("pe: {:?}\n", pe);
@@ -150,7 +153,7 @@ fn main() {
             );
             println!("raw s: {:?}", &s);
         }
-        Err(err) => Err(err).unwrap(),
+        Err(err) => panic!("{:?}", err),
     }
 }
 
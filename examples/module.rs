@@ -0,0 +1,39 @@
+use crust::bytecode::compile;
+use crust::module;
+use crust::parse::parse;
+use crust::vm;
+
+fn main() {
+    let src = "
+fn fact(n: i32) -> i32 {
+    if n < 2 {
+        return 1;
+    }
+    return n * fact(n - 1);
+}
+
+fn main() -> i32 {
+    println!(\"fact(5) = {}\", fact(5));
+    return fact(10);
+}
+";
+    let p = parse(src).unwrap();
+    let prog = compile(&p);
+
+    let path = std::env::temp_dir().join("fact.crbc");
+    module::save(&prog, &path).unwrap();
+    println!("wrote {}", path.display());
+
+    let m = match module::load(&path) {
+        Ok(m) => m,
+        Err(e) => return println!("{}", e),
+    };
+    let prog = m.program();
+    print!("{}", prog);
+    println!("{:?}", vm::run(&prog, "main", vec![]));
+
+    // a truncated module is rejected
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+    println!("{}", module::load(&path).unwrap_err());
+}
@@ -0,0 +1,53 @@
+use crust::ir::{self, eval, opt};
+use crust::parse::parse;
+
+// optimizes the program given as argument (or a builtin example) at the
+// level given, e.g., `-O2 --print-after=sccp --verify prog.crust`, prints
+// the optimized IR and runs its `main`
+fn main() {
+    let (opts, rest) = match opt::Options::parse(std::env::args().skip(1).collect()) {
+        Ok(r) => r,
+        Err(e) => return eprintln!("{}", e),
+    };
+    let src = match rest.first() {
+        Some(path) => std::fs::read_to_string(path).unwrap(),
+        None => "
+fn f(n: i32) -> i32 {
+    let k: i32 = 4;
+    let mut s: i32 = 0;
+    let mut i: i32 = 0;
+    while i < n {
+        if k > 3 {
+            s = s + i * k;
+        } else {
+            s = s - 1;
+        }
+        i = i + 1;
+    }
+    return s + i * k;
+}
+
+fn main() -> i32 {
+    return f(10);
+}
+"
+        .to_string(),
+    };
+    let p = match parse(&src) {
+        Ok(p) => p,
+        Err(d) => return print!("{}", d.render(&src)),
+    };
+    let mut m = match ir::lower(&p) {
+        Ok((m, warnings)) => {
+            warnings.iter().for_each(|d| print!("{}", d.render(&src)));
+            m
+        }
+        Err(diags) => return diags.iter().for_each(|d| print!("{}", d.render(&src))),
+    };
+    match opt::optimize(&mut m, &opts) {
+        Ok(log) => print!("{}", log),
+        Err(e) => return println!("invalid IR: {}", e),
+    }
+    print!("{}", m);
+    println!("{:?}", eval::run(&m, "main", vec![]));
+}
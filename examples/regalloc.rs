@@ -0,0 +1,49 @@
+use crust::ir::{self, regalloc};
+use crust::parse::parse;
+
+// prints the IR of the program given as argument (or of a builtin
+// example), then the register allocation of each function for the large
+// target and the spill statistics for each target
+fn main() {
+    let src = match std::env::args().nth(1) {
+        Some(path) => std::fs::read_to_string(path).unwrap(),
+        None => "
+fn f(a: i32, b: i32, c: i32) -> i32 {
+    let s: i32 = a + b * c;
+    return s + g(a) * g(b) + c;
+}
+
+fn g(x: i32) -> i32 {
+    return x * x;
+}
+
+fn main() -> i32 {
+    return f(1, 2, 3);
+}
+"
+        .to_string(),
+    };
+    let p = match parse(&src) {
+        Ok(p) => p,
+        Err(d) => return print!("{}", d.render(&src)),
+    };
+    let m = match ir::lower(&p) {
+        Ok((m, warnings)) => {
+            warnings.iter().for_each(|d| print!("{}", d.render(&src)));
+            m
+        }
+        Err(diags) => return diags.iter().for_each(|d| print!("{}", d.render(&src))),
+    };
+    print!("{}", m);
+    for f in &m.fns {
+        let a = regalloc::allocate(f, &regalloc::LARGE);
+        println!("\nfn {} on {}:", f.id.fragment, regalloc::LARGE.name);
+        print!("{}", a.render(&regalloc::LARGE));
+        if let Err(e) = regalloc::check(f, &regalloc::LARGE, &a) {
+            println!("invalid allocation: {}", e);
+        }
+    }
+    for t in &[regalloc::LARGE, regalloc::SMALL] {
+        print!("\n{}:\n{}", t.name, regalloc::report(&m, t));
+    }
+}
@@ -0,0 +1,6 @@
+use std::io::{stdin, stdout};
+
+fn main() {
+    let stdin = stdin();
+    crust::repl::run(stdin.lock(), stdout()).unwrap();
+}
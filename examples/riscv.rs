@@ -0,0 +1,58 @@
+use crust::backend::riscv::{self, sim};
+use crust::ir;
+use crust::parse::parse;
+
+// writes the RV32IM assembly of the program given as argument (or of a
+// builtin example), e.g., `cargo run --example riscv prog.crust > prog.s`;
+// with `--run`, executes it in the simulator instead, e.g., `cargo run
+// --example riscv -- --run prog.crust`
+fn main() {
+    let mut args: Vec<_> = std::env::args().skip(1).collect();
+    let run = args.first().is_some_and(|a| a == "--run");
+    if run {
+        args.remove(0);
+    }
+    let src = match args.first() {
+        Some(path) => std::fs::read_to_string(path).unwrap(),
+        None => "
+fn swap(a: &mut i32, b: &mut i32) {
+    let t: i32 = *a;
+    *a = *b;
+    *b = t;
+}
+
+fn main() -> i32 {
+    let mut x: i32 = 2;
+    let mut y: i32 = 3;
+    swap(&mut x, &mut y);
+    println!(\"x = {}, y = {}\", x, y);
+    return x ** y;
+}
+"
+        .to_string(),
+    };
+    let p = match parse(&src) {
+        Ok(p) => p,
+        Err(d) => return eprint!("{}", d.render(&src)),
+    };
+    let m = match ir::lower(&p) {
+        Ok((m, warnings)) => {
+            warnings.iter().for_each(|d| eprint!("{}", d.render(&src)));
+            m
+        }
+        Err(diags) => return diags.iter().for_each(|d| eprint!("{}", d.render(&src))),
+    };
+    let asm = riscv::emit(&m);
+    if !run {
+        return print!("{}", asm);
+    }
+    let prog = sim::assemble(&asm).unwrap();
+    match sim::run(&prog, None) {
+        Ok(r) => {
+            print!("{}", r.stdout);
+            eprint!("{}", r.stderr);
+            std::process::exit(r.status);
+        }
+        Err(t) => eprintln!("trap: {}", t),
+    }
+}
@@ -0,0 +1,30 @@
+use crust::parse::{parse, parse_expr_all};
+use crust::smallstep::{trace_expr, SmallStep};
+
+fn main() {
+    let e = parse_expr_all("(1 + 2) * 3 - 4 / 2").unwrap();
+    println!("{}\n", trace_expr(e));
+
+    let src = "
+fn double(x: i32) -> i32 {
+    return x + x;
+}
+
+fn main() -> i32 {
+    let mut a: i32 = 1;
+    while a < 4 {
+        a = double(a);
+    }
+    return a;
+}
+";
+    let p = parse(src).unwrap();
+    let m = SmallStep::new(&p, "main", vec![]).unwrap();
+    println!("{}", m.config());
+    for c in m {
+        match c {
+            Ok(c) => println!("→ {}", c),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
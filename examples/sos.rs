@@ -0,0 +1,28 @@
+use crust::parse::parse;
+use crust::sos::derive;
+
+const PROG: &str = "
+fn inc(x: i32) -> i32 {
+    return x + 1;
+}
+
+fn main() -> i32 {
+    let mut a: i32 = 0;
+    while a < 2 {
+        a = inc(a);
+    }
+    return a;
+}
+";
+
+fn main() {
+    let p = parse(PROG).unwrap();
+    match derive(&p, "main", vec![]) {
+        Ok(d) => {
+            println!("{}", d.to_text());
+            // wrap in \begin{mathpar} ... \end{mathpar} using the mathpartir package
+            println!("{}", d.to_latex());
+        }
+        Err(e) => println!("{}", e.diagnostic().render(PROG)),
+    }
+}
@@ -1,10 +1,12 @@
 extern crate nom;
 
 
-use crust::parse::test;
+use crust::eval::test;
 
+// the expected values are written as the expressions they check
+#[allow(clippy::identity_op)]
 fn main() {
-    test("- -1 + + 1", - -1 + 1);  // rust does not allow + as a unary op (I do ;)
+    test("- -1 + + 1", -(-1) + 1);  // rust does not allow + as a unary op (I do ;)
     test("(-1-1)+(-1+3)", (-1 - 1) + (-1) + 3);
     // just to check that right associative works (you don't need to implement pow)
     test("2+3**2**3*5+1", 2 + 3i32.pow(2u32.pow(3)) * 5 + 1);
@@ -0,0 +1,48 @@
+use std::time::Instant;
+
+use crust::bytecode::compile;
+use crust::interp;
+use crust::parse::parse;
+use crust::vm;
+
+fn main() {
+    let src = "
+fn collatz(n: i32) -> i32 {
+    let mut n: i32 = n;
+    let mut steps: i32 = 0;
+    while n != 1 {
+        if n - n / 2 * 2 == 0 {
+            n = n / 2;
+        } else {
+            n = 3 * n + 1;
+        }
+        steps = steps + 1;
+    }
+    return steps;
+}
+
+fn main() -> i32 {
+    let mut i: i32 = 1;
+    let mut max: i32 = 0;
+    while i < 3000 {
+        let s: i32 = collatz(i);
+        if s > max {
+            max = s;
+        }
+        i = i + 1;
+    }
+    return max;
+}
+";
+    let p = parse(src).unwrap();
+    let prog = compile(&p);
+    println!("{} instructions", prog.code.len());
+
+    let t = Instant::now();
+    let r = interp::run(&p, "main", vec![]);
+    println!("interp {:?} in {:?}", r, t.elapsed());
+
+    let t = Instant::now();
+    let r = vm::run(&prog, "main", vec![]);
+    println!("vm     {:?} in {:?}", r, t.elapsed());
+}
@@ -1,5 +1,7 @@
 // AST
 
+use std::fmt;
+
 use nom_locate::LocatedSpan;
 
 pub type Span<'a> = LocatedSpan<&'a str>;
@@ -8,6 +10,8 @@ pub type Span<'a> = LocatedSpan<&'a str>;
 pub enum Op {
     Eq,
     Neq,
+    Lt,
+    Gt,
     And,
     Or,
     Add,
@@ -16,18 +20,259 @@ pub enum Op {
     Div,
     Pow,
     Not,
+    // prefix `&`, `&mut` and `*`
+    Ref,
+    RefMut,
+    Deref,
 }
 
-type SpanOp<'a> = (Span<'a>, Op);
-
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr<'a> {
     Num(i32),
+    Bool(bool),
     Par(Box<SpanExpr<'a>>),
     // Identifier
+    Id(&'a str),
     // Function application
+    Call(&'a str, Vec<SpanExpr<'a>>),
     BinOp(Op, Box<SpanExpr<'a>>, Box<SpanExpr<'a>>),
     UnaryOp(Op, Box<SpanExpr<'a>>),
+    // the unit value `()`
+    Unit,
+    // Builtin macro, the arguments and the format string (without quotes),
+    // formatted arguments follow the fixed ones
+    Macro(Macro, Vec<SpanExpr<'a>>, Option<Span<'a>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Macro {
+    Println,
+    Assert,
+    AssertEq,
+    Panic,
+}
+
+impl Macro {
+    pub fn name(self) -> &'static str {
+        match self {
+            Macro::Println => "println",
+            Macro::Assert => "assert",
+            Macro::AssertEq => "assert_eq",
+            Macro::Panic => "panic",
+        }
+    }
+
+    // arguments preceding the format string
+    pub fn fixed(self) -> usize {
+        match self {
+            Macro::Assert => 1,
+            Macro::AssertEq => 2,
+            Macro::Println | Macro::Panic => 0,
+        }
+    }
 }
 
 pub type SpanExpr<'a> = (Span<'a>, Expr<'a>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    I32,
+    Bool,
+    Unit,
+    // &T (false) or &mut T (true)
+    Ref(bool, Box<Type>),
+}
+
+pub type SpanType<'a> = (Span<'a>, Type);
+
+// The span of a statement covers its full source text,
+// e.g., from `if` to the closing `}` of the else branch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt<'a> {
+    // let [mut] id: type = expr;
+    Let(bool, Span<'a>, SpanType<'a>, SpanExpr<'a>),
+    // lhs = expr;
+    Assign(SpanExpr<'a>, SpanExpr<'a>),
+    If(SpanExpr<'a>, SpanBlock<'a>, Option<SpanBlock<'a>>),
+    While(SpanExpr<'a>, SpanBlock<'a>),
+    Return(Option<SpanExpr<'a>>),
+    Expr(SpanExpr<'a>),
+    Block(SpanBlock<'a>),
+}
+
+pub type SpanStmt<'a> = (Span<'a>, Stmt<'a>);
+
+pub type Block<'a> = Vec<SpanStmt<'a>>;
+
+pub type SpanBlock<'a> = (Span<'a>, Block<'a>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param<'a> {
+    pub mutable: bool,
+    pub id: Span<'a>,
+    pub ty: SpanType<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FnDecl<'a> {
+    pub id: Span<'a>,
+    pub params: Vec<Param<'a>>,
+    // None for functions without `-> type`
+    pub ret: Option<SpanType<'a>>,
+    pub body: SpanBlock<'a>,
+}
+
+impl<'a> FnDecl<'a> {
+    pub fn ret_type(&self) -> Type {
+        self.ret.as_ref().map_or(Type::Unit, |(_, t)| t.clone())
+    }
+}
+
+pub type Prog<'a> = Vec<FnDecl<'a>>;
+
+// pretty printing, parentheses are only inserted where needed
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Op::Eq => "==",
+            Op::Neq => "!=",
+            Op::Lt => "<",
+            Op::Gt => ">",
+            Op::And => "&&",
+            Op::Or => "||",
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Pow => "**",
+            Op::Not => "!",
+            Op::Ref => "&",
+            Op::RefMut => "&mut ",
+            Op::Deref => "*",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// precedence and associativity of the binary operators, see `parse::climb`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Ass {
+    Left,
+    Right,
+}
+
+pub(crate) fn get_prec(op: &Op) -> Option<(u8, Ass)> {
+    match op {
+        Op::Or => Some((1, Ass::Left)),
+        Op::And => Some((2, Ass::Left)),
+        Op::Eq | Op::Neq | Op::Lt | Op::Gt => Some((3, Ass::Left)),
+        Op::Add => Some((4, Ass::Left)),
+        Op::Sub => Some((4, Ass::Left)),
+        Op::Mul => Some((5, Ass::Left)),
+        Op::Div => Some((5, Ass::Left)),
+        Op::Pow => Some((6, Ass::Right)),
+        Op::Not | Op::Ref | Op::RefMut | Op::Deref => None,
+    }
+}
+
+// writes the operand, parenthesized if binding weaker than `prec`
+// (or equally, on the non-associative side)
+fn operand(f: &mut fmt::Formatter, e: &Expr, prec: u8, side: Ass) -> fmt::Result {
+    match e {
+        Expr::BinOp(op, ..) => match get_prec(op) {
+            Some((p, ass)) if p < prec || (p == prec && ass != side) => write!(f, "({})", e),
+            _ => write!(f, "{}", e),
+        },
+        _ => write!(f, "{}", e),
+    }
+}
+
+impl<'a> fmt::Display for Expr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Num(i) => write!(f, "{}", i),
+            Expr::Bool(b) => write!(f, "{}", b),
+            Expr::Par(e) => write!(f, "({})", e.1),
+            Expr::Id(id) => write!(f, "{}", id),
+            Expr::Call(id, args) => {
+                let args: Vec<_> = args.iter().map(|(_, a)| a.to_string()).collect();
+                write!(f, "{}({})", id, args.join(", "))
+            }
+            Expr::BinOp(op, l, r) => {
+                let (prec, _) = get_prec(op).unwrap();
+                operand(f, &l.1, prec, Ass::Left)?;
+                write!(f, " {} ", op)?;
+                operand(f, &r.1, prec, Ass::Right)
+            }
+            Expr::UnaryOp(op, e) => {
+                write!(f, "{}", op)?;
+                operand(f, &e.1, u8::MAX, Ass::Left)
+            }
+            Expr::Unit => write!(f, "()"),
+            Expr::Macro(m, args, fmt) => {
+                let mut args: Vec<_> = args.iter().map(|(_, a)| a.to_string()).collect();
+                if let Some(fmt) = fmt {
+                    args.insert(m.fixed(), format!("\"{}\"", fmt.fragment));
+                }
+                write!(f, "{}!({})", m.name(), args.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::I32 => write!(f, "i32"),
+            Type::Bool => write!(f, "bool"),
+            Type::Unit => write!(f, "()"),
+            Type::Ref(false, t) => write!(f, "&{}", t),
+            Type::Ref(true, t) => write!(f, "&mut {}", t),
+        }
+    }
+}
+
+// statements print on a single line, blocks as `{ s1 s2 }`
+fn block(f: &mut fmt::Formatter, b: &Block) -> fmt::Result {
+    write!(f, "{{")?;
+    for (_, s) in b {
+        write!(f, " {}", s)?;
+    }
+    write!(f, "{}}}", if b.is_empty() { "" } else { " " })
+}
+
+impl<'a> fmt::Display for Stmt<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stmt::Let(m, id, (_, ty), (_, e)) => write!(
+                f,
+                "let {}{}: {} = {};",
+                if *m { "mut " } else { "" },
+                id.fragment,
+                ty,
+                e
+            ),
+            Stmt::Assign((_, l), (_, e)) => write!(f, "{} = {};", l, e),
+            Stmt::If((_, c), (_, t), e) => {
+                write!(f, "if {} ", c)?;
+                block(f, t)?;
+                match e {
+                    Some((_, e)) => {
+                        write!(f, " else ")?;
+                        block(f, e)
+                    }
+                    None => Ok(()),
+                }
+            }
+            Stmt::While((_, c), (_, b)) => {
+                write!(f, "while {} ", c)?;
+                block(f, b)
+            }
+            Stmt::Return(None) => write!(f, "return;"),
+            Stmt::Return(Some((_, e))) => write!(f, "return {};", e),
+            Stmt::Expr((_, e)) => write!(f, "{};", e),
+            Stmt::Block((_, b)) => block(f, b),
+        }
+    }
+}
@@ -0,0 +1,369 @@
+// C emission
+//
+// Writes a module as a single C99 file. Registers become locals of the
+// function and blocks labels; a phi node gets a second local `rN_in`,
+// assigned on each incoming edge and copied to `rN` at the start of its
+// block, so that the phis of a block are assigned in parallel. Stack
+// slots are locals `sN` whose address is taken.
+//
+// Arithmetic goes through the `crust_*` helpers, which compute in 64 bits
+// and fail like the interpreter; failing prints the message and exits.
+// Functions are named `fn_<name>`, and `main` exits with the result of
+// `fn_main`. Exclusive reference parameters (see `ir::alias`) are
+// `restrict`, and shared references point to `const`.
+
+use super::{error_message, pieces, Piece, ERROR_STATUS};
+use crate::ast::{Op, Span, Type};
+use crate::interp::ErrorKind;
+use crate::ir::alias::exclusive_params;
+use crate::ir::{Const, Function, InstKind, Module, Reg, Term};
+
+const PRELUDE: &str = "\
+#include <inttypes.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+typedef uint8_t crust_unit;
+
+static void crust_fail(const char *msg)
+{
+    fputs(msg, stderr);
+    exit(101);
+}
+
+static inline int32_t crust_checked(int64_t r, const char *msg)
+{
+    if (r < INT32_MIN || r > INT32_MAX)
+        crust_fail(msg);
+    return (int32_t)r;
+}
+
+static inline int32_t crust_add(int32_t a, int32_t b, const char *msg)
+{
+    return crust_checked((int64_t)a + b, msg);
+}
+
+static inline int32_t crust_sub(int32_t a, int32_t b, const char *msg)
+{
+    return crust_checked((int64_t)a - b, msg);
+}
+
+static inline int32_t crust_mul(int32_t a, int32_t b, const char *msg)
+{
+    return crust_checked((int64_t)a * b, msg);
+}
+
+static inline int32_t crust_neg(int32_t a, const char *msg)
+{
+    return crust_checked(-(int64_t)a, msg);
+}
+
+static inline int32_t crust_div(int32_t a, int32_t b, const char *zero, const char *msg)
+{
+    if (b == 0)
+        crust_fail(zero);
+    return crust_checked((int64_t)a / b, msg);
+}
+
+/* `checked_pow`, by squaring */
+static inline int32_t crust_pow(int32_t a, int32_t b, const char *negative, const char *msg)
+{
+    int32_t acc = 1, base = a;
+    if (b < 0)
+        crust_fail(negative);
+    if (b == 0)
+        return 1;
+    while (b > 1) {
+        if (b & 1)
+            acc = crust_mul(acc, base, msg);
+        b /= 2;
+        base = crust_mul(base, base, msg);
+    }
+    return crust_mul(acc, base, msg);
+}
+";
+
+// the C source of a module
+pub fn emit(m: &Module) -> String {
+    let exclusive = exclusive_params(m);
+    let mut s = String::from("/* crust module */\n");
+    s += PRELUDE;
+    s.push('\n');
+    let protos: Vec<_> = (m.fns.iter().zip(&exclusive))
+        .map(|(f, ex)| prototype(f, ex))
+        .collect();
+    for p in &protos {
+        s += &format!("static {};\n", p);
+    }
+    for (f, p) in m.fns.iter().zip(&protos) {
+        s += &format!("\nstatic {}\n{{\n", p);
+        s += &body(m, f);
+        s += "}\n";
+    }
+    if let Some(main) = m.function("main").map(|g| &m.fns[g]) {
+        if main.params.is_empty() {
+            s += "\nint main(void)\n{\n";
+            s += match main.ret {
+                Type::I32 | Type::Bool => "    return fn_main();\n",
+                _ => "    fn_main();\n    return 0;\n",
+            };
+            s += "}\n";
+        }
+    }
+    s
+}
+
+pub fn ty(t: &Type) -> String {
+    match t {
+        Type::I32 => "int32_t".to_string(),
+        Type::Bool => "bool".to_string(),
+        Type::Unit => "crust_unit".to_string(),
+        Type::Ref(true, t) => format!("{} *", ty(t)),
+        Type::Ref(false, t) => format!("{} const *", ty(t)),
+    }
+}
+
+// a declaration of `name` with type `t`
+fn decl(t: &Type, name: &str) -> String {
+    let t = ty(t);
+    match t.ends_with('*') {
+        true => format!("{}{}", t, name),
+        false => format!("{} {}", t, name),
+    }
+}
+
+fn prototype(f: &Function, exclusive: &[bool]) -> String {
+    let params: Vec<_> = (f.params.iter().zip(exclusive))
+        .map(|(p, ex)| match *ex {
+            true => format!("{}restrict r{}", ty(&f.regs[*p]), p),
+            false => decl(&f.regs[*p], &format!("r{}", p)),
+        })
+        .collect();
+    let params = match params.is_empty() {
+        true => "void".to_string(),
+        false => params.join(", "),
+    };
+    decl(&f.ret, &format!("fn_{}({})", f.id.fragment, params))
+}
+
+// a C string literal
+fn string(s: &str) -> String {
+    let mut lit = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' => lit += "\\\"",
+            b'\\' => lit += "\\\\",
+            b'\n' => lit += "\\n",
+            0x20..=0x7e => lit.push(b as char),
+            _ => lit += &format!("\\{:03o}", b),
+        }
+    }
+    lit + "\""
+}
+
+fn literal(c: Const) -> String {
+    match c {
+        Const::Int(i32::MIN) => "INT32_MIN".to_string(),
+        Const::Int(n) => n.to_string(),
+        Const::Bool(b) => b.to_string(),
+        Const::Unit => "0".to_string(),
+    }
+}
+
+// printf arguments for a crust format string, the format first
+fn format(f: &Function, fmt: &str, args: &[Reg]) -> Vec<String> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut vals = vec![];
+    for piece in pieces(fmt) {
+        match piece {
+            Piece::Text(t) => text += &t.replace('%', "%%"),
+            Piece::Arg(i) => match args.get(i).map(|a| (&f.regs[*a], *a)) {
+                Some((Type::I32, a)) => {
+                    parts.push(string(&(std::mem::take(&mut text) + "%")) + " PRId32");
+                    vals.push(format!("r{}", a));
+                }
+                Some((Type::Bool, a)) => {
+                    text += "%s";
+                    vals.push(format!("r{} ? \"true\" : \"false\"", a));
+                }
+                Some(_) => text += "()",
+                None => (),
+            },
+        }
+    }
+    parts.push(string(&text));
+    let mut r = vec![parts.join(" ")];
+    r.extend(vals);
+    r
+}
+
+// appends an indented line
+fn line(s: &mut String, l: String) {
+    s.push_str("    ");
+    s.push_str(&l);
+    s.push('\n');
+}
+
+fn body(m: &Module, f: &Function) -> String {
+    let mut s = String::new();
+    for (r, t) in f
+        .regs
+        .iter()
+        .enumerate()
+        .filter(|(r, _)| !f.params.contains(r))
+    {
+        line(&mut s, format!("{};", decl(t, &format!("r{}", r))));
+    }
+    for block in &f.blocks {
+        for phi in &block.phis {
+            line(
+                &mut s,
+                format!("{};", decl(&f.regs[phi.dst], &format!("r{}_in", phi.dst))),
+            );
+        }
+        for inst in &block.insts {
+            if let (Some(d), InstKind::Alloca(t)) = (inst.dst, &inst.kind) {
+                line(&mut s, format!("{};", decl(t, &format!("s{}", d))));
+            }
+        }
+    }
+    let msg = |span: Span, kind: ErrorKind| string(&error_message(span, &kind));
+    let mut out = String::new();
+    for (b, block) in f.blocks.iter().enumerate() {
+        // the entry has no predecessors
+        if b > 0 {
+            out += &format!("b{}:;\n", b);
+        }
+        for phi in &block.phis {
+            line(&mut out, format!("r{} = r{}_in;", phi.dst, phi.dst));
+        }
+        for inst in &block.insts {
+            let d = inst.dst.unwrap_or(0);
+            let span = inst.span;
+            let value = match &inst.kind {
+                InstKind::Const(c) => literal(*c),
+                InstKind::Copy(r) => format!("r{}", r),
+                InstKind::Bin(op, l, _) if f.regs[*l] == Type::Unit => (*op == Op::Eq).to_string(),
+                InstKind::Bin(op, l, r) => match op {
+                    Op::Add | Op::Sub | Op::Mul => {
+                        let name = match op {
+                            Op::Add => "add",
+                            Op::Sub => "sub",
+                            _ => "mul",
+                        };
+                        let msg = msg(span, ErrorKind::Overflow(*op));
+                        format!("crust_{}(r{}, r{}, {})", name, l, r, msg)
+                    }
+                    Op::Div => format!(
+                        "crust_div(r{}, r{}, {}, {})",
+                        l,
+                        r,
+                        msg(span, ErrorKind::DivisionByZero),
+                        msg(span, ErrorKind::Overflow(*op))
+                    ),
+                    Op::Pow => format!(
+                        "crust_pow(r{}, r{}, {}, {})",
+                        l,
+                        r,
+                        msg(span, ErrorKind::NegativeExponent),
+                        msg(span, ErrorKind::Overflow(*op))
+                    ),
+                    _ => {
+                        let op = match op {
+                            Op::Lt => "<",
+                            Op::Gt => ">",
+                            Op::Eq => "==",
+                            Op::Neq => "!=",
+                            Op::And => "&&",
+                            _ => "||",
+                        };
+                        format!("r{} {} r{}", l, op, r)
+                    }
+                },
+                InstKind::Un(Op::Not, r) => format!("!r{}", r),
+                InstKind::Un(_, r) => {
+                    format!("crust_neg(r{}, {})", r, msg(span, ErrorKind::NegOverflow))
+                }
+                InstKind::Call(g, args) => {
+                    let args: Vec<_> = args.iter().map(|a| format!("r{}", a)).collect();
+                    format!("fn_{}({})", m.fns[*g].id.fragment, args.join(", "))
+                }
+                InstKind::Alloca(_) => format!("&s{}", d),
+                InstKind::Load(p) => format!("*r{}", p),
+                InstKind::Store(p, r) => {
+                    line(&mut out, format!("*r{} = r{};", p, r));
+                    continue;
+                }
+                InstKind::Print(fmt, args) => {
+                    let args = format(f, &(fmt.clone() + "\n"), args);
+                    line(&mut out, format!("printf({});", args.join(", ")));
+                    continue;
+                }
+            };
+            match inst.dst {
+                Some(d) => line(&mut out, format!("r{} = {};", d, value)),
+                None => line(&mut out, format!("{};", value)),
+            }
+        }
+        // assigns the phi nodes of `t` for the edge from `b`
+        let edge = |t: usize| -> String {
+            let mut s = String::new();
+            for phi in &f.blocks[t].phis {
+                let (_, r) = phi.args.iter().find(|(p, _)| *p == b).unwrap();
+                s += &format!("r{}_in = r{}; ", phi.dst, r);
+            }
+            s + &format!("goto b{};", t)
+        };
+        match &block.term {
+            Term::Jump(t) => line(&mut out, edge(*t)),
+            Term::Branch(c, t, e) => {
+                line(&mut out, format!("if (r{}) {{ {} }}", c, edge(*t)));
+                line(&mut out, format!("else {{ {} }}", edge(*e)));
+            }
+            Term::Return(r) => line(&mut out, format!("return r{};", r)),
+            Term::Panic(fmt, args, span) => {
+                // the message of `ErrorKind::Panic`, still to be formatted
+                let fmt = error_message(*span, &ErrorKind::Panic(fmt.clone()));
+                let args = format(f, &fmt, args);
+                line(&mut out, format!("fprintf(stderr, {});", args.join(", ")));
+                line(&mut out, format!("exit({});", ERROR_STATUS));
+            }
+            Term::Unreachable => line(&mut out, "abort();".to_string()),
+        }
+    }
+    s + &out
+}
+
+#[test]
+fn test_cc() {
+    use super::{execute, interpret, lowered_programs};
+    use std::process::Command;
+
+    // skipped where no C compiler is installed
+    if Command::new("cc").arg("--version").output().is_err() {
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("crust-c-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, p, m) in &lowered_programs() {
+        let (c, exe) = (dir.join(format!("{}.c", name)), dir.join(name));
+        std::fs::write(&c, emit(m)).unwrap();
+        let out = (Command::new("cc").args(["-std=c99", "-pedantic-errors", "-O2", "-o"]))
+            .arg(&exe)
+            .arg(&c)
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let r = execute(&mut Command::new(&exe)).unwrap();
+        assert_eq!(r, Some(interpret(p)), "{}", name);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
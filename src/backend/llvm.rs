@@ -0,0 +1,493 @@
+// LLVM IR emission
+//
+// Writes a module as textual LLVM IR, with typed pointers as in LLVM 14:
+// `i32` and `i1` registers, `{}` for unit and pointers to `alloca` slots
+// for references. Registers and phi nodes map directly, constants and
+// copies are substituted at their uses. Arithmetic is checked with the
+// `llvm.*.with.overflow` intrinsics; a failing check calls `rt.check`,
+// which prints the message and exits. Functions are named `crust.<name>`,
+// runtime helpers `rt.<name>`, and a C `main` exits with the result of
+// `crust.main`.
+//
+// Reference parameters are `noalias` when `ir::alias` proves them
+// exclusive, and shared ones `readonly`, as the lowering never stores
+// through a `&T`.
+
+use super::{error_message, pieces, Piece, ERROR_STATUS};
+use crate::ast::{Op, Span, Type};
+use crate::interp::ErrorKind;
+use crate::ir::alias::exclusive_params;
+use crate::ir::{Const, Function, InstKind, Module, Reg, Term};
+
+const DECLS: &str = "\
+declare i32 @printf(i8*, ...)
+declare i32 @dprintf(i32, i8*, ...)
+declare void @exit(i32) noreturn
+declare {i32, i1} @llvm.sadd.with.overflow.i32(i32, i32)
+declare {i32, i1} @llvm.ssub.with.overflow.i32(i32, i32)
+declare {i32, i1} @llvm.smul.with.overflow.i32(i32, i32)
+";
+
+// `rt.check` fails with a message when its condition holds,
+// `rt.pow` is `checked_pow` (by squaring) with an overflow flag
+const HELPERS: &str = "\
+@.fmt.s = private unnamed_addr constant [3 x i8] c\"%s\\00\"
+
+define internal void @rt.check(i1 %fail, i8* %msg) {
+  br i1 %fail, label %error, label %ok
+error:
+  %f = getelementptr inbounds [3 x i8], [3 x i8]* @.fmt.s, i64 0, i64 0
+  %n = call i32 (i32, i8*, ...) @dprintf(i32 2, i8* %f, i8* %msg)
+  call void @exit(i32 101)
+  unreachable
+ok:
+  ret void
+}
+
+define internal {i32, i1} @rt.pow(i32 %b, i32 %e) {
+entry:
+  br label %loop
+loop:
+  %acc = phi i32 [1, %entry], [%acc.n, %body]
+  %base = phi i32 [%b, %entry], [%base.n, %body]
+  %exp = phi i32 [%e, %entry], [%exp.n, %body]
+  %o = phi i1 [false, %entry], [%o.n, %body]
+  %more = icmp sgt i32 %exp, 1
+  br i1 %more, label %body, label %last
+body:
+  %odd = trunc i32 %exp to i1
+  %m = call {i32, i1} @llvm.smul.with.overflow.i32(i32 %acc, i32 %base)
+  %m.v = extractvalue {i32, i1} %m, 0
+  %m.o = extractvalue {i32, i1} %m, 1
+  %acc.n = select i1 %odd, i32 %m.v, i32 %acc
+  %o.m = and i1 %odd, %m.o
+  %s = call {i32, i1} @llvm.smul.with.overflow.i32(i32 %base, i32 %base)
+  %base.n = extractvalue {i32, i1} %s, 0
+  %s.o = extractvalue {i32, i1} %s, 1
+  %exp.n = lshr i32 %exp, 1
+  %o.1 = or i1 %o, %o.m
+  %o.n = or i1 %o.1, %s.o
+  br label %loop
+last:
+  %zero = icmp eq i32 %exp, 0
+  %l = call {i32, i1} @llvm.smul.with.overflow.i32(i32 %acc, i32 %base)
+  %l.v = extractvalue {i32, i1} %l, 0
+  %l.o = extractvalue {i32, i1} %l, 1
+  %v = select i1 %zero, i32 1, i32 %l.v
+  %o.l = or i1 %o, %l.o
+  %o.r = select i1 %zero, i1 false, i1 %o.l
+  %r.0 = insertvalue {i32, i1} undef, i32 %v, 0
+  %r = insertvalue {i32, i1} %r.0, i1 %o.r, 1
+  ret {i32, i1} %r
+}
+";
+
+struct Emitter {
+    // private string constants, without the terminating nul
+    strings: Vec<String>,
+    out: String,
+    // `%tN` temporaries of the current function
+    temps: usize,
+}
+
+// the textual IR of a module
+pub fn emit(m: &Module) -> String {
+    let mut e = Emitter {
+        strings: vec![],
+        out: String::new(),
+        temps: 0,
+    };
+    let exclusive = exclusive_params(m);
+    for (f, ex) in m.fns.iter().zip(&exclusive) {
+        e.function(m, f, ex);
+    }
+    if let Some(main) = m.function("main").map(|g| &m.fns[g]) {
+        if main.params.is_empty() {
+            e.out += "define i32 @main() {\n";
+            e.out += &format!("  %r = call {} @crust.main()\n", ty(&main.ret));
+            e.out += match main.ret {
+                Type::I32 => "  ret i32 %r\n",
+                Type::Bool => "  %s = zext i1 %r to i32\n  ret i32 %s\n",
+                _ => "  ret i32 0\n",
+            };
+            e.out += "}\n";
+        }
+    }
+
+    let mut s = String::from("; crust module\nsource_filename = \"crust\"\n\n");
+    for (i, c) in e.strings.iter().enumerate() {
+        s += &format!(
+            "@.str.{} = private unnamed_addr constant [{} x i8] c\"{}\\00\"\n",
+            i,
+            c.len() + 1,
+            escape(c)
+        );
+    }
+    if !e.strings.is_empty() {
+        s.push('\n');
+    }
+    s += DECLS;
+    s.push('\n');
+    s += HELPERS;
+    s.push('\n');
+    s + &e.out
+}
+
+pub fn ty(t: &Type) -> String {
+    match t {
+        Type::I32 => "i32".to_string(),
+        Type::Bool => "i1".to_string(),
+        Type::Unit => "{}".to_string(),
+        Type::Ref(_, t) => format!("{}*", ty(t)),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'"' | b'\\' => format!("\\{:02X}", b),
+            0x20..=0x7e => (b as char).to_string(),
+            _ => format!("\\{:02X}", b),
+        })
+        .collect()
+}
+
+// the textual operand of each register, with constants and copies
+// replaced by their values
+fn operands(f: &Function) -> Vec<String> {
+    let mut vals: Vec<_> = (0..f.regs.len()).map(|r| format!("%r{}", r)).collect();
+    let mut copies = vec![];
+    for inst in f.blocks.iter().flat_map(|b| &b.insts) {
+        match (inst.dst, &inst.kind) {
+            (Some(d), InstKind::Const(c)) => vals[d] = literal(*c),
+            (Some(d), InstKind::Copy(r)) => copies.push((d, *r)),
+            // unit values are all equal
+            (Some(d), InstKind::Bin(op, l, _)) if f.regs[*l] == Type::Unit => {
+                vals[d] = (*op == Op::Eq).to_string();
+            }
+            _ => (),
+        }
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (d, r) in &copies {
+            if vals[*d] != vals[*r] {
+                vals[*d] = vals[*r].clone();
+                changed = true;
+            }
+        }
+    }
+    vals
+}
+
+fn literal(c: Const) -> String {
+    match c {
+        Const::Int(n) => n.to_string(),
+        Const::Bool(b) => b.to_string(),
+        Const::Unit => "zeroinitializer".to_string(),
+    }
+}
+
+impl Emitter {
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("%t{}", self.temps - 1)
+    }
+
+    fn line(&mut self, s: String) {
+        self.out += "  ";
+        self.out += &s;
+        self.out.push('\n');
+    }
+
+    // a pointer to a nul-terminated copy of `s`
+    fn string(&mut self, s: &str) -> String {
+        let i = match self.strings.iter().position(|c| c == s) {
+            Some(i) => i,
+            None => {
+                self.strings.push(s.to_string());
+                self.strings.len() - 1
+            }
+        };
+        let n = s.len() + 1;
+        format!(
+            "getelementptr inbounds ([{} x i8], [{} x i8]* @.str.{}, i64 0, i64 0)",
+            n, n, i
+        )
+    }
+
+    // fails with the error when `cond` holds
+    fn check(&mut self, cond: &str, span: Span, kind: ErrorKind) {
+        let msg = self.string(&error_message(span, &kind));
+        self.line(format!("call void @rt.check(i1 {}, i8* {})", cond, msg));
+    }
+
+    // `op` with overflow detection, the result goes to `dst`
+    fn overflow(
+        &mut self,
+        intrinsic: &str,
+        l: &str,
+        r: &str,
+        dst: Reg,
+        span: Span,
+        kind: ErrorKind,
+    ) {
+        let (t, o) = (self.temp(), self.temp());
+        self.line(format!(
+            "{} = call {{i32, i1}} @{}(i32 {}, i32 {})",
+            t, intrinsic, l, r
+        ));
+        self.line(format!("%r{} = extractvalue {{i32, i1}} {}, 0", dst, t));
+        self.line(format!("{} = extractvalue {{i32, i1}} {}, 1", o, t));
+        self.check(&o, span, kind);
+    }
+
+    // a printf format for a crust format string, and its arguments
+    fn format(
+        &mut self,
+        f: &Function,
+        vals: &[String],
+        fmt: &str,
+        args: &[Reg],
+    ) -> (String, Vec<String>) {
+        let mut s = String::new();
+        let mut typed = vec![];
+        for piece in pieces(fmt) {
+            match piece {
+                Piece::Text(t) => s += &t.replace('%', "%%"),
+                Piece::Arg(i) => match args.get(i).map(|a| (&f.regs[*a], &vals[*a])) {
+                    Some((Type::I32, v)) => {
+                        s += "%d";
+                        typed.push(format!("i32 {}", v));
+                    }
+                    Some((Type::Bool, v)) => {
+                        let (yes, no) = (self.string("true"), self.string("false"));
+                        let t = self.temp();
+                        self.line(format!("{} = select i1 {}, i8* {}, i8* {}", t, v, yes, no));
+                        s += "%s";
+                        typed.push(format!("i8* {}", t));
+                    }
+                    Some(_) => s += "()",
+                    None => (),
+                },
+            }
+        }
+        (s, typed)
+    }
+
+    fn function(&mut self, m: &Module, f: &Function, exclusive: &[bool]) {
+        self.temps = 0;
+        let vals = operands(f);
+        let params: Vec<_> = (f.params.iter().zip(exclusive))
+            .map(|(p, ex)| {
+                let attrs = match &f.regs[*p] {
+                    Type::Ref(true, _) if *ex => " noalias",
+                    Type::Ref(false, _) if *ex => " noalias readonly",
+                    Type::Ref(false, _) => " readonly",
+                    _ => "",
+                };
+                format!("{}{} %r{}", ty(&f.regs[*p]), attrs, p)
+            })
+            .collect();
+        self.out += &format!(
+            "define internal {} @crust.{}({}) {{\n",
+            ty(&f.ret),
+            f.id.fragment,
+            params.join(", ")
+        );
+        let t = |r: &Reg| ty(&f.regs[*r]);
+        for (b, block) in f.blocks.iter().enumerate() {
+            self.out += &format!("b{}:\n", b);
+            for phi in &block.phis {
+                let args: Vec<_> = (phi.args.iter())
+                    .map(|(p, r)| format!("[{}, %b{}]", vals[*r], p))
+                    .collect();
+                self.line(format!(
+                    "%r{} = phi {} {}",
+                    phi.dst,
+                    t(&phi.dst),
+                    args.join(", ")
+                ));
+            }
+            for inst in &block.insts {
+                let v = |r: &Reg| vals[*r].as_str();
+                let d = inst.dst.unwrap_or(0);
+                let span = inst.span;
+                match &inst.kind {
+                    InstKind::Const(_) | InstKind::Copy(_) => (),
+                    InstKind::Bin(_, l, _) if f.regs[*l] == Type::Unit => (),
+                    InstKind::Bin(op, l, r) => match op {
+                        Op::Add | Op::Sub | Op::Mul => {
+                            let name = match op {
+                                Op::Add => "llvm.sadd.with.overflow.i32",
+                                Op::Sub => "llvm.ssub.with.overflow.i32",
+                                _ => "llvm.smul.with.overflow.i32",
+                            };
+                            self.overflow(name, v(l), v(r), d, span, ErrorKind::Overflow(*op));
+                        }
+                        Op::Div => {
+                            let zero = self.temp();
+                            self.line(format!("{} = icmp eq i32 {}, 0", zero, v(r)));
+                            self.check(&zero, span, ErrorKind::DivisionByZero);
+                            let (min, neg, both) = (self.temp(), self.temp(), self.temp());
+                            self.line(format!("{} = icmp eq i32 {}, -2147483648", min, v(l)));
+                            self.line(format!("{} = icmp eq i32 {}, -1", neg, v(r)));
+                            self.line(format!("{} = and i1 {}, {}", both, min, neg));
+                            self.check(&both, span, ErrorKind::Overflow(Op::Div));
+                            self.line(format!("%r{} = sdiv i32 {}, {}", d, v(l), v(r)));
+                        }
+                        Op::Pow => {
+                            let neg = self.temp();
+                            self.line(format!("{} = icmp slt i32 {}, 0", neg, v(r)));
+                            self.check(&neg, span, ErrorKind::NegativeExponent);
+                            self.overflow("rt.pow", v(l), v(r), d, span, ErrorKind::Overflow(*op));
+                        }
+                        _ => {
+                            let inst = match op {
+                                Op::Lt => "icmp slt",
+                                Op::Gt => "icmp sgt",
+                                Op::Eq => "icmp eq",
+                                Op::Neq => "icmp ne",
+                                Op::And => "and",
+                                _ => "or",
+                            };
+                            self.line(format!("%r{} = {} {} {}, {}", d, inst, t(l), v(l), v(r)));
+                        }
+                    },
+                    InstKind::Un(Op::Not, r) => {
+                        self.line(format!("%r{} = xor i1 {}, true", d, v(r)))
+                    }
+                    InstKind::Un(_, r) => {
+                        let name = "llvm.ssub.with.overflow.i32";
+                        self.overflow(name, "0", v(r), d, span, ErrorKind::NegOverflow);
+                    }
+                    InstKind::Call(g, args) => {
+                        let g = &m.fns[*g];
+                        let args: Vec<_> = (g.params.iter().zip(args))
+                            .map(|(p, a)| format!("{} {}", ty(&g.regs[*p]), v(a)))
+                            .collect();
+                        let call = format!(
+                            "call {} @crust.{}({})",
+                            ty(&g.ret),
+                            g.id.fragment,
+                            args.join(", ")
+                        );
+                        match inst.dst {
+                            Some(d) => self.line(format!("%r{} = {}", d, call)),
+                            None => self.line(call),
+                        }
+                    }
+                    InstKind::Alloca(a) => self.line(format!("%r{} = alloca {}", d, ty(a))),
+                    InstKind::Load(p) => {
+                        let pointee = ty(&f.regs[d]);
+                        self.line(format!("%r{} = load {}, {}* {}", d, pointee, pointee, v(p)));
+                    }
+                    InstKind::Store(p, r) => {
+                        self.line(format!("store {} {}, {} {}", t(r), v(r), t(p), v(p)));
+                    }
+                    InstKind::Print(fmt, args) => {
+                        let (fmt, args) = self.format(f, &vals, fmt, args);
+                        let fmt = self.string(&(fmt + "\n"));
+                        let t = self.temp();
+                        self.line(format!(
+                            "{} = call i32 (i8*, ...) @printf(i8* {}{})",
+                            t,
+                            fmt,
+                            args.iter().map(|a| format!(", {}", a)).collect::<String>()
+                        ));
+                    }
+                }
+            }
+            match &block.term {
+                Term::Jump(b) => self.line(format!("br label %b{}", b)),
+                Term::Branch(c, then, els) => self.line(format!(
+                    "br i1 {}, label %b{}, label %b{}",
+                    vals[*c], then, els
+                )),
+                Term::Return(r) => self.line(format!("ret {} {}", ty(&f.ret), vals[*r])),
+                Term::Panic(fmt, args, span) => {
+                    let (fmt, args) = self.format(f, &vals, fmt, args);
+                    // the message of `ErrorKind::Panic`, escaped for printf
+                    let kind = ErrorKind::Panic(fmt);
+                    let fmt = self.string(&error_message(*span, &kind));
+                    let t = self.temp();
+                    self.line(format!(
+                        "{} = call i32 (i32, i8*, ...) @dprintf(i32 2, i8* {}{})",
+                        t,
+                        fmt,
+                        args.iter().map(|a| format!(", {}", a)).collect::<String>()
+                    ));
+                    self.line(format!("call void @exit(i32 {})", ERROR_STATUS));
+                    self.line("unreachable".to_string());
+                }
+                Term::Unreachable => self.line("unreachable".to_string()),
+            }
+        }
+        self.out += "}\n\n";
+    }
+}
+
+#[test]
+fn test_emit() {
+    let src = "
+fn swap(a: &mut i32, b: &mut i32) {
+    let t: i32 = *a;
+    *a = *b;
+    *b = t;
+}
+
+fn get(a: &mut i32, b: &i32, c: &i32) -> i32 {
+    *a = *b + *c;
+    return *a;
+}
+
+fn main() -> i32 {
+    let mut x: i32 = 1;
+    let mut y: i32 = 2;
+    swap(&mut x, &mut y);
+    return get(&mut x, &x, &y);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = crate::ir::lower(&p).unwrap().0;
+    let ll = emit(&m);
+    assert!(ll.contains("define internal {} @crust.swap(i32* noalias %r0, i32* noalias %r1) {"));
+    // `x` is passed twice
+    assert!(ll.contains(
+        "define internal i32 @crust.get(i32* %r0, i32* readonly %r1, i32* noalias readonly %r2) {"
+    ));
+    assert!(ll.contains("call {i32, i1} @llvm.sadd.with.overflow.i32("));
+    assert!(ll.contains("c\"error: attempt to add with overflow at 9:13\\0A\\00\""));
+}
+
+#[test]
+fn test_lli() {
+    use super::{execute, interpret, lowered_programs};
+    use std::process::Command;
+
+    // skipped where LLVM is not installed
+    if Command::new("lli").arg("--version").output().is_err() {
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("crust-llvm-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, p, m) in &lowered_programs() {
+        let expected = interpret(p);
+        let ll = dir.join(format!("{}.ll", name));
+        std::fs::write(&ll, emit(m)).unwrap();
+        let r = execute(Command::new("lli").arg(&ll)).unwrap();
+        assert_eq!(r.as_ref(), Some(&expected), "{}", name);
+        // still correct after optimizations relying on the attributes
+        let opt = dir.join(format!("{}.opt.ll", name));
+        let status = (Command::new("opt").args(["-O2", "-S", "-o"]))
+            .arg(&opt)
+            .arg(&ll)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{}", name);
+        let r = execute(Command::new("lli").arg(&opt)).unwrap();
+        assert_eq!(r, Some(expected), "{}", name);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
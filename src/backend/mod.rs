@@ -0,0 +1,244 @@
+// Native code generation
+//
+// Backends translate the IR of checked programs. A compiled program runs
+// `main` and exits with its result (truncated to a byte, booleans as 0
+// and 1, unit as 0); `println!` writes to the standard output, and a
+// runtime error writes its message to the standard error, as
+// "error: attempt to add with overflow at 3:14", and exits with status
+// 101.
+
+use std::io;
+use std::process::Command;
+
+use crate::ast::{Prog, Span};
+use crate::interp::{ErrorKind, Interp, Value};
+
+pub mod c;
+pub mod llvm;
+pub mod riscv;
+pub mod wasm;
+pub mod x86;
+
+pub const ERROR_STATUS: i32 = 101;
+
+// the line a compiled program prints for a runtime error
+pub fn error_message(span: Span, kind: &ErrorKind) -> String {
+    format!("error: {} at {}:{}\n", kind, span.line, span.get_column())
+}
+
+// a GNU assembler string literal
+pub fn ascii(s: &str) -> String {
+    let mut lit = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' => lit += "\\\"",
+            b'\\' => lit += "\\\\",
+            0x20..=0x7e => lit.push(b as char),
+            _ => lit += &format!("\\{:03o}", b),
+        }
+    }
+    lit + "\""
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece {
+    Text(String),
+    // the index of the argument
+    Arg(usize),
+}
+
+// splits a format string of `println!` or `panic!` at its `{}`,
+// unescaping `{{` and `}}` as `interp::format` does
+pub fn pieces(fmt: &str) -> Vec<Piece> {
+    let mut pieces = vec![];
+    let mut text = String::new();
+    let mut args = 0;
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('}')) => {
+                chars.next();
+                if !text.is_empty() {
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(Piece::Arg(args));
+                args += 1;
+            }
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                text.push(c);
+            }
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    pieces
+}
+
+// what running a program shows: exit status, standard output and error
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+// the outcome of a compiled program, as predicted by the interpreter
+pub fn interpret(p: &Prog) -> Outcome {
+    let mut out = vec![];
+    let mut i = Interp::new(p);
+    i.set_output(Box::new(&mut out));
+    let r = i.call("main", vec![]);
+    drop(i);
+    let stdout = String::from_utf8(out).unwrap();
+    let (status, stderr) = match r {
+        Ok(Value::Num(n)) => (n & 0xff, String::new()),
+        Ok(Value::Bool(b)) => (b as i32, String::new()),
+        Ok(_) => (0, String::new()),
+        Err(e) => (ERROR_STATUS, error_message(e.span, &e.kind)),
+    };
+    Outcome {
+        status,
+        stdout,
+        stderr,
+    }
+}
+
+// runs a compiled program, None if it was killed by a signal
+pub fn execute(cmd: &mut Command) -> io::Result<Option<Outcome>> {
+    let out = cmd.output()?;
+    Ok(out.status.code().map(|status| Outcome {
+        status,
+        stdout: String::from_utf8_lossy(&out.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+    }))
+}
+
+// the programs the backends are tested on, with their names: those in
+// programs/ and some using references, printing and failing assertions
+#[cfg(test)]
+pub fn test_programs() -> Vec<(String, String)> {
+    let programs = concat!(env!("CARGO_MANIFEST_DIR"), "/programs");
+    let mut srcs: Vec<_> = (std::fs::read_dir(programs).unwrap())
+        .map(|e| e.unwrap().path())
+        .map(|p| {
+            let name = p.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, std::fs::read_to_string(&p).unwrap())
+        })
+        .collect();
+    srcs.sort();
+    srcs.push(("refs".to_string(), REFS.to_string()));
+    srcs.push((
+        "assert".to_string(),
+        REFS.replace("assert!(b)", "assert!(!b)"),
+    ));
+    srcs.push(("assert_eq".to_string(), REFS.replace("s, s", "s, 0")));
+    srcs.push(("args".to_string(), ARGS.to_string()));
+    srcs.push(("pow".to_string(), POW.to_string()));
+    srcs.push(("pow_overflow".to_string(), POW.replace("k < 31", "k < 32")));
+    srcs
+}
+
+// the test programs lowering rejects, those of a type error and of an
+// assignment to an immutable variable, which fail in the interpreter
+#[cfg(test)]
+const NOT_LOWERED: [&str; 2] = ["immutable", "type_error"];
+
+// the test programs lowering accepts, parsed and lowered, with their
+// names; the sources are leaked as both trees borrow them
+#[cfg(test)]
+pub fn lowered_programs() -> Vec<(String, Prog<'static>, crate::ir::Module<'static>)> {
+    let mut lowered = vec![];
+    let mut skipped = vec![];
+    for (name, src) in test_programs() {
+        let p = crate::parse::parse(Box::leak(src.into_boxed_str())).unwrap();
+        match crate::ir::lower(&p) {
+            Ok((m, _)) => lowered.push((name, p, m)),
+            Err(_) => {
+                assert!(crate::interp::run(&p, "main", vec![]).is_err(), "{}", name);
+                skipped.push(name);
+            }
+        }
+    }
+    assert_eq!(skipped, NOT_LOWERED);
+    lowered
+}
+
+#[test]
+fn test_pieces() {
+    assert_eq!(
+        pieces("{{x}} = {}{}!"),
+        vec![
+            Piece::Text("{x} = ".to_string()),
+            Piece::Arg(0),
+            Piece::Arg(1),
+            Piece::Text("!".to_string())
+        ]
+    );
+    assert_eq!(pieces(""), vec![]);
+}
+
+#[cfg(test)]
+const REFS: &str = "
+fn swap(a: &mut i32, b: &mut i32) {
+    let t: i32 = *a;
+    *a = *b;
+    *b = t;
+}
+
+fn sum(n: &i32, acc: &mut i32) {
+    let mut i: i32 = 0;
+    while i < *n {
+        i = i + 1;
+        *acc = *acc + i;
+    }
+}
+
+fn main() -> i32 {
+    let mut x: i32 = 3;
+    let mut y: i32 = 40;
+    swap(&mut x, &mut y);
+    let mut s: i32 = 0;
+    sum(&x, &mut s);
+    println!(\"x = {}, y = {}, s = {}, {} 100% {{}}\", x, y, s, x > y);
+    let b: bool = x == 40 && s == 820;
+    assert!(b);
+    assert_eq!(s, s, \"s is {}\", s);
+    return s;
+}
+";
+
+#[cfg(test)]
+const POW: &str = "
+fn main() -> i32 {
+    let mut k: i32 = 0;
+    let mut r: i32 = 0;
+    while k < 31 {
+        r = (0 - 2) ** k / 7 + 1 ** k + 0 ** k - r;
+        k = k + 1;
+    }
+    println!(\"{}\", (0 - 2) ** k);
+    return r;
+}
+";
+
+#[cfg(test)]
+const ARGS: &str = "
+fn unit() {}
+
+fn f(a: i32, b: i32, c: i32, d: bool, e: &i32, g: i32, h: &mut i32, i: i32, j: ()) -> bool {
+    *h = a - b * 10 + c * 100 + *e * 1000 + g * 10000 + i * 100000;
+    println!(\"{} {}\", d, j);
+    return !d;
+}
+
+fn main() -> bool {
+    let x: i32 = 4;
+    let mut y: i32 = 0;
+    let b: bool = f(1, 2, 3, true, &x, 5, &mut y, 6, unit());
+    println!(\"{}\", y);
+    return f(y, y, y, b, &y, 0, &mut y, 0, unit()) || y < 0;
+}
+";
@@ -0,0 +1,516 @@
+// RV32IM emission
+//
+// Writes a module as GNU assembler source for 32-bit RISC-V with the `M`
+// extension, runnable on Linux or in the simulator of `sim`. Functions
+// follow the standard calling convention: the first eight arguments in
+// `a0`-`a7`, the rest on the stack, the result in `a0`.
+//
+// As for x86-64, registers are allocated to stack slots, 4 bytes below
+// the frame pointer `s0` for each register and each `alloca`, operands are
+// loaded into `t0` and `t1` and the result stored from `t2`. RISC-V has
+// no overflow flag, overflow is detected from the signs of the operands
+// and the result (`mulh` for multiplication). A failed check jumps to a
+// stub passing its message to `rt.fail`. Conditional branches only skip
+// over a `j`, so that they stay in range in long functions.
+
+use super::{ascii, error_message, pieces, Piece, ERROR_STATUS};
+use crate::ast::{Op, Type};
+use crate::interp::ErrorKind;
+use crate::ir::{Const, Function, InstKind, Module, Reg, Term};
+
+pub mod sim;
+
+const RUNTIME: &str = "\
+# write(a0 = fd, a1 = buffer, a2 = length)
+rt.write:
+    li a7, 64
+    ecall
+    ret
+
+# write(a0 = fd, a1 = value) in decimal
+rt.write_int:
+    addi sp, sp, -16
+    sw ra, 12(sp)
+    addi t1, sp, 12
+    mv t2, t1
+    li t3, 10
+    mv t4, a1
+    bge a1, zero, .Lrt_digits
+    sub t4, zero, a1
+.Lrt_digits:
+    remu t5, t4, t3
+    divu t4, t4, t3
+    addi t5, t5, 48
+    addi t2, t2, -1
+    sb t5, 0(t2)
+    bnez t4, .Lrt_digits
+    bge a1, zero, .Lrt_positive
+    li t5, 45
+    addi t2, t2, -1
+    sb t5, 0(t2)
+.Lrt_positive:
+    mv a1, t2
+    sub a2, t1, t2
+    call rt.write
+    lw ra, 12(sp)
+    addi sp, sp, 16
+    ret
+
+# write(a0 = fd, a1 = value) as `true` or `false`
+rt.write_bool:
+    beqz a1, .Lrt_false
+    la a1, rt.true
+    li a2, 4
+    j rt.write
+.Lrt_false:
+    la a1, rt.false
+    li a2, 5
+    j rt.write
+
+# writes the message (a1, length a2) to the standard error and exits
+rt.fail:
+    li a0, 2
+    call rt.write
+    li a0, 101
+
+# exit(a0 = status)
+rt.exit:
+    li a7, 93
+    ecall
+
+# a0 ** a1 (non-negative) by squaring, a1 = 1 on overflow
+rt.pow:
+    li t0, 1
+    li t1, 0
+    mv t2, a0
+    beqz a1, .Lrt_pow_done
+.Lrt_pow_loop:
+    li t3, 1
+    bge t3, a1, .Lrt_pow_last
+    andi t3, a1, 1
+    beqz t3, .Lrt_pow_square
+    mul t4, t0, t2
+    mulh t5, t0, t2
+    srai t6, t4, 31
+    beq t5, t6, .Lrt_pow_acc
+    li t1, 1
+.Lrt_pow_acc:
+    mv t0, t4
+.Lrt_pow_square:
+    srli a1, a1, 1
+    mul t4, t2, t2
+    mulh t5, t2, t2
+    srai t6, t4, 31
+    beq t5, t6, .Lrt_pow_base
+    li t1, 1
+.Lrt_pow_base:
+    mv t2, t4
+    j .Lrt_pow_loop
+.Lrt_pow_last:
+    mul t4, t0, t2
+    mulh t5, t0, t2
+    srai t6, t4, 31
+    beq t5, t6, .Lrt_pow_ok
+    li t1, 1
+.Lrt_pow_ok:
+    mv t0, t4
+.Lrt_pow_done:
+    mv a0, t0
+    mv a1, t1
+    ret
+
+    .globl _start
+_start:
+    call crust.main
+    j rt.exit
+";
+
+struct Emitter {
+    // read-only strings
+    strings: Vec<String>,
+    out: String,
+    // internal labels
+    labels: usize,
+}
+
+// the assembly of a module
+pub fn emit(m: &Module) -> String {
+    let mut e = Emitter {
+        strings: vec![],
+        out: String::new(),
+        labels: 0,
+    };
+    for (g, f) in m.fns.iter().enumerate() {
+        e.function(m, g, f);
+    }
+    let mut s = String::from("# crust module\n    .section .rodata\n");
+    s += "rt.true:\n    .ascii \"true\"\nrt.false:\n    .ascii \"false\"\n";
+    for (i, c) in e.strings.iter().enumerate() {
+        s += &format!(".Lstr{}:\n    .ascii {}\n", i, ascii(c));
+    }
+    s += "\n    .text\n";
+    if matches!(m.function("main"), Some(g) if m.fns[g].params.is_empty()) {
+        s += RUNTIME;
+    } else {
+        // a library, no entry point
+        s += &RUNTIME[..RUNTIME.find("    .globl _start").unwrap()];
+    }
+    s + &e.out
+}
+
+// the offset of the slot of a register from `s0`, below the saved
+// `ra` and `s0`
+fn slot(r: Reg) -> i32 {
+    -12 - 4 * r as i32
+}
+
+impl Emitter {
+    fn line(&mut self, s: String) {
+        self.out += "    ";
+        self.out += &s;
+        self.out.push('\n');
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(".Ll{}", self.labels - 1)
+    }
+
+    fn place(&mut self, label: &str) {
+        self.out += label;
+        self.out += ":\n";
+    }
+
+    // a string constant and its length
+    fn string(&mut self, s: &str) -> (String, usize) {
+        let i = match self.strings.iter().position(|c| c == s) {
+            Some(i) => i,
+            None => {
+                self.strings.push(s.to_string());
+                self.strings.len() - 1
+            }
+        };
+        (format!(".Lstr{}", i), s.len())
+    }
+
+    // `op reg, off(s0)`, through `t6` where the offset needs more than
+    // 12 bits
+    fn frame(&mut self, op: &str, reg: &str, off: i32) {
+        if (-2048..2048).contains(&off) {
+            self.line(format!("{} {}, {}(s0)", op, reg, off));
+        } else {
+            self.line(format!("li t6, {}", off));
+            self.line("add t6, t6, s0".to_string());
+            self.line(format!("{} {}, 0(t6)", op, reg));
+        }
+    }
+
+    fn load(&mut self, reg: &str, r: Reg) {
+        self.frame("lw", reg, slot(r));
+    }
+
+    fn store(&mut self, reg: &str, r: Reg) {
+        self.frame("sw", reg, slot(r));
+    }
+
+    // `b<cond> a, b, target` for targets out of the range of a branch
+    fn branch(&mut self, negated: &str, a: &str, b: &str, target: &str) {
+        let skip = self.label();
+        self.line(format!("{} {}, {}, {}", negated, a, b, skip));
+        self.line(format!("j {}", target));
+        self.place(&skip);
+    }
+
+    // writes a formatted message to `fd`
+    fn write(&mut self, f: &Function, fd: i32, fmt: &str, args: &[Reg]) {
+        for piece in pieces(fmt) {
+            if matches!(piece, Piece::Arg(i) if i >= args.len()) {
+                continue;
+            }
+            self.line(format!("li a0, {}", fd));
+            let routine = match piece {
+                Piece::Arg(i) => match &f.regs[args[i]] {
+                    Type::I32 => Some((args[i], "rt.write_int")),
+                    Type::Bool => Some((args[i], "rt.write_bool")),
+                    _ => None,
+                },
+                Piece::Text(_) => None,
+            };
+            match (routine, piece) {
+                (Some((a, routine)), _) => {
+                    self.load("a1", a);
+                    self.line(format!("call {}", routine));
+                }
+                (None, piece) => {
+                    let text = match piece {
+                        Piece::Text(t) => t,
+                        // unit
+                        Piece::Arg(_) => "()".to_string(),
+                    };
+                    let (s, n) = self.string(&text);
+                    self.line(format!("la a1, {}", s));
+                    self.line(format!("li a2, {}", n));
+                    self.line("call rt.write".to_string());
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, m: &Module, g: usize, f: &Function) {
+        // slots of the registers, then of the allocas
+        let mut allocas = 0;
+        let mut pointee = vec![0; f.regs.len()];
+        for inst in f.blocks.iter().flat_map(|b| &b.insts) {
+            if let (Some(d), InstKind::Alloca(_)) = (inst.dst, &inst.kind) {
+                pointee[d] = f.regs.len() + allocas;
+                allocas += 1;
+            }
+        }
+        let frame = (8 + 4 * (f.regs.len() + allocas)).next_multiple_of(16);
+        let block = |b: usize| format!(".L{}_b{}", g, b);
+
+        let name = f.id.fragment;
+        self.out += &format!("\n    .globl crust.{}\ncrust.{}:\n", name, name);
+        self.line("addi sp, sp, -16".to_string());
+        self.line("sw ra, 12(sp)".to_string());
+        self.line("sw s0, 8(sp)".to_string());
+        self.line("addi s0, sp, 16".to_string());
+        if frame > 16 {
+            self.line(format!("li t6, {}", frame - 16));
+            self.line("sub sp, sp, t6".to_string());
+        }
+        for (i, p) in f.params.iter().enumerate() {
+            match i < 8 {
+                true => self.store(&format!("a{}", i), *p),
+                false => {
+                    // in the frame of the caller
+                    self.frame("lw", "t0", 4 * (i as i32 - 8));
+                    self.store("t0", *p);
+                }
+            }
+        }
+        // failure stubs: label, message
+        let mut stubs = vec![];
+        for (b, bl) in f.blocks.iter().enumerate() {
+            self.place(&block(b));
+            for inst in &bl.insts {
+                let d = inst.dst.unwrap_or(0);
+                let span = inst.span;
+                let mut fail = |e: &mut Self, kind: ErrorKind| {
+                    let label = e.label();
+                    stubs.push((label.clone(), error_message(span, &kind)));
+                    label
+                };
+                match &inst.kind {
+                    InstKind::Const(c) => {
+                        let v = match c {
+                            Const::Int(n) => *n,
+                            Const::Bool(b) => *b as i32,
+                            Const::Unit => 0,
+                        };
+                        self.line(format!("li t2, {}", v));
+                        self.store("t2", d);
+                    }
+                    InstKind::Copy(r) => {
+                        self.load("t2", *r);
+                        self.store("t2", d);
+                    }
+                    InstKind::Bin(op, l, _) if f.regs[*l] == Type::Unit => {
+                        self.line(format!("li t2, {}", (*op == Op::Eq) as i32));
+                        self.store("t2", d);
+                    }
+                    InstKind::Bin(op, l, r) => {
+                        self.load("t0", *l);
+                        self.load("t1", *r);
+                        match op {
+                            Op::Add => {
+                                // overflow iff the sum is below `l` while `r` is
+                                // non-negative, or the other way round
+                                self.line("add t2, t0, t1".to_string());
+                                self.line("slt t3, t2, t0".to_string());
+                                self.line("slti t4, t1, 0".to_string());
+                                let stub = fail(self, ErrorKind::Overflow(*op));
+                                self.branch("beq", "t3", "t4", &stub);
+                            }
+                            Op::Sub => {
+                                self.line("sub t2, t0, t1".to_string());
+                                self.line("slt t3, t0, t2".to_string());
+                                self.line("slti t4, t1, 0".to_string());
+                                let stub = fail(self, ErrorKind::Overflow(*op));
+                                self.branch("beq", "t3", "t4", &stub);
+                            }
+                            Op::Mul => {
+                                // overflow iff the high word is not the sign
+                                // extension of the low one
+                                self.line("mul t2, t0, t1".to_string());
+                                self.line("mulh t3, t0, t1".to_string());
+                                self.line("srai t4, t2, 31".to_string());
+                                let stub = fail(self, ErrorKind::Overflow(*op));
+                                self.branch("beq", "t3", "t4", &stub);
+                            }
+                            Op::Div => {
+                                let stub = fail(self, ErrorKind::DivisionByZero);
+                                self.branch("bne", "t1", "zero", &stub);
+                                let ok = self.label();
+                                self.line("li t3, -1".to_string());
+                                self.line(format!("bne t1, t3, {}", ok));
+                                self.line("li t3, -2147483648".to_string());
+                                let stub = fail(self, ErrorKind::Overflow(*op));
+                                self.branch("bne", "t0", "t3", &stub);
+                                self.place(&ok);
+                                self.line("div t2, t0, t1".to_string());
+                            }
+                            Op::Pow => {
+                                let stub = fail(self, ErrorKind::NegativeExponent);
+                                self.branch("bge", "t1", "zero", &stub);
+                                self.line("mv a0, t0".to_string());
+                                self.line("mv a1, t1".to_string());
+                                self.line("call rt.pow".to_string());
+                                let stub = fail(self, ErrorKind::Overflow(*op));
+                                self.branch("beq", "a1", "zero", &stub);
+                                self.line("mv t2, a0".to_string());
+                            }
+                            Op::Lt => self.line("slt t2, t0, t1".to_string()),
+                            Op::Gt => self.line("slt t2, t1, t0".to_string()),
+                            Op::Eq => {
+                                self.line("xor t2, t0, t1".to_string());
+                                self.line("sltiu t2, t2, 1".to_string());
+                            }
+                            Op::Neq => {
+                                self.line("xor t2, t0, t1".to_string());
+                                self.line("sltu t2, zero, t2".to_string());
+                            }
+                            Op::And => self.line("and t2, t0, t1".to_string()),
+                            _ => self.line("or t2, t0, t1".to_string()),
+                        }
+                        self.store("t2", d);
+                    }
+                    InstKind::Un(op, r) => {
+                        self.load("t0", *r);
+                        match op {
+                            Op::Not => self.line("xori t2, t0, 1".to_string()),
+                            _ => {
+                                self.line("li t3, -2147483648".to_string());
+                                let stub = fail(self, ErrorKind::NegOverflow);
+                                self.branch("bne", "t0", "t3", &stub);
+                                self.line("sub t2, zero, t0".to_string());
+                            }
+                        }
+                        self.store("t2", d);
+                    }
+                    InstKind::Call(h, args) => {
+                        // the stack stays 16-byte aligned at the call
+                        let stack = (4 * args.len().saturating_sub(8)).next_multiple_of(16);
+                        if stack > 0 {
+                            self.line(format!("addi sp, sp, -{}", stack));
+                        }
+                        for (i, a) in args.iter().enumerate().skip(8) {
+                            self.load("t0", *a);
+                            self.line(format!("sw t0, {}(sp)", 4 * (i - 8)));
+                        }
+                        for (i, a) in args.iter().take(8).enumerate() {
+                            self.load(&format!("a{}", i), *a);
+                        }
+                        self.line(format!("call crust.{}", m.fns[*h].id.fragment));
+                        if stack > 0 {
+                            self.line(format!("addi sp, sp, {}", stack));
+                        }
+                        if let Some(d) = inst.dst {
+                            self.store("a0", d);
+                        }
+                    }
+                    InstKind::Alloca(_) => {
+                        let off = slot(pointee[d]);
+                        match (-2048..2048).contains(&off) {
+                            true => self.line(format!("addi t2, s0, {}", off)),
+                            false => {
+                                self.line(format!("li t2, {}", off));
+                                self.line("add t2, t2, s0".to_string());
+                            }
+                        }
+                        self.store("t2", d);
+                    }
+                    InstKind::Load(p) => {
+                        self.load("t0", *p);
+                        self.line("lw t2, 0(t0)".to_string());
+                        self.store("t2", d);
+                    }
+                    InstKind::Store(p, v) => {
+                        self.load("t0", *p);
+                        self.load("t1", *v);
+                        self.line("sw t1, 0(t0)".to_string());
+                    }
+                    InstKind::Print(fmt, args) => self.write(f, 1, &(fmt.clone() + "\n"), args),
+                }
+            }
+            // assigns the phi nodes of `t` for the edge from `b`, in parallel
+            // through the stack
+            let edge = |e: &mut Self, t: usize| {
+                let phis = &f.blocks[t].phis;
+                if !phis.is_empty() {
+                    let size = (4 * phis.len()).next_multiple_of(16);
+                    e.line(format!("addi sp, sp, -{}", size));
+                    for (i, phi) in phis.iter().enumerate() {
+                        let (_, r) = phi.args.iter().find(|(p, _)| *p == b).unwrap();
+                        e.load("t0", *r);
+                        e.line(format!("sw t0, {}(sp)", 4 * i));
+                    }
+                    for (i, phi) in phis.iter().enumerate() {
+                        e.line(format!("lw t0, {}(sp)", 4 * i));
+                        e.store("t0", phi.dst);
+                    }
+                    e.line(format!("addi sp, sp, {}", size));
+                }
+                e.line(format!("j {}", block(t)));
+            };
+            match &bl.term {
+                Term::Jump(t) => edge(self, *t),
+                Term::Branch(c, t, e) => {
+                    let other = self.label();
+                    self.load("t0", *c);
+                    self.branch("bne", "t0", "zero", &other);
+                    edge(self, *t);
+                    self.place(&other);
+                    edge(self, *e);
+                }
+                Term::Return(r) => {
+                    self.load("a0", *r);
+                    self.line("addi sp, s0, -16".to_string());
+                    self.line("lw ra, 12(sp)".to_string());
+                    self.line("lw s0, 8(sp)".to_string());
+                    self.line("addi sp, sp, 16".to_string());
+                    self.line("ret".to_string());
+                }
+                Term::Panic(fmt, args, span) => {
+                    let fmt = error_message(*span, &ErrorKind::Panic(fmt.clone()));
+                    self.write(f, 2, &fmt, args);
+                    self.line(format!("li a0, {}", ERROR_STATUS));
+                    self.line("j rt.exit".to_string());
+                }
+                // jumps to the address 0, outside the program
+                Term::Unreachable => self.line("jalr zero, 0(zero)".to_string()),
+            }
+        }
+        for (label, msg) in stubs {
+            let (s, n) = self.string(&msg);
+            self.place(&label);
+            self.line(format!("la a1, {}", s));
+            self.line(format!("li a2, {}", n));
+            self.line("j rt.fail".to_string());
+        }
+    }
+}
+
+#[test]
+fn test_riscv() {
+    use super::{interpret, lowered_programs};
+
+    for (name, p, m) in &lowered_programs() {
+        let prog = match sim::assemble(&emit(m)) {
+            Ok(prog) => prog,
+            Err(e) => panic!("{}: {}", name, e),
+        };
+        let r = sim::run(&prog, Some(100_000_000)).unwrap_or_else(|t| panic!("{}: {}", name, t));
+        assert_eq!(r, interpret(p), "{}", name);
+    }
+}
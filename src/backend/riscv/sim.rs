@@ -0,0 +1,728 @@
+// RV32IM simulator
+//
+// Assembles the subset of GNU assembler syntax the backend emits (labels,
+// `.text`, `.section .rodata`, `.ascii`, the base and `M` instructions
+// and the pseudo-instructions `li`, `la`, `mv`, `j`, `call`, `ret`,
+// `beqz` and `bnez`) and executes it from `_start`. Instructions live at
+// TEXT_BASE, four bytes apart, data at DATA_BASE followed by the stack.
+// System calls follow Linux: `a7` = 64 writes `a2` bytes at `a1` to the
+// file descriptor `a0` (1 or 2), `a7` = 93 exits with the status `a0`.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+
+use crate::backend::Outcome;
+
+pub const TEXT_BASE: u32 = 0x0001_0000;
+pub const DATA_BASE: u32 = 0x1000_0000;
+// data and stack, the stack grows down from the end
+pub const MEM_SIZE: u32 = 1 << 20;
+// the part of it left to the stack, the data has to fit below
+pub const STACK_SIZE: u32 = 1 << 16;
+
+const REGS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Alu {
+    Add,
+    Sub,
+    Mul,
+    Mulh,
+    Div,
+    Divu,
+    Rem,
+    Remu,
+    Slt,
+    Sltu,
+    And,
+    Or,
+    Xor,
+    Sll,
+    Srl,
+    Sra,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+type R = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Inst {
+    // rd, rs1, rs2
+    Op(Alu, R, R, R),
+    // rd, rs1, immediate
+    OpImm(Alu, R, R, i32),
+    // `li` and `la`
+    Li(R, i32),
+    // rd, base, offset, width in bytes
+    Load(R, R, i32, u32),
+    // source, base, offset, width in bytes
+    Store(R, R, i32, u32),
+    // rs1, rs2, index of the target
+    Branch(Cond, R, R, usize),
+    // rd, index of the target
+    Jal(R, usize),
+    // rd, base, offset
+    Jalr(R, R, i32),
+    Ecall,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    insts: Vec<Inst>,
+    data: Vec<u8>,
+    // index of `_start`
+    entry: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    // address of the access
+    Memory(u32),
+    Misaligned(u32),
+    // target address
+    Jump(u32),
+    // the system call number
+    Ecall(i32),
+    OutOfFuel(u64),
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::Memory(a) => write!(f, "access to unmapped memory at {:#x}", a),
+            Trap::Misaligned(a) => write!(f, "misaligned access at {:#x}", a),
+            Trap::Jump(a) => write!(f, "jump to {:#x} outside the program", a),
+            Trap::Ecall(n) => write!(f, "unknown system call {}", n),
+            Trap::OutOfFuel(n) => write!(f, "out of fuel after {} instructions", n),
+        }
+    }
+}
+
+fn reg(s: &str) -> Result<R, String> {
+    let s = if s == "fp" { "s0" } else { s };
+    if let Some(r) = REGS.iter().position(|r| *r == s) {
+        return Ok(r);
+    }
+    match s.strip_prefix('x').map(str::parse::<usize>) {
+        Some(Ok(r)) if r < 32 => Ok(r),
+        _ => Err(format!("unknown register `{}`", s)),
+    }
+}
+
+fn imm(s: &str) -> Result<i32, String> {
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(d) => (true, d),
+        None => (false, s),
+    };
+    let v = match digits.strip_prefix("0x") {
+        Some(h) => i64::from_str_radix(h, 16),
+        None => digits.parse::<i64>(),
+    }
+    .map_err(|_| format!("invalid immediate `{}`", s))?;
+    let v = if neg { -v } else { v };
+    match i32::try_from(v).or_else(|_| u32::try_from(v).map(|u| u as i32)) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("immediate `{}` out of range", s)),
+    }
+}
+
+// a 12-bit signed immediate
+fn imm12(s: &str) -> Result<i32, String> {
+    match imm(s)? {
+        v if (-2048..2048).contains(&v) => Ok(v),
+        _ => Err(format!("immediate `{}` out of range", s)),
+    }
+}
+
+// `offset(base)`
+fn mem(s: &str) -> Result<(i32, R), String> {
+    let open = s
+        .find('(')
+        .ok_or(format!("expected `offset(base)`, found `{}`", s))?;
+    let base = s[open + 1..]
+        .strip_suffix(')')
+        .ok_or(format!("missing `)` in `{}`", s))?;
+    let off = match &s[..open] {
+        "" => 0,
+        o => imm12(o)?,
+    };
+    Ok((off, reg(base)?))
+}
+
+// the bytes of a string literal
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let inner = (s.strip_prefix('"').and_then(|s| s.strip_suffix('"')))
+        .ok_or(format!("expected a string, found `{}`", s))?;
+    let mut bytes = vec![];
+    let mut it = inner.bytes().peekable();
+    while let Some(b) = it.next() {
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+        match it.next() {
+            Some(b'n') => bytes.push(b'\n'),
+            Some(b't') => bytes.push(b'\t'),
+            Some(d @ b'0'..=b'7') => {
+                let mut v = (d - b'0') as u32;
+                for _ in 0..2 {
+                    match it.peek() {
+                        Some(d @ b'0'..=b'7') => {
+                            v = v * 8 + (d - b'0') as u32;
+                            it.next();
+                        }
+                        _ => break,
+                    }
+                }
+                bytes.push(v as u8);
+            }
+            Some(c) => bytes.push(c),
+            None => return Err("unterminated escape".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+fn alu(m: &str) -> Option<Alu> {
+    Some(match m {
+        "add" => Alu::Add,
+        "sub" => Alu::Sub,
+        "mul" => Alu::Mul,
+        "mulh" => Alu::Mulh,
+        "div" => Alu::Div,
+        "divu" => Alu::Divu,
+        "rem" => Alu::Rem,
+        "remu" => Alu::Remu,
+        "slt" => Alu::Slt,
+        "sltu" => Alu::Sltu,
+        "and" => Alu::And,
+        "or" => Alu::Or,
+        "xor" => Alu::Xor,
+        "sll" => Alu::Sll,
+        "srl" => Alu::Srl,
+        "sra" => Alu::Sra,
+        _ => return None,
+    })
+}
+
+fn cond(m: &str) -> Option<Cond> {
+    Some(match m {
+        "beq" => Cond::Eq,
+        "bne" => Cond::Ne,
+        "blt" => Cond::Lt,
+        "bge" => Cond::Ge,
+        "bltu" => Cond::Ltu,
+        "bgeu" => Cond::Geu,
+        _ => return None,
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '#' if !quoted => return &line[..i],
+            '"' if !escaped => quoted = !quoted,
+            _ => (),
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    line
+}
+
+// the label of a definition, `name:`
+fn label(line: &str) -> Option<&str> {
+    let l = line.strip_suffix(':')?;
+    match !l.is_empty() && l.chars().all(|c| c.is_alphanumeric() || "._$".contains(c)) {
+        true => Some(l),
+        false => None,
+    }
+}
+
+pub fn assemble(src: &str) -> Result<Program, AsmError> {
+    // labels: instruction index or data address
+    #[derive(Clone, Copy)]
+    enum Def {
+        Text(usize),
+        Data(u32),
+    }
+    let mut labels = HashMap::new();
+    let mut text = vec![];
+    let mut data = vec![];
+    let mut in_text = true;
+    let err = |line: usize, msg: String| AsmError {
+        line: line + 1,
+        msg,
+    };
+    for (n, line) in src.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(l) = label(line) {
+            let def = match in_text {
+                true => Def::Text(text.len()),
+                false => Def::Data(DATA_BASE + data.len() as u32),
+            };
+            if labels.insert(l.to_string(), def).is_some() {
+                return Err(err(n, format!("label `{}` defined twice", l)));
+            }
+            continue;
+        }
+        let (m, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        match m {
+            ".text" => in_text = true,
+            ".data" | ".section" => in_text = false,
+            ".globl" => (),
+            ".ascii" => {
+                data.extend(unescape(rest).map_err(|e| err(n, e))?);
+                if data.len() > (MEM_SIZE - STACK_SIZE) as usize {
+                    let msg = format!("data exceeds {} bytes", MEM_SIZE - STACK_SIZE);
+                    return Err(err(n, msg));
+                }
+            }
+            _ if in_text => text.push((n, m, rest)),
+            _ => return Err(err(n, format!("instruction `{}` in the data section", m))),
+        }
+    }
+
+    let mut insts = vec![];
+    for (n, m, rest) in text {
+        let ops: Vec<_> = match rest {
+            "" => vec![],
+            r => r.split(',').map(str::trim).collect(),
+        };
+        let arity = |k: usize| match ops.len() == k {
+            true => Ok(()),
+            false => Err(format!("`{}` takes {} operands", m, k)),
+        };
+        let target = |s: &str| match labels.get(s) {
+            Some(Def::Text(i)) => Ok(*i),
+            _ => Err(format!("unknown label `{}`", s)),
+        };
+        let inst = (|| {
+            Ok(match m {
+                _ if alu(m).is_some() => {
+                    arity(3)?;
+                    Inst::Op(alu(m).unwrap(), reg(ops[0])?, reg(ops[1])?, reg(ops[2])?)
+                }
+                "addi" | "slti" | "sltiu" | "andi" | "ori" | "xori" | "slli" | "srli" | "srai" => {
+                    arity(3)?;
+                    let op = match m {
+                        "sltiu" => Alu::Sltu,
+                        _ => alu(&m[..m.len() - 1]).unwrap(),
+                    };
+                    let v = imm12(ops[2])?;
+                    if matches!(op, Alu::Sll | Alu::Srl | Alu::Sra) && !(0..32).contains(&v) {
+                        return Err(format!("shift amount `{}` out of range", ops[2]));
+                    }
+                    Inst::OpImm(op, reg(ops[0])?, reg(ops[1])?, v)
+                }
+                "li" => {
+                    arity(2)?;
+                    Inst::Li(reg(ops[0])?, imm(ops[1])?)
+                }
+                "la" => {
+                    arity(2)?;
+                    let addr = match labels.get(ops[1]) {
+                        Some(Def::Data(a)) => *a,
+                        Some(Def::Text(i)) => TEXT_BASE + 4 * *i as u32,
+                        None => return Err(format!("unknown label `{}`", ops[1])),
+                    };
+                    Inst::Li(reg(ops[0])?, addr as i32)
+                }
+                "mv" => {
+                    arity(2)?;
+                    Inst::OpImm(Alu::Add, reg(ops[0])?, reg(ops[1])?, 0)
+                }
+                "lw" | "lb" | "lbu" | "sw" | "sb" => {
+                    arity(2)?;
+                    let (off, base) = mem(ops[1])?;
+                    let width = if m.starts_with("lw") || m == "sw" {
+                        4
+                    } else {
+                        1
+                    };
+                    match m {
+                        "sw" | "sb" => Inst::Store(reg(ops[0])?, base, off, width),
+                        "lb" => return Err("`lb` is not supported, use `lbu`".to_string()),
+                        _ => Inst::Load(reg(ops[0])?, base, off, width),
+                    }
+                }
+                _ if cond(m).is_some() => {
+                    arity(3)?;
+                    let (a, b) = (reg(ops[0])?, reg(ops[1])?);
+                    Inst::Branch(cond(m).unwrap(), a, b, target(ops[2])?)
+                }
+                "beqz" | "bnez" => {
+                    arity(2)?;
+                    let c = if m == "beqz" { Cond::Eq } else { Cond::Ne };
+                    Inst::Branch(c, reg(ops[0])?, 0, target(ops[1])?)
+                }
+                "j" => {
+                    arity(1)?;
+                    Inst::Jal(0, target(ops[0])?)
+                }
+                "call" => {
+                    arity(1)?;
+                    Inst::Jal(1, target(ops[0])?)
+                }
+                "jal" if ops.len() == 1 => Inst::Jal(1, target(ops[0])?),
+                "jal" => {
+                    arity(2)?;
+                    Inst::Jal(reg(ops[0])?, target(ops[1])?)
+                }
+                "jalr" => {
+                    arity(2)?;
+                    let (off, base) = mem(ops[1])?;
+                    Inst::Jalr(reg(ops[0])?, base, off)
+                }
+                "ret" => {
+                    arity(0)?;
+                    Inst::Jalr(0, 1, 0)
+                }
+                "ecall" => {
+                    arity(0)?;
+                    Inst::Ecall
+                }
+                _ => return Err(format!("unknown instruction `{}`", m)),
+            })
+        })()
+        .map_err(|e| err(n, e))?;
+        insts.push(inst);
+    }
+    let entry = match labels.get("_start") {
+        Some(Def::Text(i)) => *i,
+        _ => return Err(err(0, "no `_start` in the text section".to_string())),
+    };
+    Ok(Program { insts, data, entry })
+}
+
+fn alu_op(op: Alu, a: i32, b: i32) -> i32 {
+    let (ua, ub) = (a as u32, b as u32);
+    match op {
+        Alu::Add => a.wrapping_add(b),
+        Alu::Sub => a.wrapping_sub(b),
+        Alu::Mul => a.wrapping_mul(b),
+        Alu::Mulh => ((a as i64 * b as i64) >> 32) as i32,
+        // division by zero and overflow as specified by RV32M
+        Alu::Div if b == 0 => -1,
+        Alu::Div => a.wrapping_div(b),
+        Alu::Divu if b == 0 => -1,
+        Alu::Divu => (ua / ub) as i32,
+        Alu::Rem if b == 0 => a,
+        Alu::Rem => a.wrapping_rem(b),
+        Alu::Remu if b == 0 => a,
+        Alu::Remu => (ua % ub) as i32,
+        Alu::Slt => (a < b) as i32,
+        Alu::Sltu => (ua < ub) as i32,
+        Alu::And => a & b,
+        Alu::Or => a | b,
+        Alu::Xor => a ^ b,
+        Alu::Sll => a.wrapping_shl(ub & 31),
+        Alu::Srl => (ua >> (ub & 31)) as i32,
+        Alu::Sra => a >> (ub & 31),
+    }
+}
+
+pub struct Machine<'p> {
+    p: &'p Program,
+    pub regs: [i32; 32],
+    // index of the next instruction
+    pc: usize,
+    mem: Vec<u8>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    steps: u64,
+}
+
+// runs the program to its exit, executing at most `fuel` instructions
+pub fn run(p: &Program, fuel: Option<u64>) -> Result<Outcome, Trap> {
+    Machine::new(p).run(fuel)
+}
+
+impl<'p> Machine<'p> {
+    pub fn new(p: &'p Program) -> Self {
+        let mut mem = vec![0; MEM_SIZE as usize];
+        mem[..p.data.len()].copy_from_slice(&p.data);
+        let mut regs = [0; 32];
+        regs[2] = (DATA_BASE + MEM_SIZE) as i32;
+        Machine {
+            p,
+            regs,
+            pc: p.entry,
+            mem,
+            stdout: vec![],
+            stderr: vec![],
+            steps: 0,
+        }
+    }
+
+    // the offset in `mem` of `width` bytes at `addr`, alignment is checked
+    // with `%` as `is_multiple_of` is not available on older toolchains
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    fn addr(&self, addr: u32, width: u32) -> Result<usize, Trap> {
+        if addr % width != 0 {
+            return Err(Trap::Misaligned(addr));
+        }
+        match addr.checked_sub(DATA_BASE) {
+            Some(off) if off + width <= MEM_SIZE => Ok(off as usize),
+            _ => Err(Trap::Memory(addr)),
+        }
+    }
+
+    fn set(&mut self, rd: R, v: i32) {
+        if rd != 0 {
+            self.regs[rd] = v;
+        }
+    }
+
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    fn jump(&self, addr: u32) -> Result<usize, Trap> {
+        let i = (addr.wrapping_sub(TEXT_BASE) / 4) as usize;
+        match addr % 4 == 0 && addr >= TEXT_BASE && i < self.p.insts.len() {
+            true => Ok(i),
+            false => Err(Trap::Jump(addr)),
+        }
+    }
+
+    pub fn run(mut self, fuel: Option<u64>) -> Result<Outcome, Trap> {
+        loop {
+            if let Some(fuel) = fuel.filter(|f| self.steps >= *f) {
+                return Err(Trap::OutOfFuel(fuel));
+            }
+            self.steps += 1;
+            let inst = match self.p.insts.get(self.pc) {
+                Some(i) => *i,
+                None => return Err(Trap::Jump(TEXT_BASE + 4 * self.pc as u32)),
+            };
+            let r = self.regs;
+            let mut next = self.pc + 1;
+            match inst {
+                Inst::Op(op, rd, a, b) => self.set(rd, alu_op(op, r[a], r[b])),
+                Inst::OpImm(op, rd, a, v) => self.set(rd, alu_op(op, r[a], v)),
+                Inst::Li(rd, v) => self.set(rd, v),
+                Inst::Load(rd, base, off, width) => {
+                    let a = self.addr(r[base].wrapping_add(off) as u32, width)?;
+                    let v = match width {
+                        4 => i32::from_le_bytes(self.mem[a..a + 4].try_into().unwrap()),
+                        _ => self.mem[a] as i32,
+                    };
+                    self.set(rd, v);
+                }
+                Inst::Store(src, base, off, width) => {
+                    let a = self.addr(r[base].wrapping_add(off) as u32, width)?;
+                    let bytes = r[src].to_le_bytes();
+                    self.mem[a..a + width as usize].copy_from_slice(&bytes[..width as usize]);
+                }
+                Inst::Branch(c, a, b, t) => {
+                    let (x, y) = (r[a], r[b]);
+                    let taken = match c {
+                        Cond::Eq => x == y,
+                        Cond::Ne => x != y,
+                        Cond::Lt => x < y,
+                        Cond::Ge => x >= y,
+                        Cond::Ltu => (x as u32) < (y as u32),
+                        Cond::Geu => (x as u32) >= (y as u32),
+                    };
+                    if taken {
+                        next = t;
+                    }
+                }
+                Inst::Jal(rd, t) => {
+                    self.set(rd, (TEXT_BASE + 4 * next as u32) as i32);
+                    next = t;
+                }
+                Inst::Jalr(rd, base, off) => {
+                    next = self.jump((r[base].wrapping_add(off) & !1) as u32)?;
+                    self.set(rd, (TEXT_BASE + 4 * (self.pc as u32 + 1)) as i32);
+                }
+                Inst::Ecall => match r[17] {
+                    64 => {
+                        let (buf, len) = (r[11] as u32, r[12] as u32);
+                        let start = self.addr(buf, 1)?;
+                        // a length wrapping around the address space reaches
+                        // the unmapped top of it
+                        let last = buf.saturating_add(len.max(1) - 1);
+                        self.addr(last, 1)?;
+                        let bytes = &self.mem[start..start + len as usize];
+                        match r[10] {
+                            1 => self.stdout.extend_from_slice(bytes),
+                            2 => self.stderr.extend_from_slice(bytes),
+                            _ => (),
+                        }
+                        self.regs[10] = len as i32;
+                    }
+                    93 => {
+                        return Ok(Outcome {
+                            status: r[10] & 0xff,
+                            stdout: String::from_utf8_lossy(&self.stdout).into_owned(),
+                            stderr: String::from_utf8_lossy(&self.stderr).into_owned(),
+                        });
+                    }
+                    n => return Err(Trap::Ecall(n)),
+                },
+            }
+            self.pc = next;
+        }
+    }
+}
+
+#[test]
+fn test_sim() {
+    let src = "
+    .section .rodata
+msg:
+    .ascii \"-\\n\"
+    .text
+_start:
+    li a0, 5
+    call fact
+    mv s1, a0
+    li t0, 0x80000000
+    li t1, -1
+    div t2, t0, t1        # overflows to INT_MIN
+    rem t3, t0, zero      # the dividend
+    bne t2, t3, fail
+    mulh t4, t0, t0       # 2 ** 62 >> 32
+    li t5, 0x40000000
+    bne t4, t5, fail
+    li a0, 1
+    la a1, msg
+    li a2, 2
+    li a7, 64
+    ecall
+    mv a0, s1
+    li a7, 93
+    ecall
+fail:
+    li a0, 1
+    li a7, 93
+    ecall
+
+# a0 = a0!
+fact:
+    addi sp, sp, -16
+    sw ra, 12(sp)
+    sw a0, 8(sp)
+    li a1, 1
+    bge a1, a0, done
+    addi a0, a0, -1
+    call fact
+    lw a1, 8(sp)
+    mul a0, a0, a1
+done:
+    lw ra, 12(sp)
+    addi sp, sp, 16
+    ret
+";
+    let p = assemble(src).unwrap();
+    assert_eq!(
+        run(&p, None),
+        Ok(Outcome {
+            status: 120,
+            stdout: "-\n".to_string(),
+            stderr: String::new()
+        })
+    );
+    assert_eq!(run(&p, Some(10)), Err(Trap::OutOfFuel(10)));
+
+    let bad = |s: &str| assemble(&format!("_start:\n{}\n", s)).unwrap_err().msg;
+    assert_eq!(bad("addi a0, a0, 2048"), "immediate `2048` out of range");
+    assert_eq!(bad("add a0, a1, a9"), "unknown register `a9`");
+    assert_eq!(bad("lw a0, 4(sp"), "missing `)` in `4(sp`");
+    assert_eq!(bad("mul a0, a1"), "`mul` takes 3 operands");
+    // numeric labels are not supported
+    assert_eq!(bad("1:\n    j 1b"), "unknown label `1b`");
+    let p = assemble("_start:\n    lw a0, 0(zero)\n").unwrap();
+    assert_eq!(run(&p, None), Err(Trap::Memory(0)));
+}
+
+#[test]
+fn test_traps() {
+    let bad = |s: &str| assemble(&format!("_start:\n{}\n", s)).unwrap_err().msg;
+    assert_eq!(bad("fence"), "unknown instruction `fence`");
+    assert_eq!(bad("lb a0, 0(sp)"), "`lb` is not supported, use `lbu`");
+    assert_eq!(bad("slli a0, a0, 32"), "shift amount `32` out of range");
+    assert_eq!(
+        bad(".data\n    ecall"),
+        "instruction `ecall` in the data section"
+    );
+    assert_eq!(bad("    la a0, msg"), "unknown label `msg`");
+    // the data has to leave room for the stack
+    let data = |n: u32| format!(".section .rodata\n.ascii \"{}\"", "x".repeat(n as usize));
+    let max = MEM_SIZE - STACK_SIZE;
+    assert!(assemble(&format!("{}\n.text\n_start:", data(max))).is_ok());
+    assert_eq!(bad(&data(max + 1)), format!("data exceeds {} bytes", max));
+
+    let run = |s: &str| run(&assemble(&format!("_start:\n{}\n", s)).unwrap(), Some(100));
+    let exit = "\n    li a7, 93\n    ecall";
+    // illegal control flow and system calls
+    assert_eq!(run("li a7, 1\n    ecall"), Err(Trap::Ecall(1)));
+    assert_eq!(run("li a0, 1"), Err(Trap::Jump(TEXT_BASE + 4)));
+    assert_eq!(
+        run("li t0, 0x10002\n    jalr zero, 0(t0)"),
+        Err(Trap::Jump(0x10002))
+    );
+    assert_eq!(run("jalr zero, 0(zero)"), Err(Trap::Jump(0)));
+
+    // memory bounds
+    let end = DATA_BASE + MEM_SIZE;
+    let at = |addr: u32, inst: &str| run(&format!("li t0, {:#x}\n    {}", addr, inst));
+    assert_eq!(
+        at(DATA_BASE - 4, "lw a0, 0(t0)"),
+        Err(Trap::Memory(DATA_BASE - 4))
+    );
+    assert_eq!(at(end, "sw a0, 0(t0)"), Err(Trap::Memory(end)));
+    assert_eq!(at(end - 2, "lw a0, 0(t0)"), Err(Trap::Misaligned(end - 2)));
+    // the last byte is mapped, the program then runs off its end
+    assert_eq!(
+        at(end, "sb a0, -1(t0)").unwrap_err(),
+        Trap::Jump(TEXT_BASE + 8)
+    );
+    assert_eq!(at(u32::MAX, "sb a0, 0(t0)"), Err(Trap::Memory(u32::MAX)));
+    let last = format!("li a0, 42\n    sw a0, -4(t0)\n    lw a0, -4(t0){}", exit);
+    assert_eq!(at(end, &last).map(|o| o.status), Ok(42));
+    // writes reaching past the end, or wrapping around
+    let write = |buf: u32, len: i32| {
+        let s = format!(
+            "li a1, {:#x}\n    li a2, {}\n    li a7, 64\n    ecall",
+            buf, len
+        );
+        run(&format!("li a0, 1\n    {}{}", s, exit))
+    };
+    // exits with the number of bytes written
+    assert_eq!(write(end - 2, 2).map(|o| o.status), Ok(2));
+    assert_eq!(write(end - 2, 4), Err(Trap::Memory(end + 1)));
+    assert_eq!(write(DATA_BASE + 16, -1), Err(Trap::Memory(u32::MAX)));
+}
@@ -0,0 +1,666 @@
+// WebAssembly emission
+//
+// Writes a module in the WebAssembly text format, one wasm function per
+// function, registers as `i32` locals (booleans as 0 and 1, unit as 0,
+// references as addresses). Stack slots live in linear memory, on a stack
+// growing down from its end (`$sp`); the constant strings at its start.
+//
+// Control flow is structured from the dominator tree, after Ramsey,
+// "Beyond Relooper" (ICFP 2022): a block reached by a single forward
+// edge is emitted in place at the branch, a join is emitted after a
+// `block` that its predecessors leave with `br`, and a loop header is
+// wrapped in a `loop` that its back edges continue with `br`. Phi nodes
+// are assigned on the edges, all arguments pushed before the first is
+// set. Lowered programs are reducible, as the scheme requires.
+//
+// Arithmetic is checked in 64 bits, `$rt.narrow` fails when the result
+// does not fit. The host provides `crust.write(fd, buffer, length)` and
+// `crust.exit(status)`, which does not return.
+
+use super::{error_message, pieces, Piece, ERROR_STATUS};
+use crate::ast::{Op, Span, Type};
+use crate::interp::ErrorKind;
+use crate::ir::dom::Dominators;
+use crate::ir::{BlockId, Const, Function, InstKind, Module, Reg, Term};
+
+// scratch for the digits of `$rt.write_int`, then "truefalse"
+const STRINGS: u32 = 25;
+
+const RUNTIME: &str = r#"  (import "crust" "write" (func $rt.write (param i32 i32 i32)))
+  (import "crust" "exit" (func $rt.exit (param i32)))
+  (memory (export "memory") 16)
+  ;; the stack starts at the end of the 16 pages of memory
+  (global $sp (mut i32) (i32.const 1048576))
+  (data (i32.const 16) "truefalse")
+
+  ;; writes the message to the standard error and exits
+  (func $rt.fail (param $msg i32) (param $len i32)
+    i32.const 2
+    local.get $msg
+    local.get $len
+    call $rt.write
+    i32.const 101
+    call $rt.exit
+    unreachable
+  )
+
+  ;; the value, failing unless it fits in 32 bits
+  (func $rt.narrow (param $v i64) (param $msg i32) (param $len i32) (result i32)
+    local.get $v
+    local.get $v
+    i32.wrap_i64
+    i64.extend_i32_s
+    i64.ne
+    if
+      local.get $msg
+      local.get $len
+      call $rt.fail
+    end
+    local.get $v
+    i32.wrap_i64
+  )
+
+  ;; the value in decimal, digits written backwards from the end of the
+  ;; scratch
+  (func $rt.write_int (param $fd i32) (param $v i32)
+    (local $n i32) (local $p i32)
+    i32.const 16
+    local.set $p
+    i32.const 0
+    local.get $v
+    i32.sub
+    local.get $v
+    local.get $v
+    i32.const 0
+    i32.lt_s
+    select
+    local.set $n
+    loop
+      local.get $p
+      i32.const 1
+      i32.sub
+      local.tee $p
+      local.get $n
+      i32.const 10
+      i32.rem_u
+      i32.const 48
+      i32.add
+      i32.store8
+      local.get $n
+      i32.const 10
+      i32.div_u
+      local.tee $n
+      br_if 0
+    end
+    local.get $v
+    i32.const 0
+    i32.lt_s
+    if
+      local.get $p
+      i32.const 1
+      i32.sub
+      local.tee $p
+      i32.const 45
+      i32.store8
+    end
+    local.get $fd
+    local.get $p
+    i32.const 16
+    local.get $p
+    i32.sub
+    call $rt.write
+  )
+
+  (func $rt.write_bool (param $fd i32) (param $v i32)
+    local.get $fd
+    i32.const 16
+    i32.const 20
+    local.get $v
+    select
+    i32.const 5
+    local.get $v
+    i32.sub
+    call $rt.write
+  )
+
+  ;; $b ** $e for a non-negative $e by squaring, as `i32::checked_pow`
+  (func $rt.pow (param $b i32) (param $e i32) (param $msg i32) (param $len i32) (result i32)
+    (local $acc i32)
+    i32.const 1
+    local.set $acc
+    local.get $e
+    i32.eqz
+    if
+      i32.const 1
+      return
+    end
+    block
+      loop
+        local.get $e
+        i32.const 1
+        i32.le_s
+        br_if 1
+        local.get $e
+        i32.const 1
+        i32.and
+        if
+          local.get $acc
+          i64.extend_i32_s
+          local.get $b
+          i64.extend_i32_s
+          i64.mul
+          local.get $msg
+          local.get $len
+          call $rt.narrow
+          local.set $acc
+        end
+        local.get $e
+        i32.const 1
+        i32.shr_u
+        local.set $e
+        local.get $b
+        i64.extend_i32_s
+        local.get $b
+        i64.extend_i32_s
+        i64.mul
+        local.get $msg
+        local.get $len
+        call $rt.narrow
+        local.set $b
+        br 0
+      end
+    end
+    local.get $acc
+    i64.extend_i32_s
+    local.get $b
+    i64.extend_i32_s
+    i64.mul
+    local.get $msg
+    local.get $len
+    call $rt.narrow
+  )
+"#;
+
+// the constant strings, from `STRINGS` on
+struct Data {
+    bytes: Vec<u8>,
+    strings: Vec<(String, u32)>,
+}
+
+impl Data {
+    // the address and length of a string
+    fn string(&mut self, s: &str) -> (u32, usize) {
+        if let Some((_, a)) = self.strings.iter().find(|(c, _)| c == s) {
+            return (*a, s.len());
+        }
+        let a = STRINGS + self.bytes.len() as u32;
+        self.bytes.extend(s.bytes());
+        self.strings.push((s.to_string(), a));
+        (a, s.len())
+    }
+}
+
+// a string literal of the text format
+fn literal(bytes: &[u8]) -> String {
+    let mut lit = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => lit += &format!("\\{}", b as char),
+            0x20..=0x7e => lit.push(b as char),
+            _ => lit += &format!("\\{:02x}", b),
+        }
+    }
+    lit + "\""
+}
+
+// the labels enclosing the current instruction, the innermost last
+enum Label {
+    // a `block` followed by the join
+    Block(BlockId),
+    // a `loop` headed by the block
+    Loop(BlockId),
+    If,
+}
+
+struct Emitter<'f, 'a> {
+    f: &'f Function<'a>,
+    data: &'f mut Data,
+    // position in the reverse postorder
+    order: Vec<usize>,
+    // merge children in the dominator tree, the latest first
+    merges: Vec<Vec<BlockId>>,
+    // reached by several forward edges
+    join: Vec<bool>,
+    // target of a back edge
+    header: Vec<bool>,
+    // address of each `alloca` in the frame
+    slots: Vec<u32>,
+    frame: u32,
+    labels: Vec<Label>,
+    out: String,
+}
+
+// the text of a module
+pub fn emit(m: &Module) -> String {
+    let mut data = Data {
+        bytes: vec![],
+        strings: vec![],
+    };
+    let mut fns = String::new();
+    for f in &m.fns {
+        fns += &function(m, f, &mut data);
+    }
+    let mut s = String::from("(module\n");
+    s += RUNTIME;
+    if !data.bytes.is_empty() {
+        s += &format!(
+            "  (data (i32.const {}) {})\n",
+            STRINGS,
+            literal(&data.bytes)
+        );
+    }
+    if matches!(m.function("main"), Some(g) if m.fns[g].params.is_empty()) {
+        s += "  (export \"main\" (func $crust.main))\n";
+    }
+    s + &fns + ")\n"
+}
+
+fn function(m: &Module, f: &Function, data: &mut Data) -> String {
+    let dom = Dominators::new(f);
+    let mut order = vec![0; f.blocks.len()];
+    for (i, b) in dom.rpo.iter().enumerate() {
+        order[*b] = i;
+    }
+    let (mut join, mut header) = (vec![false; f.blocks.len()], vec![false; f.blocks.len()]);
+    for (b, preds) in f.preds().iter().enumerate() {
+        let preds = preds.iter().filter(|p| dom.reachable(**p));
+        let forward = preds.clone().filter(|p| order[**p] < order[b]).count();
+        join[b] = forward > 1;
+        header[b] = dom.reachable(b) && preds.count() > forward;
+    }
+    let merges = (dom.children().into_iter())
+        .map(|c| c.into_iter().rev().filter(|c| join[*c]).collect())
+        .collect();
+    let mut slots = vec![0; f.regs.len()];
+    let mut frame = 0;
+    for inst in f.blocks.iter().flat_map(|b| &b.insts) {
+        if let (Some(d), InstKind::Alloca(_)) = (inst.dst, &inst.kind) {
+            slots[d] = frame;
+            frame += 4;
+        }
+    }
+    let mut e = Emitter {
+        f,
+        data,
+        order,
+        merges,
+        join,
+        header,
+        slots,
+        frame,
+        labels: vec![],
+        out: String::new(),
+    };
+
+    let mut s = format!("\n  (func $crust.{}", f.id.fragment);
+    for p in &f.params {
+        s += &format!(" (param $r{} i32)", p);
+    }
+    s += " (result i32)\n";
+    let locals: Vec<_> = (0..f.regs.len())
+        .filter(|r| !f.params.contains(r))
+        .map(|r| format!("(local $r{} i32)", r))
+        .chain(Some("(local $fp i32)".to_string()).filter(|_| frame > 0))
+        .collect();
+    if !locals.is_empty() {
+        s += &format!("    {}\n", locals.join(" "));
+    }
+    if frame > 0 {
+        e.line("global.get $sp".to_string());
+        e.line(format!("i32.const {}", frame));
+        e.line("i32.sub".to_string());
+        e.line("local.tee $fp".to_string());
+        e.line("global.set $sp".to_string());
+    }
+    e.tree(m, 0);
+    s + &e.out + "  )\n"
+}
+
+impl<'f, 'a> Emitter<'f, 'a> {
+    fn line(&mut self, s: String) {
+        for _ in 0..self.labels.len() + 2 {
+            self.out += "  ";
+        }
+        self.out += &s;
+        self.out.push('\n');
+    }
+
+    // the depth of the label a branch to `b` targets
+    fn depth(&self, b: BlockId) -> usize {
+        let i = (self.labels.iter().rposition(|l| match l {
+            Label::Block(j) => *j == b,
+            Label::Loop(h) => *h == b,
+            Label::If => false,
+        }))
+        .unwrap();
+        self.labels.len() - 1 - i
+    }
+
+    // the block and the blocks it dominates
+    fn tree(&mut self, m: &Module, b: BlockId) {
+        let merges = self.merges[b].clone();
+        if self.header[b] {
+            self.line("loop".to_string());
+            self.labels.push(Label::Loop(b));
+            self.within(m, b, &merges);
+            self.labels.pop();
+            self.line("end".to_string());
+            // not reached, the loop is only left by branches
+            self.line("unreachable".to_string());
+        } else {
+            self.within(m, b, &merges);
+        }
+    }
+
+    // the block inside a `block` for each of its merge children
+    fn within(&mut self, m: &Module, b: BlockId, merges: &[BlockId]) {
+        match merges.split_first() {
+            Some((j, rest)) => {
+                self.line("block".to_string());
+                self.labels.push(Label::Block(*j));
+                self.within(m, b, rest);
+                self.labels.pop();
+                self.line("end".to_string());
+                self.tree(m, *j);
+            }
+            None => self.block(m, b),
+        }
+    }
+
+    // whether the edge from `b` to `t` is a branch without phi nodes
+    fn plain(&self, b: BlockId, t: BlockId) -> bool {
+        self.f.blocks[t].phis.is_empty() && (self.join[t] || self.order[t] <= self.order[b])
+    }
+
+    fn edge(&mut self, m: &Module, b: BlockId, t: BlockId) {
+        let phis = &self.f.blocks[t].phis;
+        for phi in phis {
+            let (_, r) = phi.args.iter().find(|(p, _)| *p == b).unwrap();
+            self.line(format!("local.get $r{}", r));
+        }
+        for phi in phis.iter().rev() {
+            self.line(format!("local.set $r{}", phi.dst));
+        }
+        if self.join[t] || self.order[t] <= self.order[b] {
+            self.line(format!("br {}", self.depth(t)));
+        } else {
+            self.tree(m, t);
+        }
+    }
+
+    // fails with the message unless the value on the stack is zero
+    fn fail_if(&mut self, span: Span, kind: ErrorKind) {
+        let (a, n) = self.data.string(&error_message(span, &kind));
+        self.line("if".to_string());
+        self.labels.push(Label::If);
+        self.line(format!("i32.const {}", a));
+        self.line(format!("i32.const {}", n));
+        self.line("call $rt.fail".to_string());
+        self.labels.pop();
+        self.line("end".to_string());
+    }
+
+    // pushes the address and length of the message
+    fn message(&mut self, span: Span, kind: ErrorKind) {
+        let (a, n) = self.data.string(&error_message(span, &kind));
+        self.line(format!("i32.const {}", a));
+        self.line(format!("i32.const {}", n));
+    }
+
+    fn wide(&mut self, r: Reg) {
+        self.line(format!("local.get $r{}", r));
+        self.line("i64.extend_i32_s".to_string());
+    }
+
+    // writes a formatted message to `fd`
+    fn write(&mut self, fd: i32, fmt: &str, args: &[Reg]) {
+        for piece in pieces(fmt) {
+            let routine = match piece {
+                Piece::Arg(i) if i >= args.len() => continue,
+                Piece::Arg(i) => match &self.f.regs[args[i]] {
+                    Type::I32 => Some((args[i], "$rt.write_int")),
+                    Type::Bool => Some((args[i], "$rt.write_bool")),
+                    _ => None,
+                },
+                Piece::Text(_) => None,
+            };
+            self.line(format!("i32.const {}", fd));
+            match (routine, piece) {
+                (Some((a, routine)), _) => {
+                    self.line(format!("local.get $r{}", a));
+                    self.line(format!("call {}", routine));
+                }
+                (None, piece) => {
+                    let text = match piece {
+                        Piece::Text(t) => t,
+                        // unit
+                        Piece::Arg(_) => "()".to_string(),
+                    };
+                    let (a, n) = self.data.string(&text);
+                    self.line(format!("i32.const {}", a));
+                    self.line(format!("i32.const {}", n));
+                    self.line("call $rt.write".to_string());
+                }
+            }
+        }
+    }
+
+    fn block(&mut self, m: &Module, b: BlockId) {
+        let f = self.f;
+        for inst in &f.blocks[b].insts {
+            let span = inst.span;
+            match &inst.kind {
+                InstKind::Const(c) => {
+                    let v = match c {
+                        Const::Int(n) => *n,
+                        Const::Bool(b) => *b as i32,
+                        Const::Unit => 0,
+                    };
+                    self.line(format!("i32.const {}", v));
+                }
+                InstKind::Copy(r) => self.line(format!("local.get $r{}", r)),
+                InstKind::Bin(op, l, _) if f.regs[*l] == Type::Unit => {
+                    self.line(format!("i32.const {}", (*op == Op::Eq) as i32));
+                }
+                InstKind::Bin(op, l, r) => match op {
+                    Op::Add | Op::Sub | Op::Mul => {
+                        self.wide(*l);
+                        self.wide(*r);
+                        let i = match op {
+                            Op::Add => "i64.add",
+                            Op::Sub => "i64.sub",
+                            _ => "i64.mul",
+                        };
+                        self.line(i.to_string());
+                        self.message(span, ErrorKind::Overflow(*op));
+                        self.line("call $rt.narrow".to_string());
+                    }
+                    Op::Div => {
+                        self.line(format!("local.get $r{}", r));
+                        self.line("i32.eqz".to_string());
+                        self.fail_if(span, ErrorKind::DivisionByZero);
+                        // only `i32::MIN / -1` does not fit
+                        self.wide(*l);
+                        self.wide(*r);
+                        self.line("i64.div_s".to_string());
+                        self.message(span, ErrorKind::Overflow(*op));
+                        self.line("call $rt.narrow".to_string());
+                    }
+                    Op::Pow => {
+                        self.line(format!("local.get $r{}", r));
+                        self.line("i32.const 0".to_string());
+                        self.line("i32.lt_s".to_string());
+                        self.fail_if(span, ErrorKind::NegativeExponent);
+                        self.line(format!("local.get $r{}", l));
+                        self.line(format!("local.get $r{}", r));
+                        self.message(span, ErrorKind::Overflow(*op));
+                        self.line("call $rt.pow".to_string());
+                    }
+                    _ => {
+                        self.line(format!("local.get $r{}", l));
+                        self.line(format!("local.get $r{}", r));
+                        let i = match op {
+                            Op::Lt => "i32.lt_s",
+                            Op::Gt => "i32.gt_s",
+                            Op::Eq => "i32.eq",
+                            Op::Neq => "i32.ne",
+                            Op::And => "i32.and",
+                            _ => "i32.or",
+                        };
+                        self.line(i.to_string());
+                    }
+                },
+                InstKind::Un(Op::Not, r) => {
+                    self.line(format!("local.get $r{}", r));
+                    self.line("i32.eqz".to_string());
+                }
+                InstKind::Un(_, r) => {
+                    self.line("i64.const 0".to_string());
+                    self.wide(*r);
+                    self.line("i64.sub".to_string());
+                    self.message(span, ErrorKind::NegOverflow);
+                    self.line("call $rt.narrow".to_string());
+                }
+                InstKind::Call(h, args) => {
+                    for a in args {
+                        self.line(format!("local.get $r{}", a));
+                    }
+                    self.line(format!("call $crust.{}", m.fns[*h].id.fragment));
+                    // unit, when not used
+                    if inst.dst.is_none() {
+                        self.line("drop".to_string());
+                    }
+                }
+                InstKind::Alloca(_) => {
+                    let slot = self.slots[inst.dst.unwrap()];
+                    self.line("local.get $fp".to_string());
+                    self.line(format!("i32.const {}", slot));
+                    self.line("i32.add".to_string());
+                }
+                InstKind::Load(p) => {
+                    self.line(format!("local.get $r{}", p));
+                    self.line("i32.load".to_string());
+                }
+                InstKind::Store(p, v) => {
+                    self.line(format!("local.get $r{}", p));
+                    self.line(format!("local.get $r{}", v));
+                    self.line("i32.store".to_string());
+                }
+                InstKind::Print(fmt, args) => self.write(1, &(fmt.clone() + "\n"), args),
+            }
+            if let Some(d) = inst.dst {
+                self.line(format!("local.set $r{}", d));
+            }
+        }
+        match &f.blocks[b].term {
+            Term::Jump(t) => self.edge(m, b, *t),
+            Term::Branch(c, t, e) => {
+                self.line(format!("local.get $r{}", c));
+                if self.plain(b, *t) {
+                    self.line(format!("br_if {}", self.depth(*t)));
+                } else {
+                    self.line("if".to_string());
+                    self.labels.push(Label::If);
+                    self.edge(m, b, *t);
+                    self.labels.pop();
+                    self.line("end".to_string());
+                }
+                self.edge(m, b, *e);
+            }
+            Term::Return(r) => {
+                self.line(format!("local.get $r{}", r));
+                if self.frame > 0 {
+                    self.line("local.get $fp".to_string());
+                    self.line(format!("i32.const {}", self.frame));
+                    self.line("i32.add".to_string());
+                    self.line("global.set $sp".to_string());
+                }
+                self.line("return".to_string());
+            }
+            Term::Panic(fmt, args, span) => {
+                let fmt = error_message(*span, &ErrorKind::Panic(fmt.clone()));
+                self.write(2, &fmt, args);
+                self.line(format!("i32.const {}", ERROR_STATUS));
+                self.line("call $rt.exit".to_string());
+                self.line("unreachable".to_string());
+            }
+            Term::Unreachable => self.line("unreachable".to_string()),
+        }
+    }
+}
+
+// runs a module under wasmi, as the host of a compiled program
+#[cfg(test)]
+fn run(wat: &str) -> super::Outcome {
+    use wasmi::core::Trap;
+    use wasmi::{Caller, Engine, Extern, Linker, Store};
+
+    type Host<'a> = Caller<'a, (String, String)>;
+    let engine = Engine::default();
+    let module = wasmi::Module::new(&engine, &wat::parse_str(wat).unwrap()[..]).unwrap();
+    let mut store = Store::new(&engine, (String::new(), String::new()));
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap(
+            "crust",
+            "write",
+            |mut c: Host, fd: i32, buf: i32, len: i32| {
+                let memory = c
+                    .get_export("memory")
+                    .and_then(Extern::into_memory)
+                    .unwrap();
+                let bytes = &memory.data(&c)[buf as usize..(buf + len) as usize];
+                let s = String::from_utf8_lossy(bytes).into_owned();
+                match fd {
+                    1 => c.data_mut().0 += &s,
+                    _ => c.data_mut().1 += &s,
+                }
+            },
+        )
+        .unwrap();
+    linker
+        .func_wrap(
+            "crust",
+            "exit",
+            |_: Host, status: i32| -> Result<(), Trap> { Err(Trap::i32_exit(status)) },
+        )
+        .unwrap();
+    let instance = (linker.instantiate(&mut store, &module).unwrap())
+        .start(&mut store)
+        .unwrap();
+    let main = instance.get_typed_func::<(), i32>(&store, "main").unwrap();
+    let status = match main.call(&mut store, ()) {
+        Ok(v) => v & 0xff,
+        Err(t) => t.i32_exit_status().unwrap_or_else(|| panic!("{}", t)),
+    };
+    let (stdout, stderr) = store.into_data();
+    super::Outcome {
+        status,
+        stdout,
+        stderr,
+    }
+}
+
+#[test]
+fn test_literal() {
+    assert_eq!(literal(b"a \"b\"\\\n"), "\"a \\\"b\\\"\\\\\\0a\"");
+}
+
+#[test]
+fn test_wasm() {
+    use super::{interpret, lowered_programs};
+
+    for (name, p, m) in &lowered_programs() {
+        assert_eq!(run(&emit(m)), interpret(p), "{}", name);
+    }
+}
@@ -0,0 +1,469 @@
+// x86-64 emission
+//
+// Writes a module as GNU assembler source (AT&T syntax) for Linux, to be
+// assembled and linked without the C library (`as -o p.o p.s && ld -o p
+// p.o`). Functions follow the System V calling convention: the first six
+// arguments in registers, the rest on the stack, the result in `%rax`.
+//
+// Registers are allocated to stack slots, one 8-byte slot below `%rbp`
+// for each register and each `alloca`. An instruction loads its operands
+// from their slots into `%rax` and `%rcx` and stores the result to the
+// slot of its destination; phi nodes are assigned on the incoming edges,
+// through the stack so that they are assigned in parallel. A failed
+// check jumps to a stub at the end of the function, passing its message
+// to `rt.fail`. The runtime (`rt.*`) prints with the `write` system call,
+// and `_start` exits with the result of `crust.main`.
+
+use super::{ascii, error_message, pieces, Piece, ERROR_STATUS};
+use crate::ast::{Op, Type};
+use crate::interp::ErrorKind;
+use crate::ir::{Const, Function, InstKind, Module, Reg, Term};
+
+const RUNTIME: &str = "\
+# write(%edi = fd, %rsi = buffer, %edx = length)
+rt.write:
+    movl $1, %eax
+    syscall
+    ret
+
+# write(%edi = fd, %esi = value) in decimal
+rt.write_int:
+    pushq %rbp
+    movq %rsp, %rbp
+    subq $32, %rsp
+    movslq %esi, %rax
+    movq %rax, %r8
+    testq %rax, %rax
+    jns 1f
+    negq %rax
+1:  movq %rbp, %rsi
+    movq $10, %r9
+2:  xorl %edx, %edx
+    divq %r9
+    addb $'0', %dl
+    decq %rsi
+    movb %dl, (%rsi)
+    testq %rax, %rax
+    jnz 2b
+    testq %r8, %r8
+    jns 3f
+    decq %rsi
+    movb $'-', (%rsi)
+3:  movq %rbp, %rdx
+    subq %rsi, %rdx
+    call rt.write
+    leave
+    ret
+
+# write(%edi = fd, %esi = value) as `true` or `false`
+rt.write_bool:
+    testl %esi, %esi
+    jz 1f
+    leaq rt.true(%rip), %rsi
+    movl $4, %edx
+    jmp rt.write
+1:  leaq rt.false(%rip), %rsi
+    movl $5, %edx
+    jmp rt.write
+
+# writes the message (%rsi, length %edx) to the standard error and exits
+rt.fail:
+    movl $2, %edi
+    call rt.write
+    movl $101, %edi
+    jmp rt.exit
+
+# exit(%edi = status)
+rt.exit:
+    movl $60, %eax
+    syscall
+
+# %edi ** %esi (non-negative) by squaring, %edx = 1 on overflow
+rt.pow:
+    movl $1, %eax
+    xorl %edx, %edx
+    testl %esi, %esi
+    jz 3f
+1:  cmpl $1, %esi
+    jle 2f
+    testl $1, %esi
+    jz 4f
+    imull %edi, %eax
+    jno 4f
+    movl $1, %edx
+4:  shrl %esi
+    imull %edi, %edi
+    jno 1b
+    movl $1, %edx
+    jmp 1b
+2:  imull %edi, %eax
+    jno 3f
+    movl $1, %edx
+3:  ret
+
+.globl _start
+_start:
+    call crust.main
+    movl %eax, %edi
+    jmp rt.exit
+";
+
+const ARGS64: [&str; 6] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+
+struct Emitter {
+    // read-only strings
+    strings: Vec<String>,
+    out: String,
+    // internal labels
+    labels: usize,
+}
+
+// the assembly of a module
+pub fn emit(m: &Module) -> String {
+    let mut e = Emitter {
+        strings: vec![],
+        out: String::new(),
+        labels: 0,
+    };
+    for (g, f) in m.fns.iter().enumerate() {
+        e.function(m, g, f);
+    }
+    let mut s = String::from("# crust module\n    .section .rodata\n");
+    s += "rt.true:\n    .ascii \"true\"\nrt.false:\n    .ascii \"false\"\n";
+    for (i, c) in e.strings.iter().enumerate() {
+        s += &format!(".Lstr{}:\n    .ascii {}\n", i, ascii(c));
+    }
+    s += "\n    .text\n";
+    if matches!(m.function("main"), Some(g) if m.fns[g].params.is_empty()) {
+        s += RUNTIME;
+    } else {
+        // a library, no entry point
+        s += &RUNTIME[..RUNTIME.find(".globl _start").unwrap()];
+    }
+    s + &e.out
+}
+
+// the slot of a register
+fn slot(r: Reg) -> String {
+    format!("-{}(%rbp)", 8 * (r + 1))
+}
+
+impl Emitter {
+    fn line(&mut self, s: String) {
+        self.out += "    ";
+        self.out += &s;
+        self.out.push('\n');
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(".Ll{}", self.labels - 1)
+    }
+
+    // a string constant and its length
+    fn string(&mut self, s: &str) -> (String, usize) {
+        let i = match self.strings.iter().position(|c| c == s) {
+            Some(i) => i,
+            None => {
+                self.strings.push(s.to_string());
+                self.strings.len() - 1
+            }
+        };
+        (format!(".Lstr{}", i), s.len())
+    }
+
+    // writes a formatted message to `fd`
+    fn write(&mut self, f: &Function, fd: i32, fmt: &str, args: &[Reg]) {
+        for piece in pieces(fmt) {
+            if matches!(piece, Piece::Arg(i) if i >= args.len()) {
+                continue;
+            }
+            self.line(format!("movl ${}, %edi", fd));
+            let routine = match piece {
+                Piece::Arg(i) => match args.get(i).map(|a| (*a, &f.regs[*a])) {
+                    Some((a, Type::I32)) => Some((a, "rt.write_int")),
+                    Some((a, Type::Bool)) => Some((a, "rt.write_bool")),
+                    _ => None,
+                },
+                Piece::Text(_) => None,
+            };
+            match (routine, piece) {
+                (Some((a, routine)), _) => {
+                    self.line(format!("movl {}, %esi", slot(a)));
+                    self.line(format!("call {}", routine));
+                }
+                (None, piece) => {
+                    let text = match piece {
+                        Piece::Text(t) => t,
+                        // unit
+                        Piece::Arg(_) => "()".to_string(),
+                    };
+                    let (s, n) = self.string(&text);
+                    self.line(format!("leaq {}(%rip), %rsi", s));
+                    self.line(format!("movl ${}, %edx", n));
+                    self.line("call rt.write".to_string());
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, m: &Module, g: usize, f: &Function) {
+        // slots of the registers, then of the allocas
+        let mut allocas = 0;
+        let mut pointee = vec![0; f.regs.len()];
+        for inst in f.blocks.iter().flat_map(|b| &b.insts) {
+            if let (Some(d), InstKind::Alloca(_)) = (inst.dst, &inst.kind) {
+                pointee[d] = f.regs.len() + allocas;
+                allocas += 1;
+            }
+        }
+        let frame = (8 * (f.regs.len() + allocas)).next_multiple_of(16);
+        let block = |b: usize| format!(".L{}_b{}", g, b);
+
+        self.out += &format!(
+            "\n    .globl crust.{}\ncrust.{}:\n",
+            f.id.fragment, f.id.fragment
+        );
+        self.line("pushq %rbp".to_string());
+        self.line("movq %rsp, %rbp".to_string());
+        if frame > 0 {
+            self.line(format!("subq ${}, %rsp", frame));
+        }
+        for (i, p) in f.params.iter().enumerate() {
+            match i < 6 {
+                true => self.line(format!("movq {}, {}", ARGS64[i], slot(*p))),
+                false => {
+                    self.line(format!("movq {}(%rbp), %rax", 16 + 8 * (i - 6)));
+                    self.line(format!("movq %rax, {}", slot(*p)));
+                }
+            }
+        }
+        // failure stubs: label, message
+        let mut stubs = vec![];
+        for (b, bl) in f.blocks.iter().enumerate() {
+            self.out += &format!("{}:\n", block(b));
+            for inst in &bl.insts {
+                let d = inst.dst.unwrap_or(0);
+                let span = inst.span;
+                let mut fail = |e: &mut Self, kind: ErrorKind| {
+                    let label = e.label();
+                    stubs.push((label.clone(), error_message(span, &kind)));
+                    label
+                };
+                match &inst.kind {
+                    InstKind::Const(c) => {
+                        let v = match c {
+                            Const::Int(n) => *n,
+                            Const::Bool(b) => *b as i32,
+                            Const::Unit => 0,
+                        };
+                        self.line(format!("movl ${}, {}", v, slot(d)));
+                    }
+                    InstKind::Copy(r) => {
+                        self.line(format!("movq {}, %rax", slot(*r)));
+                        self.line(format!("movq %rax, {}", slot(d)));
+                    }
+                    InstKind::Bin(op, l, _) if f.regs[*l] == Type::Unit => {
+                        self.line(format!("movl ${}, {}", (*op == Op::Eq) as i32, slot(d)));
+                    }
+                    InstKind::Bin(op, l, r) => {
+                        self.line(format!("movl {}, %eax", slot(*l)));
+                        self.line(format!("movl {}, %ecx", slot(*r)));
+                        match op {
+                            Op::Add | Op::Sub | Op::Mul => {
+                                let inst = match op {
+                                    Op::Add => "addl",
+                                    Op::Sub => "subl",
+                                    _ => "imull",
+                                };
+                                self.line(format!("{} %ecx, %eax", inst));
+                                let stub = fail(self, ErrorKind::Overflow(*op));
+                                self.line(format!("jo {}", stub));
+                            }
+                            Op::Div => {
+                                self.line("testl %ecx, %ecx".to_string());
+                                let stub = fail(self, ErrorKind::DivisionByZero);
+                                self.line(format!("jz {}", stub));
+                                let ok = self.label();
+                                self.line("cmpl $-1, %ecx".to_string());
+                                self.line(format!("jne {}", ok));
+                                self.line("cmpl $-2147483648, %eax".to_string());
+                                let stub = fail(self, ErrorKind::Overflow(*op));
+                                self.line(format!("je {}", stub));
+                                self.out += &format!("{}:\n", ok);
+                                self.line("cltd".to_string());
+                                self.line("idivl %ecx".to_string());
+                            }
+                            Op::Pow => {
+                                self.line("testl %ecx, %ecx".to_string());
+                                let stub = fail(self, ErrorKind::NegativeExponent);
+                                self.line(format!("js {}", stub));
+                                self.line("movl %eax, %edi".to_string());
+                                self.line("movl %ecx, %esi".to_string());
+                                self.line("call rt.pow".to_string());
+                                self.line("testl %edx, %edx".to_string());
+                                let stub = fail(self, ErrorKind::Overflow(*op));
+                                self.line(format!("jnz {}", stub));
+                            }
+                            Op::And => self.line("andl %ecx, %eax".to_string()),
+                            Op::Or => self.line("orl %ecx, %eax".to_string()),
+                            _ => {
+                                let set = match op {
+                                    Op::Lt => "setl",
+                                    Op::Gt => "setg",
+                                    Op::Eq => "sete",
+                                    _ => "setne",
+                                };
+                                self.line("cmpl %ecx, %eax".to_string());
+                                self.line(format!("{} %al", set));
+                                self.line("movzbl %al, %eax".to_string());
+                            }
+                        }
+                        self.line(format!("movl %eax, {}", slot(d)));
+                    }
+                    InstKind::Un(op, r) => {
+                        self.line(format!("movl {}, %eax", slot(*r)));
+                        match op {
+                            Op::Not => self.line("xorl $1, %eax".to_string()),
+                            _ => {
+                                self.line("negl %eax".to_string());
+                                let stub = fail(self, ErrorKind::NegOverflow);
+                                self.line(format!("jo {}", stub));
+                            }
+                        }
+                        self.line(format!("movl %eax, {}", slot(d)));
+                    }
+                    InstKind::Call(h, args) => {
+                        let stack = args.len().saturating_sub(6);
+                        // the stack stays 16-byte aligned at the call
+                        let pad = 8 * (stack % 2);
+                        if pad > 0 {
+                            self.line(format!("subq ${}, %rsp", pad));
+                        }
+                        for a in args.iter().skip(6).rev() {
+                            self.line(format!("pushq {}", slot(*a)));
+                        }
+                        for (i, a) in args.iter().take(6).enumerate() {
+                            self.line(format!("movq {}, {}", slot(*a), ARGS64[i]));
+                        }
+                        self.line(format!("call crust.{}", m.fns[*h].id.fragment));
+                        if stack > 0 {
+                            self.line(format!("addq ${}, %rsp", 8 * stack + pad));
+                        }
+                        if let Some(d) = inst.dst {
+                            self.line(format!("movq %rax, {}", slot(d)));
+                        }
+                    }
+                    InstKind::Alloca(_) => {
+                        self.line(format!("leaq {}, %rax", slot(pointee[d])));
+                        self.line(format!("movq %rax, {}", slot(d)));
+                    }
+                    InstKind::Load(p) => {
+                        // whole slots, whatever the type
+                        self.line(format!("movq {}, %rcx", slot(*p)));
+                        self.line("movq (%rcx), %rax".to_string());
+                        self.line(format!("movq %rax, {}", slot(d)));
+                    }
+                    InstKind::Store(p, v) => {
+                        self.line(format!("movq {}, %rcx", slot(*p)));
+                        self.line(format!("movq {}, %rax", slot(*v)));
+                        self.line("movq %rax, (%rcx)".to_string());
+                    }
+                    InstKind::Print(fmt, args) => self.write(f, 1, &(fmt.clone() + "\n"), args),
+                }
+            }
+            // assigns the phi nodes of `t` for the edge from `b`
+            let edge = |e: &mut Self, t: usize| {
+                let phis = &f.blocks[t].phis;
+                for phi in phis {
+                    let (_, r) = phi.args.iter().find(|(p, _)| *p == b).unwrap();
+                    e.line(format!("pushq {}", slot(*r)));
+                }
+                for phi in phis.iter().rev() {
+                    e.line(format!("popq {}", slot(phi.dst)));
+                }
+                e.line(format!("jmp {}", block(t)));
+            };
+            match &bl.term {
+                Term::Jump(t) => edge(self, *t),
+                Term::Branch(c, t, e) => {
+                    let other = self.label();
+                    self.line(format!("cmpl $0, {}", slot(*c)));
+                    self.line(format!("je {}", other));
+                    edge(self, *t);
+                    self.out += &format!("{}:\n", other);
+                    edge(self, *e);
+                }
+                Term::Return(r) => {
+                    self.line(format!("movq {}, %rax", slot(*r)));
+                    self.line("leave".to_string());
+                    self.line("ret".to_string());
+                }
+                Term::Panic(fmt, args, span) => {
+                    let fmt = error_message(*span, &ErrorKind::Panic(fmt.clone()));
+                    self.write(f, 2, &fmt, args);
+                    self.line(format!("movl ${}, %edi", ERROR_STATUS));
+                    self.line("jmp rt.exit".to_string());
+                }
+                Term::Unreachable => self.line("ud2".to_string()),
+            }
+        }
+        for (label, msg) in stubs {
+            let (s, n) = self.string(&msg);
+            self.out += &format!("{}:\n", label);
+            self.line(format!("leaq {}(%rip), %rsi", s));
+            self.line(format!("movl ${}, %edx", n));
+            self.line("jmp rt.fail".to_string());
+        }
+    }
+}
+
+#[test]
+fn test_x86() {
+    use super::{execute, interpret, lowered_programs};
+    use std::process::Command;
+
+    // skipped where the programs cannot be assembled or run
+    if !cfg!(all(target_arch = "x86_64", target_os = "linux"))
+        || Command::new("as").arg("--version").output().is_err()
+    {
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("crust-x86-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, p, m) in &lowered_programs() {
+        let (s, o, exe) = (
+            dir.join(format!("{}.s", name)),
+            dir.join(format!("{}.o", name)),
+            dir.join(name),
+        );
+        std::fs::write(&s, emit(m)).unwrap();
+        let out = Command::new("as")
+            .arg("-o")
+            .arg(&o)
+            .arg(&s)
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let out = Command::new("ld")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&o)
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let r = execute(&mut Command::new(&exe)).unwrap();
+        assert_eq!(r, Some(interpret(p)), "{}", name);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,152 @@
+// Dynamic aliasing checker (Stacked Borrows)
+//
+// Each store cell has a stack of the tags allowed to access it, with the
+// tag of the variable itself at the bottom. Borrowing pushes a new tag
+// above the one it was derived from (`Unique` for `&mut`, `SharedRO` for
+// `&`), after an access through the parent:
+//
+// - a write through a tag pops everything above it,
+// - a read through a tag pops the `Unique` tags above it,
+// - an access through a tag not in the stack, or a write through a
+//   `SharedRO` tag, is a violation.
+//
+// Popped tags are remembered with the access that invalidated them.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Perm {
+    Unique,
+    SharedRO,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// an access through `tag`, which is no longer (or never was) permitted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation<'a> {
+    pub access: Access,
+    pub tag: usize,
+    // the access that popped the tag
+    pub invalidated: Option<(Access, Span<'a>)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stacks<'a> {
+    stacks: HashMap<usize, Vec<(usize, Perm)>>,
+    invalidated: HashMap<usize, (Access, Span<'a>)>,
+}
+
+impl<'a> Stacks<'a> {
+    pub fn new() -> Self {
+        Stacks::default()
+    }
+
+    // a new cell, owned by `tag`
+    pub fn alloc(&mut self, addr: usize, tag: usize) {
+        self.stacks.insert(addr, vec![(tag, Perm::Unique)]);
+    }
+
+    pub fn free(&mut self, addr: usize) {
+        self.stacks.remove(&addr);
+    }
+
+    // the tags allowed to access the cell, bottom first
+    pub fn stack(&self, addr: usize) -> &[(usize, Perm)] {
+        self.stacks.get(&addr).map_or(&[], |s| s.as_slice())
+    }
+
+    // checks an access to `addr` through `tag` at `span`, popping the
+    // tags it invalidates
+    pub fn access(
+        &mut self,
+        addr: usize,
+        tag: usize,
+        access: Access,
+        span: Span<'a>,
+    ) -> Result<(), Violation<'a>> {
+        let stack = match self.stacks.get_mut(&addr) {
+            Some(stack) => stack,
+            None => return Ok(()),
+        };
+        let violation = Violation {
+            access,
+            tag,
+            invalidated: self.invalidated.get(&tag).copied(),
+        };
+        let n = match stack.iter().rposition(|(t, _)| *t == tag) {
+            Some(n) => n,
+            None => return Err(violation),
+        };
+        if access == Access::Write && stack[n].1 == Perm::SharedRO {
+            return Err(violation);
+        }
+        let mut kept = stack[..=n].to_vec();
+        for (t, perm) in stack.drain(n + 1..) {
+            if access == Access::Read && perm == Perm::SharedRO {
+                kept.push((t, perm));
+            } else {
+                self.invalidated.insert(t, (access, span));
+            }
+        }
+        *stack = kept;
+        Ok(())
+    }
+
+    // a reference `tag` derived from `parent`, created at `span`
+    pub fn retag(
+        &mut self,
+        addr: usize,
+        parent: usize,
+        tag: usize,
+        perm: Perm,
+        span: Span<'a>,
+    ) -> Result<(), Violation<'a>> {
+        let access = match perm {
+            Perm::Unique => Access::Write,
+            Perm::SharedRO => Access::Read,
+        };
+        self.access(addr, parent, access, span)?;
+        if let Some(stack) = self.stacks.get_mut(&addr) {
+            stack.push((tag, perm));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[test]
+fn test_stacks() {
+    let s = Span::new("x");
+    let mut st = Stacks::new();
+    st.alloc(0, 0);
+    // let r1 = &mut x; let r2 = &*r1; read *r2; write *r1; read *r2
+    st.retag(0, 0, 1, Perm::Unique, s).unwrap();
+    st.retag(0, 1, 2, Perm::SharedRO, s).unwrap();
+    assert_eq!(st.access(0, 2, Access::Read, s), Ok(()));
+    assert_eq!(st.access(0, 2, Access::Write, s).unwrap_err().tag, 2);
+    st.access(0, 1, Access::Write, s).unwrap();
+    assert_eq!(st.stack(0), &[(0, Perm::Unique), (1, Perm::Unique)]);
+    let v = st.access(0, 2, Access::Read, s).unwrap_err();
+    assert_eq!(v.invalidated, Some((Access::Write, s)));
+    // reading the variable invalidates `&mut` but not `&` references
+    st.retag(0, 0, 3, Perm::SharedRO, s).unwrap();
+    st.access(0, 0, Access::Read, s).unwrap();
+    assert_eq!(st.stack(0), &[(0, Perm::Unique), (3, Perm::SharedRO)]);
+    assert!(st.access(0, 1, Access::Read, s).is_err());
+}
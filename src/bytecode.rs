@@ -0,0 +1,585 @@
+// Bytecode
+//
+// Programs compile to a single vector of stack machine instructions,
+// functions are entry points into it. Each function has a fixed number of
+// local slots, parameters first, one per `let` and borrowed temporary.
+// Names are resolved at compile time; constructs the tree-walking
+// interpreter fails on when reached (e.g., an unbound variable) compile
+// to `Fail`, raising the same error when executed.
+//
+// The line table maps instructions back to the spans of the constructs
+// they were compiled from, a run of instructions shares an entry.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{
+    Block, Expr, FnDecl, Macro, Op, Prog, Span, SpanBlock, SpanExpr, SpanStmt, Stmt, Type,
+};
+use crate::interp::{ErrorKind, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    // pushes a constant
+    Const(usize),
+    // pushes the value of a local
+    Load(usize),
+    // assigns the popped value to a local, keeping its type
+    Store(usize),
+    // allocates a local, initialized by the popped value
+    Let(usize),
+    // checks the type of the top value, `&mut T` is converted where `&T`
+    // is expected
+    Check(usize),
+    // pushes a borrow of a local, mutable (true) or shared
+    Borrow(usize, bool),
+    // replaces the reference on top by a new borrow of its cell
+    Reborrow(bool),
+    // checks that the top value refers to a live cell
+    Place,
+    // checks that the reference on top is mutable
+    PlaceMut,
+    // pops a reference and a value, assigning the value to the cell
+    StoreInd,
+    // replaces the reference on top by the referenced value
+    Deref,
+    // follows references, operands of operators are values
+    DerefAll,
+    Bin(Op),
+    Un(Op),
+    // jumps if the top value decides the `&&` or `||`, keeping it
+    Short(Op, usize),
+    Jump(usize),
+    // pops a `bool`, jumping if false
+    JumpIfNot(usize),
+    Pop,
+    // calls a function with the given number of arguments on top
+    Call(usize, usize),
+    // returns the top value to the caller
+    Ret,
+    // frees the live locals of the slot range, ending a scope
+    Exit(usize, usize),
+    // applies a macro to the given number of arguments on top, with the
+    // format string and source text of the condition (string constants)
+    Macro(Macro, usize, Option<usize>, Option<usize>),
+    // raises a runtime error
+    Fail(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function<'a> {
+    pub id: Span<'a>,
+    pub entry: usize,
+    pub params: Vec<Type>,
+    // declared names of the local slots, None for temporaries
+    pub locals: Vec<Option<Span<'a>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program<'a> {
+    pub code: Vec<Instr>,
+    pub consts: Vec<Value>,
+    pub types: Vec<Type>,
+    pub strings: Vec<String>,
+    pub errors: Vec<ErrorKind>,
+    pub fns: Vec<Function<'a>>,
+    // the span of the instructions from the given one on
+    pub lines: Vec<(usize, Span<'a>)>,
+}
+
+impl<'a> Program<'a> {
+    // the span an instruction was compiled from
+    pub fn span(&self, pc: usize) -> Span<'a> {
+        let i = match self.lines.binary_search_by_key(&pc, |(p, _)| *p) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        self.lines[i].1
+    }
+
+    pub fn function(&self, id: &str) -> Option<usize> {
+        self.fns.iter().position(|f| f.id.fragment == id)
+    }
+}
+
+struct Var<'a> {
+    id: &'a str,
+    slot: usize,
+    mutable: bool,
+}
+
+// the locals declared by a block, from the first slot on
+struct Scope<'a> {
+    start: usize,
+    vars: Vec<Var<'a>>,
+}
+
+struct Compiler<'a> {
+    prog: Program<'a>,
+    fns: HashMap<&'a str, usize>,
+    // of the function being compiled
+    scopes: Vec<Scope<'a>>,
+    locals: Vec<Option<Span<'a>>>,
+    // `return` jumps to patch with the epilogue
+    returns: Vec<usize>,
+}
+
+// the place denoted by an expression, see `Interp::place`
+enum Place<'e, 'a> {
+    Var(Span<'a>),
+    Deref(Span<'a>, &'e SpanExpr<'a>),
+    None,
+}
+
+fn place<'e, 'a>(e: &'e SpanExpr<'a>) -> Place<'e, 'a> {
+    match &e.1 {
+        Expr::Id(_) => Place::Var(e.0),
+        Expr::Par(e) => place(e),
+        Expr::UnaryOp(Op::Deref, inner) => Place::Deref(e.0, inner),
+        _ => Place::None,
+    }
+}
+
+pub fn compile<'a>(p: &Prog<'a>) -> Program<'a> {
+    let mut c = Compiler {
+        prog: Program::default(),
+        fns: p
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.id.fragment, i))
+            .collect(),
+        scopes: vec![],
+        locals: vec![],
+        returns: vec![],
+    };
+    for f in p {
+        c.function(f);
+    }
+    c.prog
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, span: Span<'a>, i: Instr) -> usize {
+        let pc = self.prog.code.len();
+        if self.prog.lines.last().map(|(_, s)| *s) != Some(span) {
+            self.prog.lines.push((pc, span));
+        }
+        self.prog.code.push(i);
+        pc
+    }
+
+    // sets the target of the jump at `pc` to the next instruction
+    fn patch(&mut self, pc: usize) {
+        let target = self.prog.code.len();
+        match &mut self.prog.code[pc] {
+            Instr::Jump(t) | Instr::JumpIfNot(t) | Instr::Short(_, t) => *t = target,
+            i => unreachable!("{:?}", i),
+        }
+    }
+
+    fn constant(&mut self, v: Value) -> usize {
+        match self.prog.consts.iter().position(|c| *c == v) {
+            Some(i) => i,
+            None => {
+                self.prog.consts.push(v);
+                self.prog.consts.len() - 1
+            }
+        }
+    }
+
+    fn ty(&mut self, t: &Type) -> usize {
+        match self.prog.types.iter().position(|u| u == t) {
+            Some(i) => i,
+            None => {
+                self.prog.types.push(t.clone());
+                self.prog.types.len() - 1
+            }
+        }
+    }
+
+    fn string(&mut self, s: String) -> usize {
+        self.prog.strings.push(s);
+        self.prog.strings.len() - 1
+    }
+
+    fn fail(&mut self, span: Span<'a>, kind: ErrorKind) {
+        self.prog.errors.push(kind);
+        let i = self.prog.errors.len() - 1;
+        self.emit(span, Instr::Fail(i));
+    }
+
+    fn local(&mut self, id: Option<Span<'a>>) -> usize {
+        self.locals.push(id);
+        self.locals.len() - 1
+    }
+
+    fn declare(&mut self, id: &'a str, slot: usize, mutable: bool) {
+        let scope = self.scopes.last_mut().unwrap();
+        scope.vars.push(Var { id, slot, mutable });
+    }
+
+    fn lookup(&self, id: &str) -> Option<&Var<'a>> {
+        (self.scopes.iter().rev())
+            .flat_map(|sc| sc.vars.iter().rev())
+            .find(|v| v.id == id)
+    }
+
+    // ends the scope at the given depth, and the scopes nested in it
+    fn exit(&mut self, span: Span<'a>, scope: usize) {
+        let start = self.scopes[scope].start;
+        self.emit(span, Instr::Exit(start, self.locals.len()));
+    }
+
+    // parameters are freed after the checked return value, see `Interp::call_body`
+    fn function(&mut self, f: &FnDecl<'a>) {
+        let entry = self.prog.code.len();
+        self.locals = vec![];
+        self.scopes = vec![Scope {
+            start: 0,
+            vars: vec![],
+        }];
+        for p in &f.params {
+            let slot = self.local(Some(p.id));
+            self.declare(p.id.fragment, slot, p.mutable);
+        }
+        self.block(&f.body);
+        if f.ret_type() == Type::Unit {
+            let c = self.constant(Value::Unit);
+            self.emit(f.body.0, Instr::Const(c));
+        } else {
+            self.fail(
+                f.body.0,
+                ErrorKind::MissingReturn(f.id.fragment.to_string()),
+            );
+        }
+        for pc in std::mem::take(&mut self.returns) {
+            self.patch(pc);
+        }
+        let ret = f.ret.as_ref().map_or(f.id, |(s, _)| *s);
+        let t = self.ty(&f.ret_type());
+        self.emit(ret, Instr::Check(t));
+        self.exit(f.id, 0);
+        self.emit(f.id, Instr::Ret);
+        self.prog.fns.push(Function {
+            id: f.id,
+            entry,
+            params: f.params.iter().map(|p| p.ty.1.clone()).collect(),
+            locals: std::mem::take(&mut self.locals),
+        });
+    }
+
+    fn block(&mut self, (s, b): &SpanBlock<'a>) {
+        self.scopes.push(Scope {
+            start: self.locals.len(),
+            vars: vec![],
+        });
+        self.stmts(b);
+        self.exit(*s, self.scopes.len() - 1);
+        self.scopes.pop();
+    }
+
+    fn stmts(&mut self, b: &Block<'a>) {
+        for s in b {
+            self.stmt(s);
+        }
+    }
+
+    fn stmt(&mut self, (s, stmt): &SpanStmt<'a>) {
+        match stmt {
+            Stmt::Let(m, id, (_, ty), e) => {
+                self.expr(e);
+                let t = self.ty(ty);
+                self.emit(*s, Instr::Check(t));
+                let slot = self.local(Some(*id));
+                self.emit(*s, Instr::Let(slot));
+                self.declare(id.fragment, slot, *m);
+            }
+            Stmt::Assign(l, e) => {
+                self.expr(e);
+                self.assign(*s, l);
+            }
+            Stmt::If(c, t, e) => {
+                self.expr(c);
+                let jump = self.emit(c.0, Instr::JumpIfNot(0));
+                self.block(t);
+                if let Some(e) = e {
+                    let end = self.emit(t.0, Instr::Jump(0));
+                    self.patch(jump);
+                    self.block(e);
+                    self.patch(end);
+                } else {
+                    self.patch(jump);
+                }
+            }
+            Stmt::While(c, b) => {
+                let top = self.prog.code.len();
+                self.expr(c);
+                let jump = self.emit(c.0, Instr::JumpIfNot(0));
+                self.block(b);
+                self.emit(*s, Instr::Jump(top));
+                self.patch(jump);
+            }
+            Stmt::Return(e) => {
+                match e {
+                    Some(e) => self.expr(e),
+                    None => {
+                        let c = self.constant(Value::Unit);
+                        self.emit(*s, Instr::Const(c));
+                    }
+                }
+                // the scopes of the body, innermost first
+                for scope in (1..self.scopes.len()).rev() {
+                    self.exit(*s, scope);
+                }
+                let pc = self.emit(*s, Instr::Jump(0));
+                self.returns.push(pc);
+            }
+            Stmt::Expr(e) => {
+                self.expr(e);
+                self.emit(*s, Instr::Pop);
+            }
+            Stmt::Block(b) => self.block(b),
+        }
+    }
+
+    // assigns the value on top to `l`, see `Interp::assign`
+    fn assign(&mut self, s: Span<'a>, l: &SpanExpr<'a>) {
+        match place(l) {
+            Place::Var(id) => match self.lookup(id.fragment) {
+                Some(v) if !v.mutable => {
+                    let kind = ErrorKind::AssignImmutable(id.fragment.to_string());
+                    self.fail(l.0, kind);
+                }
+                Some(v) => {
+                    let slot = v.slot;
+                    self.emit(s, Instr::Store(slot));
+                }
+                None => self.fail(id, ErrorKind::UnboundVariable(id.fragment.to_string())),
+            },
+            Place::Deref(ds, e) => {
+                self.expr(e);
+                self.emit(ds, Instr::Place);
+                self.emit(l.0, Instr::PlaceMut);
+                self.emit(s, Instr::StoreInd);
+            }
+            Place::None => self.fail(l.0, ErrorKind::InvalidAssign),
+        }
+    }
+
+    // `&e` or `&mut e`, see `Interp::borrow`
+    fn borrow(&mut self, s: Span<'a>, mutable: bool, e: &SpanExpr<'a>) {
+        match place(e) {
+            Place::Var(id) => match self.lookup(id.fragment) {
+                Some(v) if mutable && !v.mutable => {
+                    let id = Some(id.fragment.to_string()).filter(|_| matches!(e.1, Expr::Id(_)));
+                    self.fail(s, ErrorKind::BorrowMut(id));
+                }
+                Some(v) => {
+                    let slot = v.slot;
+                    self.emit(s, Instr::Borrow(slot, mutable));
+                }
+                None => self.fail(id, ErrorKind::UnboundVariable(id.fragment.to_string())),
+            },
+            Place::Deref(ds, inner) => {
+                self.expr(inner);
+                self.emit(ds, Instr::Place);
+                self.emit(s, Instr::Reborrow(mutable));
+            }
+            // a temporary in the innermost scope
+            Place::None => {
+                self.expr(e);
+                let slot = self.local(None);
+                self.emit(e.0, Instr::Let(slot));
+                self.emit(s, Instr::Borrow(slot, mutable));
+            }
+        }
+    }
+
+    fn expr(&mut self, (s, e): &SpanExpr<'a>) {
+        match e {
+            Expr::Num(i) => {
+                let c = self.constant(Value::Num(*i));
+                self.emit(*s, Instr::Const(c));
+            }
+            Expr::Bool(b) => {
+                let c = self.constant(Value::Bool(*b));
+                self.emit(*s, Instr::Const(c));
+            }
+            Expr::Unit => {
+                let c = self.constant(Value::Unit);
+                self.emit(*s, Instr::Const(c));
+            }
+            Expr::Par(e) => self.expr(e),
+            Expr::Id(id) => match self.lookup(id) {
+                Some(v) => {
+                    let slot = v.slot;
+                    self.emit(*s, Instr::Load(slot));
+                }
+                None => self.fail(*s, ErrorKind::UnboundVariable(id.to_string())),
+            },
+            Expr::Call(id, args) => match self.fns.get(id) {
+                Some(&f) => {
+                    args.iter().for_each(|a| self.expr(a));
+                    self.emit(*s, Instr::Call(f, args.len()));
+                }
+                None => self.fail(*s, ErrorKind::UnboundFunction(id.to_string())),
+            },
+            Expr::BinOp(op, l, r) => {
+                self.expr(l);
+                self.emit(*s, Instr::DerefAll);
+                let short = match op {
+                    Op::And | Op::Or => Some(self.emit(*s, Instr::Short(*op, 0))),
+                    _ => None,
+                };
+                self.expr(r);
+                self.emit(*s, Instr::DerefAll);
+                self.emit(*s, Instr::Bin(*op));
+                if let Some(pc) = short {
+                    self.patch(pc);
+                }
+            }
+            Expr::UnaryOp(op @ Op::Ref, e) | Expr::UnaryOp(op @ Op::RefMut, e) => {
+                self.borrow(*s, *op == Op::RefMut, e)
+            }
+            Expr::UnaryOp(Op::Deref, e) => {
+                self.expr(e);
+                self.emit(*s, Instr::Deref);
+            }
+            Expr::UnaryOp(op, e) => {
+                self.expr(e);
+                self.emit(*s, Instr::DerefAll);
+                self.emit(*s, Instr::Un(*op));
+            }
+            Expr::Macro(m, args, fmt) => {
+                for a in args {
+                    self.expr(a);
+                    self.emit(a.0, Instr::DerefAll);
+                }
+                let fmt = fmt.map(|f| self.string(f.fragment.to_string()));
+                let cond = match m {
+                    Macro::Assert => Some(self.string(args[0].1.to_string())),
+                    _ => None,
+                };
+                self.emit(*s, Instr::Macro(*m, args.len(), fmt, cond));
+            }
+        }
+    }
+}
+
+// disassembly, e.g.,
+//
+// fn main at 2:4
+//   locals: x, y
+//        0  load 0          x              ; 3:18
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instr::Const(c) => write!(f, "const {}", c),
+            Instr::Load(l) => write!(f, "load {}", l),
+            Instr::Store(l) => write!(f, "store {}", l),
+            Instr::Let(l) => write!(f, "let {}", l),
+            Instr::Check(t) => write!(f, "check {}", t),
+            Instr::Borrow(l, m) => write!(f, "borrow {}{}", l, if *m { " mut" } else { "" }),
+            Instr::Reborrow(m) => write!(f, "reborrow{}", if *m { " mut" } else { "" }),
+            Instr::Place => write!(f, "place"),
+            Instr::PlaceMut => write!(f, "placemut"),
+            Instr::StoreInd => write!(f, "storeind"),
+            Instr::Deref => write!(f, "deref"),
+            Instr::DerefAll => write!(f, "derefall"),
+            Instr::Bin(op) => write!(f, "bin {}", op),
+            Instr::Un(op) => write!(f, "un {}", op.to_string().trim()),
+            Instr::Short(op, t) => write!(f, "short {} {}", op, t),
+            Instr::Jump(t) => write!(f, "jump {}", t),
+            Instr::JumpIfNot(t) => write!(f, "jumpifnot {}", t),
+            Instr::Pop => write!(f, "pop"),
+            Instr::Call(g, n) => write!(f, "call {} {}", g, n),
+            Instr::Ret => write!(f, "ret"),
+            Instr::Exit(s, e) => write!(f, "exit {}..{}", s, e),
+            Instr::Macro(m, n, _, _) => write!(f, "macro {} {}", m.name(), n),
+            Instr::Fail(e) => write!(f, "fail {}", e),
+        }
+    }
+}
+
+impl<'a> Program<'a> {
+    // the entries of the pools an instruction refers to
+    fn comment(&self, fun: &Function, i: Instr) -> String {
+        let local = |l: usize| fun.locals[l].map_or("_", |s| s.fragment).to_string();
+        match i {
+            Instr::Const(c) => self.consts[c].to_string(),
+            Instr::Load(l) | Instr::Store(l) | Instr::Let(l) | Instr::Borrow(l, _) => local(l),
+            Instr::Check(t) => self.types[t].to_string(),
+            Instr::Call(g, _) => self.fns[g].id.fragment.to_string(),
+            Instr::Macro(_, _, Some(s), _) => format!("\"{}\"", self.strings[s]),
+            Instr::Fail(e) => self.errors[e].to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Program<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (n, fun) in self.fns.iter().enumerate() {
+            let end = self.fns.get(n + 1).map_or(self.code.len(), |g| g.entry);
+            writeln!(
+                f,
+                "fn {} at {}:{}",
+                fun.id.fragment,
+                fun.id.line,
+                fun.id.get_column()
+            )?;
+            let locals: Vec<_> = (fun.locals.iter())
+                .map(|l| l.map_or("_", |s| s.fragment))
+                .collect();
+            if !locals.is_empty() {
+                writeln!(f, "  locals: {}", locals.join(", "))?;
+            }
+            for pc in fun.entry..end {
+                let i = self.code[pc];
+                let s = self.span(pc);
+                writeln!(
+                    f,
+                    "{:>8}  {:<16}{:<16}; {}:{}",
+                    pc,
+                    i.to_string(),
+                    self.comment(fun, i),
+                    s.line,
+                    s.get_column()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_compile() {
+    let src = "
+fn main(x: i32) -> i32 {
+    let y: i32 = x * 2;
+    return y;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let prog = compile(&p);
+    let f = &prog.fns[0];
+    let locals: Vec<_> = f.locals.iter().map(|l| l.unwrap().fragment).collect();
+    assert_eq!(locals, vec!["x", "y"]);
+    assert_eq!(
+        &prog.code[f.entry..f.entry + 5],
+        &[
+            Instr::Load(0),
+            Instr::DerefAll,
+            Instr::Const(0),
+            Instr::DerefAll,
+            Instr::Bin(Op::Mul),
+        ]
+    );
+    assert_eq!(prog.span(4).fragment, "*");
+    assert_eq!(prog.span(5).fragment, "let y: i32 = x * 2;");
+    let listing = prog.to_string();
+    assert!(listing.starts_with("fn main at 2:4\n  locals: x, y\n"));
+    assert!(listing.contains(&format!("       4  {:<32}; 3:20\n", "bin *")));
+    assert!(listing.contains("       6  let 1           y               ; 3:5\n"));
+}
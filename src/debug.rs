@@ -0,0 +1,316 @@
+// Interactive debugger
+//
+// Runs a program in the interpreter, stopping before statements according
+// to breakpoints (by line) and the current stepping mode. At each stop
+// commands are read from the input until execution is resumed:
+//
+// break <line>, delete <line>     set/remove a breakpoint
+// step, next, finish              step into, over, out of calls
+// continue                        run to the next breakpoint
+// locals, backtrace               print variables, call stack
+// print <expr>, watch <expr>      evaluate once, at every stop
+// unwatch <n>, quit, help
+//
+// Commands can be abbreviated to their first letter (`bt` for backtrace).
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use crate::ast::{Prog, SpanStmt};
+use crate::interp::{Interp, RuntimeError, Value};
+use crate::parse::parse_expr_all;
+
+// stop before the next statement ...
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    // ... at a breakpoint
+    Continue,
+    // ... anywhere
+    Step,
+    // ... in a frame at most (`Next`) or less than (`Finish`) this deep
+    Next(usize),
+    Finish(usize),
+}
+
+pub struct Debugger<'a, R, W> {
+    src: &'a str,
+    input: R,
+    output: W,
+    mode: Mode,
+    breakpoints: BTreeSet<u32>,
+    watches: Vec<String>,
+}
+
+// runs the function `id` under the debugger, stopping before the first
+// statement; failing to read commands or to write aborts the execution
+pub fn debug<'a, R: BufRead + Send, W: Write + Send>(
+    p: &Prog<'a>,
+    src: &'a str,
+    id: &str,
+    args: Vec<Value>,
+    input: R,
+    output: W,
+) -> io::Result<Result<Value, RuntimeError<'a>>> {
+    let mut d = Debugger {
+        src,
+        input,
+        output,
+        mode: Mode::Step,
+        breakpoints: BTreeSet::new(),
+        watches: vec![],
+    };
+    let mut failed = None;
+    let mut i = Interp::new(p);
+    i.set_hook(Some(Box::new(|i, s| match d.stop(i, s) {
+        Ok(go) => go,
+        Err(e) => {
+            failed = Some(e);
+            false
+        }
+    })));
+    let r = i.call(id, args);
+    drop(i);
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(r),
+    }
+}
+
+impl<'a, R: BufRead, W: Write> Debugger<'a, R, W> {
+    // decides whether to stop before `s`, returns false on quit
+    fn stop(&mut self, i: &Interp<'_, 'a>, (s, _): &SpanStmt<'a>) -> io::Result<bool> {
+        let depth = i.stack().len();
+        let stop = self.breakpoints.contains(&s.line)
+            || match self.mode {
+                Mode::Continue => false,
+                Mode::Step => true,
+                Mode::Next(d) => depth <= d,
+                Mode::Finish(d) => depth < d,
+            };
+        if !stop {
+            return Ok(true);
+        }
+        let frame = i.stack().last().unwrap();
+        let line = self.src.lines().nth(s.line as usize - 1).unwrap_or("");
+        self.out(&format!(
+            "{} at {}:{}\n{:>4} | {}",
+            frame.id.fragment,
+            s.line,
+            s.get_column(),
+            s.line,
+            line.trim_end()
+        ))?;
+        for n in 0..self.watches.len() {
+            let w = self.watches[n].clone();
+            let v = self.eval(i, &w);
+            self.out(&format!("{}: {} = {}", n, w, v))?;
+        }
+        self.commands(i, depth)
+    }
+
+    fn out(&mut self, s: &str) -> io::Result<()> {
+        writeln!(self.output, "{}", s)
+    }
+
+    // command loop, until execution is resumed
+    fn commands(&mut self, i: &Interp<'_, 'a>, depth: usize) -> io::Result<bool> {
+        loop {
+            write!(self.output, "(dbg) ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                // end of input, run to completion
+                self.breakpoints.clear();
+                self.mode = Mode::Continue;
+                return Ok(true);
+            }
+            let line = line.trim();
+            let (cmd, arg) = match line.find(' ') {
+                Some(n) => (&line[..n], line[n..].trim()),
+                None => (line, ""),
+            };
+            let mode = match cmd {
+                "s" | "step" => Some(Mode::Step),
+                "n" | "next" => Some(Mode::Next(depth)),
+                "f" | "finish" => Some(Mode::Finish(depth)),
+                "c" | "continue" => Some(Mode::Continue),
+                "q" | "quit" => return Ok(false),
+                _ => None,
+            };
+            if let Some(mode) = mode {
+                self.mode = mode;
+                return Ok(true);
+            }
+            match cmd {
+                "b" | "break" | "d" | "delete" => match arg.parse::<u32>() {
+                    Ok(n) if cmd.starts_with('b') => {
+                        self.breakpoints.insert(n);
+                        self.out(&format!("breakpoint at line {}", n))?;
+                    }
+                    Ok(n) if self.breakpoints.remove(&n) => {
+                        self.out(&format!("deleted breakpoint at line {}", n))?
+                    }
+                    Ok(n) => self.out(&format!("no breakpoint at line {}", n))?,
+                    Err(_) => self.out("expected a line number")?,
+                },
+                "l" | "locals" => {
+                    for (x, v) in i.env() {
+                        self.out(&format!("{} = {}", x, v))?;
+                    }
+                }
+                "bt" | "backtrace" => {
+                    for (n, f) in i.stack().iter().rev().enumerate() {
+                        match f.call {
+                            Some(c) => self.out(&format!(
+                                "#{} {} called at {}:{}",
+                                n,
+                                f.id.fragment,
+                                c.line,
+                                c.get_column()
+                            ))?,
+                            None => self.out(&format!("#{} {}", n, f.id.fragment))?,
+                        }
+                    }
+                }
+                "p" | "print" => {
+                    let v = self.eval(i, arg);
+                    self.out(&v)?;
+                }
+                "w" | "watch" => {
+                    let v = self.eval(i, arg);
+                    self.out(&format!("{}: {} = {}", self.watches.len(), arg, v))?;
+                    self.watches.push(arg.to_string());
+                }
+                "u" | "unwatch" => match arg.parse::<usize>() {
+                    Ok(n) if n < self.watches.len() => {
+                        self.watches.remove(n);
+                    }
+                    _ => self.out("no such watch expression")?,
+                },
+                "h" | "help" => self.out(
+                    "break <line>, delete <line>, step, next, finish, continue, \
+                     locals, backtrace, print <expr>, watch <expr>, unwatch <n>, quit",
+                )?,
+                "" => (),
+                _ => self.out(&format!("unknown command `{}`, try `help`", cmd))?,
+            }
+        }
+    }
+
+    // evaluates an expression in the current frame, or describes the error
+    fn eval(&self, i: &Interp<'_, 'a>, src: &str) -> String {
+        match parse_expr_all(src) {
+            Ok(e) => match i.eval_here(&e) {
+                Ok(v) => v.to_string(),
+                Err(e) => format!("error: {}", e.kind),
+            },
+            Err(d) => format!("error: {}", d.msg),
+        }
+    }
+}
+
+#[test]
+fn test_debug() {
+    let src = "
+fn sq(x: i32) -> i32 {
+    let y: i32 = x * x;
+    return y;
+}
+
+fn main() -> i32 {
+    let mut s: i32 = 0;
+    let mut i: i32 = 1;
+    while i < 4 {
+        s = s + sq(i);
+        i = i + 1;
+    }
+    return s;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let input = "break 4\nwatch s + 1\nc\nbt\nlocals\nfinish\nnext\nnext\n\
+                 delete 4\nprint sq(i) + \nprint x ** 2\ncontinue\n";
+    let mut out = vec![];
+    let r = debug(&p, src, "main", vec![], input.as_bytes(), &mut out).unwrap();
+    assert_eq!(r, Ok(Value::Num(14)));
+    let out = String::from_utf8(out).unwrap();
+    let expected = "\
+main at 8:5
+   8 |     let mut s: i32 = 0;
+(dbg) breakpoint at line 4
+(dbg) 0: s + 1 = error: cannot find value `s` in this scope
+(dbg) sq at 4:5
+   4 |     return y;
+0: s + 1 = error: cannot find value `s` in this scope
+(dbg) #0 sq called at 11:17
+#1 main
+(dbg) x = 1
+y = 1
+(dbg) main at 12:9
+  12 |         i = i + 1;
+0: s + 1 = 2
+(dbg) main at 11:9
+  11 |         s = s + sq(i);
+0: s + 1 = 2
+(dbg) sq at 4:5
+   4 |     return y;
+0: s + 1 = error: cannot find value `s` in this scope
+(dbg) deleted breakpoint at line 4
+(dbg) error: unexpected `+`
+(dbg) 4
+(dbg) ";
+    assert_eq!(out, expected);
+
+    let mut out = vec![];
+    let e = debug(&p, src, "main", vec![], "n\nq\n".as_bytes(), &mut out);
+    let e = e.unwrap().unwrap_err();
+    assert_eq!(e.kind, crate::interp::ErrorKind::Aborted);
+    assert_eq!(e.span.line, 9);
+}
+
+#[test]
+fn test_print_deref() {
+    let src = "
+fn f(r: &i32, k: i32) -> i32 {
+    return *r + k;
+}
+
+fn main() -> i32 {
+    let w: i32 = 20;
+    return f(&w, 1);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let input = "break 3\nc\nprint *r\nprint *r + k\nprint f(r, 2)\nc\n";
+    let mut out = vec![];
+    let r = debug(&p, src, "main", vec![], input.as_bytes(), &mut out).unwrap();
+    assert_eq!(r, Ok(Value::Num(21)));
+    let out = String::from_utf8(out).unwrap();
+    assert!(
+        out.ends_with("(dbg) 20\n(dbg) 21\n(dbg) 22\n(dbg) "),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn test_closed_output() {
+    // a writer failing after a few bytes, as a closed pipe
+    struct Closed(usize);
+    impl Write for Closed {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 < buf.len() {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.0 -= buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let src = "fn main() -> i32 {\n    return 1;\n}\n";
+    let p = crate::parse::parse(src).unwrap();
+    let e = debug(&p, src, "main", vec![], "c\n".as_bytes(), Closed(20)).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+}
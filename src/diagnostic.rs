@@ -0,0 +1,147 @@
+// Diagnostics (errors and warnings with location information)
+
+use std::fmt;
+
+use crate::ast::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic<'a> {
+    pub level: Level,
+    pub span: Span<'a>,
+    pub msg: String,
+    // secondary locations, e.g., the branch lacking a return
+    pub notes: Vec<(Span<'a>, String)>,
+}
+
+impl<'a> Diagnostic<'a> {
+    pub fn error(span: Span<'a>, msg: String) -> Self {
+        Diagnostic {
+            level: Level::Error,
+            span,
+            msg,
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(span: Span<'a>, msg: String) -> Self {
+        Diagnostic {
+            level: Level::Warning,
+            span,
+            msg,
+            notes: vec![],
+        }
+    }
+
+    pub fn note(mut self, span: Span<'a>, msg: String) -> Self {
+        self.notes.push((span, msg));
+        self
+    }
+
+    // renders the diagnostic with source excerpts, `src` is the parsed input
+    //
+    // error: function `f` does not return a value on every path
+    //  --> 1:4
+    //   |
+    // 1 | fn f(x: bool) -> i32 {
+    //   |    ^
+    pub fn render(&self, src: &str) -> String {
+        let mut out = format!("{}: {}\n", self.level, self.msg);
+        out.push_str(&excerpt(src, &self.span));
+        for (s, msg) in &self.notes {
+            out.push_str(&format!("note: {}\n", msg));
+            out.push_str(&excerpt(src, s));
+        }
+        out
+    }
+}
+
+// source line of the span, underlined
+fn excerpt(src: &str, s: &Span) -> String {
+    let start = src[..s.offset].rfind('\n').map_or(0, |p| p + 1);
+    let line = src[start..].lines().next().unwrap_or("");
+    let col = src[start..s.offset].chars().count();
+    let len = s
+        .fragment
+        .lines()
+        .next()
+        .map_or(0, |l| l.chars().count())
+        .min(line.chars().count().saturating_sub(col))
+        .max(1);
+    let w = s.line.to_string().len();
+    format!(
+        "{:w$}--> {}:{}\n{:w$} |\n{} | {}\n{:w$} | {}{}\n",
+        "",
+        s.line,
+        col + 1,
+        "",
+        s.line,
+        line,
+        "",
+        " ".repeat(col),
+        "^".repeat(len),
+        w = w
+    )
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Diagnostic<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} at {}:{}",
+            self.level,
+            self.msg,
+            self.span.line,
+            self.span.get_column()
+        )
+    }
+}
+
+#[test]
+fn test_render() {
+    let src = "fn f() -> i32 {\n    if true {\n        return 1;\n    }\n}\n";
+    let p = crate::parse::parse(src).unwrap();
+    let d = crate::flow::check_prog(&p);
+    assert_eq!(
+        d[0].render(src),
+        "error: function `f` does not return a value on every path
+ --> 1:4
+  |
+1 | fn f() -> i32 {
+  |    ^
+note: this `if` lacks an `else` branch that returns
+ --> 2:5
+  |
+2 |     if true {
+  |     ^^^^^^^^^
+"
+    );
+    assert_eq!(
+        d[0].to_string(),
+        "error: function `f` does not return a value on every path at 1:4"
+    );
+}
+
+#[test]
+fn test_end_of_input() {
+    // the span of an unexpected end of input is empty, it is still marked
+    let src = "fn f() {\n    let x: i32 = 1";
+    let d = crate::parse::parse(src).unwrap_err();
+    assert!(d
+        .render(src)
+        .ends_with("2 |     let x: i32 = 1\n  |                   ^\n"));
+}
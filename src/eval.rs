@@ -0,0 +1,87 @@
+// Evaluation of closed expressions
+//
+// Expressions without variables or calls evaluate directly, with the
+// operators (and checked arithmetic) of `interp`. `test` parses and
+// evaluates an expression, comparing with the value computed by Rust.
+
+use crate::ast::{Expr, Span, SpanExpr};
+use crate::interp::{binop, short_circuit, unop, ErrorKind, RuntimeError, Value};
+use crate::parse::{climb_all, parse_tokens, Nesting};
+
+pub fn test(s: &str, v: i32) {
+    let n = Nesting::default();
+    match parse_tokens(&n, Span::new(s)) {
+        Ok((Span { fragment: "", .. }, (s, t))) => {
+            println!("{:?}", &t);
+            match climb_all(&n, &t, s) {
+                Ok(e) => {
+                    println!("{:?}", &e);
+                    println!("eval {:?} {}", math_eval(&e), v);
+                    assert_eq!(math_eval(&e), Ok(Value::Num(v)));
+                }
+                Err(s) => println!("parse error at {:?}", s),
+            }
+        }
+        Ok((s, t)) => println!(
+            "parse incomplete, \n parsed tokens \t{:?}, \n remaining \t{:?}",
+            t, s
+        ),
+        Err(err) => println!("{:?}", err),
+    }
+}
+
+pub fn math_eval<'a>(e: &SpanExpr<'a>) -> Result<Value, RuntimeError<'a>> {
+    let error = |kind| RuntimeError {
+        span: e.0,
+        kind,
+        backtrace: vec![],
+        notes: vec![],
+    };
+    match &e.1 {
+        Expr::Num(i) => Ok(Value::Num(*i)),
+        Expr::Bool(b) => Ok(Value::Bool(*b)),
+        Expr::Unit => Ok(Value::Unit),
+        Expr::Par(e) => math_eval(e),
+        Expr::BinOp(op, l, r) => {
+            let lv = math_eval(l)?;
+            if short_circuit(*op, &lv) {
+                return Ok(lv);
+            }
+            let rv = math_eval(r)?;
+            binop(*op, lv, rv).map_err(error)
+        }
+        Expr::UnaryOp(op, e) => unop(*op, math_eval(e)?).map_err(error),
+        // closed expressions only
+        Expr::Id(id) => Err(error(ErrorKind::UnboundVariable(id.to_string()))),
+        Expr::Call(id, _) => Err(error(ErrorKind::UnboundFunction(id.to_string()))),
+        Expr::Macro(m, ..) => Err(error(ErrorKind::UnboundFunction(format!("{}!", m.name())))),
+    }
+}
+
+#[test]
+fn test_math_eval() {
+    let eval = |s| math_eval(&crate::parse::parse_expr_all(s).unwrap());
+    assert_eq!(eval("2 + 3 ** 2 * -1"), Ok(Value::Num(-7)));
+    let e = eval("1 + 7 / (2 - 2)").unwrap_err();
+    assert_eq!(
+        (e.kind, e.span.get_column()),
+        (ErrorKind::DivisionByZero, 7)
+    );
+    let e = eval("2 ** 31").unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Overflow(crate::ast::Op::Pow));
+    let e = eval("2 ** (0 - 1)").unwrap_err();
+    assert_eq!(e.kind, ErrorKind::NegativeExponent);
+    assert_eq!(eval("(1 < 2) == !false"), Ok(Value::Bool(true)));
+    let e = eval("2 * (1 + false)").unwrap_err();
+    assert_eq!(
+        (e.span.get_column(), e.kind.to_string()),
+        (8, "cannot apply `+` to `i32` and `bool`".to_string())
+    );
+    let e = eval("-true").unwrap_err();
+    assert_eq!(e.kind.to_string(), "cannot apply `-` to `bool`");
+    // the right operand is only evaluated when needed
+    assert_eq!(eval("1 > 2 && 1 / 0 == 0"), Ok(Value::Bool(false)));
+    assert_eq!(eval("true || 2 ** 31 > 0"), Ok(Value::Bool(true)));
+    let e = eval("true && 1 / 0 == 0").unwrap_err();
+    assert_eq!(e.kind, ErrorKind::DivisionByZero);
+}
@@ -0,0 +1,178 @@
+// Return-path and unreachable code analysis
+//
+// Functions with a non-unit return type must end in an explicit `return`
+// on every path. Statements following an unconditional `return` (or
+// `panic!`) and bodies of loops whose condition folds to `false` (see
+// `fold`), e.g., `while !true`, are reported as unreachable.
+
+use crate::ast::{Block, Expr, FnDecl, Macro, Prog, Span, SpanBlock, Stmt, Type};
+use crate::diagnostic::Diagnostic;
+use crate::fold::fold_constants;
+
+pub fn check_prog<'a>(p: &Prog<'a>) -> Vec<Diagnostic<'a>> {
+    p.iter().flat_map(check_fn).collect()
+}
+
+pub fn check_fn<'a>(f: &FnDecl<'a>) -> Vec<Diagnostic<'a>> {
+    let mut diags = vec![];
+    unreachable_block(&f.body.1, &mut diags);
+    if f.ret_type() != Type::Unit && !returns_block(&f.body.1) {
+        let (s, msg) = fall_through(&f.body);
+        diags.push(
+            Diagnostic::error(
+                f.id,
+                format!(
+                    "function `{}` does not return a value on every path",
+                    f.id.fragment
+                ),
+            )
+            .note(s, msg),
+        );
+    }
+    diags
+}
+
+// true if every path through the statement ends in a `return` or panics
+pub fn returns(s: &Stmt) -> bool {
+    match s {
+        Stmt::Return(_) | Stmt::Expr((_, Expr::Macro(Macro::Panic, ..))) => true,
+        Stmt::If(_, (_, t), Some((_, e))) => returns_block(t) && returns_block(e),
+        Stmt::Block((_, b)) => returns_block(b),
+        _ => false,
+    }
+}
+
+pub fn returns_block(b: &Block) -> bool {
+    b.iter().any(|(_, s)| returns(s))
+}
+
+// locates the branch falling through the end of a non-returning block
+fn fall_through<'a>((s, b): &SpanBlock<'a>) -> (Span<'a>, String) {
+    match b.last() {
+        None => (*s, "this block is empty".to_string()),
+        Some((s, Stmt::If(_, t, e))) => match e {
+            _ if !returns_block(&t.1) => fall_through(t),
+            None => (
+                *s,
+                "this `if` lacks an `else` branch that returns".to_string(),
+            ),
+            Some(e) => fall_through(e),
+        },
+        Some((_, Stmt::Block(b))) => fall_through(b),
+        Some((s, Stmt::While(..))) => (
+            *s,
+            "execution may leave this `while` loop without returning".to_string(),
+        ),
+        Some((s, _)) => (
+            *s,
+            "execution falls through after this statement".to_string(),
+        ),
+    }
+}
+
+fn unreachable_block<'a>(b: &Block<'a>, diags: &mut Vec<Diagnostic<'a>>) {
+    let mut ret = None;
+    for (s, stmt) in b {
+        if let Some(r) = ret {
+            diags.push(
+                Diagnostic::warning(*s, "unreachable statement".to_string()).note(
+                    r,
+                    "any code following this statement is unreachable".to_string(),
+                ),
+            );
+            return;
+        }
+        unreachable_stmt(stmt, diags);
+        if returns(stmt) {
+            ret = Some(*s);
+        }
+    }
+}
+
+fn unreachable_stmt<'a>(stmt: &Stmt<'a>, diags: &mut Vec<Diagnostic<'a>>) {
+    match stmt {
+        Stmt::If(_, (_, t), e) => {
+            unreachable_block(t, diags);
+            if let Some((_, e)) = e {
+                unreachable_block(e, diags);
+            }
+        }
+        // errors in the condition are left for `fold` to report
+        Stmt::While(c, (s, _)) if fold_constants(c.clone(), &mut vec![]).1 == Expr::Bool(false) => {
+            diags.push(
+                Diagnostic::warning(*s, "unreachable loop body".to_string())
+                    .note(c.0, "the condition is always `false`".to_string()),
+            )
+        }
+        Stmt::While(_, (_, b)) => unreachable_block(b, diags),
+        Stmt::Block((_, b)) => unreachable_block(b, diags),
+        _ => (),
+    }
+}
+
+#[test]
+fn test_missing_else() {
+    let src = "
+fn f(x: bool) -> i32 {
+    if x {
+        return 1;
+    }
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let d = check_prog(&p);
+    assert_eq!(d.len(), 1);
+    assert_eq!(d[0].span.fragment, "f");
+    assert_eq!(d[0].notes[0].0.line, 3);
+    assert!(d[0].notes[0].1.contains("`else`"));
+}
+
+#[test]
+fn test_nested_branch() {
+    let src = "
+fn f(x: bool) -> i32 {
+    if x {
+        return 1;
+    } else {
+        if x { return 2; } else { x = false; }
+    }
+}
+
+fn g(x: bool) -> i32 {
+    if x { return 1; } else { return 2; }
+}
+
+fn h() {
+    let x: i32 = 1;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let d = check_prog(&p);
+    assert_eq!(d.len(), 1);
+    assert_eq!(d[0].notes[0].0.fragment, "x = false;");
+}
+
+#[test]
+fn test_unreachable() {
+    let src = "
+fn f() -> i32 {
+    while false {
+        return 2;
+    }
+    while (false) {}
+    while !true && f() > 0 {}
+    while 1 > 2 * 3 {}
+    while 1 / 0 > 0 {}
+    return 1;
+    f();
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let d = check_prog(&p);
+    assert_eq!(d.len(), 5);
+    let lines: Vec<_> = d.iter().map(|d| d.span.line).collect();
+    assert_eq!(lines, vec![3, 6, 7, 8, 11]);
+    let d = &d[3..];
+    assert_eq!(d[1].span.fragment, "f();");
+    assert_eq!(d[1].notes[0].0.fragment, "return 1;");
+}
@@ -0,0 +1,165 @@
+// Constant folding
+//
+// Sub-trees consisting of literals only are replaced by their value,
+// keeping the span of the sub-tree root (the operator for BinOp/UnaryOp).
+// Operations that would overflow or divide by zero are left in place and
+// reported as errors. A literal left operand deciding `&&` or `||` folds
+// the operation, the right operand is never evaluated.
+
+use crate::ast::{Expr, FnDecl, Prog, Span, SpanBlock, SpanExpr, SpanStmt, Stmt};
+use crate::diagnostic::Diagnostic;
+use crate::interp::{binop, short_circuit, unop, ErrorKind, Value};
+
+pub fn fold_constants<'a>(e: SpanExpr<'a>, diags: &mut Vec<Diagnostic<'a>>) -> SpanExpr<'a> {
+    let (s, e) = e;
+    match e {
+        Expr::Par(e) => match fold_constants(*e, diags) {
+            (_, Expr::Num(i)) => (s, Expr::Num(i)),
+            (_, Expr::Bool(b)) => (s, Expr::Bool(b)),
+            e => (s, Expr::Par(Box::new(e))),
+        },
+        Expr::Call(id, args) => (
+            s,
+            Expr::Call(
+                id,
+                args.into_iter().map(|a| fold_constants(a, diags)).collect(),
+            ),
+        ),
+        Expr::Macro(m, args, fmt) => (
+            s,
+            Expr::Macro(
+                m,
+                args.into_iter().map(|a| fold_constants(a, diags)).collect(),
+                fmt,
+            ),
+        ),
+        Expr::BinOp(op, l, r) => {
+            let l = fold_constants(*l, diags);
+            if let Some(lv) = literal(&l.1).filter(|lv| short_circuit(op, lv)) {
+                return fold_result(s, Some(Ok(lv)), l.1, diags);
+            }
+            let r = fold_constants(*r, diags);
+            let v = match (literal(&l.1), literal(&r.1)) {
+                (Some(lv), Some(rv)) => Some(binop(op, lv, rv)),
+                _ => None,
+            };
+            fold_result(s, v, Expr::BinOp(op, Box::new(l), Box::new(r)), diags)
+        }
+        Expr::UnaryOp(op, e) => {
+            let e = fold_constants(*e, diags);
+            let v = literal(&e.1).map(|v| unop(op, v));
+            fold_result(s, v, Expr::UnaryOp(op, Box::new(e)), diags)
+        }
+        e => (s, e),
+    }
+}
+
+const OVERFLOW: &str = "this arithmetic operation will overflow";
+const DIV_ZERO: &str = "this operation will panic at runtime: attempt to divide by zero";
+const NEG_EXP: &str = "this operation will panic at runtime: negative exponent";
+
+fn literal(e: &Expr) -> Option<Value> {
+    match e {
+        Expr::Num(i) => Some(Value::Num(*i)),
+        Expr::Bool(b) => Some(Value::Bool(*b)),
+        Expr::Unit => Some(Value::Unit),
+        _ => None,
+    }
+}
+
+// ill-typed operations are left for the type checker to report
+fn fold_result<'a>(
+    s: Span<'a>,
+    v: Option<Result<Value, ErrorKind>>,
+    unfolded: Expr<'a>,
+    diags: &mut Vec<Diagnostic<'a>>,
+) -> SpanExpr<'a> {
+    let msg = match v {
+        Some(Ok(Value::Num(i))) => return (s, Expr::Num(i)),
+        Some(Ok(Value::Bool(b))) => return (s, Expr::Bool(b)),
+        Some(Err(ErrorKind::Overflow(_))) | Some(Err(ErrorKind::NegOverflow)) => OVERFLOW,
+        Some(Err(ErrorKind::DivisionByZero)) => DIV_ZERO,
+        Some(Err(ErrorKind::NegativeExponent)) => NEG_EXP,
+        _ => return (s, unfolded),
+    };
+    diags.push(Diagnostic::error(s, msg.to_string()));
+    (s, unfolded)
+}
+
+pub fn fold_prog<'a>(p: Prog<'a>, diags: &mut Vec<Diagnostic<'a>>) -> Prog<'a> {
+    p.into_iter().map(|f| fold_fn(f, diags)).collect()
+}
+
+pub fn fold_fn<'a>(f: FnDecl<'a>, diags: &mut Vec<Diagnostic<'a>>) -> FnDecl<'a> {
+    FnDecl {
+        body: fold_block(f.body, diags),
+        ..f
+    }
+}
+
+fn fold_block<'a>((s, b): SpanBlock<'a>, diags: &mut Vec<Diagnostic<'a>>) -> SpanBlock<'a> {
+    (s, b.into_iter().map(|s| fold_stmt(s, diags)).collect())
+}
+
+fn fold_stmt<'a>((s, stmt): SpanStmt<'a>, diags: &mut Vec<Diagnostic<'a>>) -> SpanStmt<'a> {
+    let stmt = match stmt {
+        Stmt::Let(m, id, ty, e) => Stmt::Let(m, id, ty, fold_constants(e, diags)),
+        Stmt::Assign(l, e) => Stmt::Assign(fold_constants(l, diags), fold_constants(e, diags)),
+        Stmt::If(c, t, e) => Stmt::If(
+            fold_constants(c, diags),
+            fold_block(t, diags),
+            e.map(|e| fold_block(e, diags)),
+        ),
+        Stmt::While(c, b) => Stmt::While(fold_constants(c, diags), fold_block(b, diags)),
+        Stmt::Return(e) => Stmt::Return(e.map(|e| fold_constants(e, diags))),
+        Stmt::Expr(e) => Stmt::Expr(fold_constants(e, diags)),
+        Stmt::Block(b) => Stmt::Block(fold_block(b, diags)),
+    };
+    (s, stmt)
+}
+
+#[test]
+fn test_fold() {
+    use crate::ast::Op;
+    use crate::parse::parse_expr_all;
+
+    let mut diags = vec![];
+    let e = parse_expr_all("x + 2 * (3 - 1) ** 2").unwrap();
+    match fold_constants(e, &mut diags) {
+        (_, Expr::BinOp(Op::Add, _, r)) => {
+            assert_eq!(r.0.fragment, "*");
+            assert_eq!(r.1, Expr::Num(8));
+        }
+        e => panic!("{:?}", e),
+    }
+    let e = parse_expr_all("!(1 == 2) && 3 > 2").unwrap();
+    assert_eq!(fold_constants(e, &mut diags).1, Expr::Bool(true));
+    assert!(diags.is_empty());
+}
+
+#[test]
+fn test_fold_errors() {
+    use crate::ast::Op;
+    use crate::parse::parse_expr_all;
+
+    let mut diags = vec![];
+    let e = parse_expr_all("1 + 2147483647 * 1 + 4 / (2 - 2) + 2 ** -1").unwrap();
+    let e = fold_constants(e, &mut diags);
+    assert!(matches!(e.1, Expr::BinOp(Op::Add, _, _)));
+    let d: Vec<_> = diags
+        .iter()
+        .map(|d| (d.span.get_column(), d.msg.as_str()))
+        .collect();
+    assert_eq!(d, vec![(3, OVERFLOW), (24, DIV_ZERO), (38, NEG_EXP)]);
+
+    // unevaluated operands are not folded
+    diags.clear();
+    let e = parse_expr_all("false && 1 / 0 == 0").unwrap();
+    assert_eq!(fold_constants(e, &mut diags).1, Expr::Bool(false));
+    let e = parse_expr_all("x || 1 / 0 == 0").unwrap();
+    assert!(matches!(
+        fold_constants(e, &mut diags).1,
+        Expr::BinOp(Op::Or, ..)
+    ));
+    assert_eq!(diags.len(), 1);
+}
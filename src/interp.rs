@@ -0,0 +1,1629 @@
+// Interpreter
+//
+// Big-step evaluation of programs. Each call pushes a frame holding a
+// stack of scopes (one per block), statements return `Some(value)` when
+// a `return` was executed. Errors carry the span of the failing
+// construct and a backtrace of the active calls.
+//
+// Variables live in a store of addressable cells, owned by the scope
+// that declared them, references are addresses into the store. When a
+// scope ends its cells are freed, and references to them that are still
+// reachable (from the store or the returned value) are reported.
+// Optionally, accesses are checked against the borrow stacks of the
+// cells (see `borrows`).
+//
+// The builtin macros (`println!`, `assert!`, `assert_eq!` and `panic!`)
+// write to a configurable output, stdout by default.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::ast::{Expr, FnDecl, Macro, Op, Prog, Span, SpanBlock, SpanExpr, SpanStmt, Stmt, Type};
+use crate::borrows::{Access, Perm, Stacks, Violation};
+use crate::diagnostic::Diagnostic;
+use crate::sos::{Conclusion, Derivation, Env, State};
+pub use crate::value::{Ref, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    UnboundVariable(String),
+    UnboundFunction(String),
+    // expected, found
+    ArgCount(usize, usize),
+    TypeMismatch(Type, Type),
+    Operands(Op, Vec<Type>),
+    InvalidAssign,
+    MissingReturn(String),
+    Overflow(Op),
+    NegOverflow,
+    DivisionByZero,
+    NegativeExponent,
+    Aborted,
+    OutOfFuel(u64),
+    CallDepth(usize),
+    StoreSize(usize),
+    // variable (None for a temporary) freed while still borrowed
+    Dangling(Option<String>),
+    // variable (None for data behind a `&`) borrowed as mutable
+    BorrowMut(Option<String>),
+    AssignShared,
+    // assignment to a variable not declared as mutable
+    AssignImmutable(String),
+    // access through a reference popped from the borrow stack
+    Invalidated(Access),
+    // `panic!` or a failed assertion, with the message
+    Panic(String),
+}
+
+// applies a binary operator, arithmetic is checked
+pub fn binop(op: Op, lv: Value, rv: Value) -> std::result::Result<Value, ErrorKind> {
+    let overflow = || ErrorKind::Overflow(op);
+    match (op, &lv, &rv) {
+        (Op::Add, &Value::Num(l), &Value::Num(r)) => {
+            l.checked_add(r).map(Value::Num).ok_or_else(overflow)
+        }
+        (Op::Sub, &Value::Num(l), &Value::Num(r)) => {
+            l.checked_sub(r).map(Value::Num).ok_or_else(overflow)
+        }
+        (Op::Mul, &Value::Num(l), &Value::Num(r)) => {
+            l.checked_mul(r).map(Value::Num).ok_or_else(overflow)
+        }
+        (Op::Div, Value::Num(_), Value::Num(0)) => Err(ErrorKind::DivisionByZero),
+        (Op::Div, &Value::Num(l), &Value::Num(r)) => {
+            l.checked_div(r).map(Value::Num).ok_or_else(overflow)
+        }
+        (Op::Pow, Value::Num(_), &Value::Num(r)) if r < 0 => Err(ErrorKind::NegativeExponent),
+        (Op::Pow, &Value::Num(l), &Value::Num(r)) => {
+            l.checked_pow(r as u32).map(Value::Num).ok_or_else(overflow)
+        }
+        (Op::Lt, Value::Num(l), Value::Num(r)) => Ok(Value::Bool(l < r)),
+        (Op::Gt, Value::Num(l), Value::Num(r)) => Ok(Value::Bool(l > r)),
+        (Op::And, &Value::Bool(l), &Value::Bool(r)) => Ok(Value::Bool(l && r)),
+        (Op::Or, &Value::Bool(l), &Value::Bool(r)) => Ok(Value::Bool(l || r)),
+        (Op::Eq, l, r) if l.ty() == r.ty() => Ok(Value::Bool(l == r)),
+        (Op::Neq, l, r) if l.ty() == r.ty() => Ok(Value::Bool(l != r)),
+        _ => Err(ErrorKind::Operands(op, vec![lv.ty(), rv.ty()])),
+    }
+}
+
+// true if `lv` decides a `&&` or `||`, whose right operand is then
+// not evaluated
+pub fn short_circuit(op: Op, lv: &Value) -> bool {
+    matches!(
+        (op, lv),
+        (Op::And, Value::Bool(false)) | (Op::Or, Value::Bool(true))
+    )
+}
+
+pub fn unop(op: Op, v: Value) -> std::result::Result<Value, ErrorKind> {
+    match (op, &v) {
+        (Op::Add, &Value::Num(i)) => Ok(Value::Num(i)),
+        (Op::Sub, &Value::Num(i)) => i
+            .checked_neg()
+            .map(Value::Num)
+            .ok_or(ErrorKind::NegOverflow),
+        (Op::Not, &Value::Bool(b)) => Ok(Value::Bool(!b)),
+        _ => Err(ErrorKind::Operands(op, vec![v.ty()])),
+    }
+}
+
+// substitutes the values for the `{}` of a format string,
+// `{{` and `}}` are escaped braces
+pub fn format(fmt: &str, vals: &[Value]) -> String {
+    let mut vals = vals.iter();
+    let mut s = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('}')) => {
+                chars.next();
+                if let Some(v) = vals.next() {
+                    s += &v.to_string();
+                }
+            }
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                s.push(c);
+            }
+            _ => s.push(c),
+        }
+    }
+    s
+}
+
+// performs a builtin macro on the (dereferenced) values of its arguments,
+// `cond` gives the source text of an `assert!` condition
+pub fn builtin(
+    m: Macro,
+    fmt: Option<&str>,
+    cond: impl FnOnce() -> String,
+    vals: &[Value],
+    out: &mut dyn Write,
+) -> std::result::Result<Value, ErrorKind> {
+    let msg = fmt.map(|f| format(f, &vals[m.fixed()..]));
+    match (m, vals) {
+        (Macro::Println, _) => {
+            writeln!(out, "{}", msg.unwrap_or_default())
+                .map_err(|e| ErrorKind::Panic(format!("failed printing to output: {}", e)))?;
+        }
+        (Macro::Assert, [Value::Bool(true), ..]) => (),
+        (Macro::Assert, [Value::Bool(false), ..]) => {
+            let msg = msg.unwrap_or_else(|| format!("assertion failed: {}", cond()));
+            return Err(ErrorKind::Panic(msg));
+        }
+        (Macro::Assert, _) => return Err(ErrorKind::TypeMismatch(Type::Bool, vals[0].ty())),
+        (Macro::AssertEq, [l, r, ..]) if l.ty() != r.ty() => {
+            return Err(ErrorKind::Operands(Op::Eq, vec![l.ty(), r.ty()]))
+        }
+        (Macro::AssertEq, [l, r, ..]) if l != r => {
+            let msg = msg.map_or(String::new(), |m| format!(": {}", m));
+            return Err(ErrorKind::Panic(format!(
+                "assertion `left == right` failed{}\n  left: {}\n right: {}",
+                msg, l, r
+            )));
+        }
+        (Macro::AssertEq, _) => (),
+        (Macro::Panic, _) => {
+            return Err(ErrorKind::Panic(
+                msg.unwrap_or_else(|| "explicit panic".to_string()),
+            ))
+        }
+    }
+    Ok(Value::Unit)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError<'a> {
+    pub span: Span<'a>,
+    pub kind: ErrorKind,
+    // active calls at the time of the error, innermost first,
+    // function identifier and call site (None for the entry call)
+    pub backtrace: Vec<(Span<'a>, Option<Span<'a>>)>,
+    // related spans, e.g., the borrow of an invalidated reference
+    pub notes: Vec<(Span<'a>, String)>,
+}
+
+impl<'a> RuntimeError<'a> {
+    pub fn diagnostic(&self) -> Diagnostic<'a> {
+        let d = (self.notes.iter()).fold(
+            Diagnostic::error(self.span, self.kind.to_string()),
+            |d, (s, msg)| d.note(*s, msg.clone()),
+        );
+        self.backtrace.iter().fold(d, |d, (id, call)| match call {
+            Some(call) => d.note(*call, format!("in `{}`, called from here", id.fragment)),
+            None => d,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Frame<'a> {
+    pub id: Span<'a>,
+    pub call: Option<Span<'a>>,
+    pub scopes: Vec<Scope<'a>>,
+}
+
+#[derive(Clone, Default)]
+pub struct Scope<'a> {
+    // addresses of the visible variables
+    pub vars: HashMap<&'a str, usize>,
+    // cells owned by the scope (including shadowed variables and
+    // borrowed temporaries), freed when it ends
+    pub cells: Vec<usize>,
+}
+
+#[derive(Clone)]
+pub struct Cell<'a> {
+    pub value: Value,
+    pub mutable: bool,
+    // the declared variable, None for a temporary
+    pub id: Option<&'a str>,
+    // set when the cell is borrowed
+    pub borrowed: bool,
+    // tag of the variable, at the bottom of the borrow stack
+    pub tag: usize,
+}
+
+// a cell accessed through `tag`
+#[derive(Debug, Clone, Copy)]
+struct Place {
+    addr: usize,
+    mutable: bool,
+    tag: usize,
+}
+
+// execution limits, exceeding one is a runtime error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    // evaluated statements and expressions, None for no limit
+    pub fuel: Option<u64>,
+    // active calls
+    pub max_depth: usize,
+    // live cells in the store
+    pub max_store: usize,
+}
+
+// default limit of active calls
+pub const MAX_DEPTH: usize = 1000;
+
+// host stack used by a call (about 25 KB in a debug build), and by each
+// level of statements and expressions nested in the function body (3-5 KB)
+pub const STACK_PER_CALL: usize = 32 << 10;
+pub const STACK_PER_LEVEL: usize = 6 << 10;
+
+// bound on the host stack of an evaluation, `max_depth` is clamped to it
+pub const MAX_STACK: usize = 1 << 30;
+
+impl Limits {
+    // the limits with `max_depth` clamped such that the calls, using
+    // `per_call` bytes of host stack each, fit in `MAX_STACK`
+    pub fn clamped(self, per_call: usize) -> Self {
+        Limits {
+            max_depth: self.max_depth.min(MAX_STACK / per_call - 1),
+            ..self
+        }
+    }
+
+    // host stack for `max_depth` calls, see `with_stack`
+    pub fn stack_size(&self, per_call: usize) -> usize {
+        (self.max_depth + 1) * per_call
+    }
+}
+
+// the deepest nesting of statements, blocks and expressions
+fn block_depth(b: &[SpanStmt]) -> usize {
+    1 + b.iter().map(|(_, s)| stmt_depth(s)).max().unwrap_or(0)
+}
+
+fn stmt_depth(s: &Stmt) -> usize {
+    1 + match s {
+        Stmt::Let(_, _, _, (_, e)) | Stmt::Expr((_, e)) | Stmt::Return(Some((_, e))) => {
+            expr_depth(e)
+        }
+        Stmt::Assign((_, l), (_, r)) => expr_depth(l).max(expr_depth(r)),
+        Stmt::If((_, c), (_, t), e) => {
+            (expr_depth(c).max(block_depth(t))).max(e.as_ref().map_or(0, |(_, e)| block_depth(e)))
+        }
+        Stmt::While((_, c), (_, b)) => expr_depth(c).max(block_depth(b)),
+        Stmt::Return(None) => 0,
+        Stmt::Block((_, b)) => block_depth(b),
+    }
+}
+
+fn expr_depth(e: &Expr) -> usize {
+    1 + match e {
+        Expr::Par(e) | Expr::UnaryOp(_, e) => expr_depth(&e.1),
+        Expr::BinOp(_, l, r) => expr_depth(&l.1).max(expr_depth(&r.1)),
+        Expr::Call(_, args) | Expr::Macro(_, args, _) => {
+            args.iter().map(|(_, a)| expr_depth(a)).max().unwrap_or(0)
+        }
+        Expr::Num(_) | Expr::Bool(_) | Expr::Id(_) | Expr::Unit => 0,
+    }
+}
+
+// runs `f` on a thread with `size` bytes of host stack, such that deep
+// recursion ends at the depth limit rather than overflowing the stack
+pub fn with_stack<T: Send>(size: usize, f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|s| {
+        let t = std::thread::Builder::new()
+            .stack_size(size)
+            .spawn_scoped(s, f);
+        match t.expect("cannot spawn the evaluation thread").join() {
+            Ok(r) => r,
+            Err(e) => std::panic::resume_unwind(e),
+        }
+    })
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: None,
+            max_depth: MAX_DEPTH,
+            max_store: 1 << 16,
+        }
+    }
+}
+
+// called before each (non-block) statement, e.g., by a debugger,
+// returning false aborts the execution
+pub type Hook<'p, 'a> = Box<dyn FnMut(&Interp<'p, 'a>, &SpanStmt<'a>) -> bool + Send + 'p>;
+
+pub struct Interp<'p, 'a> {
+    fns: HashMap<&'a str, &'p FnDecl<'a>>,
+    stack: Vec<Frame<'a>>,
+    store: HashMap<usize, Cell<'a>>,
+    // next free address, addresses are not reused
+    next: usize,
+    // spans creating the tags, declarations of cells and borrows
+    tags: Vec<Span<'a>>,
+    // borrow stacks, when checking aliasing
+    stacks: Option<Stacks<'a>>,
+    // premises of the rule applications in progress, when tracing
+    trace: Option<Vec<Vec<Derivation>>>,
+    hook: Option<Hook<'p, 'a>>,
+    limits: Limits,
+    // fuel used so far
+    steps: u64,
+    // output of `println!`
+    out: Box<dyn Write + Send + 'p>,
+    // host stack per call, for the most deeply nested function
+    per_call: usize,
+}
+
+type Result<'a, T> = std::result::Result<T, RuntimeError<'a>>;
+
+// runs the function `id` of the program with the given arguments
+pub fn run<'a>(p: &Prog<'a>, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+    Interp::new(p).call(id, args)
+}
+
+// runs the function `id` checking accesses against borrow stacks
+pub fn run_checked<'a>(p: &Prog<'a>, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+    let mut i = Interp::new(p);
+    i.set_check_aliasing(true);
+    i.call(id, args)
+}
+
+// variables with their mutability and values, which may not be
+// references as they outlive the store
+pub type Vars = Vec<(String, bool, Value)>;
+
+// evaluates `e` in a frame binding the given variables
+pub fn eval_in<'a>(p: &Prog<'a>, vars: &'a Vars, e: &SpanExpr<'a>) -> Result<'a, Value> {
+    let mut i = Interp::with_vars(p, vars)?;
+    i.on_stack(expr_depth(&e.1), |i| {
+        let v = i.eval_expr(e)?;
+        i.pop_scope(std::iter::once(&v))?;
+        Ok(v)
+    })
+}
+
+// executes `stmt` in a frame binding the given variables, returns the
+// variables afterwards and the value of an executed `return`, neither
+// may refer to the store, which does not outlive the frame
+pub fn exec_in<'a>(
+    p: &Prog<'a>,
+    vars: &'a Vars,
+    stmt: &SpanStmt<'a>,
+) -> Result<'a, (Vars, Option<Value>)> {
+    let mut i = Interp::with_vars(p, vars)?;
+    let r = i.on_stack(stmt_depth(&stmt.1), |i| i.exec_stmt(stmt))?;
+    let vars = i.vars();
+    i.pop_scope(r.iter().chain(vars.iter().map(|(_, _, v)| v)))?;
+    Ok((vars, r))
+}
+
+impl<'p, 'a> Interp<'p, 'a> {
+    pub fn new(p: &'p Prog<'a>) -> Self {
+        let depth = p.iter().map(|f| block_depth(&f.body.1)).max();
+        let per_call = STACK_PER_CALL + depth.unwrap_or(0) * STACK_PER_LEVEL;
+        Interp {
+            fns: p.iter().map(|f| (f.id.fragment, f)).collect(),
+            stack: vec![],
+            store: HashMap::new(),
+            next: 0,
+            tags: vec![],
+            stacks: None,
+            trace: None,
+            hook: None,
+            limits: Limits::default().clamped(per_call),
+            steps: 0,
+            out: Box::new(io::stdout()),
+            per_call,
+        }
+    }
+
+    // redirects the output of `println!`, e.g., to capture it in tests
+    pub fn set_output(&mut self, out: Box<dyn Write + Send + 'p>) {
+        self.out = out;
+    }
+
+    // `max_depth` is clamped, see `MAX_STACK`
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits.clamped(self.per_call);
+    }
+
+    // check accesses against borrow stacks (Stacked Borrows), for cells
+    // allocated from now on
+    pub fn set_check_aliasing(&mut self, on: bool) {
+        self.stacks = if on { Some(Stacks::new()) } else { None };
+    }
+
+    // a new tag created at `span`
+    fn tag(&mut self, span: Span<'a>) -> usize {
+        self.tags.push(span);
+        self.tags.len() - 1
+    }
+
+    // checks an access to a place, when checking aliasing
+    fn access(&mut self, span: Span<'a>, p: Place, access: Access) -> Result<'a, ()> {
+        let r = match &mut self.stacks {
+            Some(stacks) => stacks.access(p.addr, p.tag, access, span),
+            None => Ok(()),
+        };
+        r.map_err(|v| self.violation(span, v))
+    }
+
+    fn violation(&self, span: Span<'a>, v: Violation<'a>) -> RuntimeError<'a> {
+        let mut e = self.error(span, ErrorKind::Invalidated(v.access));
+        let created = self.tags[v.tag];
+        e.notes
+            .push((created, "the reference was created here".to_string()));
+        if let Some((access, s)) = v.invalidated {
+            e.notes
+                .push((s, format!("and invalidated by this {}", access)));
+        }
+        e
+    }
+
+    // consumes one unit of fuel for evaluating the construct at `span`
+    fn tick(&mut self, span: Span<'a>) -> Result<'a, ()> {
+        match self.limits.fuel {
+            Some(fuel) if self.steps >= fuel => Err(self.error(span, ErrorKind::OutOfFuel(fuel))),
+            _ => {
+                self.steps += 1;
+                Ok(())
+            }
+        }
+    }
+
+    // allocates a cell owned by the innermost scope
+    fn alloc(
+        &mut self,
+        span: Span<'a>,
+        id: Option<&'a str>,
+        mutable: bool,
+        value: Value,
+    ) -> Result<'a, usize> {
+        if self.store.len() >= self.limits.max_store {
+            return Err(self.error(span, ErrorKind::StoreSize(self.limits.max_store)));
+        }
+        let addr = self.next;
+        self.next += 1;
+        let tag = self.tag(span);
+        if let Some(stacks) = &mut self.stacks {
+            stacks.alloc(addr, tag);
+        }
+        let cell = Cell {
+            value,
+            mutable,
+            id,
+            borrowed: false,
+            tag,
+        };
+        self.store.insert(addr, cell);
+        let scope = self.stack.last_mut().unwrap().scopes.last_mut().unwrap();
+        scope.cells.push(addr);
+        if let Some(id) = id {
+            scope.vars.insert(id, addr);
+        }
+        Ok(addr)
+    }
+
+    // frees the cells of the innermost scope, the `escaping` values (e.g.,
+    // a returned value) must not refer to them either
+    fn pop_scope<'v>(&mut self, mut escaping: impl Iterator<Item = &'v Value>) -> Result<'a, ()> {
+        let scope = self.stack.last_mut().unwrap().scopes.pop().unwrap();
+        let freed: Vec<_> = scope
+            .cells
+            .iter()
+            .filter_map(|a| Some((*a, self.free(*a)?)))
+            .collect();
+        if !freed.iter().any(|(_, c)| c.borrowed) {
+            return Ok(());
+        }
+        // the borrow and variable of a reference to a freed cell
+        let dangling = |v: &Value| match v {
+            Value::Ref(r) => (freed.iter())
+                .find(|(a, _)| *a == r.addr)
+                .map(|(_, c)| (r.tag, c.id)),
+            _ => None,
+        };
+        let found = (escaping.find_map(&dangling))
+            .or_else(|| self.store.values().find_map(|c| dangling(&c.value)));
+        match found {
+            Some((tag, id)) => {
+                Err(self.error(self.tags[tag], ErrorKind::Dangling(id.map(str::to_string))))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn free(&mut self, addr: usize) -> Option<Cell<'a>> {
+        if let Some(stacks) = &mut self.stacks {
+            stacks.free(addr);
+        }
+        self.store.remove(&addr)
+    }
+
+    // the place a reference points to
+    fn deref(&self, span: Span<'a>, r: &Ref) -> Result<'a, Place> {
+        match self.store.get(&r.addr) {
+            Some(_) => Ok(Place {
+                addr: r.addr,
+                mutable: r.mutable,
+                tag: r.tag,
+            }),
+            None => Err(self.error(span, ErrorKind::Dangling(None))),
+        }
+    }
+
+    // an interpreter with a top-level frame binding the given variables
+    fn with_vars(p: &'p Prog<'a>, vars: &'a Vars) -> Result<'a, Self> {
+        let mut i = Interp::new(p);
+        i.stack.push(Frame {
+            id: Span::new(""),
+            call: None,
+            scopes: vec![Scope::default()],
+        });
+        for (x, mutable, v) in vars {
+            i.alloc(Span::new(""), Some(x), *mutable, v.clone())?;
+        }
+        Ok(i)
+    }
+
+    // evaluates `e` in the current frame, on a copy of the store so that
+    // the execution is not affected, e.g., for watch expressions; the
+    // output of `println!` is discarded
+    pub fn eval_here<'b>(&self, e: &SpanExpr<'b>) -> Result<'b, Value>
+    where
+        'a: 'b,
+    {
+        let mut i: Interp<'_, 'b> = Interp {
+            fns: self.fns.clone(),
+            stack: self.stack.last().cloned().into_iter().collect(),
+            store: self.store.clone(),
+            next: self.next,
+            tags: self.tags.clone(),
+            stacks: self.stacks.clone(),
+            trace: None,
+            hook: None,
+            limits: self.limits,
+            steps: self.steps,
+            out: Box::new(io::sink()),
+            per_call: self.per_call,
+        };
+        if i.stack.is_empty() {
+            i.stack.push(Frame {
+                id: Span::new(""),
+                call: None,
+                scopes: vec![],
+            });
+        }
+        // a scope for the temporaries
+        i.stack[0].scopes.push(Scope::default());
+        i.on_stack(expr_depth(&e.1), |i| {
+            let v = i.eval_expr(e)?;
+            i.pop_scope(std::iter::once(&v))?;
+            Ok(v)
+        })
+    }
+
+    pub fn set_hook(&mut self, hook: Option<Hook<'p, 'a>>) {
+        self.hook = hook;
+    }
+
+    // record SOS derivations for subsequent calls
+    pub fn set_trace(&mut self, on: bool) {
+        self.trace = if on { Some(vec![vec![]]) } else { None };
+    }
+
+    // the derivation of the last completed call
+    pub fn derivation(&mut self) -> Option<Derivation> {
+        self.trace.as_mut()?.first_mut()?.pop()
+    }
+
+    // snapshot of the variables visible in the current frame
+    pub fn env(&self) -> Env {
+        let mut env = std::collections::BTreeMap::new();
+        if let Some(frame) = self.stack.last() {
+            for scope in &frame.scopes {
+                env.extend(
+                    (scope.vars.iter()).map(|(x, a)| (x.to_string(), self.store[a].value.clone())),
+                );
+            }
+        }
+        env.into_iter().collect()
+    }
+
+    // the variables visible in the current frame and their mutability
+    pub fn vars(&self) -> Vars {
+        let mut vars = std::collections::BTreeMap::new();
+        if let Some(frame) = self.stack.last() {
+            for scope in &frame.scopes {
+                vars.extend(scope.vars.iter().map(|(x, a)| {
+                    let c = &self.store[a];
+                    (x.to_string(), (c.mutable, c.value.clone()))
+                }));
+            }
+        }
+        (vars.into_iter()).map(|(x, (m, v))| (x, m, v)).collect()
+    }
+
+    // the addresses of the variables visible in the current frame, and
+    // the store
+    pub fn state(&self) -> State {
+        let mut env = std::collections::BTreeMap::new();
+        if let Some(frame) = self.stack.last() {
+            for scope in &frame.scopes {
+                env.extend(scope.vars.iter().map(|(x, a)| (x.to_string(), *a)));
+            }
+        }
+        let mut store: Vec<_> = (self.store.iter())
+            .map(|(a, c)| (*a, c.value.clone()))
+            .collect();
+        store.sort_by_key(|(a, _)| *a);
+        State {
+            env: env.into_iter().collect(),
+            store,
+        }
+    }
+
+    // starts a rule application, returns the state before
+    fn begin(&mut self) -> Option<State> {
+        let state = self.trace.as_ref().map(|_| self.state())?;
+        self.trace.as_mut().unwrap().push(vec![]);
+        Some(state)
+    }
+
+    // concludes the rule application started by the matching `begin`
+    fn end<F>(&mut self, before: Option<State>, rule: &'static str, term: F, c: Conclusion)
+    where
+        F: FnOnce() -> String,
+    {
+        if let Some(before) = before {
+            let after = self.state();
+            let trace = self.trace.as_mut().unwrap();
+            let premises = trace.pop().unwrap();
+            trace.last_mut().unwrap().push(Derivation {
+                rule,
+                term: term(),
+                before,
+                after,
+                conclusion: c,
+                premises,
+            });
+        }
+    }
+
+    pub fn stack(&self) -> &[Frame<'a>] {
+        &self.stack
+    }
+
+    // calls run on a thread of their own, with a host stack for the
+    // maximum call depth
+    pub fn call(&mut self, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+        self.on_stack(0, |i| i.call_here(id, args))
+    }
+
+    // runs `f` with a host stack for the maximum call depth, entered
+    // `depth` levels deep in a statement or expression
+    fn on_stack<T: Send>(&mut self, depth: usize, f: impl FnOnce(&mut Self) -> T + Send) -> T {
+        let size = self.limits.stack_size(self.per_call) + depth * STACK_PER_LEVEL;
+        with_stack(size, || f(self))
+    }
+
+    fn call_here(&mut self, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+        let f = match self.fns.get(id) {
+            Some(f) => *f,
+            None => {
+                return Err(self.error(Span::new(""), ErrorKind::UnboundFunction(id.to_string())))
+            }
+        };
+        if self.trace.is_some() {
+            self.set_trace(true);
+        }
+        let term = {
+            let args: Vec<_> = args.iter().map(Value::to_string).collect();
+            format!("{}({})", id, args.join(", "))
+        };
+        let before = self.begin();
+        let v = self.call_fn(f, args, None)?;
+        self.end(before, "E-Call", || term, Conclusion::Value(v.clone()));
+        Ok(v)
+    }
+
+    fn error(&self, span: Span<'a>, kind: ErrorKind) -> RuntimeError<'a> {
+        RuntimeError {
+            span,
+            kind,
+            backtrace: self.stack.iter().rev().map(|f| (f.id, f.call)).collect(),
+            notes: vec![],
+        }
+    }
+
+    // `&mut T` is accepted where `&T` is expected
+    fn check_type(&self, span: Span<'a>, expected: &Type, v: Value) -> Result<'a, Value> {
+        match (expected, v) {
+            (e, v) if v.ty() == *e => Ok(v),
+            (Type::Ref(false, t), Value::Ref(r)) if r.mutable && r.ty == *t => {
+                Ok(Value::Ref(Ref {
+                    mutable: false,
+                    ..r
+                }))
+            }
+            (e, v) => Err(self.error(span, ErrorKind::TypeMismatch(e.clone(), v.ty()))),
+        }
+    }
+
+    fn call_fn(
+        &mut self,
+        f: &'p FnDecl<'a>,
+        args: Vec<Value>,
+        call: Option<Span<'a>>,
+    ) -> Result<'a, Value> {
+        let span = call.unwrap_or(f.id);
+        if args.len() != f.params.len() {
+            return Err(self.error(span, ErrorKind::ArgCount(f.params.len(), args.len())));
+        }
+        if self.stack.len() >= self.limits.max_depth {
+            return Err(self.error(span, ErrorKind::CallDepth(self.limits.max_depth)));
+        }
+        if self.store.len() + args.len() > self.limits.max_store {
+            return Err(self.error(span, ErrorKind::StoreSize(self.limits.max_store)));
+        }
+        let args = (f.params.iter().zip(args))
+            .map(|(p, v)| self.check_type(span, &p.ty.1, v))
+            .collect::<Result<Vec<_>>>()?;
+        self.stack.push(Frame {
+            id: f.id,
+            call,
+            scopes: vec![Scope::default()],
+        });
+        let r = self.call_body(f, span, args);
+        // after an error, scopes may be left
+        for sc in self.stack.pop().unwrap().scopes {
+            for a in sc.cells {
+                self.free(a);
+            }
+        }
+        r
+    }
+
+    // runs the body in the new frame, the parameter scope is popped but
+    // not the frame itself
+    fn call_body(
+        &mut self,
+        f: &'p FnDecl<'a>,
+        span: Span<'a>,
+        args: Vec<Value>,
+    ) -> Result<'a, Value> {
+        for (p, v) in f.params.iter().zip(args) {
+            self.alloc(span, Some(p.id.fragment), p.mutable, v)?;
+        }
+        let r = match self.exec_block(&f.body) {
+            Ok(Some(v)) => {
+                self.check_type(f.ret.as_ref().map_or(f.id, |(s, _)| *s), &f.ret_type(), v)
+            }
+            Ok(None) if f.ret_type() == Type::Unit => Ok(Value::Unit),
+            Ok(None) => Err(self.error(
+                f.body.0,
+                ErrorKind::MissingReturn(f.id.fragment.to_string()),
+            )),
+            Err(e) => Err(e),
+        }?;
+        self.pop_scope(std::iter::once(&r))?;
+        Ok(r)
+    }
+
+    // the place of the variable `s`
+    fn var(&self, s: Span<'a>) -> Result<'a, Place> {
+        let scopes = &self.stack.last().unwrap().scopes;
+        match scopes.iter().rev().find_map(|sc| sc.vars.get(s.fragment)) {
+            Some(addr) => {
+                let cell = &self.store[addr];
+                Ok(Place {
+                    addr: *addr,
+                    mutable: cell.mutable,
+                    tag: cell.tag,
+                })
+            }
+            None => Err(self.error(s, ErrorKind::UnboundVariable(s.fragment.to_string()))),
+        }
+    }
+
+    // the place denoted by `x` or `*e`, None for other expressions
+    fn place(&mut self, (s, e): &SpanExpr<'a>) -> Result<'a, Option<Place>> {
+        match e {
+            Expr::Id(_) => self.var(*s).map(Some),
+            Expr::Par(e) => self.place(e),
+            Expr::UnaryOp(Op::Deref, e) => self.deref_place(*s, e).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    // the place `*e`
+    fn deref_place(&mut self, s: Span<'a>, e: &SpanExpr<'a>) -> Result<'a, Place> {
+        match self.eval_expr(e)? {
+            Value::Ref(r) => self.deref(s, &r),
+            v => Err(self.error(s, ErrorKind::Operands(Op::Deref, vec![v.ty()]))),
+        }
+    }
+
+    // reads the value at a place
+    fn read(&mut self, s: Span<'a>, p: Place) -> Result<'a, Value> {
+        self.access(s, p, Access::Read)?;
+        Ok(self.store[&p.addr].value.clone())
+    }
+
+    // follows references, operators apply to the referenced values
+    fn deref_all(&mut self, s: Span<'a>, v: Value) -> Result<'a, Value> {
+        match v {
+            Value::Ref(r) => {
+                let p = self.deref(s, &r)?;
+                let v = self.read(s, p)?;
+                self.deref_all(s, v)
+            }
+            v => Ok(v),
+        }
+    }
+
+    // `&e` or `&mut e`, borrowing a place or a temporary holding the
+    // value of `e`
+    fn borrow(&mut self, s: Span<'a>, mutable: bool, e: &SpanExpr<'a>) -> Result<'a, Value> {
+        let p = match self.place(e)? {
+            Some(p) if mutable && !p.mutable => {
+                let id = self.store[&p.addr]
+                    .id
+                    .filter(|_| matches!(e.1, Expr::Id(_)));
+                return Err(self.error(s, ErrorKind::BorrowMut(id.map(str::to_string))));
+            }
+            Some(p) => p,
+            None => {
+                let v = self.eval_expr(e)?;
+                let addr = self.alloc(e.0, None, true, v)?;
+                Place {
+                    addr,
+                    mutable: true,
+                    tag: self.store[&addr].tag,
+                }
+            }
+        };
+        let tag = self.tag(s);
+        if let Some(stacks) = &mut self.stacks {
+            let perm = if mutable {
+                Perm::Unique
+            } else {
+                Perm::SharedRO
+            };
+            let r = stacks.retag(p.addr, p.tag, tag, perm, s);
+            r.map_err(|v| self.violation(s, v))?;
+        }
+        let cell = self.store.get_mut(&p.addr).unwrap();
+        cell.borrowed = true;
+        Ok(Value::Ref(Ref {
+            addr: p.addr,
+            mutable,
+            tag,
+            ty: Box::new(cell.value.ty()),
+        }))
+    }
+
+    pub fn eval_expr(&mut self, e: &SpanExpr<'a>) -> Result<'a, Value> {
+        self.tick(e.0)?;
+        let before = self.begin();
+        let v = self.eval(e)?;
+        self.end(
+            before,
+            expr_rule(&e.1),
+            || e.1.to_string(),
+            Conclusion::Value(v.clone()),
+        );
+        Ok(v)
+    }
+
+    fn eval(&mut self, (s, e): &SpanExpr<'a>) -> Result<'a, Value> {
+        match e {
+            Expr::Num(i) => Ok(Value::Num(*i)),
+            Expr::Bool(b) => Ok(Value::Bool(*b)),
+            Expr::Unit => Ok(Value::Unit),
+            Expr::Par(e) => self.eval_expr(e),
+            Expr::Id(_) => {
+                let p = self.var(*s)?;
+                self.read(*s, p)
+            }
+            Expr::Call(id, args) => self.call_expr(*s, id, args),
+            Expr::BinOp(op, l, r) => self.binop_expr(*s, *op, l, r),
+            Expr::UnaryOp(op @ Op::Ref, e) | Expr::UnaryOp(op @ Op::RefMut, e) => {
+                self.borrow(*s, *op == Op::RefMut, e)
+            }
+            Expr::UnaryOp(Op::Deref, e) => {
+                let p = self.deref_place(*s, e)?;
+                self.read(*s, p)
+            }
+            Expr::UnaryOp(op, e) => self.unop_expr(*s, *op, e),
+            Expr::Macro(m, args, fmt) => self.macro_expr(*s, *m, args, *fmt),
+        }
+    }
+
+    fn eval_bool(&mut self, e: &SpanExpr<'a>) -> Result<'a, bool> {
+        match self.eval_expr(e)? {
+            Value::Bool(b) => Ok(b),
+            v => Err(self.error(e.0, ErrorKind::TypeMismatch(Type::Bool, v.ty()))),
+        }
+    }
+
+    // returns Some(value) if a `return` was executed
+    pub fn exec_stmt(&mut self, stmt: &SpanStmt<'a>) -> Result<'a, Option<Value>> {
+        if let Stmt::Block(b) = &stmt.1 {
+            return self.exec_block(b);
+        }
+        // the hook is taken while it runs, so it is not re-entered
+        if let Some(mut hook) = self.hook.take() {
+            let go = hook(self, stmt);
+            self.hook = Some(hook);
+            if !go {
+                return Err(self.error(stmt.0, ErrorKind::Aborted));
+            }
+        }
+        self.tick(stmt.0)?;
+        let before = self.begin();
+        let (rule, r) = self.exec(stmt)?;
+        self.end(before, rule, || stmt.0.fragment.to_string(), conclusion(&r));
+        Ok(r)
+    }
+
+    // executes the statement, returning the applied rule
+    fn exec(&mut self, (s, stmt): &SpanStmt<'a>) -> Result<'a, (&'static str, Option<Value>)> {
+        match stmt {
+            Stmt::Let(m, id, (_, ty), e) => {
+                let v = self.eval_expr(e)?;
+                let v = self.check_type(*s, ty, v)?;
+                self.alloc(*s, Some(id.fragment), *m, v)?;
+                Ok(("S-Let", None))
+            }
+            Stmt::Assign(l, e) => {
+                self.assign(*s, l, e)?;
+                Ok(("S-Assign", None))
+            }
+            Stmt::If(c, t, e) => {
+                if self.eval_bool(c)? {
+                    Ok(("S-If-True", self.exec_block(t)?))
+                } else if let Some(e) = e {
+                    Ok(("S-If-False", self.exec_block(e)?))
+                } else {
+                    Ok(("S-If-False", None))
+                }
+            }
+            Stmt::While(c, b) => self.exec_while(*s, c, b),
+            Stmt::Return(e) => {
+                let v = match e {
+                    Some(e) => self.eval_expr(e)?,
+                    None => Value::Unit,
+                };
+                Ok(("S-Return", Some(v)))
+            }
+            Stmt::Expr(e) => {
+                self.eval_expr(e)?;
+                Ok(("S-Expr", None))
+            }
+            Stmt::Block(b) => Ok(("S-Block", self.exec_block(b)?)),
+        }
+    }
+
+    // the arms of `exec` and `eval` below are separate functions, keeping
+    // the (debug build) stack frames of the recursion small
+
+    fn assign(&mut self, s: Span<'a>, l: &SpanExpr<'a>, e: &SpanExpr<'a>) -> Result<'a, ()> {
+        let v = self.eval_expr(e)?;
+        let p = match self.place(l)? {
+            Some(p) if !p.mutable => {
+                let kind = match variable(l) {
+                    Some(id) => ErrorKind::AssignImmutable(id.to_string()),
+                    None => ErrorKind::AssignShared,
+                };
+                return Err(self.error(l.0, kind));
+            }
+            Some(p) => p,
+            None => return Err(self.error(l.0, ErrorKind::InvalidAssign)),
+        };
+        let ty = self.store[&p.addr].value.ty();
+        let v = self.check_type(s, &ty, v)?;
+        self.access(l.0, p, Access::Write)?;
+        self.store.get_mut(&p.addr).unwrap().value = v;
+        Ok(())
+    }
+
+    fn exec_while(
+        &mut self,
+        s: Span<'a>,
+        c: &SpanExpr<'a>,
+        b: &SpanBlock<'a>,
+    ) -> Result<'a, (&'static str, Option<Value>)> {
+        // each iteration is a premise of the previous one
+        let mut iterations = vec![];
+        let (mut rule, r) = loop {
+            if !self.eval_bool(c)? {
+                break ("S-While-False", None);
+            }
+            if let Some(v) = self.exec_block(b)? {
+                break ("S-While-True", Some(v));
+            }
+            iterations.push(self.begin());
+        };
+        for before in iterations.into_iter().rev() {
+            self.end(before, rule, || s.fragment.to_string(), conclusion(&r));
+            rule = "S-While-True";
+        }
+        Ok((rule, r))
+    }
+
+    fn call_expr(&mut self, s: Span<'a>, id: &str, args: &[SpanExpr<'a>]) -> Result<'a, Value> {
+        let f = match self.fns.get(id) {
+            Some(f) => *f,
+            None => return Err(self.error(s, ErrorKind::UnboundFunction(id.to_string()))),
+        };
+        let args = args
+            .iter()
+            .map(|a| self.eval_expr(a))
+            .collect::<Result<_>>()?;
+        self.call_fn(f, args, Some(s))
+    }
+
+    fn binop_expr(
+        &mut self,
+        s: Span<'a>,
+        op: Op,
+        l: &SpanExpr<'a>,
+        r: &SpanExpr<'a>,
+    ) -> Result<'a, Value> {
+        let lv = self.eval_expr(l)?;
+        let lv = self.deref_all(s, lv)?;
+        if short_circuit(op, &lv) {
+            return Ok(lv);
+        }
+        let rv = self.eval_expr(r)?;
+        let rv = self.deref_all(s, rv)?;
+        binop(op, lv, rv).map_err(|kind| self.error(s, kind))
+    }
+
+    fn unop_expr(&mut self, s: Span<'a>, op: Op, e: &SpanExpr<'a>) -> Result<'a, Value> {
+        let v = self.eval_expr(e)?;
+        let v = self.deref_all(s, v)?;
+        unop(op, v).map_err(|kind| self.error(s, kind))
+    }
+
+    fn macro_expr(
+        &mut self,
+        s: Span<'a>,
+        m: Macro,
+        args: &[SpanExpr<'a>],
+        fmt: Option<Span<'a>>,
+    ) -> Result<'a, Value> {
+        let mut vals = vec![];
+        for a in args {
+            let v = self.eval_expr(a)?;
+            vals.push(self.deref_all(a.0, v)?);
+        }
+        let cond = || args[0].1.to_string();
+        let fmt = fmt.map(|f| f.fragment);
+        builtin(m, fmt, cond, &vals, &mut self.out).map_err(|kind| self.error(s, kind))
+    }
+
+    pub fn exec_block(&mut self, (s, b): &SpanBlock<'a>) -> Result<'a, Option<Value>> {
+        let before = self.begin();
+        self.stack.last_mut().unwrap().scopes.push(Scope::default());
+        let mut r = Ok(None);
+        for s in b {
+            r = self.exec_stmt(s);
+            if let Ok(None) = r {
+                continue;
+            }
+            break;
+        }
+        let r = match r {
+            Ok(r) => self.pop_scope(r.iter()).map(|_| r),
+            Err(e) => {
+                let _ = self.pop_scope(std::iter::empty());
+                Err(e)
+            }
+        }?;
+        self.end(before, "S-Block", || s.fragment.to_string(), conclusion(&r));
+        Ok(r)
+    }
+}
+
+// the variable an assigned place names, through parentheses
+pub fn variable<'a>(e: &SpanExpr<'a>) -> Option<&'a str> {
+    match &e.1 {
+        Expr::Id(id) => Some(id),
+        Expr::Par(e) => variable(e),
+        _ => None,
+    }
+}
+
+fn conclusion(r: &Option<Value>) -> Conclusion {
+    match r {
+        Some(v) => Conclusion::Return(v.clone()),
+        None => Conclusion::Env,
+    }
+}
+
+fn expr_rule(e: &Expr) -> &'static str {
+    match e {
+        Expr::Num(_) => "E-Num",
+        Expr::Bool(_) => "E-Bool",
+        Expr::Unit => "E-Unit",
+        Expr::Par(_) => "E-Par",
+        Expr::Id(_) => "E-Var",
+        Expr::Call(..) => "E-Call",
+        Expr::BinOp(op, ..) => match op {
+            Op::Eq => "E-Eq",
+            Op::Neq => "E-Neq",
+            Op::Lt => "E-Lt",
+            Op::Gt => "E-Gt",
+            Op::And => "E-And",
+            Op::Or => "E-Or",
+            Op::Add => "E-Add",
+            Op::Sub => "E-Sub",
+            Op::Mul => "E-Mul",
+            Op::Div => "E-Div",
+            Op::Pow => "E-Pow",
+            _ => "E-Op",
+        },
+        Expr::UnaryOp(op, _) => match op {
+            Op::Sub => "E-Neg",
+            Op::Not => "E-Not",
+            Op::Ref => "E-Ref",
+            Op::RefMut => "E-RefMut",
+            Op::Deref => "E-Deref",
+            _ => "E-Pos",
+        },
+        Expr::Macro(..) => "E-Macro",
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnboundVariable(id) => write!(f, "cannot find value `{}` in this scope", id),
+            ErrorKind::UnboundFunction(id) => write!(f, "cannot find function `{}`", id),
+            ErrorKind::ArgCount(e, n) => {
+                write!(f, "function takes {} arguments but {} were supplied", e, n)
+            }
+            ErrorKind::TypeMismatch(e, t) => {
+                write!(f, "mismatched types: expected `{}`, found `{}`", e, t)
+            }
+            ErrorKind::Operands(op, tys) => match tys.as_slice() {
+                [l, r] => write!(f, "cannot apply `{}` to `{}` and `{}`", op, l, r),
+                _ => {
+                    let tys: Vec<_> = tys.iter().map(|t| format!("`{}`", t)).collect();
+                    write!(
+                        f,
+                        "cannot apply `{}` to {}",
+                        op.to_string().trim(),
+                        tys.join(", ")
+                    )
+                }
+            },
+            ErrorKind::InvalidAssign => write!(f, "invalid left-hand side of assignment"),
+            ErrorKind::MissingReturn(id) => {
+                write!(f, "function `{}` finished without returning a value", id)
+            }
+            ErrorKind::Overflow(op) => write!(
+                f,
+                "attempt to {} with overflow",
+                match op {
+                    Op::Add => "add",
+                    Op::Sub => "subtract",
+                    Op::Mul => "multiply",
+                    Op::Div => "divide",
+                    _ => "compute the power",
+                }
+            ),
+            ErrorKind::NegOverflow => write!(f, "attempt to negate with overflow"),
+            ErrorKind::DivisionByZero => write!(f, "attempt to divide by zero"),
+            ErrorKind::NegativeExponent => write!(f, "attempt to raise to a negative power"),
+            ErrorKind::Aborted => write!(f, "execution aborted"),
+            ErrorKind::OutOfFuel(n) => write!(f, "out of fuel after {} steps", n),
+            ErrorKind::CallDepth(n) => write!(f, "call depth exceeds the limit of {}", n),
+            ErrorKind::StoreSize(n) => write!(f, "store exceeds the limit of {} variables", n),
+            ErrorKind::Dangling(Some(id)) => write!(f, "`{}` does not live long enough", id),
+            ErrorKind::Dangling(None) => write!(f, "temporary value dropped while borrowed"),
+            ErrorKind::BorrowMut(Some(id)) => write!(
+                f,
+                "cannot borrow `{}` as mutable, as it is not declared as mutable",
+                id
+            ),
+            ErrorKind::BorrowMut(None) => {
+                write!(f, "cannot borrow data in a `&` reference as mutable")
+            }
+            ErrorKind::AssignShared => write!(f, "cannot assign to data in a `&` reference"),
+            ErrorKind::AssignImmutable(id) => {
+                write!(f, "cannot assign twice to immutable variable `{}`", id)
+            }
+            ErrorKind::Invalidated(access) => {
+                write!(f, "{} access through an invalidated reference", access)
+            }
+            ErrorKind::Panic(msg) => write!(f, "panicked: {}", msg),
+        }
+    }
+}
+
+impl<'a> fmt::Display for RuntimeError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}:{}",
+            self.kind,
+            self.span.line,
+            self.span.get_column()
+        )?;
+        for (s, msg) in &self.notes {
+            write!(f, "\n  {} at {}:{}", msg, s.line, s.get_column())?;
+        }
+        for (id, call) in &self.backtrace {
+            match call {
+                Some(call) => write!(
+                    f,
+                    "\n  in `{}` called at {}:{}",
+                    id.fragment,
+                    call.line,
+                    call.get_column()
+                )?,
+                None => write!(f, "\n  in `{}`", id.fragment)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_run() {
+    let src = "
+fn fact(n: i32) -> i32 {
+    if n == 0 {
+        return 1;
+    }
+    return n * fact(n - 1);
+}
+
+fn main() -> i32 {
+    let mut i: i32 = 0;
+    let mut s: i32 = 0;
+    while i < 5 {
+        let i2: i32 = i * i;
+        s = s + i2;
+        i = i + 1;
+    }
+    let b: bool = s == 30 && !false;
+    if b {
+        let s: i32 = 1000;
+    }
+    return fact(5) + s;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    assert_eq!(run(&p, "main", vec![]), Ok(Value::Num(150)));
+    assert_eq!(run(&p, "fact", vec![Value::Num(3)]), Ok(Value::Num(6)));
+}
+
+#[test]
+fn test_run_error() {
+    let src = "
+fn f(x: i32) -> i32 {
+    return x + true;
+}
+
+fn main() {
+    let y: i32 = f(1);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let e = run(&p, "main", vec![]).unwrap_err();
+    assert_eq!(e.span.line, 3);
+    assert_eq!(
+        e.kind,
+        ErrorKind::Operands(Op::Add, vec![Type::I32, Type::Bool])
+    );
+    assert_eq!(e.backtrace.len(), 2);
+    assert_eq!(e.backtrace[0].0.fragment, "f");
+    assert_eq!(e.backtrace[0].1.unwrap().line, 7);
+    assert_eq!(e.backtrace[1].1, None);
+}
+
+#[test]
+fn test_run_arith_error() {
+    let src = "
+fn f(x: i32, y: i32) -> i32 {
+    return x / y;
+}
+
+fn main() -> i32 {
+    let m: i32 = 2147483647;
+    let a: i32 = f(4, 2) ** 2;
+    return a + f(m, 0 - 1) - f(m, 0);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let e = run(&p, "main", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::DivisionByZero);
+    assert_eq!((e.span.line, e.span.fragment), (3, "/"));
+    assert_eq!(e.backtrace[0].1.unwrap().get_column(), 30);
+
+    let src = "
+fn main() -> i32 {
+    let m: i32 = 2147483647;
+    let a: i32 = -m - 1;
+    if a < 0 {
+        return 2 ** a;
+    }
+    return -a;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let e = run(&p, "main", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::NegativeExponent);
+    assert_eq!((e.span.line, e.span.fragment), (6, "**"));
+    assert_eq!(
+        unop(Op::Sub, Value::Num(-2147483648)),
+        Err(ErrorKind::NegOverflow)
+    );
+    assert_eq!(
+        binop(Op::Mul, Value::Num(65536), Value::Num(65536)),
+        Err(ErrorKind::Overflow(Op::Mul))
+    );
+}
+
+#[test]
+fn test_limits() {
+    let src = "
+fn f(n: i32) -> i32 {
+    if n == 0 {
+        return 0;
+    }
+    let x: i32 = n;
+    return f(n - 1) + x;
+}
+
+fn main() {
+    while true {}
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let e = run(&p, "f", vec![Value::Num(1000)]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::CallDepth(MAX_DEPTH));
+    assert_eq!((e.span.line, e.span.fragment), (7, "f"));
+    assert_eq!(e.backtrace.len(), MAX_DEPTH);
+    assert_eq!(run(&p, "f", vec![Value::Num(900)]), Ok(Value::Num(405450)));
+
+    // deeper limits are clamped to fit the host stack
+    let mut i = Interp::new(&p);
+    i.set_limits(Limits {
+        max_depth: usize::MAX,
+        ..Limits::default()
+    });
+    match i.call("f", vec![Value::Num(i32::MAX)]).unwrap_err().kind {
+        ErrorKind::CallDepth(n) => assert!(n > MAX_DEPTH && n < MAX_STACK / STACK_PER_CALL),
+        e => panic!("{:?}", e),
+    }
+
+    let mut i = Interp::new(&p);
+    i.set_limits(Limits {
+        fuel: Some(1000),
+        ..Limits::default()
+    });
+    let e = i.call("main", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::OutOfFuel(1000));
+    assert_eq!(e.span.line, 11);
+
+    let mut i = Interp::new(&p);
+    i.set_limits(Limits {
+        max_store: 20,
+        ..Limits::default()
+    });
+    let e = i.call("f", vec![Value::Num(50)]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::StoreSize(20));
+    assert_eq!(e.span.line, 7);
+    assert_eq!(e.backtrace.len(), 10);
+}
+
+#[test]
+fn test_refs() {
+    let src = "
+fn swap(a: &mut i32, b: &mut i32) {
+    let t: i32 = *a;
+    *a = *b;
+    *b = t;
+}
+
+fn main() -> i32 {
+    let mut x: i32 = 1;
+    let mut y: i32 = 2;
+    swap(&mut x, &mut y);
+    let r: &i32 = &x;
+    let rr: &&i32 = &r;
+    let s: &i32 = &mut y;
+    return x * 10 + *s + **rr - *r + *&3;
+}
+
+fn dangle() -> &i32 {
+    let x: i32 = 1;
+    return &x;
+}
+
+fn block() -> i32 {
+    let mut r: &i32 = &0;
+    {
+        let y: i32 = 5;
+        r = &y;
+    }
+    return *r;
+}
+
+fn immutable(x: i32) {
+    let r: &mut i32 = &mut x;
+}
+
+fn shared(mut x: i32) {
+    let r: &i32 = &x;
+    *r = 1;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    assert_eq!(run(&p, "main", vec![]), Ok(Value::Num(24)));
+    let e = run(&p, "dangle", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Dangling(Some("x".to_string())));
+    assert_eq!((e.span.line, e.span.fragment), (20, "&"));
+    let e = run(&p, "block", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Dangling(Some("y".to_string())));
+    assert_eq!(e.span.line, 27);
+    let e = run(&p, "immutable", vec![Value::Num(1)]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::BorrowMut(Some("x".to_string())));
+    let e = run(&p, "shared", vec![Value::Num(1)]).unwrap_err();
+    assert_eq!((e.kind, e.span.line), (ErrorKind::AssignShared, 38));
+}
+
+#[test]
+fn test_assign_immutable() {
+    let src = "
+fn local() -> i32 {
+    let x: i32 = 1;
+    x = 5;
+    return x;
+}
+
+fn param(x: i32) {
+    (x) = 2;
+}
+
+fn shared(mut x: i32) {
+    let r: &i32 = &x;
+    (*r) = 1;
+}
+
+fn unique() -> i32 {
+    let x: i32 = 1;
+    let mut y: i32 = 2;
+    let r: &mut i32 = &mut y;
+    *r = x;
+    return y;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let e = run(&p, "local", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::AssignImmutable("x".to_string()));
+    assert_eq!((e.span.line, e.span.fragment), (4, "x"));
+    assert_eq!(
+        e.kind.to_string(),
+        "cannot assign twice to immutable variable `x`"
+    );
+    let e = run(&p, "param", vec![Value::Num(1)]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::AssignImmutable("x".to_string()));
+    let e = run(&p, "shared", vec![Value::Num(1)]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::AssignShared);
+    assert_eq!(run(&p, "unique", vec![]), Ok(Value::Num(1)));
+}
+
+#[test]
+fn test_short_circuit() {
+    let src = "
+fn spin() -> bool {
+    while true {}
+    return true;
+}
+
+fn main(x: i32) -> bool {
+    return x != 0 && 10 / x > 1 || x == 0 || spin();
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut i = Interp::new(&p);
+    i.set_limits(Limits {
+        fuel: Some(1000),
+        ..Limits::default()
+    });
+    assert_eq!(i.call("main", vec![Value::Num(0)]), Ok(Value::Bool(true)));
+    assert_eq!(i.call("main", vec![Value::Num(2)]), Ok(Value::Bool(true)));
+    let e = i.call("main", vec![Value::Num(20)]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::OutOfFuel(1000));
+}
+
+#[test]
+fn test_aliasing() {
+    let src = "
+fn write() -> i32 {
+    let mut x: i32 = 1;
+    let r: &mut i32 = &mut x;
+    x = 2;
+    *r = 3;
+    return x;
+}
+
+fn read() -> i32 {
+    let mut x: i32 = 1;
+    let r: &i32 = &x;
+    let m: &mut i32 = &mut x;
+    *m = 2;
+    return *r;
+}
+
+fn ok() -> i32 {
+    let mut x: i32 = 1;
+    let r: &mut i32 = &mut x;
+    *r = 2;
+    let s: &i32 = &*r;
+    let t: i32 = *s + *r;
+    x = t;
+    return x;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    assert_eq!(run(&p, "write", vec![]), Ok(Value::Num(3)));
+    let e = run_checked(&p, "write", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Invalidated(Access::Write));
+    assert_eq!(e.span.line, 6);
+    let notes: Vec<_> = e.notes.iter().map(|(s, _)| (s.line, s.fragment)).collect();
+    assert_eq!(notes, vec![(4, "&mut"), (5, "x")]);
+    let e = run_checked(&p, "read", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Invalidated(Access::Read));
+    assert_eq!((e.span.line, e.notes[1].0.line), (15, 13));
+    assert_eq!(run_checked(&p, "ok", vec![]), Ok(Value::Num(4)));
+}
+
+#[test]
+fn test_builtins() {
+    let src = "
+fn main() -> i32 {
+    let x: i32 = 6 * 7;
+    let r: &i32 = &x;
+    println!(\"x = {}, {{r}} = {}\", x, r);
+    println!();
+    assert!(x > 0);
+    assert_eq!(*r, 42, \"r points to {}\", x);
+    return x;
+}
+
+fn fail(x: i32) {
+    assert!(x < 0);
+}
+
+fn fail_eq(x: i32) {
+    assert_eq!(x + 1, 2, \"x is {}\", x);
+}
+
+fn boom() -> i32 {
+    panic!(\"boom {}\", 1 + 2);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut out = vec![];
+    let mut i = Interp::new(&p);
+    i.set_output(Box::new(&mut out));
+    assert_eq!(i.call("main", vec![]), Ok(Value::Num(42)));
+    drop(i);
+    assert_eq!(String::from_utf8(out).unwrap(), "x = 42, {r} = 42\n\n");
+
+    let e = run(&p, "fail", vec![Value::Num(1)]).unwrap_err();
+    assert_eq!(e.kind.to_string(), "panicked: assertion failed: x < 0");
+    assert_eq!((e.span.line, e.span.fragment), (13, "assert"));
+    let e = run(&p, "fail_eq", vec![Value::Num(3)]).unwrap_err();
+    assert_eq!(
+        e.kind,
+        ErrorKind::Panic("assertion `left == right` failed: x is 3\n  left: 4\n right: 2".into())
+    );
+    let e = run(&p, "boom", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Panic("boom 3".to_string()));
+}
@@ -0,0 +1,162 @@
+// Exclusive reference parameters
+//
+// A reference parameter is exclusive when, during a call, the memory it
+// points to is accessed through no pointer other than those derived from
+// it (`noalias` in LLVM, `restrict` in C). Memory is only reachable
+// through the pointers passed to a function, so it suffices that at every
+// call site the argument points into stack slots of the caller, or into
+// exclusive parameters of the caller, that no other argument reaches.
+// Pointers of unknown origin (loaded from memory, returned by a call) and
+// arguments holding references to references may reach anything.
+//
+// Exclusivity is the greatest fixpoint: a recursive call passing the
+// parameter on keeps it exclusive.
+
+use std::collections::BTreeSet;
+
+use super::{Function, InstKind, Module, Reg};
+use crate::ast::Type;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Root {
+    // the register defined by the `alloca`
+    Slot(Reg),
+    // index of the parameter
+    Param(usize),
+}
+
+// None for pointers of unknown origin
+type Roots = Option<BTreeSet<Root>>;
+
+// for each function, whether each of its parameters is an exclusive reference
+pub fn exclusive_params(m: &Module) -> Vec<Vec<bool>> {
+    let roots: Vec<_> = m.fns.iter().map(roots).collect();
+    let mut ex: Vec<Vec<bool>> = (m.fns.iter())
+        .map(|f| f.params.iter().map(|p| is_ref(&f.regs[*p])).collect())
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (c, f) in m.fns.iter().enumerate() {
+            let calls = f.blocks.iter().flat_map(|b| &b.insts);
+            for (g, args) in calls.filter_map(|i| match &i.kind {
+                InstKind::Call(g, args) => Some((*g, args)),
+                _ => None,
+            }) {
+                for i in 0..args.len() {
+                    if ex[g][i] && !exclusive_arg(f, &roots[c], &ex[c], args, i) {
+                        ex[g][i] = false;
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+    ex
+}
+
+fn is_ref(t: &Type) -> bool {
+    matches!(t, Type::Ref(..))
+}
+
+// the argument `i` of a call in `f` reaches memory no other argument reaches
+fn exclusive_arg(f: &Function, roots: &[Roots], ex: &[bool], args: &[Reg], i: usize) -> bool {
+    let mine = match &roots[args[i]] {
+        Some(r) => r,
+        None => return false,
+    };
+    // parameters of the caller might alias each other unless exclusive
+    if mine.iter().any(|r| matches!(r, Root::Param(p) if !ex[*p])) {
+        return false;
+    }
+    (args.iter().enumerate())
+        .filter(|(j, a)| *j != i && is_ref(&f.regs[**a]))
+        .all(|(_, a)| match (&f.regs[*a], &roots[*a]) {
+            (Type::Ref(_, t), _) if is_ref(t) => false,
+            (_, Some(other)) => mine.is_disjoint(other),
+            (_, None) => false,
+        })
+}
+
+// the slots and parameters each pointer register of `f` may point into
+fn roots(f: &Function) -> Vec<Roots> {
+    let mut roots = vec![Some(BTreeSet::new()); f.regs.len()];
+    for (i, p) in f.params.iter().enumerate() {
+        roots[*p] = Some(std::iter::once(Root::Param(i)).collect());
+    }
+    // propagated through copies and phi nodes until nothing changes
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut join = |roots: &mut Vec<Roots>, dst: Reg, from: Roots| {
+            let new = match (&roots[dst], from) {
+                (None, _) | (_, None) => None,
+                (Some(a), Some(b)) => Some(a.union(&b).copied().collect()),
+            };
+            if roots[dst] != new {
+                roots[dst] = new;
+                changed = true;
+            }
+        };
+        for block in &f.blocks {
+            for phi in &block.phis {
+                for (_, r) in &phi.args {
+                    let from = roots[*r].clone();
+                    join(&mut roots, phi.dst, from);
+                }
+            }
+            for inst in &block.insts {
+                let (d, from) = match (inst.dst, &inst.kind) {
+                    (Some(d), _) if !is_ref(&f.regs[d]) => continue,
+                    (Some(d), InstKind::Alloca(_)) => {
+                        (d, Some(std::iter::once(Root::Slot(d)).collect()))
+                    }
+                    (Some(d), InstKind::Copy(r)) => (d, roots[*r].clone()),
+                    (Some(d), _) => (d, None),
+                    (None, _) => continue,
+                };
+                join(&mut roots, d, from);
+            }
+        }
+    }
+    roots
+}
+
+#[test]
+fn test_exclusive_params() {
+    let src = "
+fn swap(a: &mut i32, b: &mut i32) {
+    let t: i32 = *a;
+    *a = *b;
+    *b = t;
+}
+
+fn get(a: &mut i32, b: &i32) -> i32 {
+    *a = 1;
+    return *b;
+}
+
+fn count(n: &mut i32, k: i32) {
+    if k > 0 {
+        *n = *n + 1;
+        count(n, k - 1);
+    }
+}
+
+fn main() -> i32 {
+    let mut x: i32 = 1;
+    let mut y: i32 = 2;
+    swap(&mut x, &mut y);
+    count(&mut x, 3);
+    let r: &mut i32 = &mut y;
+    return get(r, &y) + get(&mut x, &x);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = super::lower(&p).unwrap().0;
+    let ex = exclusive_params(&m);
+    assert_eq!(ex[0], vec![true, true]);
+    // `get(&mut x, &x)`
+    assert_eq!(ex[1], vec![false, false]);
+    assert_eq!(ex[2], vec![true, false]);
+}
@@ -0,0 +1,186 @@
+// Dominators
+//
+// Block `a` dominates `b` when every path from the entry to `b` passes
+// through `a`. Immediate dominators are computed iteratively over the
+// reverse postorder (Cooper, Harvey and Kennedy, "A Simple, Fast
+// Dominance Algorithm"); the dominance frontier of `a` holds the blocks
+// where the dominance of `a` ends, i.e., the joins needing phi nodes for
+// the variables assigned in `a`.
+
+use super::{BlockId, Function};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dominators {
+    // reachable blocks in reverse postorder, the entry first
+    pub rpo: Vec<BlockId>,
+    // position of each block in `rpo`, None for unreachable blocks
+    order: Vec<Option<usize>>,
+    // immediate dominator, the entry is its own
+    idom: Vec<Option<BlockId>>,
+}
+
+// reachable blocks in reverse postorder
+pub fn reverse_postorder(f: &Function) -> Vec<BlockId> {
+    let mut post = vec![];
+    let mut seen = vec![false; f.blocks.len()];
+    // block and its successors not yet visited
+    let mut stack = vec![(0, f.blocks[0].term.succs())];
+    seen[0] = true;
+    while let Some((b, succs)) = stack.last_mut() {
+        match succs.pop() {
+            Some(s) if !seen[s] => {
+                seen[s] = true;
+                let succs = f.blocks[s].term.succs();
+                stack.push((s, succs));
+            }
+            Some(_) => (),
+            None => {
+                post.push(*b);
+                stack.pop();
+            }
+        }
+    }
+    post.reverse();
+    post
+}
+
+impl Dominators {
+    pub fn new(f: &Function) -> Self {
+        let rpo = reverse_postorder(f);
+        let mut order = vec![None; f.blocks.len()];
+        for (i, b) in rpo.iter().enumerate() {
+            order[*b] = Some(i);
+        }
+        let preds = f.preds();
+        let mut idom = vec![None; f.blocks.len()];
+        idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in &rpo[1..] {
+                let mut new = None;
+                for &p in preds[b].iter().filter(|p| idom[**p].is_some()) {
+                    new = Some(match new {
+                        None => p,
+                        Some(n) => intersect(&idom, &order, p, n),
+                    });
+                }
+                if idom[b] != new {
+                    idom[b] = new;
+                    changed = true;
+                }
+            }
+        }
+        Dominators { rpo, order, idom }
+    }
+
+    pub fn reachable(&self, b: BlockId) -> bool {
+        self.order[b].is_some()
+    }
+
+    // None for the entry and unreachable blocks
+    pub fn idom(&self, b: BlockId) -> Option<BlockId> {
+        self.idom[b].filter(|d| *d != b)
+    }
+
+    pub fn dominates(&self, a: BlockId, mut b: BlockId) -> bool {
+        if !self.reachable(b) {
+            return false;
+        }
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom(b) {
+                Some(d) => b = d,
+                None => return false,
+            }
+        }
+    }
+
+    // the blocks immediately dominated by each block
+    pub fn children(&self) -> Vec<Vec<BlockId>> {
+        let mut children = vec![vec![]; self.order.len()];
+        for &b in &self.rpo {
+            if let Some(d) = self.idom(b) {
+                children[d].push(b);
+            }
+        }
+        children
+    }
+
+    pub fn frontiers(&self, f: &Function) -> Vec<Vec<BlockId>> {
+        let mut df = vec![vec![]; f.blocks.len()];
+        for (b, preds) in f.preds().iter().enumerate() {
+            if preds.len() < 2 || !self.reachable(b) {
+                continue;
+            }
+            for &p in preds.iter().filter(|p| self.reachable(**p)) {
+                // up from the predecessor to the dominator of the join
+                let mut runner = p;
+                while Some(runner) != self.idom(b) {
+                    if !df[runner].contains(&b) {
+                        df[runner].push(b);
+                    }
+                    match self.idom(runner) {
+                        Some(d) => runner = d,
+                        None => break,
+                    }
+                }
+            }
+        }
+        df
+    }
+}
+
+fn intersect(
+    idom: &[Option<BlockId>],
+    order: &[Option<usize>],
+    mut a: usize,
+    mut b: usize,
+) -> usize {
+    while a != b {
+        while order[a] > order[b] {
+            a = idom[a].unwrap();
+        }
+        while order[b] > order[a] {
+            b = idom[b].unwrap();
+        }
+    }
+    a
+}
+
+#[test]
+fn test_dominators() {
+    let src = "
+fn f(n: i32) -> i32 {
+    let mut i: i32 = 0;
+    while i < n {
+        if i > 5 {
+            i = i + 2;
+        } else {
+            i = i + 1;
+        }
+    }
+    return i;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = super::lower(&p).unwrap().0;
+    let f = &m.fns[0];
+    // b0 entry, b1 loop header, b2 body, b3 exit, b4 then, b5 else, b6 join
+    assert_eq!(f.blocks[1].term, super::Term::Branch(3, 2, 3));
+    let d = Dominators::new(f);
+    assert_eq!(d.rpo.len(), 7);
+    let idom: Vec<_> = (0..7).map(|b| d.idom(b)).collect();
+    assert_eq!(
+        idom,
+        vec![None, Some(0), Some(1), Some(1), Some(2), Some(2), Some(2)]
+    );
+    assert!(d.dominates(1, 6) && !d.dominates(4, 6));
+    let df = d.frontiers(f);
+    assert_eq!(df[4], vec![6]);
+    assert_eq!(df[6], vec![1]);
+    assert_eq!(df[2], vec![1]);
+    assert!(df[0].is_empty() && df[3].is_empty());
+}
@@ -0,0 +1,266 @@
+// IR evaluator
+//
+// Executes functions of a module, for testing the lowering and the passes
+// against the interpreter. Registers hold values, stack slots are cells
+// of a memory released when the allocating call returns, and pointers are
+// references to cells. Results and runtime errors are those of the
+// interpreter, except that fuel counts executed instructions and
+// terminators.
+
+use std::io::{self, Write};
+
+use super::{Const, Function, InstKind, Module, Reg, Term};
+use crate::ast::Span;
+use crate::interp::{
+    binop, format, unop, with_stack, ErrorKind, Limits, Ref, RuntimeError, Value, STACK_PER_CALL,
+};
+
+type Result<'a, T> = std::result::Result<T, RuntimeError<'a>>;
+
+pub struct Eval<'m, 'a> {
+    m: &'m Module<'a>,
+    mem: Vec<Value>,
+    // active calls, function identifier and call site
+    stack: Vec<(Span<'a>, Option<Span<'a>>)>,
+    limits: Limits,
+    steps: u64,
+    out: Box<dyn Write + Send + 'm>,
+}
+
+pub fn run<'a>(m: &Module<'a>, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+    Eval::new(m).call(id, args)
+}
+
+impl<'m, 'a> Eval<'m, 'a> {
+    pub fn new(m: &'m Module<'a>) -> Self {
+        Eval {
+            m,
+            mem: vec![],
+            stack: vec![],
+            limits: Limits::default(),
+            steps: 0,
+            out: Box::new(io::stdout()),
+        }
+    }
+
+    // `max_depth` is clamped, see `interp::MAX_STACK`
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits.clamped(STACK_PER_CALL);
+    }
+
+    pub fn set_output(&mut self, out: Box<dyn Write + Send + 'm>) {
+        self.out = out;
+    }
+
+    // runs on a thread with a host stack for the maximum call depth
+    pub fn call(&mut self, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+        with_stack(self.limits.stack_size(STACK_PER_CALL), || {
+            self.call_here(id, args)
+        })
+    }
+
+    fn call_here(&mut self, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+        self.mem.clear();
+        self.stack.clear();
+        self.steps = 0;
+        let g = match self.m.function(id) {
+            Some(g) => g,
+            None => {
+                let kind = ErrorKind::UnboundFunction(id.to_string());
+                return Err(self.error(Span::new(""), kind));
+            }
+        };
+        let f = &self.m.fns[g];
+        if args.len() != f.params.len() {
+            let kind = ErrorKind::ArgCount(f.params.len(), args.len());
+            return Err(self.error(f.id, kind));
+        }
+        for (p, v) in f.params.iter().zip(&args) {
+            if !super::compatible(&f.regs[*p], &v.ty()) {
+                let kind = ErrorKind::TypeMismatch(f.regs[*p].clone(), v.ty());
+                return Err(self.error(f.id, kind));
+            }
+        }
+        self.call_fn(g, args, None)
+    }
+
+    fn error(&self, span: Span<'a>, kind: ErrorKind) -> RuntimeError<'a> {
+        RuntimeError {
+            span,
+            kind,
+            backtrace: self.stack.iter().rev().copied().collect(),
+            notes: vec![],
+        }
+    }
+
+    fn tick(&mut self, span: Span<'a>) -> Result<'a, ()> {
+        match self.limits.fuel {
+            Some(fuel) if self.steps >= fuel => Err(self.error(span, ErrorKind::OutOfFuel(fuel))),
+            _ => {
+                self.steps += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn call_fn(&mut self, g: usize, args: Vec<Value>, call: Option<Span<'a>>) -> Result<'a, Value> {
+        let f = &self.m.fns[g];
+        if self.stack.len() >= self.limits.max_depth {
+            let kind = ErrorKind::CallDepth(self.limits.max_depth);
+            return Err(self.error(call.unwrap_or(f.id), kind));
+        }
+        self.stack.push((f.id, call));
+        let base = self.mem.len();
+        let r = self.exec(f, args);
+        self.mem.truncate(base);
+        self.stack.pop();
+        r
+    }
+
+    // the cell a pointer points to
+    fn addr(&self, span: Span<'a>, p: &Value) -> Result<'a, usize> {
+        match p {
+            Value::Ref(r) if r.addr < self.mem.len() => Ok(r.addr),
+            _ => Err(self.error(span, ErrorKind::Dangling(None))),
+        }
+    }
+
+    fn exec(&mut self, f: &'m Function<'a>, args: Vec<Value>) -> Result<'a, Value> {
+        let mut regs = vec![Value::Unit; f.regs.len()];
+        for (p, v) in f.params.iter().zip(args) {
+            regs[*p] = v;
+        }
+        let vals = |regs: &[Value], args: &[Reg]| -> Vec<Value> {
+            args.iter().map(|a| regs[*a].clone()).collect()
+        };
+        let (mut b, mut prev) = (0, None);
+        loop {
+            let block = &f.blocks[b];
+            // phi nodes take the values at the end of the predecessor
+            if let Some(p) = prev {
+                let merged: Vec<_> = (block.phis.iter())
+                    .map(|phi| {
+                        let (_, r) = phi.args.iter().find(|(q, _)| *q == p).unwrap();
+                        regs[*r].clone()
+                    })
+                    .collect();
+                for (phi, v) in block.phis.iter().zip(merged) {
+                    regs[phi.dst] = v;
+                }
+            }
+            for i in &block.insts {
+                self.tick(i.span)?;
+                let err = |e: &Self, kind| e.error(i.span, kind);
+                let v = match &i.kind {
+                    InstKind::Const(Const::Int(n)) => Value::Num(*n),
+                    InstKind::Const(Const::Bool(c)) => Value::Bool(*c),
+                    InstKind::Const(Const::Unit) => Value::Unit,
+                    InstKind::Copy(r) => regs[*r].clone(),
+                    InstKind::Bin(op, l, r) => {
+                        binop(*op, regs[*l].clone(), regs[*r].clone()).map_err(|k| err(self, k))?
+                    }
+                    InstKind::Un(op, r) => unop(*op, regs[*r].clone()).map_err(|k| err(self, k))?,
+                    InstKind::Call(g, args) => self.call_fn(*g, vals(&regs, args), Some(i.span))?,
+                    InstKind::Alloca(t) => {
+                        if self.mem.len() >= self.limits.max_store {
+                            return Err(err(self, ErrorKind::StoreSize(self.limits.max_store)));
+                        }
+                        self.mem.push(Value::Unit);
+                        Value::Ref(Ref {
+                            addr: self.mem.len() - 1,
+                            mutable: true,
+                            tag: 0,
+                            ty: Box::new(t.clone()),
+                        })
+                    }
+                    InstKind::Load(p) => self.mem[self.addr(i.span, &regs[*p])?].clone(),
+                    InstKind::Store(p, v) => {
+                        let a = self.addr(i.span, &regs[*p])?;
+                        self.mem[a] = regs[*v].clone();
+                        Value::Unit
+                    }
+                    InstKind::Print(fmt, args) => {
+                        let s = format(fmt, &vals(&regs, args));
+                        if let Err(e) = writeln!(self.out, "{}", s) {
+                            let msg = format!("failed printing to output: {}", e);
+                            return Err(err(self, ErrorKind::Panic(msg)));
+                        }
+                        Value::Unit
+                    }
+                };
+                if let Some(d) = i.dst {
+                    regs[d] = v;
+                }
+            }
+            self.tick(f.id)?;
+            prev = Some(b);
+            b = match &block.term {
+                Term::Jump(t) => *t,
+                Term::Branch(c, t, e) => match regs[*c] {
+                    Value::Bool(true) => *t,
+                    _ => *e,
+                },
+                Term::Return(r) => return Ok(regs[*r].clone()),
+                Term::Panic(fmt, args, s) => {
+                    let msg = format(fmt, &vals(&regs, args));
+                    return Err(self.error(*s, ErrorKind::Panic(msg)));
+                }
+                Term::Unreachable => unreachable!("`{}` reached unreachable code", f.id.fragment),
+            };
+        }
+    }
+}
+
+#[test]
+fn test_programs() {
+    for (name, p, m) in crate::backend::lowered_programs() {
+        assert_eq!(super::verify(&m), Ok(()), "{}", name);
+        assert_eq!(
+            run(&m, "main", vec![]),
+            crate::interp::run(&p, "main", vec![]),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_eval() {
+    let src = "
+fn inc(x: &mut i32) {
+    *x = *x + 1;
+}
+
+fn main() -> i32 {
+    let mut n: i32 = 0;
+    let mut i: i32 = 0;
+    while i < 5 {
+        inc(&mut n);
+        let r: &i32 = &n;
+        println!(\"{} {}\", i, *r > 2 && *r < 5);
+        i = i + 1;
+    }
+    assert_eq!(n, 4, \"n is {}\", n);
+    return n;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = super::lower(&p).unwrap().0;
+    assert_eq!(super::verify(&m), Ok(()));
+    let mut out = vec![];
+    let mut e = Eval::new(&m);
+    e.set_output(Box::new(&mut out));
+    let r = e.call("main", vec![]).unwrap_err();
+    drop(e);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "0 false\n1 false\n2 true\n3 true\n4 false\n"
+    );
+    assert_eq!(
+        r.kind,
+        ErrorKind::Panic(
+            "assertion `left == right` failed: n is 5\n  left: 5\n right: 4".to_string()
+        )
+    );
+    assert_eq!((r.span.line, r.span.get_column()), (15, 5));
+}
@@ -0,0 +1,182 @@
+// Liveness and live intervals
+//
+// A register is live where its value may still be used. Liveness is
+// computed backwards over the control-flow graph until nothing changes; a
+// phi argument is used at the end of its predecessor (on the edge, not in
+// the block of the phi node), a phi node and the parameters are defined at
+// the start of their block.
+//
+// For linear scan the blocks are laid out in reverse postorder and the
+// program points numbered: the start of a block (where its phi nodes are
+// defined), each instruction, and the terminator. The interval of a
+// register spans all points where it is live, from its definition to its
+// last use, including the blocks it is only live through; intervals have
+// no holes.
+
+use std::collections::BTreeSet;
+
+use super::dom::reverse_postorder;
+use super::{BlockId, Function, InstKind, Reg};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Liveness {
+    // live at the start of each block, after its phi nodes
+    pub live_in: Vec<BTreeSet<Reg>>,
+    // live at the end of each block, phi arguments of its successors included
+    pub live_out: Vec<BTreeSet<Reg>>,
+}
+
+pub fn liveness(f: &Function) -> Liveness {
+    let n = f.blocks.len();
+    // upward exposed uses and definitions of each block
+    let mut uses = vec![BTreeSet::new(); n];
+    let mut defs = vec![BTreeSet::new(); n];
+    defs[0].extend(f.params.iter().copied());
+    for (b, block) in f.blocks.iter().enumerate() {
+        defs[b].extend(block.phis.iter().map(|phi| phi.dst));
+        let used = block.insts.iter().map(|i| (i.kind.uses(), i.dst));
+        for (us, dst) in used.chain(Some((block.term.uses(), None))) {
+            for u in us {
+                if !defs[b].contains(&u) {
+                    uses[b].insert(u);
+                }
+            }
+            defs[b].extend(dst);
+        }
+    }
+    let mut live = Liveness {
+        live_in: vec![BTreeSet::new(); n],
+        live_out: vec![BTreeSet::new(); n],
+    };
+    let order = reverse_postorder(f);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in order.iter().rev() {
+            let mut out = BTreeSet::new();
+            for s in f.blocks[b].term.succs() {
+                out.extend(live.live_in[s].iter().copied());
+                let args = f.blocks[s].phis.iter().flat_map(|phi| &phi.args);
+                out.extend(args.filter(|(p, _)| *p == b).map(|(_, r)| *r));
+            }
+            let mut into: BTreeSet<_> = out.difference(&defs[b]).copied().collect();
+            into.extend(uses[b].iter().copied());
+            if into != live.live_in[b] || out != live.live_out[b] {
+                live.live_in[b] = into;
+                live.live_out[b] = out;
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Intervals {
+    // blocks in the order of the program points
+    pub order: Vec<BlockId>,
+    // the point of the start of each block, its instruction `i` is at
+    // `start + 1 + i` and its terminator after the last instruction
+    pub start: Vec<usize>,
+    // first and last point of each register
+    pub ranges: Vec<(usize, usize)>,
+    // the points of the calls
+    pub calls: Vec<usize>,
+}
+
+impl Intervals {
+    // the point of the terminator of a block
+    pub fn end(&self, f: &Function, b: BlockId) -> usize {
+        self.start[b] + 1 + f.blocks[b].insts.len()
+    }
+
+    // the register is live across a call, before and after it
+    pub fn crosses_call(&self, r: Reg) -> bool {
+        let (start, end) = self.ranges[r];
+        self.calls.iter().any(|c| start < *c && *c < end)
+    }
+}
+
+pub fn intervals(f: &Function) -> Intervals {
+    let live = liveness(f);
+    let order = reverse_postorder(f);
+    let mut start = vec![0; f.blocks.len()];
+    let mut point = 0;
+    for &b in &order {
+        start[b] = point;
+        point += f.blocks[b].insts.len() + 2;
+    }
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; f.regs.len()];
+    let mut cover = |r: Reg, p: usize| {
+        ranges[r] = Some(match ranges[r] {
+            Some((s, e)) => (s.min(p), e.max(p)),
+            None => (p, p),
+        });
+    };
+    let mut calls = vec![];
+    for p in &f.params {
+        cover(*p, 0);
+    }
+    for &b in &order {
+        let block = &f.blocks[b];
+        let end = start[b] + 1 + block.insts.len();
+        for phi in &block.phis {
+            cover(phi.dst, start[b]);
+        }
+        for (i, inst) in block.insts.iter().enumerate() {
+            let p = start[b] + 1 + i;
+            inst.kind
+                .uses()
+                .into_iter()
+                .chain(inst.dst)
+                .for_each(|r| cover(r, p));
+            if let InstKind::Call(..) = inst.kind {
+                calls.push(p);
+            }
+        }
+        block.term.uses().into_iter().for_each(|r| cover(r, end));
+        live.live_in[b].iter().for_each(|r| cover(*r, start[b]));
+        live.live_out[b].iter().for_each(|r| cover(*r, end));
+    }
+    Intervals {
+        order,
+        start,
+        ranges: ranges.into_iter().map(|r| r.unwrap_or((0, 0))).collect(),
+        calls,
+    }
+}
+
+#[test]
+fn test_intervals() {
+    let src = "
+fn f(n: i32) -> i32 {
+    let mut i: i32 = 0;
+    let mut s: i32 = 0;
+    while i < n {
+        s = s + g(i);
+        i = i + 1;
+    }
+    return s;
+}
+
+fn g(x: i32) -> i32 {
+    return x;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = super::lower(&p).unwrap().0;
+    let f = &m.fns[0];
+    let live = liveness(f);
+    // the parameter is live around the loop, not after it
+    assert!(live.live_in[1].contains(&0) && live.live_out[1].contains(&0));
+    assert!(live
+        .live_in
+        .iter()
+        .all(|l| !l.contains(&1) && !l.contains(&2)));
+    let iv = intervals(f);
+    assert_eq!(iv.calls.len(), 1);
+    // `n`, `i` and `s` are live across the call, its result is not
+    assert!(iv.crosses_call(0) && iv.crosses_call(3) && iv.crosses_call(4));
+    assert_eq!(iv.ranges[6].0, iv.calls[0]);
+    assert!(!iv.crosses_call(6));
+}
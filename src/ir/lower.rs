@@ -0,0 +1,806 @@
+// Lowering to the IR
+//
+// Function bodies are lowered statement by statement into a control-flow
+// graph, type checking expressions on the way: programs the interpreter
+// would stop with a type error (or an unbound name, a mutable borrow of
+// an immutable variable, ...) are rejected, as are functions not
+// returning on every path (see `flow`).
+//
+// Variables are registers assigned by `copy`, or stack slots if borrowed
+// anywhere in the function. The graph is then put into SSA form (Cytron
+// et al.): phi nodes are placed at the iterated dominance frontiers of
+// the assignments of each variable, where it is live, after which the
+// variables are renamed along the dominator tree, replacing each copy by
+// its source.
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::dom::{reverse_postorder, Dominators};
+use super::{compatible, Block, BlockId, Const, Function, Inst, InstKind, Module, Phi, Reg, Term};
+use crate::ast::{Block as AstBlock, Expr, FnDecl, Macro, Op, Prog, Span, SpanBlock, SpanExpr};
+use crate::ast::{SpanStmt, Stmt, Type};
+use crate::diagnostic::{Diagnostic, Level};
+use crate::flow;
+use crate::interp::{variable, ErrorKind};
+
+type Result<'a, T> = std::result::Result<T, Diagnostic<'a>>;
+
+type Sigs<'p, 'a> = HashMap<&'a str, (usize, &'p FnDecl<'a>)>;
+
+// the module and the warnings, or all errors (and warnings) found
+pub type Lowered<'a> = std::result::Result<(Module<'a>, Vec<Diagnostic<'a>>), Vec<Diagnostic<'a>>>;
+
+pub fn lower<'a>(p: &Prog<'a>) -> Lowered<'a> {
+    let mut diags = vec![];
+    let mut sigs = HashMap::new();
+    for (i, f) in p.iter().enumerate() {
+        if sigs.insert(f.id.fragment, (i, f)).is_some() {
+            diags.push(Diagnostic::error(
+                f.id,
+                format!("the name `{}` is defined multiple times", f.id.fragment),
+            ));
+        }
+    }
+    let mut fns = vec![];
+    for f in p {
+        let flow = flow::check_fn(f);
+        let failed = flow.iter().any(|d| d.level == Level::Error);
+        diags.extend(flow);
+        if failed {
+            continue;
+        }
+        match lower_fn(&sigs, f) {
+            Ok(f) => fns.push(f),
+            Err(d) => diags.push(d),
+        }
+    }
+    match diags.iter().any(|d| d.level == Level::Error) {
+        true => Err(diags),
+        false => Ok((Module { fns }, diags)),
+    }
+}
+
+fn error(s: Span, kind: ErrorKind) -> Diagnostic {
+    Diagnostic::error(s, kind.to_string())
+}
+
+#[derive(Clone)]
+enum Loc {
+    Reg(Reg),
+    // pointer to the stack slot
+    Slot(Reg),
+}
+
+#[derive(Clone)]
+struct Var {
+    loc: Loc,
+    ty: Type,
+    mutable: bool,
+}
+
+// an assignable place, with its type and mutability
+enum Place {
+    Reg(Reg, Type, bool),
+    Ptr(Reg, Type, bool),
+}
+
+struct Lower<'p, 'a> {
+    sigs: &'p Sigs<'p, 'a>,
+    decl: &'p FnDecl<'a>,
+    // names of the variables borrowed somewhere in the function
+    borrowed: BTreeSet<&'a str>,
+    fun: Function<'a>,
+    // the block being lowered
+    cur: BlockId,
+    scopes: Vec<Vec<(&'a str, Var)>>,
+    // registers of variables (assigned more than once before SSA)
+    vars: BTreeSet<Reg>,
+    // `alloca`s at the start of the entry block
+    allocas: usize,
+}
+
+fn lower_fn<'a>(sigs: &Sigs<'_, 'a>, f: &FnDecl<'a>) -> Result<'a, Function<'a>> {
+    let mut borrowed = BTreeSet::new();
+    borrowed_block(&f.body.1, &mut borrowed);
+    let mut l = Lower {
+        sigs,
+        decl: f,
+        borrowed,
+        fun: Function {
+            id: f.id,
+            params: vec![],
+            ret: f.ret_type(),
+            regs: vec![],
+            blocks: vec![Block::new(Term::Unreachable)],
+        },
+        cur: 0,
+        scopes: vec![vec![]],
+        vars: BTreeSet::new(),
+        allocas: 0,
+    };
+    for p in &f.params {
+        let r = l.fun.new_reg(p.ty.1.clone());
+        l.fun.params.push(r);
+        l.declare(p.id, p.mutable, p.ty.1.clone(), r, p.id);
+    }
+    l.block(&f.body)?;
+    // falling through the end, unreachable unless returning `()`
+    if l.fun.ret == Type::Unit {
+        let u = l.value(Type::Unit, InstKind::Const(Const::Unit), f.body.0);
+        l.term(Term::Return(u));
+    }
+    let mut fun = l.fun;
+    into_ssa(&mut fun, &l.vars);
+    Ok(fun)
+}
+
+impl<'p, 'a> Lower<'p, 'a> {
+    fn emit(&mut self, dst: Option<Reg>, kind: InstKind, span: Span<'a>) {
+        let i = Inst { dst, kind, span };
+        self.fun.blocks[self.cur].insts.push(i);
+    }
+
+    // emits an instruction defining a new register
+    fn value(&mut self, ty: Type, kind: InstKind, span: Span<'a>) -> Reg {
+        let r = self.fun.new_reg(ty);
+        self.emit(Some(r), kind, span);
+        r
+    }
+
+    fn alloca(&mut self, ty: Type, span: Span<'a>) -> Reg {
+        let r = self.fun.new_reg(Type::Ref(true, Box::new(ty.clone())));
+        let i = Inst {
+            dst: Some(r),
+            kind: InstKind::Alloca(ty),
+            span,
+        };
+        self.fun.blocks[0].insts.insert(self.allocas, i);
+        self.allocas += 1;
+        r
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.fun.blocks.push(Block::new(Term::Unreachable));
+        self.fun.blocks.len() - 1
+    }
+
+    fn term(&mut self, t: Term<'a>) {
+        self.fun.blocks[self.cur].term = t;
+    }
+
+    fn declare(&mut self, id: Span<'a>, mutable: bool, ty: Type, v: Reg, span: Span<'a>) {
+        let loc = if self.borrowed.contains(id.fragment) {
+            let p = self.alloca(ty.clone(), span);
+            self.emit(None, InstKind::Store(p, v), span);
+            Loc::Slot(p)
+        } else {
+            let r = self.fun.new_reg(ty.clone());
+            self.vars.insert(r);
+            self.emit(Some(r), InstKind::Copy(v), span);
+            Loc::Reg(r)
+        };
+        let var = Var { loc, ty, mutable };
+        self.scopes.last_mut().unwrap().push((id.fragment, var));
+    }
+
+    fn lookup(&self, s: Span<'a>) -> Result<'a, Var> {
+        let mut vars = self.scopes.iter().rev().flat_map(|sc| sc.iter().rev());
+        match vars.find(|(id, _)| *id == s.fragment) {
+            Some((_, v)) => Ok(v.clone()),
+            None => Err(error(s, ErrorKind::UnboundVariable(s.fragment.to_string()))),
+        }
+    }
+
+    // the value as the type, `&mut T` is accepted for `&T`
+    fn check(&self, s: Span<'a>, expected: &Type, (r, ty): (Reg, Type)) -> Result<'a, Reg> {
+        match compatible(expected, &ty) {
+            true => Ok(r),
+            false => Err(error(s, ErrorKind::TypeMismatch(expected.clone(), ty))),
+        }
+    }
+
+    // follows references, operators apply to the referenced values
+    fn deref_all(&mut self, s: Span<'a>, (mut r, mut ty): (Reg, Type)) -> (Reg, Type) {
+        while let Type::Ref(_, t) = ty {
+            r = self.value((*t).clone(), InstKind::Load(r), s);
+            ty = *t;
+        }
+        (r, ty)
+    }
+
+    fn operand(&mut self, s: Span<'a>, e: &SpanExpr<'a>) -> Result<'a, (Reg, Type)> {
+        let v = self.expr(e)?;
+        Ok(self.deref_all(s, v))
+    }
+
+    fn expr(&mut self, (s, e): &SpanExpr<'a>) -> Result<'a, (Reg, Type)> {
+        let s = *s;
+        match e {
+            Expr::Num(i) => Ok(self.constant(Const::Int(*i), s)),
+            Expr::Bool(b) => Ok(self.constant(Const::Bool(*b), s)),
+            Expr::Unit => Ok(self.constant(Const::Unit, s)),
+            Expr::Par(e) => self.expr(e),
+            Expr::Id(_) => {
+                let v = self.lookup(s)?;
+                match v.loc {
+                    Loc::Reg(r) => Ok((r, v.ty)),
+                    Loc::Slot(p) => Ok((self.value(v.ty.clone(), InstKind::Load(p), s), v.ty)),
+                }
+            }
+            Expr::Call(id, args) => self.call(s, id, args),
+            Expr::BinOp(op @ Op::And, l, r) | Expr::BinOp(op @ Op::Or, l, r) => {
+                self.short_circuit(s, *op, l, r)
+            }
+            Expr::BinOp(op, l, r) => {
+                let (l, lt) = self.operand(s, l)?;
+                let (r, rt) = self.operand(s, r)?;
+                let ty = match (op, &lt, &rt) {
+                    (Op::Add, Type::I32, Type::I32)
+                    | (Op::Sub, Type::I32, Type::I32)
+                    | (Op::Mul, Type::I32, Type::I32)
+                    | (Op::Div, Type::I32, Type::I32)
+                    | (Op::Pow, Type::I32, Type::I32) => Type::I32,
+                    (Op::Lt, Type::I32, Type::I32) | (Op::Gt, Type::I32, Type::I32) => Type::Bool,
+                    (Op::Eq, l, r) | (Op::Neq, l, r) if l == r => Type::Bool,
+                    _ => return Err(error(s, ErrorKind::Operands(*op, vec![lt, rt]))),
+                };
+                Ok((self.value(ty.clone(), InstKind::Bin(*op, l, r), s), ty))
+            }
+            Expr::UnaryOp(op @ Op::Ref, e) | Expr::UnaryOp(op @ Op::RefMut, e) => {
+                self.borrow(s, *op == Op::RefMut, e)
+            }
+            Expr::UnaryOp(Op::Deref, e) => match self.expr(e)? {
+                (p, Type::Ref(_, t)) => Ok((self.value((*t).clone(), InstKind::Load(p), s), *t)),
+                (_, ty) => Err(error(s, ErrorKind::Operands(Op::Deref, vec![ty]))),
+            },
+            Expr::UnaryOp(op, e) => match (op, self.operand(s, e)?) {
+                (Op::Add, (r, Type::I32)) => Ok((r, Type::I32)),
+                (Op::Sub, (r, Type::I32)) => Ok((
+                    self.value(Type::I32, InstKind::Un(Op::Sub, r), s),
+                    Type::I32,
+                )),
+                (Op::Not, (r, Type::Bool)) => Ok((
+                    self.value(Type::Bool, InstKind::Un(Op::Not, r), s),
+                    Type::Bool,
+                )),
+                (_, (_, ty)) => Err(error(s, ErrorKind::Operands(*op, vec![ty]))),
+            },
+            Expr::Macro(m, args, fmt) => self.builtin(s, *m, args, *fmt),
+        }
+    }
+
+    fn constant(&mut self, c: Const, s: Span<'a>) -> (Reg, Type) {
+        (self.value(c.ty(), InstKind::Const(c), s), c.ty())
+    }
+
+    fn call(&mut self, s: Span<'a>, id: &str, args: &[SpanExpr<'a>]) -> Result<'a, (Reg, Type)> {
+        let (g, decl) = match self.sigs.get(id) {
+            Some(sig) => *sig,
+            None => return Err(error(s, ErrorKind::UnboundFunction(id.to_string()))),
+        };
+        if args.len() != decl.params.len() {
+            let kind = ErrorKind::ArgCount(decl.params.len(), args.len());
+            return Err(error(s, kind));
+        }
+        let mut regs = vec![];
+        for (p, a) in decl.params.iter().zip(args) {
+            let v = self.expr(a)?;
+            regs.push(self.check(s, &p.ty.1, v)?);
+        }
+        let ty = decl.ret_type();
+        Ok((self.value(ty.clone(), InstKind::Call(g, regs), s), ty))
+    }
+
+    // `l && r` and `l || r`, the result is a variable assigned in both
+    // branches
+    fn short_circuit(
+        &mut self,
+        s: Span<'a>,
+        op: Op,
+        l: &SpanExpr<'a>,
+        r: &SpanExpr<'a>,
+    ) -> Result<'a, (Reg, Type)> {
+        let (l, lt) = self.operand(s, l)?;
+        if lt != Type::Bool {
+            let (_, rt) = self.operand(s, r)?;
+            return Err(error(s, ErrorKind::Operands(op, vec![lt, rt])));
+        }
+        let t = self.fun.new_reg(Type::Bool);
+        self.vars.insert(t);
+        self.emit(Some(t), InstKind::Copy(l), s);
+        let rhs = self.new_block();
+        let join = self.new_block();
+        self.term(match op {
+            Op::And => Term::Branch(l, rhs, join),
+            _ => Term::Branch(l, join, rhs),
+        });
+        self.cur = rhs;
+        let (r, rt) = self.operand(s, r)?;
+        if rt != Type::Bool {
+            return Err(error(s, ErrorKind::Operands(op, vec![lt, rt])));
+        }
+        self.emit(Some(t), InstKind::Copy(r), s);
+        self.term(Term::Jump(join));
+        self.cur = join;
+        Ok((t, Type::Bool))
+    }
+
+    // the place `x` or `*e`, None for other expressions
+    fn place(&mut self, (s, e): &SpanExpr<'a>) -> Result<'a, Option<Place>> {
+        match e {
+            Expr::Id(_) => {
+                let v = self.lookup(*s)?;
+                Ok(Some(match v.loc {
+                    Loc::Reg(r) => Place::Reg(r, v.ty, v.mutable),
+                    Loc::Slot(p) => Place::Ptr(p, v.ty, v.mutable),
+                }))
+            }
+            Expr::Par(e) => self.place(e),
+            Expr::UnaryOp(Op::Deref, e) => match self.expr(e)? {
+                (p, Type::Ref(m, t)) => Ok(Some(Place::Ptr(p, *t, m))),
+                (_, ty) => Err(error(*s, ErrorKind::Operands(Op::Deref, vec![ty]))),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // `&e` or `&mut e`, pointing to the place or to a slot holding the
+    // value of a temporary
+    fn borrow(&mut self, s: Span<'a>, mutable: bool, e: &SpanExpr<'a>) -> Result<'a, (Reg, Type)> {
+        let (p, ty) = match self.place(e)? {
+            Some(Place::Ptr(_, _, false)) if mutable => {
+                let id = match e.1 {
+                    Expr::Id(id) => Some(id.to_string()),
+                    _ => None,
+                };
+                return Err(error(s, ErrorKind::BorrowMut(id)));
+            }
+            Some(Place::Ptr(p, ty, _)) => (p, ty),
+            Some(Place::Reg(..)) => unreachable!("borrowed variables live in slots"),
+            None => {
+                let (v, ty) = self.expr(e)?;
+                let p = self.alloca(ty.clone(), e.0);
+                self.emit(None, InstKind::Store(p, v), e.0);
+                (p, ty)
+            }
+        };
+        Ok((p, Type::Ref(mutable, Box::new(ty))))
+    }
+
+    fn builtin(
+        &mut self,
+        s: Span<'a>,
+        m: Macro,
+        args: &[SpanExpr<'a>],
+        fmt: Option<Span<'a>>,
+    ) -> Result<'a, (Reg, Type)> {
+        let mut vals = vec![];
+        for a in args {
+            vals.push(self.operand(a.0, a)?);
+        }
+        let fixed: Vec<_> = vals
+            .iter()
+            .take(m.fixed())
+            .map(|(_, t)| t.clone())
+            .collect();
+        let mut regs: Vec<_> = vals.iter().map(|(r, _)| *r).collect();
+        let fmt = fmt.map(|f| f.fragment);
+        match (m, fixed.as_slice()) {
+            (Macro::Println, _) => {
+                let fmt = fmt.unwrap_or("").to_string();
+                self.emit(None, InstKind::Print(fmt, regs), s);
+            }
+            (Macro::Assert, [Type::Bool]) => {
+                let fmt = match fmt {
+                    Some(f) => f.to_string(),
+                    None => format!("assertion failed: {}", escape(&args[0].1.to_string())),
+                };
+                let c = regs.remove(0);
+                self.assert(s, c, fmt, regs);
+            }
+            (Macro::Assert, _) => {
+                let kind = ErrorKind::TypeMismatch(Type::Bool, fixed[0].clone());
+                return Err(error(s, kind));
+            }
+            (Macro::AssertEq, [l, r]) if l == r => {
+                let msg = fmt.map_or(String::new(), |f| format!(": {}", f));
+                let fmt = format!(
+                    "assertion `left == right` failed{}\n  left: {{}}\n right: {{}}",
+                    msg
+                );
+                let c = self.value(Type::Bool, InstKind::Bin(Op::Eq, regs[0], regs[1]), s);
+                regs.rotate_left(2);
+                self.assert(s, c, fmt, regs);
+            }
+            (Macro::AssertEq, _) => return Err(error(s, ErrorKind::Operands(Op::Eq, fixed))),
+            (Macro::Panic, _) => {
+                let fmt = fmt.unwrap_or("explicit panic").to_string();
+                self.term(Term::Panic(fmt, regs, s));
+                self.cur = self.new_block();
+            }
+        }
+        Ok(self.constant(Const::Unit, s))
+    }
+
+    // panics with the message unless `c` holds
+    fn assert(&mut self, s: Span<'a>, c: Reg, fmt: String, args: Vec<Reg>) {
+        let fail = self.new_block();
+        let ok = self.new_block();
+        self.term(Term::Branch(c, ok, fail));
+        self.cur = fail;
+        self.term(Term::Panic(fmt, args, s));
+        self.cur = ok;
+    }
+
+    fn cond(&mut self, c: &SpanExpr<'a>) -> Result<'a, Reg> {
+        match self.expr(c)? {
+            (r, Type::Bool) => Ok(r),
+            (_, ty) => Err(error(c.0, ErrorKind::TypeMismatch(Type::Bool, ty))),
+        }
+    }
+
+    fn block(&mut self, (_, b): &SpanBlock<'a>) -> Result<'a, ()> {
+        self.scopes.push(vec![]);
+        for s in b {
+            self.stmt(s)?;
+        }
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn stmt(&mut self, (s, stmt): &SpanStmt<'a>) -> Result<'a, ()> {
+        match stmt {
+            Stmt::Let(m, id, (_, ty), e) => {
+                let v = self.expr(e)?;
+                let r = self.check(*s, ty, v)?;
+                self.declare(*id, *m, ty.clone(), r, *s);
+            }
+            Stmt::Assign(l, e) => {
+                let v = self.expr(e)?;
+                match self.place(l)? {
+                    Some(Place::Ptr(_, _, false)) | Some(Place::Reg(_, _, false)) => {
+                        let kind = match variable(l) {
+                            Some(id) => ErrorKind::AssignImmutable(id.to_string()),
+                            None => ErrorKind::AssignShared,
+                        };
+                        return Err(error(l.0, kind));
+                    }
+                    Some(Place::Ptr(p, ty, _)) => {
+                        let r = self.check(*s, &ty, v)?;
+                        self.emit(None, InstKind::Store(p, r), l.0);
+                    }
+                    Some(Place::Reg(x, ty, _)) => {
+                        let r = self.check(*s, &ty, v)?;
+                        self.emit(Some(x), InstKind::Copy(r), *s);
+                    }
+                    None => return Err(error(l.0, ErrorKind::InvalidAssign)),
+                }
+            }
+            Stmt::If(c, t, e) => {
+                let c = self.cond(c)?;
+                let then = self.new_block();
+                let els = e.as_ref().map(|_| self.new_block());
+                let join = self.new_block();
+                self.term(Term::Branch(c, then, els.unwrap_or(join)));
+                self.cur = then;
+                self.block(t)?;
+                self.term(Term::Jump(join));
+                if let (Some(els), Some(e)) = (els, e) {
+                    self.cur = els;
+                    self.block(e)?;
+                    self.term(Term::Jump(join));
+                }
+                self.cur = join;
+            }
+            Stmt::While(c, b) => {
+                let head = self.new_block();
+                self.term(Term::Jump(head));
+                self.cur = head;
+                let c = self.cond(c)?;
+                let body = self.new_block();
+                let exit = self.new_block();
+                self.term(Term::Branch(c, body, exit));
+                self.cur = body;
+                self.block(b)?;
+                self.term(Term::Jump(head));
+                self.cur = exit;
+            }
+            Stmt::Return(e) => {
+                let v = match e {
+                    Some(e) => self.expr(e)?,
+                    None => self.constant(Const::Unit, *s),
+                };
+                let span = self.decl.ret.as_ref().map_or(self.decl.id, |(s, _)| *s);
+                let ret = self.fun.ret.clone();
+                let r = self.check(span, &ret, v)?;
+                self.term(Term::Return(r));
+                // following statements are unreachable
+                self.cur = self.new_block();
+            }
+            Stmt::Expr(e) => {
+                self.expr(e)?;
+            }
+            Stmt::Block(b) => self.block(b)?,
+        }
+        Ok(())
+    }
+}
+
+// escapes braces for a format string
+fn escape(s: &str) -> String {
+    s.replace('{', "{{").replace('}', "}}")
+}
+
+// names of the variables borrowed in the block (`&x`, `&mut (x)`)
+
+fn borrowed_block<'a>(b: &AstBlock<'a>, names: &mut BTreeSet<&'a str>) {
+    for (_, s) in b {
+        match s {
+            Stmt::Let(_, _, _, e) | Stmt::Expr(e) | Stmt::Return(Some(e)) => {
+                borrowed_expr(e, names)
+            }
+            Stmt::Assign(l, e) => {
+                borrowed_expr(l, names);
+                borrowed_expr(e, names);
+            }
+            Stmt::If(c, t, e) => {
+                borrowed_expr(c, names);
+                borrowed_block(&t.1, names);
+                if let Some(e) = e {
+                    borrowed_block(&e.1, names);
+                }
+            }
+            Stmt::While(c, b) => {
+                borrowed_expr(c, names);
+                borrowed_block(&b.1, names);
+            }
+            Stmt::Block(b) => borrowed_block(&b.1, names),
+            Stmt::Return(None) => (),
+        }
+    }
+}
+
+fn borrowed_expr<'a>((_, e): &SpanExpr<'a>, names: &mut BTreeSet<&'a str>) {
+    match e {
+        Expr::UnaryOp(Op::Ref, x) | Expr::UnaryOp(Op::RefMut, x) => {
+            let mut base = x;
+            while let (_, Expr::Par(e)) = &**base {
+                base = e;
+            }
+            if let (_, Expr::Id(id)) = &**base {
+                names.insert(id);
+            }
+            borrowed_expr(x, names);
+        }
+        Expr::Par(e) | Expr::UnaryOp(_, e) => borrowed_expr(e, names),
+        Expr::BinOp(_, l, r) => {
+            borrowed_expr(l, names);
+            borrowed_expr(r, names);
+        }
+        Expr::Call(_, args) | Expr::Macro(_, args, _) => {
+            args.iter().for_each(|a| borrowed_expr(a, names));
+        }
+        Expr::Num(_) | Expr::Bool(_) | Expr::Id(_) | Expr::Unit => (),
+    }
+}
+
+// SSA construction
+
+fn into_ssa(f: &mut Function, vars: &BTreeSet<Reg>) {
+    remove_unreachable(f);
+    let dom = Dominators::new(f);
+    let df = dom.frontiers(f);
+    let live = live_in(f, vars);
+
+    // the variable of each phi node
+    let mut phi_vars = vec![vec![]; f.blocks.len()];
+    for &v in vars {
+        let mut has_phi = vec![false; f.blocks.len()];
+        let mut work: Vec<_> = (0..f.blocks.len())
+            .filter(|b| f.blocks[*b].insts.iter().any(|i| i.dst == Some(v)))
+            .collect();
+        while let Some(b) = work.pop() {
+            for &d in &df[b] {
+                if !has_phi[d] && live[d].contains(&v) {
+                    has_phi[d] = true;
+                    phi_vars[d].push(v);
+                    work.push(d);
+                }
+            }
+        }
+    }
+    for (b, vs) in phi_vars.iter().enumerate() {
+        for &v in vs {
+            let dst = f.new_reg(f.regs[v].clone());
+            f.blocks[b].phis.push(Phi { dst, args: vec![] });
+        }
+    }
+
+    // renaming, the current value of each variable is on top of its stack
+    let mut stacks: HashMap<Reg, Vec<Reg>> = HashMap::new();
+    let children = dom.children();
+    // blocks to enter, and the variables pushed by blocks to leave
+    let mut work = vec![Ok(0)];
+    while let Some(w) = work.pop() {
+        let b = match w {
+            Ok(b) => b,
+            Err(pushed) => {
+                for v in pushed {
+                    stacks.get_mut(&v).unwrap().pop();
+                }
+                continue;
+            }
+        };
+        let mut pushed: Vec<Reg> = vec![];
+        for (phi, v) in f.blocks[b].phis.iter().zip(&phi_vars[b]) {
+            stacks.entry(*v).or_default().push(phi.dst);
+            pushed.push(*v);
+        }
+        let current = |stacks: &HashMap<Reg, Vec<Reg>>, v: &Reg| {
+            *stacks[v]
+                .last()
+                .expect("variable used before its declaration")
+        };
+        let block = &mut f.blocks[b];
+        let mut insts = vec![];
+        for mut i in std::mem::take(&mut block.insts) {
+            for u in i.kind.uses_mut().into_iter().filter(|u| vars.contains(u)) {
+                *u = current(&stacks, u);
+            }
+            match (i.dst, &i.kind) {
+                (Some(d), InstKind::Copy(src)) if vars.contains(&d) => {
+                    stacks.entry(d).or_default().push(*src);
+                    pushed.push(d);
+                }
+                _ => insts.push(i),
+            }
+        }
+        block.insts = insts;
+        for u in block
+            .term
+            .uses_mut()
+            .into_iter()
+            .filter(|u| vars.contains(u))
+        {
+            *u = current(&stacks, u);
+        }
+        for s in block.term.succs() {
+            for (phi, v) in f.blocks[s].phis.iter_mut().zip(&phi_vars[s]) {
+                phi.args.push((b, current(&stacks, v)));
+            }
+        }
+        work.push(Err(pushed));
+        work.extend(children[b].iter().rev().map(|c| Ok(*c)));
+    }
+    f.compact();
+}
+
+fn remove_unreachable(f: &mut Function) {
+    let mut reachable = vec![false; f.blocks.len()];
+    for b in reverse_postorder(f) {
+        reachable[b] = true;
+    }
+    let mut index = vec![0; f.blocks.len()];
+    let mut n = 0;
+    for (b, r) in reachable.iter().enumerate() {
+        index[b] = n;
+        n += *r as usize;
+    }
+    let mut b = 0;
+    f.blocks.retain(|_| {
+        b += 1;
+        reachable[b - 1]
+    });
+    for block in &mut f.blocks {
+        match &mut block.term {
+            Term::Jump(t) => *t = index[*t],
+            Term::Branch(_, t, e) => {
+                *t = index[*t];
+                *e = index[*e];
+            }
+            _ => (),
+        }
+    }
+}
+
+// the variables live at the start of each block
+fn live_in(f: &Function, vars: &BTreeSet<Reg>) -> Vec<BTreeSet<Reg>> {
+    // variables read before being assigned, and assigned in each block
+    let mut gen = vec![BTreeSet::new(); f.blocks.len()];
+    let mut kill = vec![BTreeSet::new(); f.blocks.len()];
+    for (b, block) in f.blocks.iter().enumerate() {
+        let uses = (block.insts.iter())
+            .map(|i| (i.kind.uses(), i.dst))
+            .chain(std::iter::once((block.term.uses(), None)));
+        for (uses, dst) in uses {
+            for u in uses.into_iter().filter(|u| vars.contains(u)) {
+                if !kill[b].contains(&u) {
+                    gen[b].insert(u);
+                }
+            }
+            if let Some(d) = dst.filter(|d| vars.contains(d)) {
+                kill[b].insert(d);
+            }
+        }
+    }
+    let mut live = gen.clone();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in (0..f.blocks.len()).rev() {
+            for s in f.blocks[b].term.succs() {
+                let new: Vec<_> = (live[s].iter())
+                    .filter(|v| !kill[b].contains(v) && !live[b].contains(v))
+                    .copied()
+                    .collect();
+                changed |= !new.is_empty();
+                live[b].extend(new);
+            }
+        }
+    }
+    live
+}
+
+#[test]
+fn test_lower_errors() {
+    let err = |src| match crate::parse::parse(src).map(|p| lower(&p).map(|_| ())) {
+        Ok(Err(d)) if d.len() == 1 => d[0].to_string(),
+        r => panic!("{:?}", r),
+    };
+    assert_eq!(
+        err("fn f(x: i32) -> bool { return x; }"),
+        "error: mismatched types: expected `bool`, found `i32` at 1:17"
+    );
+    assert_eq!(
+        err("fn f(x: &i32) { *x = 1; }"),
+        "error: cannot assign to data in a `&` reference at 1:17"
+    );
+    assert_eq!(
+        err("fn f(x: i32) { x = 1; }"),
+        "error: cannot assign twice to immutable variable `x` at 1:16"
+    );
+    assert_eq!(
+        err("fn f() { let x: i32 = 1; let r: &i32 = &x; (x) = 2; }"),
+        "error: cannot assign twice to immutable variable `x` at 1:45"
+    );
+    assert_eq!(
+        err("fn f() { let x: i32 = 1; let r: &mut i32 = &mut x; }"),
+        "error: cannot borrow `x` as mutable, as it is not declared as mutable at 1:44"
+    );
+    assert_eq!(
+        err("fn f() -> i32 { if true { return 1; } }"),
+        "error: function `f` does not return a value on every path at 1:4"
+    );
+    assert_eq!(
+        err("fn f() { g(1 && 2); }"),
+        "error: cannot find function `g` at 1:10"
+    );
+    assert!(err("fn f() { let b: bool = 1 && 2; }").starts_with("error: cannot apply `&&`"));
+    assert!(err("fn f() { assert_eq!(1, true); }").contains("1:10"));
+}
+
+#[test]
+fn test_lower_diagnostics() {
+    let src = "\
+fn f() -> i32 { return 1; f(); }
+fn g(x: i32) { x = 1; }
+fn h() -> i32 { if true { return 1; } }
+";
+    let p = crate::parse::parse(src).unwrap();
+    let d: Vec<_> = lower(&p)
+        .unwrap_err()
+        .iter()
+        .map(|d| d.to_string())
+        .collect();
+    assert_eq!(
+        d,
+        vec![
+            "warning: unreachable statement at 1:27",
+            "error: cannot assign twice to immutable variable `x` at 2:16",
+            "error: function `h` does not return a value on every path at 3:4",
+        ]
+    );
+    let p = crate::parse::parse(&src[..33]).unwrap();
+    let (m, warnings) = lower(&p).unwrap();
+    assert_eq!((m.fns.len(), warnings.len()), (1, 1));
+}
@@ -0,0 +1,406 @@
+// Intermediate representation
+//
+// Functions are control-flow graphs of basic blocks in SSA form: every
+// virtual register is defined once and has a type, values reaching a
+// block from several predecessors are merged by the phi nodes at its
+// start. A block ends in a terminator, the first block is the entry.
+//
+// %2: i32 = add %0, %1
+//
+// Arithmetic is checked, an instruction overflowing or dividing by zero
+// fails with the error of the interpreter, at the span of the instruction.
+// Operands of arithmetic and comparisons are values, never references.
+// References are pointers to stack slots (`alloca`), allocated for the
+// variables whose address is taken and for borrowed temporaries; all
+// other variables live in registers (see `lower`).
+
+use std::fmt;
+
+use crate::ast::{Op, Span, Type};
+
+pub mod alias;
+pub mod dom;
+pub mod eval;
+pub mod live;
+pub mod lower;
+pub mod opt;
+pub mod regalloc;
+pub mod verify;
+
+pub use lower::lower;
+pub use verify::verify;
+
+pub type Reg = usize;
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Const {
+    Int(i32),
+    Bool(bool),
+    Unit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstKind {
+    Const(Const),
+    Copy(Reg),
+    // checked arithmetic and comparisons
+    Bin(Op, Reg, Reg),
+    // checked negation, `!`
+    Un(Op, Reg),
+    // index of the function in the module
+    Call(usize, Vec<Reg>),
+    // a stack slot for a value of the type, the result points to it
+    Alloca(Type),
+    Load(Reg),
+    // pointer, value
+    Store(Reg, Reg),
+    // `println!`, the format string and its arguments
+    Print(String, Vec<Reg>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inst<'a> {
+    // None for `store` and `print`
+    pub dst: Option<Reg>,
+    pub kind: InstKind,
+    pub span: Span<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phi {
+    pub dst: Reg,
+    // the value coming from each predecessor
+    pub args: Vec<(BlockId, Reg)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term<'a> {
+    Jump(BlockId),
+    // condition, then, else (distinct blocks)
+    Branch(Reg, BlockId, BlockId),
+    Return(Reg),
+    // fails with the message, formatted as `Print`
+    Panic(String, Vec<Reg>, Span<'a>),
+    // never reached, e.g., the end of a function returning on every path
+    Unreachable,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block<'a> {
+    pub phis: Vec<Phi>,
+    pub insts: Vec<Inst<'a>>,
+    pub term: Term<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function<'a> {
+    pub id: Span<'a>,
+    pub params: Vec<Reg>,
+    pub ret: Type,
+    // the type of each register
+    pub regs: Vec<Type>,
+    pub blocks: Vec<Block<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Module<'a> {
+    pub fns: Vec<Function<'a>>,
+}
+
+impl Const {
+    pub fn ty(self) -> Type {
+        match self {
+            Const::Int(_) => Type::I32,
+            Const::Bool(_) => Type::Bool,
+            Const::Unit => Type::Unit,
+        }
+    }
+}
+
+// a value of type `found` can be used as `expected`, a `&mut T` as `&T`
+pub fn compatible(expected: &Type, found: &Type) -> bool {
+    match (expected, found) {
+        (Type::Ref(false, e), Type::Ref(true, f)) => e == f,
+        (e, f) => e == f,
+    }
+}
+
+impl InstKind {
+    pub fn uses(&self) -> Vec<Reg> {
+        match self {
+            InstKind::Const(_) | InstKind::Alloca(_) => vec![],
+            InstKind::Copy(r) | InstKind::Un(_, r) | InstKind::Load(r) => vec![*r],
+            InstKind::Bin(_, l, r) | InstKind::Store(l, r) => vec![*l, *r],
+            InstKind::Call(_, args) | InstKind::Print(_, args) => args.clone(),
+        }
+    }
+
+    pub fn uses_mut(&mut self) -> Vec<&mut Reg> {
+        match self {
+            InstKind::Const(_) | InstKind::Alloca(_) => vec![],
+            InstKind::Copy(r) | InstKind::Un(_, r) | InstKind::Load(r) => vec![r],
+            InstKind::Bin(_, l, r) | InstKind::Store(l, r) => vec![l, r],
+            InstKind::Call(_, args) | InstKind::Print(_, args) => args.iter_mut().collect(),
+        }
+    }
+}
+
+impl<'a> Term<'a> {
+    pub fn succs(&self) -> Vec<BlockId> {
+        match self {
+            Term::Jump(b) => vec![*b],
+            Term::Branch(_, t, e) => vec![*t, *e],
+            _ => vec![],
+        }
+    }
+
+    pub fn uses(&self) -> Vec<Reg> {
+        match self {
+            Term::Branch(r, ..) | Term::Return(r) => vec![*r],
+            Term::Panic(_, args, _) => args.clone(),
+            Term::Jump(_) | Term::Unreachable => vec![],
+        }
+    }
+
+    pub fn uses_mut(&mut self) -> Vec<&mut Reg> {
+        match self {
+            Term::Branch(r, ..) | Term::Return(r) => vec![r],
+            Term::Panic(_, args, _) => args.iter_mut().collect(),
+            Term::Jump(_) | Term::Unreachable => vec![],
+        }
+    }
+}
+
+impl<'a> Block<'a> {
+    pub fn new(term: Term<'a>) -> Self {
+        Block {
+            phis: vec![],
+            insts: vec![],
+            term,
+        }
+    }
+}
+
+impl<'a> Function<'a> {
+    pub fn new_reg(&mut self, ty: Type) -> Reg {
+        self.regs.push(ty);
+        self.regs.len() - 1
+    }
+
+    // renumbers the registers in the order of their definitions,
+    // dropping unused ones
+    pub fn compact(&mut self) {
+        let mut index = vec![None; self.regs.len()];
+        let mut regs = vec![];
+        let types = std::mem::take(&mut self.regs);
+        let mut define = |r: &mut Reg| {
+            regs.push(types[*r].clone());
+            index[*r] = Some(regs.len() - 1);
+            *r = regs.len() - 1;
+        };
+        self.params.iter_mut().for_each(&mut define);
+        for block in &mut self.blocks {
+            block.phis.iter_mut().for_each(|phi| define(&mut phi.dst));
+            block
+                .insts
+                .iter_mut()
+                .filter_map(|i| i.dst.as_mut())
+                .for_each(&mut define);
+        }
+        let rename = |r: &mut Reg| *r = index[*r].expect("register without definition");
+        for block in &mut self.blocks {
+            for phi in &mut block.phis {
+                phi.args.iter_mut().for_each(|(_, r)| rename(r));
+            }
+            for i in &mut block.insts {
+                i.kind.uses_mut().into_iter().for_each(rename);
+            }
+            block.term.uses_mut().into_iter().for_each(rename);
+        }
+        self.regs = regs;
+    }
+
+    // the predecessors of each block
+    pub fn preds(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![vec![]; self.blocks.len()];
+        for (b, block) in self.blocks.iter().enumerate() {
+            for s in block.term.succs() {
+                preds[s].push(b);
+            }
+        }
+        preds
+    }
+}
+
+impl<'a> Module<'a> {
+    pub fn function(&self, id: &str) -> Option<usize> {
+        self.fns.iter().position(|f| f.id.fragment == id)
+    }
+}
+
+// printing, functions print with the names of the called functions
+
+fn op_name(op: Op) -> &'static str {
+    match op {
+        Op::Eq => "eq",
+        Op::Neq => "ne",
+        Op::Lt => "lt",
+        Op::Gt => "gt",
+        Op::And => "and",
+        Op::Or => "or",
+        Op::Add => "add",
+        Op::Sub => "sub",
+        Op::Mul => "mul",
+        Op::Div => "div",
+        Op::Pow => "pow",
+        Op::Not => "not",
+        Op::Ref => "ref",
+        Op::RefMut => "refmut",
+        Op::Deref => "deref",
+    }
+}
+
+fn regs(rs: &[Reg]) -> String {
+    let rs: Vec<_> = rs.iter().map(|r| format!("%{}", r)).collect();
+    rs.join(", ")
+}
+
+// the format string and its arguments
+fn message(fmt: &str, args: &[Reg]) -> String {
+    match args {
+        [] => format!("{:?}", fmt),
+        _ => format!("{:?}, {}", fmt, regs(args)),
+    }
+}
+
+impl fmt::Display for Const {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Const::Int(i) => write!(f, "{}", i),
+            Const::Bool(b) => write!(f, "{}", b),
+            Const::Unit => write!(f, "()"),
+        }
+    }
+}
+
+impl<'a> Function<'a> {
+    pub fn write(&self, f: &mut fmt::Formatter, names: &[&str]) -> fmt::Result {
+        let params: Vec<_> = (self.params.iter())
+            .map(|p| format!("%{}: {}", p, self.regs[*p]))
+            .collect();
+        writeln!(
+            f,
+            "fn {}({}) -> {} {{",
+            self.id.fragment,
+            params.join(", "),
+            self.ret
+        )?;
+        for (b, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "b{}:", b)?;
+            for phi in &block.phis {
+                let args: Vec<_> = (phi.args.iter())
+                    .map(|(b, r)| format!("[b{} %{}]", b, r))
+                    .collect();
+                let ty = &self.regs[phi.dst];
+                writeln!(f, "    %{}: {} = phi {}", phi.dst, ty, args.join(", "))?;
+            }
+            for i in &block.insts {
+                write!(f, "    ")?;
+                if let Some(d) = i.dst {
+                    write!(f, "%{}: {} = ", d, self.regs[d])?;
+                }
+                match &i.kind {
+                    InstKind::Const(c) => writeln!(f, "const {}", c)?,
+                    InstKind::Copy(r) => writeln!(f, "copy %{}", r)?,
+                    InstKind::Bin(op, l, r) => writeln!(f, "{} %{}, %{}", op_name(*op), l, r)?,
+                    InstKind::Un(Op::Sub, r) => writeln!(f, "neg %{}", r)?,
+                    InstKind::Un(op, r) => writeln!(f, "{} %{}", op_name(*op), r)?,
+                    InstKind::Call(g, args) => {
+                        let name = names.get(*g).copied().unwrap_or("?");
+                        writeln!(f, "call {}({})", name, regs(args))?
+                    }
+                    InstKind::Alloca(t) => writeln!(f, "alloca {}", t)?,
+                    InstKind::Load(p) => writeln!(f, "load %{}", p)?,
+                    InstKind::Store(p, v) => writeln!(f, "store %{}, %{}", p, v)?,
+                    InstKind::Print(fmt, args) => writeln!(f, "print {}", message(fmt, args))?,
+                }
+            }
+            match &block.term {
+                Term::Jump(t) => writeln!(f, "    jump b{}", t)?,
+                Term::Branch(c, t, e) => writeln!(f, "    branch %{}, b{}, b{}", c, t, e)?,
+                Term::Return(r) => writeln!(f, "    return %{}", r)?,
+                Term::Panic(fmt, args, _) => writeln!(f, "    panic {}", message(fmt, args))?,
+                Term::Unreachable => writeln!(f, "    unreachable")?,
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl<'a> fmt::Display for Module<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = self.fns.iter().map(|f| f.id.fragment).collect();
+        for (i, fun) in self.fns.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            fun.write(f, &names)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_print() {
+    let src = "
+fn max(a: i32, b: i32) -> i32 {
+    let mut m: i32 = a;
+    if b > a {
+        m = b;
+    }
+    return m;
+}
+
+fn main() {
+    let mut x: i32 = 1;
+    let r: &mut i32 = &mut x;
+    *r = max(*r, -2);
+    println!(\"x = {}\", x);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = lower(&p).unwrap().0;
+    assert_eq!(
+        m.to_string(),
+        "\
+fn max(%0: i32, %1: i32) -> i32 {
+b0:
+    %2: bool = gt %1, %0
+    branch %2, b1, b2
+b1:
+    jump b2
+b2:
+    %3: i32 = phi [b0 %0], [b1 %1]
+    return %3
+}
+
+fn main() -> () {
+b0:
+    %0: &mut i32 = alloca i32
+    %1: i32 = const 1
+    store %0, %1
+    %2: i32 = load %0
+    %3: i32 = const 2
+    %4: i32 = neg %3
+    %5: i32 = call max(%2, %4)
+    store %0, %5
+    %6: i32 = load %0
+    print \"x = {}\", %6
+    %7: () = const ()
+    %8: () = const ()
+    return %8
+}
+"
+    );
+}
@@ -0,0 +1,185 @@
+// Copy propagation
+//
+// Replaces the uses of the result of a `copy` by its source, and of a phi
+// node whose arguments are all the same register (apart from the phi
+// node itself, around a loop) by that register, then removes the copies
+// and phi nodes. Replacing a phi node may make another one trivial, so
+// this is repeated until nothing changes.
+//
+// Phi nodes may also only merge one register with each other, e.g., a
+// variable assigned the same value in a loop: after Braun et al., each
+// strongly connected component of phi nodes (linked to the phi nodes
+// among their arguments) with a single argument from outside it is
+// replaced by that argument.
+
+use std::collections::BTreeSet;
+
+use super::super::{Function, InstKind, Reg};
+use super::substitute;
+
+// the register replacing `r`, following chains of replacements
+fn find(subst: &[Reg], mut r: Reg) -> Reg {
+    while subst[r] != r {
+        r = subst[r];
+    }
+    r
+}
+
+pub fn run(f: &mut Function) -> bool {
+    let mut subst: Vec<Reg> = (0..f.regs.len()).collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &f.blocks {
+            for phi in &block.phis {
+                if subst[phi.dst] != phi.dst {
+                    continue;
+                }
+                let mut args = phi.args.iter().map(|(_, a)| find(&subst, *a));
+                let mut args = args.by_ref().filter(|a| *a != phi.dst);
+                if let Some(a) = args.next() {
+                    if args.all(|b| b == a) {
+                        subst[phi.dst] = a;
+                        changed = true;
+                    }
+                }
+            }
+            for inst in &block.insts {
+                if let (Some(d), InstKind::Copy(s)) = (inst.dst, &inst.kind) {
+                    let s = find(&subst, *s);
+                    if subst[d] == d && s != d {
+                        subst[d] = s;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            changed = redundant_sccs(f, &mut subst);
+        }
+    }
+    if (0..subst.len()).all(|r| subst[r] == r) {
+        return false;
+    }
+    let subst: Vec<_> = (0..subst.len()).map(|r| find(&subst, r)).collect();
+    for block in &mut f.blocks {
+        block.phis.retain(|phi| subst[phi.dst] == phi.dst);
+        block.insts.retain(|i| i.dst.is_none_or(|d| subst[d] == d));
+    }
+    substitute(f, &subst);
+    true
+}
+
+// replaces the components of phi nodes merging a single register
+fn redundant_sccs(f: &Function, subst: &mut [Reg]) -> bool {
+    let mut t = Tarjan {
+        args: vec![None; f.regs.len()],
+        visited: 0,
+        index: vec![None; f.regs.len()],
+        low: vec![0; f.regs.len()],
+        stack: vec![],
+        on_stack: vec![false; f.regs.len()],
+        sccs: vec![],
+    };
+    let phis = f.blocks.iter().flat_map(|b| &b.phis);
+    let phis: Vec<_> = phis.filter(|phi| subst[phi.dst] == phi.dst).collect();
+    for phi in &phis {
+        let args = phi.args.iter().map(|(_, a)| find(subst, *a));
+        t.args[phi.dst] = Some(args.collect());
+    }
+    for phi in &phis {
+        if t.index[phi.dst].is_none() {
+            t.visit(phi.dst);
+        }
+    }
+    let mut changed = false;
+    for scc in &t.sccs {
+        let args = scc.iter().flat_map(|p| t.args[*p].as_ref().unwrap());
+        let outer: BTreeSet<_> = args.filter(|a| !scc.contains(a)).collect();
+        if scc.len() > 1 && outer.len() == 1 {
+            let a = **outer.iter().next().unwrap();
+            scc.iter().for_each(|p| subst[*p] = a);
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Tarjan's strongly connected components, of the phi nodes with arguments
+struct Tarjan {
+    args: Vec<Option<Vec<Reg>>>,
+    visited: usize,
+    index: Vec<Option<usize>>,
+    low: Vec<usize>,
+    stack: Vec<Reg>,
+    on_stack: Vec<bool>,
+    sccs: Vec<Vec<Reg>>,
+}
+
+impl Tarjan {
+    fn visit(&mut self, v: Reg) {
+        self.index[v] = Some(self.visited);
+        self.low[v] = self.visited;
+        self.visited += 1;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+        for w in self.args[v].clone().unwrap() {
+            if self.args[w].is_none() {
+                continue;
+            }
+            match self.index[w] {
+                None => {
+                    self.visit(w);
+                    self.low[v] = self.low[v].min(self.low[w]);
+                }
+                Some(i) if self.on_stack[w] => self.low[v] = self.low[v].min(i),
+                Some(_) => (),
+            }
+        }
+        if Some(self.low[v]) == self.index[v] {
+            let mut scc = vec![];
+            while let Some(w) = self.stack.pop() {
+                self.on_stack[w] = false;
+                scc.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            self.sccs.push(scc);
+        }
+    }
+}
+
+#[test]
+fn test_copyprop() {
+    let src = "
+fn f(n: i32) -> i32 {
+    let mut m: i32 = n;
+    let mut i: i32 = 0;
+    while i < n {
+        if i > 10 {
+            m = n;
+        }
+        i = i + 1;
+    }
+    return m;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut m = super::super::lower(&p).unwrap().0;
+    let f = &mut m.fns[0];
+    let phis = |f: &Function| f.blocks.iter().map(|b| b.phis.len()).sum::<usize>();
+    assert_eq!(phis(f), 3);
+    assert!(run(f));
+    f.compact();
+    assert_eq!(super::super::verify(&m), Ok(()));
+    // `m` is always `n`: its phi nodes in the loop header and after the
+    // `if` only merge `n` with each other, the one of `i` is left
+    let f = &m.fns[0];
+    assert_eq!(phis(f), 1);
+    let ret = f.blocks.iter().find_map(|b| match b.term {
+        super::super::Term::Return(r) => Some(r),
+        _ => None,
+    });
+    assert_eq!(ret, Some(0));
+}
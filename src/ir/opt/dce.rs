@@ -0,0 +1,99 @@
+// Dead code elimination
+//
+// Removes the instructions and phi nodes whose results are never used.
+// Starting from the terminators and the instructions with an effect
+// (calls, stores, printing, and checked arithmetic, which may fail), the
+// definitions of their operands are marked live, transitively; the
+// others are removed. Branches are kept, folding them is left to `sccp`.
+
+use super::super::{Function, InstKind, Reg};
+use crate::ast::Op;
+
+#[derive(Clone, Copy)]
+enum Def {
+    // block and index of the phi node
+    Phi(usize, usize),
+    // block and index of the instruction
+    Inst(usize, usize),
+}
+
+pub fn has_effect(kind: &InstKind) -> bool {
+    match kind {
+        InstKind::Call(..) | InstKind::Store(..) | InstKind::Print(..) => true,
+        InstKind::Bin(op, ..) => matches!(op, Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow),
+        InstKind::Un(op, _) => *op != Op::Not,
+        _ => false,
+    }
+}
+
+pub fn run(f: &mut Function) -> bool {
+    let mut defs = vec![None; f.regs.len()];
+    let mut work: Vec<Reg> = vec![];
+    for (b, block) in f.blocks.iter().enumerate() {
+        for (i, phi) in block.phis.iter().enumerate() {
+            defs[phi.dst] = Some(Def::Phi(b, i));
+        }
+        for (i, inst) in block.insts.iter().enumerate() {
+            if let Some(d) = inst.dst {
+                defs[d] = Some(Def::Inst(b, i));
+            }
+            if has_effect(&inst.kind) {
+                work.extend(inst.kind.uses());
+            }
+        }
+        work.extend(block.term.uses());
+    }
+    let mut live = vec![false; f.regs.len()];
+    while let Some(r) = work.pop() {
+        if live[r] {
+            continue;
+        }
+        live[r] = true;
+        match defs[r] {
+            Some(Def::Phi(b, i)) => work.extend(f.blocks[b].phis[i].args.iter().map(|(_, a)| *a)),
+            Some(Def::Inst(b, i)) => work.extend(f.blocks[b].insts[i].kind.uses()),
+            // a parameter
+            None => (),
+        }
+    }
+    let mut changed = false;
+    for block in &mut f.blocks {
+        let (phis, insts) = (block.phis.len(), block.insts.len());
+        block.phis.retain(|phi| live[phi.dst]);
+        (block.insts).retain(|i| has_effect(&i.kind) || i.dst.is_some_and(|d| live[d]));
+        changed |= phis != block.phis.len() || insts != block.insts.len();
+    }
+    changed
+}
+
+#[test]
+fn test_dce() {
+    let src = "
+fn f(a: i32, b: bool) -> i32 {
+    let unused: bool = !b;
+    let sum: i32 = a + 1;
+    let mut x: i32 = 0;
+    if b {
+        x = 1;
+    }
+    println!(\"{}\", a > 0);
+    return a;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut m = super::super::lower(&p).unwrap().0;
+    let f = &mut m.fns[0];
+    assert!(run(f));
+    f.compact();
+    // the addition may overflow, the phi node for `x` is gone
+    assert!(!run(f));
+    let insts: Vec<_> = f
+        .blocks
+        .iter()
+        .flat_map(|b| &b.insts)
+        .map(|i| &i.kind)
+        .collect();
+    assert_eq!(insts.len(), 5);
+    assert!(matches!(insts[1], InstKind::Bin(Op::Add, ..)));
+    assert!(f.blocks.iter().all(|b| b.phis.is_empty()));
+}
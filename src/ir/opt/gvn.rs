@@ -0,0 +1,143 @@
+// Global value numbering
+//
+// Replaces an instruction by an equal one dominating it: the same
+// constant, or the same operator on the same operands (in either order
+// for commutative operators). The dominator tree is walked from the
+// entry with the instructions available on the path, i.e., those of the
+// dominators, operands renamed as instructions are replaced. Loads,
+// calls and allocas are never equal: memory may change in between, and
+// each `alloca` is a new slot. Checked arithmetic is merged too, the
+// dominating instruction fails first.
+
+use super::super::dom::Dominators;
+use super::super::{BlockId, Function, InstKind, Reg};
+use super::substitute;
+use crate::ast::Op;
+
+struct Numbering {
+    children: Vec<Vec<BlockId>>,
+    subst: Vec<Reg>,
+    // the available instructions and their results, innermost last
+    available: Vec<(InstKind, Reg)>,
+    // block and index of the instructions replaced
+    removed: Vec<(BlockId, usize)>,
+}
+
+fn commutative(op: Op) -> bool {
+    matches!(op, Op::Add | Op::Mul | Op::Eq | Op::Neq | Op::And | Op::Or)
+}
+
+impl Numbering {
+    fn block(&mut self, f: &Function, b: BlockId) {
+        let scope = self.available.len();
+        for (i, inst) in f.blocks[b].insts.iter().enumerate() {
+            let mut key = inst.kind.clone();
+            key.uses_mut().into_iter().for_each(|r| *r = self.subst[*r]);
+            match &mut key {
+                InstKind::Bin(op, l, r) => {
+                    if commutative(*op) && l > r {
+                        std::mem::swap(l, r);
+                    }
+                }
+                InstKind::Const(_) | InstKind::Un(..) => (),
+                _ => continue,
+            }
+            let d = inst.dst.unwrap();
+            match self.available.iter().rev().find(|(k, _)| *k == key) {
+                Some((_, r)) => {
+                    self.subst[d] = *r;
+                    self.removed.push((b, i));
+                }
+                None => self.available.push((key, d)),
+            }
+        }
+        for c in self.children[b].clone() {
+            self.block(f, c);
+        }
+        self.available.truncate(scope);
+    }
+}
+
+pub fn run(f: &mut Function) -> bool {
+    let mut n = Numbering {
+        children: Dominators::new(f).children(),
+        subst: (0..f.regs.len()).collect(),
+        available: vec![],
+        removed: vec![],
+    };
+    n.block(f, 0);
+    if n.removed.is_empty() {
+        return false;
+    }
+    for (b, i) in n.removed.into_iter().rev() {
+        f.blocks[b].insts.remove(i);
+    }
+    substitute(f, &n.subst);
+    true
+}
+
+#[test]
+fn test_gvn() {
+    let src = "
+fn f(a: i32, b: i32) -> i32 {
+    let x: i32 = a * b + 1;
+    let mut y: i32 = 0;
+    if a > 0 {
+        y = b * a + 1;
+    }
+    return x + y;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut m = super::super::lower(&p).unwrap().0;
+    let f = &mut m.fns[0];
+    assert!(run(f));
+    f.compact();
+    assert_eq!(super::super::verify(&m), Ok(()));
+    // the constants, and `b * a + 1` in the `if`, are those before it
+    let f = &m.fns[0];
+    assert!(f.blocks[1].insts.is_empty());
+    let sizes: Vec<_> = f.blocks.iter().map(|b| b.insts.len()).collect();
+    assert_eq!(sizes, vec![5, 0, 1]);
+    assert_eq!(f.blocks[2].phis[0].args, vec![(0, 5), (1, 4)]);
+}
+
+#[test]
+fn test_gvn_loads() {
+    let src = "
+fn inc(r: &mut i32) {
+    *r = *r + 1;
+}
+
+fn f(a: i32) -> i32 {
+    let mut x: i32 = a;
+    let r: &mut i32 = &mut x;
+    let u: i32 = *r;
+    *r = u + 1;
+    let v: i32 = *r;
+    inc(r);
+    let w: i32 = *r;
+    return u * 100 + v * 10 + w;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut m = super::super::lower(&p).unwrap().0;
+    let loads = |f: &Function| {
+        let insts = f.blocks.iter().flat_map(|b| &b.insts);
+        insts
+            .filter(|i| matches!(i.kind, InstKind::Load(_)))
+            .count()
+    };
+    let before = loads(&m.fns[1]);
+    assert!(before >= 3);
+    run(&mut m.fns[1]);
+    m.fns[1].compact();
+    assert_eq!(super::super::verify(&m), Ok(()));
+    // the loads of `*r` before and after the store and the call stay
+    assert_eq!(loads(&m.fns[1]), before);
+    let args = vec![crate::interp::Value::Num(1)];
+    assert_eq!(
+        super::super::eval::run(&m, "f", args),
+        Ok(crate::interp::Value::Num(123))
+    );
+}
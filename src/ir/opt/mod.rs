@@ -0,0 +1,245 @@
+// Optimization
+//
+// Passes rewrite the functions of a module in place, keeping them valid
+// (see `verify`) and their behaviour unchanged: results, output and
+// runtime errors, at the same spans. Checked arithmetic may fail, so it
+// is only removed when evaluated without failing, or when an equal
+// instruction dominates it (and fails first).
+//
+// The pass manager runs the pipeline of an optimization level: nothing
+// at -O0, copy propagation then dead code elimination at -O1, and at -O2
+// SCCP, copy propagation, GVN and DCE, repeated while they change
+// anything (at most `ROUNDS` times, as folding a branch may expose more
+// constants). Registers are renumbered after each pass changing a
+// function. With `--print-after=<pass>` the module is printed after
+// each run of the pass, with `--verify` verified.
+
+use std::fmt::Write;
+
+use super::{Function, Module, Reg};
+
+pub mod copyprop;
+pub mod dce;
+pub mod gvn;
+pub mod sccp;
+
+pub struct Pass {
+    pub name: &'static str,
+    // whether the function changed
+    pub run: fn(&mut Function) -> bool,
+}
+
+pub const PASSES: [Pass; 4] = [
+    Pass {
+        name: "dce",
+        run: dce::run,
+    },
+    Pass {
+        name: "gvn",
+        run: gvn::run,
+    },
+    Pass {
+        name: "copyprop",
+        run: copyprop::run,
+    },
+    Pass {
+        name: "sccp",
+        run: sccp::run,
+    },
+];
+
+const ROUNDS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    O0,
+    O1,
+    O2,
+}
+
+impl Level {
+    fn pipeline(self) -> (&'static [&'static str], usize) {
+        match self {
+            Level::O0 => (&[], 0),
+            Level::O1 => (&["copyprop", "dce"], 1),
+            Level::O2 => (&["sccp", "copyprop", "gvn", "dce"], ROUNDS),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub level: Level,
+    // name of the pass after which the module is printed
+    pub print_after: Option<&'static str>,
+    // verify the module after each pass
+    pub verify: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            level: Level::O0,
+            print_after: None,
+            verify: false,
+        }
+    }
+}
+
+impl Options {
+    // takes the options it knows (`-O0`, `-O1`, `-O2`, `--print-after=<pass>`
+    // and `--verify`) from the arguments, returning the others
+    pub fn parse(args: Vec<String>) -> Result<(Options, Vec<String>), String> {
+        let mut opts = Options::default();
+        let mut rest = vec![];
+        for a in args {
+            match a.as_str() {
+                "-O0" => opts.level = Level::O0,
+                "-O1" => opts.level = Level::O1,
+                "-O2" => opts.level = Level::O2,
+                "--verify" => opts.verify = true,
+                _ if a.starts_with("--print-after=") => {
+                    let name = &a["--print-after=".len()..];
+                    match PASSES.iter().find(|p| p.name == name) {
+                        Some(p) => opts.print_after = Some(p.name),
+                        None => {
+                            let names: Vec<_> = PASSES.iter().map(|p| p.name).collect();
+                            return Err(format!(
+                                "unknown pass `{}`, expected one of {}",
+                                name,
+                                names.join(", ")
+                            ));
+                        }
+                    }
+                }
+                _ => rest.push(a),
+            }
+        }
+        Ok((opts, rest))
+    }
+}
+
+// optimizes the module, returning what is printed after passes, or the
+// first verification error, e.g., "after gvn: fn f: ..."
+pub fn optimize(m: &mut Module, opts: &Options) -> Result<String, String> {
+    let mut log = String::new();
+    let (pipeline, rounds) = opts.level.pipeline();
+    for round in 0..rounds {
+        let mut changed = false;
+        for name in pipeline {
+            let pass = PASSES.iter().find(|p| p.name == *name).unwrap();
+            for f in &mut m.fns {
+                if (pass.run)(f) {
+                    f.compact();
+                    changed = true;
+                }
+            }
+            if opts.verify {
+                super::verify(m).map_err(|e| format!("after {}: {}", name, e))?;
+            }
+            if opts.print_after == Some(pass.name) {
+                let _ = write!(log, "; after {} (round {})\n{}", name, round + 1, m);
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    Ok(log)
+}
+
+// replaces each use of a register `r` by `subst[r]`
+fn substitute(f: &mut Function, subst: &[Reg]) {
+    for block in &mut f.blocks {
+        for phi in &mut block.phis {
+            phi.args.iter_mut().for_each(|(_, r)| *r = subst[*r]);
+        }
+        for inst in &mut block.insts {
+            inst.kind
+                .uses_mut()
+                .into_iter()
+                .for_each(|r| *r = subst[*r]);
+        }
+        block
+            .term
+            .uses_mut()
+            .into_iter()
+            .for_each(|r| *r = subst[*r]);
+    }
+}
+
+// the result and output of `main`, unoptimized and at each level
+#[cfg(test)]
+fn differential(m: &Module) -> Vec<(String, String)> {
+    let run = |m: &Module| {
+        let mut out = vec![];
+        let mut e = super::eval::Eval::new(m);
+        e.set_output(Box::new(&mut out));
+        let r = e.call("main", vec![]);
+        drop(e);
+        (format!("{:?}", r), String::from_utf8(out).unwrap())
+    };
+    let mut runs = vec![run(m)];
+    for level in &[Level::O0, Level::O1, Level::O2] {
+        let mut o = m.clone();
+        let opts = Options {
+            level: *level,
+            verify: true,
+            ..Options::default()
+        };
+        optimize(&mut o, &opts).unwrap();
+        let size = |m: &Module| {
+            m.fns
+                .iter()
+                .flat_map(|f| &f.blocks)
+                .map(|b| b.insts.len())
+                .sum::<usize>()
+        };
+        assert!(size(&o) <= size(m));
+        runs.push(run(&o));
+    }
+    runs
+}
+
+#[test]
+fn test_optimize() {
+    for (name, _, m) in crate::backend::lowered_programs() {
+        let runs = differential(&m);
+        for (level, r) in runs.iter().enumerate().skip(1) {
+            assert_eq!(r, &runs[0], "{} at -O{}", name, level - 1);
+        }
+    }
+
+    let args = vec!["-O2", "x.crust", "--print-after=gvn"];
+    let (opts, rest) = Options::parse(args.into_iter().map(String::from).collect()).unwrap();
+    assert_eq!((opts.level, opts.print_after), (Level::O2, Some("gvn")));
+    assert_eq!(rest, vec!["x.crust"]);
+    assert_eq!(
+        Options::parse(vec!["--print-after=cse".to_string()]),
+        Err("unknown pass `cse`, expected one of dce, gvn, copyprop, sccp".to_string())
+    );
+
+    let src = "
+fn main() -> i32 {
+    let x: i32 = 6;
+    let y: i32 = x * 7;
+    if y > 40 {
+        return y + 1;
+    }
+    return 0;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut m = super::lower(&p).unwrap().0;
+    let opts = Options {
+        level: Level::O2,
+        print_after: Some("sccp"),
+        verify: true,
+    };
+    let log = optimize(&mut m, &opts).unwrap();
+    assert!(log.starts_with("; after sccp (round 1)\nfn main() -> i32 {\n"));
+    assert_eq!(
+        m.to_string(),
+        "fn main() -> i32 {\nb0:\n    jump b1\nb1:\n    %0: i32 = const 43\n    return %0\n}\n"
+    );
+}
@@ -0,0 +1,283 @@
+// Sparse conditional constant propagation
+//
+// After Wegman and Zadeck: a register is undefined (no value seen yet),
+// a constant, or overdefined, and a block is executable once an edge
+// into it is. Phi nodes only merge the values of executable edges, and a
+// branch on a constant only makes its taken edge executable, so that
+// values on paths never taken do not spoil the others. The executable
+// blocks are evaluated until nothing changes; an operation that would
+// fail is overdefined, left to fail at runtime.
+//
+// Then registers known constant are defined by a `const`, branches on a
+// constant become jumps, and the blocks never executable are removed.
+
+use std::collections::BTreeSet;
+
+use super::super::dom::reverse_postorder;
+use super::super::{BlockId, Const, Function, Inst, InstKind, Term};
+use crate::interp::{binop, unop, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Lattice {
+    Undefined,
+    Constant(Const),
+    Overdefined,
+}
+
+use Lattice::*;
+
+fn meet(a: Lattice, b: Lattice) -> Lattice {
+    match (a, b) {
+        (Undefined, x) | (x, Undefined) => x,
+        (Constant(x), Constant(y)) if x == y => a,
+        _ => Overdefined,
+    }
+}
+
+fn value(c: Const) -> Value {
+    match c {
+        Const::Int(n) => Value::Num(n),
+        Const::Bool(b) => Value::Bool(b),
+        Const::Unit => Value::Unit,
+    }
+}
+
+// the constant result of an evaluation succeeding
+fn constant<E>(r: Result<Value, E>) -> Lattice {
+    match r {
+        Ok(Value::Num(n)) => Constant(Const::Int(n)),
+        Ok(Value::Bool(b)) => Constant(Const::Bool(b)),
+        Ok(Value::Unit) => Constant(Const::Unit),
+        _ => Overdefined,
+    }
+}
+
+pub fn run(f: &mut Function) -> bool {
+    let mut vals = vec![Undefined; f.regs.len()];
+    for p in &f.params {
+        vals[*p] = Overdefined;
+    }
+    let mut edges = BTreeSet::new();
+    let mut executable = vec![false; f.blocks.len()];
+    executable[0] = true;
+    let order = reverse_postorder(f);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in &order {
+            if !executable[b] {
+                continue;
+            }
+            let block = &f.blocks[b];
+            let mut set = |vals: &mut Vec<Lattice>, r: usize, v: Lattice| {
+                if vals[r] != v {
+                    vals[r] = v;
+                    changed = true;
+                }
+            };
+            for phi in &block.phis {
+                let args = phi.args.iter().filter(|(p, _)| edges.contains(&(*p, b)));
+                let v = args.fold(Undefined, |v, (_, a)| meet(v, vals[*a]));
+                set(&mut vals, phi.dst, v);
+            }
+            for inst in &block.insts {
+                let v = match &inst.kind {
+                    InstKind::Const(c) => Constant(*c),
+                    InstKind::Copy(r) => vals[*r],
+                    InstKind::Bin(op, l, r) => match (vals[*l], vals[*r]) {
+                        (Constant(l), Constant(r)) => constant(binop(*op, value(l), value(r))),
+                        (Overdefined, _) | (_, Overdefined) => Overdefined,
+                        _ => Undefined,
+                    },
+                    InstKind::Un(op, r) => match vals[*r] {
+                        Constant(c) => constant(unop(*op, value(c))),
+                        v => v,
+                    },
+                    _ => Overdefined,
+                };
+                if let Some(d) = inst.dst {
+                    set(&mut vals, d, v);
+                }
+            }
+            let succs = match &block.term {
+                Term::Branch(c, t, _) if vals[*c] == Constant(Const::Bool(true)) => vec![*t],
+                Term::Branch(c, _, e) if vals[*c] == Constant(Const::Bool(false)) => vec![*e],
+                term => term.succs(),
+            };
+            for s in succs {
+                if edges.insert((b, s)) {
+                    executable[s] = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut changed = false;
+    for (b, block) in f.blocks.iter_mut().enumerate() {
+        if !executable[b] {
+            continue;
+        }
+        let span = f.id;
+        let known = |r: usize| match vals[r] {
+            Constant(c) => Some(c),
+            _ => None,
+        };
+        let phis = std::mem::take(&mut block.phis);
+        let (folded, phis): (Vec<_>, Vec<_>) =
+            phis.into_iter().partition(|p| known(p.dst).is_some());
+        block.phis = phis;
+        let consts = folded.iter().map(|p| Inst {
+            dst: Some(p.dst),
+            kind: InstKind::Const(known(p.dst).unwrap()),
+            span,
+        });
+        block.insts.splice(0..0, consts);
+        changed |= !folded.is_empty();
+        for inst in &mut block.insts {
+            match (inst.dst.and_then(known), &inst.kind) {
+                (_, InstKind::Const(_)) | (None, _) => (),
+                (Some(c), _) => {
+                    inst.kind = InstKind::Const(c);
+                    changed = true;
+                }
+            }
+        }
+        if let Term::Branch(_, t, e) = block.term {
+            match (edges.contains(&(b, t)), edges.contains(&(b, e))) {
+                (true, false) => block.term = Term::Jump(t),
+                (false, true) => block.term = Term::Jump(e),
+                _ => continue,
+            }
+            changed = true;
+        }
+    }
+    // the phi arguments of edges never taken
+    for (b, block) in f.blocks.iter_mut().enumerate() {
+        for phi in &mut block.phis {
+            let before = phi.args.len();
+            phi.args.retain(|(p, _)| edges.contains(&(*p, b)));
+            changed |= phi.args.len() != before;
+        }
+    }
+    if executable.iter().any(|e| !e) {
+        remove_blocks(f, &executable);
+        changed = true;
+    }
+    changed
+}
+
+// keeps the blocks marked, which no removed block jumps to
+fn remove_blocks(f: &mut Function, keep: &[bool]) {
+    let mut index = vec![0; keep.len()];
+    let mut n = 0;
+    for (b, k) in keep.iter().enumerate() {
+        index[b] = n;
+        n += *k as usize;
+    }
+    let mut b = 0;
+    f.blocks.retain(|_| {
+        b += 1;
+        keep[b - 1]
+    });
+    let rename = |b: &mut BlockId| *b = index[*b];
+    for block in &mut f.blocks {
+        for phi in &mut block.phis {
+            phi.args.iter_mut().for_each(|(p, _)| rename(p));
+        }
+        match &mut block.term {
+            Term::Jump(t) => rename(t),
+            Term::Branch(_, t, e) => {
+                rename(t);
+                rename(e);
+            }
+            _ => (),
+        }
+    }
+}
+
+#[test]
+fn test_sccp() {
+    let src = "
+fn f(n: i32) -> i32 {
+    let mut x: i32 = 1;
+    let mut i: i32 = 0;
+    while i < n {
+        if x == 1 {
+            x = 2 - 1;
+        } else {
+            x = x + 10;
+        }
+        i = i + 1;
+    }
+    if x > 5 {
+        panic!(\"unreachable\");
+    }
+    return x * (1 - 2147483647 - 3);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut m = super::super::lower(&p).unwrap().0;
+    let blocks = m.fns[0].blocks.len();
+    let f = &mut m.fns[0];
+    assert!(run(f));
+    f.compact();
+    assert_eq!(super::super::verify(&m), Ok(()));
+    // `x` stays 1: the `else` branch and the panic are gone
+    let f = &m.fns[0];
+    assert_eq!(f.blocks.len(), blocks - 2);
+    assert!(f.blocks.iter().all(|b| !matches!(b.term, Term::Panic(..))));
+    // `1 - 2147483647 - 3` overflows at runtime
+    let kinds: Vec<_> = f
+        .blocks
+        .iter()
+        .flat_map(|b| &b.insts)
+        .map(|i| &i.kind)
+        .collect();
+    assert!(kinds
+        .iter()
+        .any(|k| matches!(k, InstKind::Bin(crate::ast::Op::Sub, ..))));
+}
+
+#[test]
+fn test_sccp_overflow() {
+    use crate::ast::Op;
+
+    let src = "
+fn f(n: i32) -> i32 {
+    let mut x: i32 = 2147483647;
+    let mut i: i32 = 0;
+    while i < n {
+        let big: i32 = 2147483647 * 2;
+        x = x + 1;
+        i = i + big;
+    }
+    return x;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut m = super::super::lower(&p).unwrap().0;
+    run(&mut m.fns[0]);
+    m.fns[0].compact();
+    assert_eq!(super::super::verify(&m), Ok(()));
+    // neither the loop-invariant product nor the increment of the phi
+    // is folded, both overflow only once the loop runs
+    let ops: Vec<_> = (m.fns[0].blocks.iter())
+        .flat_map(|b| &b.insts)
+        .filter_map(|i| match i.kind {
+            InstKind::Bin(op, ..) => Some(op),
+            _ => None,
+        })
+        .collect();
+    assert!(
+        ops.contains(&Op::Mul) && ops.contains(&Op::Add),
+        "{:?}",
+        ops
+    );
+    for n in 0..2 {
+        let args = vec![Value::Num(n)];
+        let r = super::super::eval::run(&m, "f", args.clone());
+        assert_eq!(r, crate::interp::run(&p, "f", args));
+        assert_eq!(r.is_ok(), n == 0);
+    }
+}
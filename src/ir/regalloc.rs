@@ -0,0 +1,346 @@
+// Linear-scan register allocation
+//
+// Assigns each register of a function a machine register or a stack slot,
+// after Poletto and Sarkar, "Linear Scan Register Allocation" (TOPLAS
+// 1999): the live intervals are visited by increasing start, those ended
+// free their machine register, and when none is free the interval ending
+// last, among the current one and those holding a usable register, is
+// spilled to a slot of its own for its whole life.
+//
+// An interval live across a call only gets a callee-saved register, the
+// others prefer the caller-saved ones, which need no saving in the
+// prologue. An interval ending where another starts (the last use by the
+// instruction defining the other) may hand its register over.
+//
+// The allocation is checked against the exact liveness: no two registers
+// live at once share a machine register, none live across a call is in a
+// caller-saved one. It is an analysis of its own: the backends do not use
+// it, and its targets are register files of abstract machines rather than
+// those of the backends' instruction sets.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use super::live::{intervals, liveness};
+use super::{Function, InstKind, Module, Reg};
+
+// the allocatable registers of a machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub name: &'static str,
+    // clobbered by calls
+    pub caller_saved: &'static [&'static str],
+    // preserved by calls
+    pub callee_saved: &'static [&'static str],
+}
+
+// twelve caller-saved and eleven callee-saved registers
+pub const LARGE: Target = Target {
+    name: "large",
+    caller_saved: &[
+        "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    ],
+    callee_saved: &[
+        "r12", "r13", "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21", "r22",
+    ],
+};
+
+// seven caller-saved and five callee-saved registers
+pub const SMALL: Target = Target {
+    name: "small",
+    caller_saved: &["r0", "r1", "r2", "r3", "r4", "r5", "r6"],
+    callee_saved: &["r7", "r8", "r9", "r10", "r11"],
+};
+
+impl Target {
+    pub fn count(&self) -> usize {
+        self.caller_saved.len() + self.callee_saved.len()
+    }
+
+    // machine registers are numbered caller-saved first
+    pub fn register(&self, i: usize) -> &'static str {
+        match i.checked_sub(self.caller_saved.len()) {
+            None => self.caller_saved[i],
+            Some(j) => self.callee_saved[j],
+        }
+    }
+
+    pub fn is_callee_saved(&self, i: usize) -> bool {
+        i >= self.caller_saved.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loc {
+    // a machine register of the target
+    Reg(usize),
+    // a stack slot
+    Slot(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub intervals: usize,
+    pub spilled: usize,
+    // distinct callee-saved registers used, to save in the prologue
+    pub callee_saved: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} intervals, {} spilled, {} callee-saved register{}",
+            self.intervals,
+            self.spilled,
+            self.callee_saved,
+            if self.callee_saved == 1 { "" } else { "s" }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    // the location of each register
+    pub locs: Vec<Loc>,
+    pub slots: usize,
+    pub stats: Stats,
+}
+
+impl Allocation {
+    // the location of each register, one per line
+    pub fn render(&self, t: &Target) -> String {
+        let loc = |l: &Loc| match l {
+            Loc::Reg(i) => t.register(*i).to_string(),
+            Loc::Slot(s) => format!("slot {}", s),
+        };
+        (self.locs.iter().enumerate())
+            .map(|(r, l)| format!("    %{} -> {}\n", r, loc(l)))
+            .collect()
+    }
+}
+
+pub fn allocate(f: &Function, t: &Target) -> Allocation {
+    let iv = intervals(f);
+    let mut order: Vec<Reg> = (0..f.regs.len()).collect();
+    order.sort_by_key(|r| (iv.ranges[*r].0, *r));
+
+    let mut locs = vec![Loc::Slot(0); f.regs.len()];
+    let mut slots = 0;
+    let mut free = vec![true; t.count()];
+    // registers holding a machine register
+    let mut active: Vec<Reg> = vec![];
+    let mut spill = |locs: &mut Vec<Loc>, r: Reg| {
+        locs[r] = Loc::Slot(slots);
+        slots += 1;
+    };
+    for r in order {
+        let (start, end) = iv.ranges[r];
+        active.retain(|a| match (iv.ranges[*a].1 <= start, locs[*a]) {
+            (true, Loc::Reg(i)) => {
+                free[i] = true;
+                false
+            }
+            _ => true,
+        });
+        let crosses = iv.crosses_call(r);
+        let usable = |i: usize| !crosses || t.is_callee_saved(i);
+        match (0..t.count()).find(|i| free[*i] && usable(*i)) {
+            Some(i) => {
+                free[i] = false;
+                locs[r] = Loc::Reg(i);
+                active.push(r);
+            }
+            None => {
+                // the usable active interval ending last
+                let victim = (active.iter().copied())
+                    .filter(|a| matches!(locs[*a], Loc::Reg(i) if usable(i)))
+                    .max_by_key(|a| (iv.ranges[*a].1, *a));
+                match victim {
+                    Some(v) if iv.ranges[v].1 > end => {
+                        locs[r] = locs[v];
+                        spill(&mut locs, v);
+                        active.retain(|a| *a != v);
+                        active.push(r);
+                    }
+                    _ => spill(&mut locs, r),
+                }
+            }
+        }
+    }
+    let used: BTreeSet<_> = (locs.iter())
+        .filter_map(|l| match l {
+            Loc::Reg(i) if t.is_callee_saved(*i) => Some(*i),
+            _ => None,
+        })
+        .collect();
+    Allocation {
+        stats: Stats {
+            intervals: f.regs.len(),
+            spilled: slots,
+            callee_saved: used.len(),
+        },
+        locs,
+        slots,
+    }
+}
+
+// the first conflict of the allocation, e.g., "b2: %4 and %6 share t3"
+pub fn check(f: &Function, t: &Target, a: &Allocation) -> Result<(), String> {
+    if a.locs.len() != f.regs.len() {
+        return Err(format!(
+            "{} locations for {} registers",
+            a.locs.len(),
+            f.regs.len()
+        ));
+    }
+    for (r, loc) in a.locs.iter().enumerate() {
+        match loc {
+            Loc::Reg(i) if *i >= t.count() => {
+                return Err(format!("%{} in the missing register {}", r, i))
+            }
+            Loc::Slot(s) if *s >= a.slots => {
+                return Err(format!("%{} in the missing slot {}", r, s))
+            }
+            _ => (),
+        }
+    }
+    // a definition conflicts with the registers live after it
+    let conflict = |d: Reg, live: &BTreeSet<Reg>| {
+        let other = live.iter().find(|r| **r != d && a.locs[**r] == a.locs[d]);
+        other.map(|r| match a.locs[d] {
+            Loc::Reg(i) => format!("%{} and %{} share {}", d, r, t.register(i)),
+            Loc::Slot(s) => format!("%{} and %{} share slot {}", d, r, s),
+        })
+    };
+    let live = liveness(f);
+    for (b, block) in f.blocks.iter().enumerate() {
+        let err = |msg: String| format!("b{}: {}", b, msg);
+        let mut now = live.live_out[b].clone();
+        now.extend(block.term.uses());
+        for inst in block.insts.iter().rev() {
+            if let Some(d) = inst.dst {
+                if let Some(msg) = conflict(d, &now) {
+                    return Err(err(msg));
+                }
+                now.remove(&d);
+            }
+            if let InstKind::Call(..) = inst.kind {
+                let saved = now.iter().find(|r| match a.locs[**r] {
+                    Loc::Reg(i) => !t.is_callee_saved(i),
+                    Loc::Slot(_) => false,
+                });
+                if let Some(r) = saved {
+                    return Err(err(format!("%{} is clobbered by a call", r)));
+                }
+            }
+            now.extend(inst.kind.uses());
+        }
+        let defs = block.phis.iter().map(|phi| phi.dst);
+        let defs: Vec<_> = match b {
+            0 => defs.chain(f.params.iter().copied()).collect(),
+            _ => defs.collect(),
+        };
+        // defined at once, with the registers live into the block
+        now.extend(defs);
+        if let Some(msg) = (now.iter()).find_map(|d| conflict(*d, &now)) {
+            return Err(err(msg));
+        }
+    }
+    Ok(())
+}
+
+// the statistics of each function, e.g., "fn gcd: 7 intervals, 0 spilled,
+// 1 callee-saved register"
+pub fn report(m: &Module, t: &Target) -> String {
+    (m.fns.iter())
+        .map(|f| format!("fn {}: {}\n", f.id.fragment, allocate(f, t).stats))
+        .collect()
+}
+
+#[test]
+fn test_allocate() {
+    // few registers, to spill
+    const TINY: Target = Target {
+        name: "tiny",
+        caller_saved: &["r0", "r1"],
+        callee_saved: &["r2"],
+    };
+    let mut spilled = 0;
+    for (name, _, m) in crate::backend::lowered_programs() {
+        for f in &m.fns {
+            for t in &[LARGE, SMALL, TINY] {
+                let a = allocate(f, t);
+                let fail = |e| panic!("{}: fn {} on {}: {}", name, f.id.fragment, t.name, e);
+                check(f, t, &a).unwrap_or_else(fail);
+                if t.name == "tiny" {
+                    spilled += a.stats.spilled;
+                }
+            }
+        }
+    }
+    assert!(spilled > 0);
+
+    let src = "
+fn f(a: i32, b: i32) -> i32 {
+    return a + f(b, a);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = super::lower(&p).unwrap().0;
+    let f = &m.fns[0];
+    let mut a = allocate(f, &TINY);
+    // `a` is live across the call, `b` is not
+    assert_eq!(a.locs[0], Loc::Reg(2));
+    assert_eq!(a.locs[1], Loc::Reg(0));
+    assert_eq!(
+        a.stats.to_string(),
+        "4 intervals, 0 spilled, 1 callee-saved register"
+    );
+    a.locs[0] = Loc::Reg(1);
+    assert_eq!(
+        check(f, &TINY, &a),
+        Err("b0: %0 is clobbered by a call".to_string())
+    );
+    a.locs[0] = Loc::Reg(0);
+    assert_eq!(
+        check(f, &TINY, &a),
+        Err("b0: %2 and %0 share r0".to_string())
+    );
+}
+
+#[test]
+fn test_targets() {
+    // eight parameters live across a call
+    let src = "
+fn g(x: i32) -> i32 {
+    return x;
+}
+
+fn f(a: i32, b: i32, c: i32, d: i32, e: i32, f: i32, h: i32, i: i32) -> i32 {
+    let x: i32 = g(a);
+    return a + b + c + d + e + f + h + i + x;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = super::lower(&p).unwrap().0;
+    let f = &m.fns[1];
+    for (t, spilled, saved) in &[(LARGE, 0, 8), (SMALL, 3, 5)] {
+        let a = allocate(f, t);
+        assert_eq!(check(f, t, &a), Ok(()), "{}", t.name);
+        assert_eq!((a.stats.spilled, a.stats.callee_saved), (*spilled, *saved));
+        for (r, l) in a.locs.iter().enumerate().take(8) {
+            if let Loc::Reg(i) = l {
+                assert!(t.is_callee_saved(*i), "%{} in {}", r, t.register(*i));
+            }
+        }
+    }
+    let large = allocate(f, &LARGE).render(&LARGE);
+    assert!(
+        large.starts_with("    %0 -> r12\n    %1 -> r13\n"),
+        "{}",
+        large
+    );
+    let small = allocate(f, &SMALL).render(&SMALL);
+    assert!(small.contains("-> slot 2\n"), "{}", small);
+}
@@ -0,0 +1,380 @@
+// IR verifier
+//
+// Checks the invariants the passes and code generators rely on: targets
+// and called functions exist, every block is reachable from the entry
+// (which has no predecessors), every register is defined once (as a
+// parameter, by a phi node or an instruction) by a definition dominating
+// its uses, phi nodes have an argument for each predecessor, and
+// instructions and terminators are well typed.
+
+use super::dom::Dominators;
+use super::{compatible, BlockId, Function, InstKind, Module, Reg, Term};
+use crate::ast::{Op, Type};
+
+#[derive(Clone, Copy)]
+enum Def {
+    Param,
+    Phi(BlockId),
+    // block and index of the instruction
+    Inst(BlockId, usize),
+}
+
+// the first violation found, e.g., "fn f: b2: %4 is used before its definition"
+pub fn verify(m: &Module) -> Result<(), String> {
+    for f in &m.fns {
+        verify_fn(m, f).map_err(|e| format!("fn {}: {}", f.id.fragment, e))?;
+    }
+    Ok(())
+}
+
+fn verify_fn(m: &Module, f: &Function) -> Result<(), String> {
+    let n = f.blocks.len();
+    if n == 0 {
+        return Err("no blocks".to_string());
+    }
+    for (b, block) in f.blocks.iter().enumerate() {
+        if let Some(s) = block.term.succs().into_iter().find(|s| *s >= n) {
+            return Err(format!("b{}: jump to the missing block b{}", b, s));
+        }
+        if let Term::Branch(_, t, e) = block.term {
+            if t == e {
+                return Err(format!("b{}: branch to b{} on both edges", b, t));
+            }
+        }
+    }
+    let dom = Dominators::new(f);
+    if let Some(b) = (0..n).find(|b| !dom.reachable(*b)) {
+        return Err(format!("b{} is unreachable", b));
+    }
+    let preds = f.preds();
+    if !preds[0].is_empty() {
+        return Err("the entry block has predecessors".to_string());
+    }
+
+    let mut defs = vec![None; f.regs.len()];
+    let mut define = |r: Reg, d: Def| match defs.get_mut(r) {
+        None => Err(format!("%{} has no type", r)),
+        Some(Some(_)) => Err(format!("%{} is defined more than once", r)),
+        Some(def) => {
+            *def = Some(d);
+            Ok(())
+        }
+    };
+    for p in &f.params {
+        define(*p, Def::Param)?;
+    }
+    for (b, block) in f.blocks.iter().enumerate() {
+        for phi in &block.phis {
+            define(phi.dst, Def::Phi(b))?;
+        }
+        for (i, inst) in block.insts.iter().enumerate() {
+            if let Some(d) = inst.dst {
+                define(d, Def::Inst(b, i))?;
+            }
+        }
+    }
+    // the definition of `r` is available before instruction `i` of `b`
+    // (the terminator for i = the number of instructions)
+    let available = |r: Reg, b: BlockId, i: usize| match defs.get(r).copied().flatten() {
+        None => false,
+        Some(Def::Param) => true,
+        Some(Def::Phi(d)) => dom.dominates(d, b),
+        Some(Def::Inst(d, j)) if d == b => j < i,
+        Some(Def::Inst(d, _)) => dom.dominates(d, b),
+    };
+    let ty = |r: Reg| &f.regs[r];
+
+    for (b, block) in f.blocks.iter().enumerate() {
+        let err = |msg: String| format!("b{}: {}", b, msg);
+        for phi in &block.phis {
+            let mut from: Vec<_> = phi.args.iter().map(|(p, _)| *p).collect();
+            let mut expected = preds[b].clone();
+            from.sort_unstable();
+            expected.sort_unstable();
+            if from != expected {
+                return Err(err(format!(
+                    "phi %{} does not match the predecessors",
+                    phi.dst
+                )));
+            }
+            for (p, r) in &phi.args {
+                if !available(*r, *p, f.blocks[*p].insts.len()) {
+                    return Err(err(format!("%{} is not available at the end of b{}", r, p)));
+                }
+                if !compatible(ty(phi.dst), ty(*r)) {
+                    return Err(err(format!("phi %{} merges `{}`", phi.dst, ty(*r))));
+                }
+            }
+        }
+        for (i, inst) in block.insts.iter().enumerate() {
+            let uses = inst.kind.uses();
+            if let Some(u) = uses.iter().find(|u| !available(**u, b, i)) {
+                return Err(err(format!("%{} is used before its definition", u)));
+            }
+            let result = inst_type(m, f, &inst.kind).map_err(err)?;
+            match (inst.dst, result) {
+                (Some(d), Some(t)) if compatible(ty(d), &t) => (),
+                (Some(d), Some(t)) => {
+                    return Err(err(format!("%{}: {} is assigned a `{}`", d, ty(d), t)));
+                }
+                (None, None) => (),
+                (Some(d), None) => return Err(err(format!("%{} is assigned no value", d))),
+                (None, Some(_)) => return Err(err(format!("instruction {} has no result", i))),
+            }
+        }
+        let uses = block.term.uses();
+        if let Some(u) = uses.iter().find(|u| !available(**u, b, block.insts.len())) {
+            return Err(err(format!("%{} is used before its definition", u)));
+        }
+        match &block.term {
+            Term::Branch(c, ..) if *ty(*c) != Type::Bool => {
+                return Err(err(format!("branch on `{}`", ty(*c))));
+            }
+            Term::Return(r) if !compatible(&f.ret, ty(*r)) => {
+                return Err(err(format!(
+                    "`{}` returned from a function returning `{}`",
+                    ty(*r),
+                    f.ret
+                )));
+            }
+            Term::Panic(_, args, _) => values(f, args).map_err(err)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// the type of the result, None for `store` and `print`
+fn inst_type(m: &Module, f: &Function, kind: &InstKind) -> Result<Option<Type>, String> {
+    let ty = |r: Reg| &f.regs[r];
+    Ok(Some(match kind {
+        InstKind::Const(c) => c.ty(),
+        InstKind::Copy(r) => ty(*r).clone(),
+        InstKind::Bin(op, l, r) => match (op, ty(*l), ty(*r)) {
+            (Op::Add, Type::I32, Type::I32)
+            | (Op::Sub, Type::I32, Type::I32)
+            | (Op::Mul, Type::I32, Type::I32)
+            | (Op::Div, Type::I32, Type::I32)
+            | (Op::Pow, Type::I32, Type::I32) => Type::I32,
+            (Op::Lt, Type::I32, Type::I32) | (Op::Gt, Type::I32, Type::I32) => Type::Bool,
+            (Op::And, Type::Bool, Type::Bool) | (Op::Or, Type::Bool, Type::Bool) => Type::Bool,
+            (Op::Eq, Type::Ref(..), _) | (Op::Neq, Type::Ref(..), _) => {
+                return Err(format!("`{}` applied to references", op))
+            }
+            (Op::Eq, l, r) | (Op::Neq, l, r) if l == r => Type::Bool,
+            (_, l, r) => return Err(format!("cannot apply `{}` to `{}` and `{}`", op, l, r)),
+        },
+        InstKind::Un(op, r) => match (op, ty(*r)) {
+            (Op::Sub, Type::I32) => Type::I32,
+            (Op::Not, Type::Bool) => Type::Bool,
+            (_, t) => return Err(format!("cannot apply `{}` to `{}`", op, t)),
+        },
+        InstKind::Call(g, args) => {
+            let g = match m.fns.get(*g) {
+                Some(g) => g,
+                None => return Err(format!("call of the missing function {}", g)),
+            };
+            if args.len() != g.params.len() {
+                return Err(format!("wrong number of arguments to `{}`", g.id.fragment));
+            }
+            for (p, a) in g.params.iter().zip(args) {
+                if !compatible(&g.regs[*p], ty(*a)) {
+                    return Err(format!("`{}` passed for `{}`", ty(*a), g.regs[*p]));
+                }
+            }
+            g.ret.clone()
+        }
+        InstKind::Alloca(t) => Type::Ref(true, Box::new(t.clone())),
+        InstKind::Load(p) => match ty(*p) {
+            Type::Ref(_, t) => (**t).clone(),
+            t => return Err(format!("load from `{}`", t)),
+        },
+        InstKind::Store(p, v) => match ty(*p) {
+            Type::Ref(true, t) if compatible(t, ty(*v)) => return Ok(None),
+            t => return Err(format!("store of `{}` to `{}`", ty(*v), t)),
+        },
+        InstKind::Print(_, args) => {
+            values(f, args)?;
+            return Ok(None);
+        }
+    }))
+}
+
+// formatted arguments, references print as addresses in the interpreter
+fn values(f: &Function, args: &[Reg]) -> Result<(), String> {
+    match args.iter().find(|a| matches!(f.regs[**a], Type::Ref(..))) {
+        Some(a) => Err(format!("%{} formatted as a reference", a)),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn test_verify() {
+    let src = "
+fn f(x: i32) -> i32 {
+    let mut y: i32 = x;
+    while y < 10 {
+        y = y + 1;
+    }
+    return y;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = super::lower(&p).unwrap().0;
+    assert_eq!(verify(&m), Ok(()));
+    // the loop header merges `x` and `y + 1`
+    let phi = m.fns[0].blocks[1].phis[0].clone();
+    let add = phi.args[1].1;
+
+    let mut bad = m.clone();
+    bad.fns[0].blocks[1].phis[0].args.pop();
+    assert_eq!(
+        verify(&bad),
+        Err(format!(
+            "fn f: b1: phi %{} does not match the predecessors",
+            phi.dst
+        ))
+    );
+    let mut bad = m.clone();
+    bad.fns[0].blocks[1].term = Term::Return(add);
+    assert!(verify(&bad).is_err());
+    let mut bad = m.clone();
+    let i = bad.fns[0].blocks[2].insts.len() - 1;
+    bad.fns[0].blocks[2].insts[i].kind = InstKind::Bin(Op::Add, add, phi.dst);
+    assert_eq!(
+        verify(&bad),
+        Err(format!("fn f: b2: %{} is used before its definition", add))
+    );
+    let mut bad = m;
+    bad.fns[0].regs[add] = Type::Bool;
+    assert!(verify(&bad).unwrap_err().contains("merges `bool`"));
+}
+
+#[test]
+fn test_reject() {
+    use super::Phi;
+
+    let src = "
+fn g(x: i32) -> i32 {
+    return x;
+}
+
+fn f(x: i32, b: bool) -> i32 {
+    let mut n: i32 = 1;
+    let r: &mut i32 = &mut n;
+    *r = g(x);
+    if b {
+        println!(\"{}\", n);
+    }
+    return n;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = super::lower(&p).unwrap().0;
+    assert_eq!(verify(&m), Ok(()));
+    // b0: %2 = alloca, %3 = const 1, store, %4 = call g(%0), store, branch %1
+    // b1: %5 = load %2, print %5, %6 = const (), jump b2
+    // b2: %7 = load %2, return %7
+    let reject = |edit: fn(&mut Function), msg: &str| {
+        let mut bad = m.clone();
+        edit(&mut bad.fns[1]);
+        assert_eq!(verify(&bad), Err(format!("fn f: {}", msg)));
+    };
+    fn kind(f: &mut Function, b: BlockId, i: usize, k: InstKind) {
+        f.blocks[b].insts[i].kind = k;
+    }
+
+    // control flow
+    reject(|f| f.blocks.clear(), "no blocks");
+    reject(
+        |f| f.blocks[1].term = Term::Jump(5),
+        "b1: jump to the missing block b5",
+    );
+    reject(
+        |f| f.blocks[0].term = Term::Branch(1, 2, 2),
+        "b0: branch to b2 on both edges",
+    );
+    reject(|f| f.blocks[0].term = Term::Jump(2), "b1 is unreachable");
+    reject(
+        |f| f.blocks[2].term = Term::Jump(0),
+        "the entry block has predecessors",
+    );
+
+    // definitions
+    reject(|f| f.blocks[0].insts[1].dst = Some(100), "%100 has no type");
+    reject(
+        |f| f.blocks[1].insts[2].dst = Some(5),
+        "%5 is defined more than once",
+    );
+    reject(
+        |f| {
+            let dst = f.new_reg(Type::I32);
+            let args = vec![(0, 5), (1, 5)];
+            f.blocks[2].phis.push(Phi { dst, args });
+        },
+        "b2: %5 is not available at the end of b0",
+    );
+    reject(
+        |f| f.blocks[2].insts[0].kind = InstKind::Load(7),
+        "b2: %7 is used before its definition",
+    );
+
+    // results
+    reject(
+        |f| f.regs[3] = Type::Bool,
+        "b0: %3: bool is assigned a `i32`",
+    );
+    reject(
+        |f| f.blocks[0].insts[2].dst = Some(f.new_reg(Type::Unit)),
+        "b0: %8 is assigned no value",
+    );
+    reject(
+        |f| f.blocks[1].insts[2].dst = None,
+        "b1: instruction 2 has no result",
+    );
+
+    // operands
+    reject(
+        |f| kind(f, 0, 1, InstKind::Bin(Op::Add, 0, 1)),
+        "b0: cannot apply `+` to `i32` and `bool`",
+    );
+    reject(
+        |f| kind(f, 0, 1, InstKind::Bin(Op::Eq, 2, 2)),
+        "b0: `==` applied to references",
+    );
+    reject(
+        |f| kind(f, 0, 1, InstKind::Un(Op::Not, 0)),
+        "b0: cannot apply `!` to `i32`",
+    );
+    reject(
+        |f| kind(f, 0, 3, InstKind::Call(5, vec![0])),
+        "b0: call of the missing function 5",
+    );
+    reject(
+        |f| kind(f, 0, 3, InstKind::Call(0, vec![])),
+        "b0: wrong number of arguments to `g`",
+    );
+    reject(
+        |f| kind(f, 0, 3, InstKind::Call(0, vec![1])),
+        "b0: `bool` passed for `i32`",
+    );
+    reject(|f| kind(f, 2, 0, InstKind::Load(0)), "b2: load from `i32`");
+    reject(
+        |f| kind(f, 0, 2, InstKind::Store(3, 3)),
+        "b0: store of `i32` to `i32`",
+    );
+    reject(
+        |f| kind(f, 1, 1, InstKind::Print("{}".to_string(), vec![2])),
+        "b1: %2 formatted as a reference",
+    );
+
+    // terminators
+    reject(
+        |f| f.blocks[0].term = Term::Branch(0, 1, 2),
+        "b0: branch on `i32`",
+    );
+    reject(
+        |f| f.blocks[2].term = Term::Return(1),
+        "b2: `bool` returned from a function returning `i32`",
+    );
+}
@@ -1,4 +1,21 @@
 // lib
 
 pub mod ast;
+pub mod backend;
+pub mod borrows;
+pub mod bytecode;
+pub mod debug;
+pub mod diagnostic;
+pub mod eval;
+pub mod flow;
+pub mod fold;
+pub mod interp;
+pub mod ir;
+pub mod liveness;
+pub mod module;
 pub mod parse;
+pub mod repl;
+pub mod smallstep;
+pub mod sos;
+pub mod value;
+pub mod vm;
@@ -0,0 +1,345 @@
+// Unused variable, parameter and assignment warnings
+//
+// Identifiers are first resolved to their declarations (respecting block
+// scopes and shadowing), after which a backward liveness analysis finds
+// assignments whose value is never read. Names starting with `_` are
+// exempt from unused and dead store warnings, like in rustc.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::ast::{Block, Expr, FnDecl, Op, Prog, Span, SpanExpr, SpanStmt, Stmt};
+use crate::diagnostic::Diagnostic;
+
+pub fn check_prog<'a>(p: &Prog<'a>) -> Vec<Diagnostic<'a>> {
+    p.iter().flat_map(check_fn).collect()
+}
+
+pub fn check_fn<'a>(f: &FnDecl<'a>) -> Vec<Diagnostic<'a>> {
+    let mut r = Resolver {
+        vars: vec![],
+        scopes: vec![vec![]],
+        res: HashMap::new(),
+    };
+    for p in &f.params {
+        r.declare(p.id, p.mutable, true);
+    }
+    r.block(&f.body.1);
+
+    let mut l = Liveness {
+        res: &r.res,
+        report: true,
+        dead: vec![],
+    };
+    let entry = l.block(&f.body.1, Live::new());
+    let dead = l.dead;
+
+    let mut diags = vec![];
+    for (v, var) in r.vars.iter().enumerate() {
+        let name = var.id.fragment;
+        let silent = name.starts_with('_');
+        if !var.read && !silent {
+            diags.push(Diagnostic::warning(
+                var.id,
+                match (var.param, var.written) {
+                    (true, _) => format!("unused parameter `{}`", name),
+                    (false, true) => format!("variable `{}` is assigned to, but never used", name),
+                    (false, false) => format!("unused variable `{}`", name),
+                },
+            ));
+        } else if var.param && !silent && !entry.contains(&v) {
+            diags.push(Diagnostic::warning(
+                var.id,
+                format!("value passed to `{}` is never read", name),
+            ));
+        }
+        if var.mutable && !var.written {
+            diags.push(Diagnostic::warning(
+                var.id,
+                format!("variable `{}` does not need to be mutable", name),
+            ));
+        }
+    }
+    for (s, v) in dead {
+        let var = &r.vars[v];
+        if var.read && !var.id.fragment.starts_with('_') {
+            diags.push(Diagnostic::warning(
+                s,
+                format!("value assigned to `{}` is never read", var.id.fragment),
+            ));
+        }
+    }
+    diags.sort_by_key(|d| d.span.offset);
+    diags
+}
+
+struct Var<'a> {
+    id: Span<'a>,
+    mutable: bool,
+    param: bool,
+    read: bool,
+    written: bool,
+}
+
+// maps each identifier occurrence (by offset) to its declaration
+struct Resolver<'a> {
+    vars: Vec<Var<'a>>,
+    scopes: Vec<Vec<(&'a str, usize)>>,
+    res: HashMap<usize, usize>,
+}
+
+impl<'a> Resolver<'a> {
+    fn declare(&mut self, id: Span<'a>, mutable: bool, param: bool) {
+        let v = self.vars.len();
+        self.vars.push(Var {
+            id,
+            mutable,
+            param,
+            read: false,
+            written: false,
+        });
+        self.res.insert(id.offset, v);
+        self.scopes.last_mut().unwrap().push((id.fragment, v));
+    }
+
+    fn lookup(&mut self, s: Span<'a>) -> Option<usize> {
+        let v = self
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(id, _)| *id == s.fragment)
+            .map(|(_, v)| *v)?;
+        self.res.insert(s.offset, v);
+        Some(v)
+    }
+
+    fn expr(&mut self, (s, e): &SpanExpr<'a>) {
+        match e {
+            Expr::Id(_) => {
+                if let Some(v) = self.lookup(*s) {
+                    self.vars[v].read = true;
+                }
+            }
+            Expr::Call(_, args) | Expr::Macro(_, args, _) => args.iter().for_each(|a| self.expr(a)),
+            // `&mut x` may write to `x`
+            Expr::UnaryOp(Op::RefMut, e) if matches!(e.1, Expr::Id(_)) => {
+                self.expr(e);
+                if let Some(v) = self.lookup(e.0) {
+                    self.vars[v].written = true;
+                }
+            }
+            Expr::Par(e) | Expr::UnaryOp(_, e) => self.expr(e),
+            Expr::BinOp(_, l, r) => {
+                self.expr(l);
+                self.expr(r);
+            }
+            Expr::Num(_) | Expr::Bool(_) | Expr::Unit => (),
+        }
+    }
+
+    fn stmt(&mut self, (_, stmt): &SpanStmt<'a>) {
+        match stmt {
+            Stmt::Let(m, id, _, e) => {
+                self.expr(e);
+                self.declare(*id, *m, false);
+            }
+            Stmt::Assign((s, Expr::Id(_)), e) => {
+                self.expr(e);
+                if let Some(v) = self.lookup(*s) {
+                    self.vars[v].written = true;
+                }
+            }
+            Stmt::Assign(l, e) => {
+                self.expr(e);
+                self.expr(l);
+            }
+            Stmt::If(c, (_, t), e) => {
+                self.expr(c);
+                self.block(t);
+                if let Some((_, e)) = e {
+                    self.block(e);
+                }
+            }
+            Stmt::While(c, (_, b)) => {
+                self.expr(c);
+                self.block(b);
+            }
+            Stmt::Return(e) => {
+                if let Some(e) = e {
+                    self.expr(e);
+                }
+            }
+            Stmt::Expr(e) => self.expr(e),
+            Stmt::Block((_, b)) => self.block(b),
+        }
+    }
+
+    fn block(&mut self, b: &Block<'a>) {
+        self.scopes.push(vec![]);
+        b.iter().for_each(|s| self.stmt(s));
+        self.scopes.pop();
+    }
+}
+
+type Live = BTreeSet<usize>;
+
+// backward liveness, `dead` collects stores not live afterwards
+struct Liveness<'r, 'a> {
+    res: &'r HashMap<usize, usize>,
+    report: bool,
+    dead: Vec<(Span<'a>, usize)>,
+}
+
+impl<'r, 'a> Liveness<'r, 'a> {
+    fn uses(&self, (s, e): &SpanExpr<'a>, live: &mut Live) {
+        match e {
+            Expr::Id(_) => {
+                if let Some(v) = self.res.get(&s.offset) {
+                    live.insert(*v);
+                }
+            }
+            Expr::Call(_, args) | Expr::Macro(_, args, _) => {
+                args.iter().for_each(|a| self.uses(a, live))
+            }
+            Expr::Par(e) | Expr::UnaryOp(_, e) => self.uses(e, live),
+            Expr::BinOp(_, l, r) => {
+                self.uses(l, live);
+                self.uses(r, live);
+            }
+            Expr::Num(_) | Expr::Bool(_) | Expr::Unit => (),
+        }
+    }
+
+    fn store(&mut self, s: Span<'a>, v: usize, live: &mut Live) {
+        if !live.remove(&v) && self.report {
+            self.dead.push((s, v));
+        }
+    }
+
+    // computes the live variables before the statement from those after it
+    fn stmt(&mut self, (s, stmt): &SpanStmt<'a>, mut live: Live) -> Live {
+        match stmt {
+            Stmt::Let(_, id, _, e) => {
+                if let Some(v) = self.res.get(&id.offset) {
+                    self.store(*id, *v, &mut live);
+                }
+                self.uses(e, &mut live);
+            }
+            Stmt::Assign((ls, Expr::Id(_)), e) => {
+                if let Some(v) = self.res.get(&ls.offset) {
+                    self.store(*s, *v, &mut live);
+                }
+                self.uses(e, &mut live);
+            }
+            Stmt::Assign(l, e) => {
+                self.uses(l, &mut live);
+                self.uses(e, &mut live);
+            }
+            Stmt::If(c, (_, t), e) => {
+                let after = live.clone();
+                live = self.block(t, live);
+                match e {
+                    Some((_, e)) => live.extend(self.block(e, after)),
+                    None => live.extend(after),
+                }
+                self.uses(c, &mut live);
+            }
+            Stmt::While(c, (_, b)) => {
+                // iterate to a fixpoint for the loop head, then report once
+                let report = self.report;
+                self.report = false;
+                let mut head = live.clone();
+                self.uses(c, &mut head);
+                loop {
+                    let mut next = live.clone();
+                    next.extend(self.block(b, head.clone()));
+                    self.uses(c, &mut next);
+                    if next == head {
+                        break;
+                    }
+                    head = next;
+                }
+                self.report = report;
+                self.block(b, head.clone());
+                live = head;
+            }
+            Stmt::Return(e) => {
+                live.clear();
+                if let Some(e) = e {
+                    self.uses(e, &mut live);
+                }
+            }
+            Stmt::Expr(e) => self.uses(e, &mut live),
+            Stmt::Block((_, b)) => live = self.block(b, live),
+        }
+        live
+    }
+
+    fn block(&mut self, b: &Block<'a>, live: Live) -> Live {
+        b.iter().rev().fold(live, |live, s| self.stmt(s, live))
+    }
+}
+
+#[test]
+fn test_unused() {
+    let src = "
+fn f(a: i32, _b: i32, mut c: i32) -> i32 {
+    let x: i32 = 1;
+    let mut y: i32 = 2;
+    let _z: i32 = 3;
+    let mut w: i32 = 0;
+    w = 1;
+    let mut v: i32 = 0;
+    let r: &mut i32 = &mut v;
+    *r = 1;
+    return y;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let d: Vec<_> = check_prog(&p).iter().map(|d| d.msg.clone()).collect();
+    assert_eq!(
+        d,
+        vec![
+            "unused parameter `a`",
+            "unused parameter `c`",
+            "variable `c` does not need to be mutable",
+            "unused variable `x`",
+            "variable `y` does not need to be mutable",
+            "variable `w` is assigned to, but never used",
+        ]
+    );
+}
+
+#[test]
+fn test_dead_store() {
+    let src = "
+fn f(mut a: i32, b: bool) -> i32 {
+    a = 1;
+    let mut x: i32 = 5;
+    x = 6;
+    let mut i: i32 = 0;
+    while i < a {
+        if b {
+            x = i;
+        }
+        i = i + 1;
+    }
+    x = 7;
+    if b { x = 8; } else { return x; }
+    return 0;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let d = check_prog(&p);
+    let d: Vec<_> = d.iter().map(|d| (d.span.line, d.msg.as_str())).collect();
+    assert_eq!(
+        d,
+        vec![
+            (2, "value passed to `a` is never read"),
+            (4, "value assigned to `x` is never read"),
+            (5, "value assigned to `x` is never read"),
+            (9, "value assigned to `x` is never read"),
+            (14, "value assigned to `x` is never read"),
+        ]
+    );
+}
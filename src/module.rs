@@ -0,0 +1,985 @@
+// Module files
+//
+// Compiled programs are stored in a versioned binary container, integers
+// are little endian and 32 bits unless noted:
+//
+//   header     "CRBC", version (16 bits)
+//   text       the source text covered by spans, other bytes blanked
+//   spans      offset, length and line of each span of the text
+//   constants  tag (8 bits) and value
+//   types      tag (8 bits), references followed by the referenced type
+//   strings    length and bytes
+//   errors     tag (8 bits) and payload, the errors of `Fail`
+//   functions  identifier (span), entry, parameter types, local names
+//   code       opcode (8 bits) and operands
+//   lines      first instruction and span of each line table entry
+//
+// Loading validates the complete file, including indices, jump targets,
+// the operand stack depth at every instruction, and that every path to a
+// `ret` frees the locals of the frame (after the last borrow), so that no
+// reference outlives its frame. Malformed code then fails with runtime
+// errors of the VM rather than crashing it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::ast::{Macro, Op, Span, Type};
+use crate::bytecode::{Function, Instr, Program};
+use crate::interp::{ErrorKind, Value};
+
+const MAGIC: &[u8; 4] = b"CRBC";
+pub const VERSION: u16 = 1;
+
+// nesting of reference types, deeper types are rejected
+const MAX_TYPE_DEPTH: usize = 64;
+
+const OPS: [Op; 15] = [
+    Op::Eq,
+    Op::Neq,
+    Op::Lt,
+    Op::Gt,
+    Op::And,
+    Op::Or,
+    Op::Add,
+    Op::Sub,
+    Op::Mul,
+    Op::Div,
+    Op::Pow,
+    Op::Not,
+    Op::Ref,
+    Op::RefMut,
+    Op::Deref,
+];
+
+const MACROS: [Macro; 4] = [Macro::Println, Macro::Assert, Macro::AssertEq, Macro::Panic];
+
+// absent optional indices
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    Io(String),
+    Magic,
+    Version(u16),
+    Truncated,
+    TrailingBytes,
+    // section and description
+    Invalid(&'static str, String),
+}
+
+// identifier (span), entry, parameter types and local names (spans)
+type FnEntry = (usize, usize, Vec<Type>, Vec<Option<usize>>);
+
+// a loaded module, see `program`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    text: String,
+    // offset, length and line
+    spans: Vec<(usize, usize, u32)>,
+    code: Vec<Instr>,
+    consts: Vec<Value>,
+    types: Vec<Type>,
+    strings: Vec<String>,
+    errors: Vec<ErrorKind>,
+    fns: Vec<FnEntry>,
+    // first instruction and span
+    lines: Vec<(usize, usize)>,
+}
+
+struct Writer {
+    buf: Vec<u8>,
+    // the name and start of each section, read by the tests to locate fields
+    #[cfg_attr(not(test), allow(dead_code))]
+    sections: Vec<(&'static str, usize)>,
+}
+
+impl Writer {
+    fn section(&mut self, name: &'static str) {
+        self.sections.push((name, self.buf.len()));
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u32(&mut self, v: usize) -> io::Result<()> {
+        if v >= NONE as usize {
+            return Err(invalid_input("index out of range"));
+        }
+        self.buf.extend_from_slice(&(v as u32).to_le_bytes());
+        Ok(())
+    }
+
+    fn opt(&mut self, v: Option<usize>) -> io::Result<()> {
+        match v {
+            Some(v) => self.u32(v),
+            None => {
+                self.buf.extend_from_slice(&NONE.to_le_bytes());
+                Ok(())
+            }
+        }
+    }
+
+    fn str(&mut self, s: &str) -> io::Result<()> {
+        self.u32(s.len())?;
+        self.buf.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn ty(&mut self, t: &Type) {
+        match t {
+            Type::I32 => self.u8(0),
+            Type::Bool => self.u8(1),
+            Type::Unit => self.u8(2),
+            Type::Ref(m, t) => {
+                self.u8(3);
+                self.u8(*m as u8);
+                self.ty(t);
+            }
+        }
+    }
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+fn op_code(op: Op) -> u8 {
+    OPS.iter().position(|o| *o == op).unwrap() as u8
+}
+
+fn macro_code(m: Macro) -> u8 {
+    MACROS.iter().position(|n| *n == m).unwrap() as u8
+}
+
+// the spans of the program, each once
+struct Spans<'a> {
+    spans: Vec<Span<'a>>,
+    index: HashMap<(usize, usize), usize>,
+}
+
+impl<'a> Spans<'a> {
+    fn add(&mut self, s: Span<'a>) -> usize {
+        let spans = &mut self.spans;
+        *self
+            .index
+            .entry((s.offset, s.fragment.len()))
+            .or_insert_with(|| {
+                spans.push(s);
+                spans.len() - 1
+            })
+    }
+
+    // the text of the spans at their offsets, with the line breaks
+    // preceding them, so columns are kept
+    fn text(&self) -> String {
+        let len = self.spans.iter().map(|s| s.offset + s.fragment.len());
+        let mut text = vec![b' '; len.max().unwrap_or(0)];
+        for s in &self.spans {
+            text[s.offset..s.offset + s.fragment.len()].copy_from_slice(s.fragment.as_bytes());
+            let col = s.get_column();
+            if col <= s.offset {
+                text[s.offset - col] = b'\n';
+            }
+        }
+        String::from_utf8(text).unwrap()
+    }
+}
+
+// encodes the program, failing for values and errors that only occur at
+// runtime (references and runtime errors)
+pub fn to_bytes(p: &Program) -> io::Result<Vec<u8>> {
+    encode(p).map(|w| w.buf)
+}
+
+fn encode(p: &Program) -> io::Result<Writer> {
+    let mut spans = Spans {
+        spans: vec![],
+        index: HashMap::new(),
+    };
+    let fns: Vec<_> = (p.fns.iter())
+        .map(|f| {
+            let locals: Vec<_> = f.locals.iter().map(|l| l.map(|s| spans.add(s))).collect();
+            (spans.add(f.id), locals)
+        })
+        .collect();
+    let lines: Vec<_> = p.lines.iter().map(|(pc, s)| (*pc, spans.add(*s))).collect();
+
+    let mut w = Writer {
+        buf: vec![],
+        sections: vec![],
+    };
+    w.buf.extend_from_slice(MAGIC);
+    w.buf.extend_from_slice(&VERSION.to_le_bytes());
+    w.section("text");
+    w.str(&spans.text())?;
+    w.section("spans");
+    w.u32(spans.spans.len())?;
+    for s in &spans.spans {
+        w.u32(s.offset)?;
+        w.u32(s.fragment.len())?;
+        w.u32(s.line as usize)?;
+    }
+    w.section("constants");
+    w.u32(p.consts.len())?;
+    for c in &p.consts {
+        match c {
+            Value::Num(i) => {
+                w.u8(0);
+                w.buf.extend_from_slice(&i.to_le_bytes());
+            }
+            Value::Bool(b) => {
+                w.u8(1);
+                w.u8(*b as u8);
+            }
+            Value::Unit => w.u8(2),
+            Value::Ref(_) => return Err(invalid_input("reference constant")),
+        }
+    }
+    w.section("types");
+    w.u32(p.types.len())?;
+    p.types.iter().for_each(|t| w.ty(t));
+    w.section("strings");
+    w.u32(p.strings.len())?;
+    for s in &p.strings {
+        w.str(s)?;
+    }
+    w.section("errors");
+    w.u32(p.errors.len())?;
+    for e in &p.errors {
+        match e {
+            ErrorKind::UnboundVariable(id) => {
+                w.u8(0);
+                w.str(id)?;
+            }
+            ErrorKind::UnboundFunction(id) => {
+                w.u8(1);
+                w.str(id)?;
+            }
+            ErrorKind::InvalidAssign => w.u8(2),
+            ErrorKind::MissingReturn(id) => {
+                w.u8(3);
+                w.str(id)?;
+            }
+            ErrorKind::BorrowMut(id) => {
+                w.u8(4);
+                w.u8(id.is_some() as u8);
+                w.str(id.as_deref().unwrap_or(""))?;
+            }
+            ErrorKind::AssignImmutable(id) => {
+                w.u8(5);
+                w.str(id)?;
+            }
+            _ => return Err(invalid_input("runtime error in the error pool")),
+        }
+    }
+    w.section("functions");
+    w.u32(p.fns.len())?;
+    for (f, (id, locals)) in p.fns.iter().zip(fns) {
+        w.u32(id)?;
+        w.u32(f.entry)?;
+        w.u32(f.params.len())?;
+        f.params.iter().for_each(|t| w.ty(t));
+        w.u32(locals.len())?;
+        for l in locals {
+            w.opt(l)?;
+        }
+    }
+    w.section("code");
+    w.u32(p.code.len())?;
+    for i in &p.code {
+        match *i {
+            Instr::Const(c) => {
+                w.u8(0);
+                w.u32(c)?;
+            }
+            Instr::Load(l) => {
+                w.u8(1);
+                w.u32(l)?;
+            }
+            Instr::Store(l) => {
+                w.u8(2);
+                w.u32(l)?;
+            }
+            Instr::Let(l) => {
+                w.u8(3);
+                w.u32(l)?;
+            }
+            Instr::Check(t) => {
+                w.u8(4);
+                w.u32(t)?;
+            }
+            Instr::Borrow(l, m) => {
+                w.u8(5);
+                w.u32(l)?;
+                w.u8(m as u8);
+            }
+            Instr::Reborrow(m) => {
+                w.u8(6);
+                w.u8(m as u8);
+            }
+            Instr::Place => w.u8(7),
+            Instr::PlaceMut => w.u8(8),
+            Instr::StoreInd => w.u8(9),
+            Instr::Deref => w.u8(10),
+            Instr::DerefAll => w.u8(11),
+            Instr::Bin(op) => {
+                w.u8(12);
+                w.u8(op_code(op));
+            }
+            Instr::Un(op) => {
+                w.u8(13);
+                w.u8(op_code(op));
+            }
+            Instr::Short(op, t) => {
+                w.u8(14);
+                w.u8(op_code(op));
+                w.u32(t)?;
+            }
+            Instr::Jump(t) => {
+                w.u8(15);
+                w.u32(t)?;
+            }
+            Instr::JumpIfNot(t) => {
+                w.u8(16);
+                w.u32(t)?;
+            }
+            Instr::Pop => w.u8(17),
+            Instr::Call(f, n) => {
+                w.u8(18);
+                w.u32(f)?;
+                w.u32(n)?;
+            }
+            Instr::Ret => w.u8(19),
+            Instr::Exit(s, e) => {
+                w.u8(20);
+                w.u32(s)?;
+                w.u32(e)?;
+            }
+            Instr::Macro(m, n, fmt, cond) => {
+                w.u8(21);
+                w.u8(macro_code(m));
+                w.u32(n)?;
+                w.opt(fmt)?;
+                w.opt(cond)?;
+            }
+            Instr::Fail(e) => {
+                w.u8(22);
+                w.u32(e)?;
+            }
+        }
+    }
+    w.section("lines");
+    w.u32(lines.len())?;
+    for (pc, s) in lines {
+        w.u32(pc)?;
+        w.u32(s)?;
+    }
+    Ok(w)
+}
+
+pub fn save<P: AsRef<Path>>(p: &Program, path: P) -> io::Result<()> {
+    std::fs::write(path, to_bytes(p)?)
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Module, LoadError> {
+    let data = std::fs::read(path).map_err(|e| LoadError::Io(e.to_string()))?;
+    from_bytes(&data)
+}
+
+struct Reader<'d> {
+    data: &'d [u8],
+    // the section being read
+    section: &'static str,
+}
+
+impl<'d> Reader<'d> {
+    fn bytes(&mut self, n: usize) -> Result<&'d [u8], LoadError> {
+        if self.data.len() < n {
+            return Err(LoadError::Truncated);
+        }
+        let (b, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn usize(&mut self) -> Result<usize, LoadError> {
+        self.u32().map(|v| v as usize)
+    }
+
+    fn opt(&mut self) -> Result<Option<usize>, LoadError> {
+        self.u32().map(|v| Some(v as usize).filter(|_| v != NONE))
+    }
+
+    fn bool(&mut self) -> Result<bool, LoadError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(self.invalid(format!("invalid boolean {}", b))),
+        }
+    }
+
+    fn str(&mut self) -> Result<String, LoadError> {
+        let n = self.usize()?;
+        let b = self.bytes(n)?;
+        match std::str::from_utf8(b) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => Err(self.invalid("invalid UTF-8".to_string())),
+        }
+    }
+
+    fn ty(&mut self, depth: usize) -> Result<Type, LoadError> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(self.invalid("type nested too deeply".to_string()));
+        }
+        match self.u8()? {
+            0 => Ok(Type::I32),
+            1 => Ok(Type::Bool),
+            2 => Ok(Type::Unit),
+            3 => {
+                let m = self.bool()?;
+                Ok(Type::Ref(m, Box::new(self.ty(depth + 1)?)))
+            }
+            t => Err(self.invalid(format!("invalid type tag {}", t))),
+        }
+    }
+
+    fn op(&mut self) -> Result<Op, LoadError> {
+        let b = self.u8()?;
+        match OPS.get(b as usize) {
+            Some(op) => Ok(*op),
+            None => Err(self.invalid(format!("invalid operator {}", b))),
+        }
+    }
+
+    // reads `count` entries of a section
+    fn section<T, F>(&mut self, section: &'static str, mut f: F) -> Result<Vec<T>, LoadError>
+    where
+        F: FnMut(&mut Self) -> Result<T, LoadError>,
+    {
+        self.section = section;
+        let n = self.usize()?;
+        let mut v = vec![];
+        for _ in 0..n {
+            v.push(f(self)?);
+        }
+        Ok(v)
+    }
+
+    fn invalid(&self, msg: String) -> LoadError {
+        LoadError::Invalid(self.section, msg)
+    }
+}
+
+fn read_instr(r: &mut Reader) -> Result<Instr, LoadError> {
+    Ok(match r.u8()? {
+        0 => Instr::Const(r.usize()?),
+        1 => Instr::Load(r.usize()?),
+        2 => Instr::Store(r.usize()?),
+        3 => Instr::Let(r.usize()?),
+        4 => Instr::Check(r.usize()?),
+        5 => Instr::Borrow(r.usize()?, r.bool()?),
+        6 => Instr::Reborrow(r.bool()?),
+        7 => Instr::Place,
+        8 => Instr::PlaceMut,
+        9 => Instr::StoreInd,
+        10 => Instr::Deref,
+        11 => Instr::DerefAll,
+        12 => Instr::Bin(r.op()?),
+        13 => Instr::Un(r.op()?),
+        14 => Instr::Short(r.op()?, r.usize()?),
+        15 => Instr::Jump(r.usize()?),
+        16 => Instr::JumpIfNot(r.usize()?),
+        17 => Instr::Pop,
+        18 => Instr::Call(r.usize()?, r.usize()?),
+        19 => Instr::Ret,
+        20 => Instr::Exit(r.usize()?, r.usize()?),
+        21 => {
+            let b = r.u8()?;
+            let m = match MACROS.get(b as usize) {
+                Some(m) => *m,
+                None => return Err(r.invalid(format!("invalid macro {}", b))),
+            };
+            Instr::Macro(m, r.usize()?, r.opt()?, r.opt()?)
+        }
+        22 => Instr::Fail(r.usize()?),
+        b => return Err(r.invalid(format!("invalid opcode {}", b))),
+    })
+}
+
+pub fn from_bytes(data: &[u8]) -> Result<Module, LoadError> {
+    let mut r = Reader {
+        data,
+        section: "header",
+    };
+    if r.bytes(4).map_err(|_| LoadError::Magic)? != MAGIC {
+        return Err(LoadError::Magic);
+    }
+    let version = u16::from_le_bytes([r.u8()?, r.u8()?]);
+    if version != VERSION {
+        return Err(LoadError::Version(version));
+    }
+    r.section = "text";
+    let text = r.str()?;
+    let spans = r.section("spans", |r| {
+        let (offset, len, line) = (r.usize()?, r.usize()?, r.u32()?);
+        let end = offset.checked_add(len).filter(|e| *e <= text.len());
+        match end {
+            Some(end) if text.is_char_boundary(offset) && text.is_char_boundary(end) => {
+                Ok((offset, len, line))
+            }
+            _ => Err(r.invalid(format!("span {}+{} outside of the text", offset, len))),
+        }
+    })?;
+    let consts = r.section("constants", |r| match r.u8()? {
+        0 => Ok(Value::Num(r.u32()? as i32)),
+        1 => Ok(Value::Bool(r.bool()?)),
+        2 => Ok(Value::Unit),
+        t => Err(r.invalid(format!("invalid constant tag {}", t))),
+    })?;
+    let types = r.section("types", |r| r.ty(0))?;
+    let strings = r.section("strings", |r| r.str())?;
+    let errors = r.section("errors", |r| match r.u8()? {
+        0 => Ok(ErrorKind::UnboundVariable(r.str()?)),
+        1 => Ok(ErrorKind::UnboundFunction(r.str()?)),
+        2 => Ok(ErrorKind::InvalidAssign),
+        3 => Ok(ErrorKind::MissingReturn(r.str()?)),
+        4 => {
+            let some = r.bool()?;
+            let id = r.str()?;
+            Ok(ErrorKind::BorrowMut(Some(id).filter(|_| some)))
+        }
+        5 => Ok(ErrorKind::AssignImmutable(r.str()?)),
+        t => Err(r.invalid(format!("invalid error tag {}", t))),
+    })?;
+    let fns = r.section("functions", |r| {
+        let id = r.usize()?;
+        let entry = r.usize()?;
+        let params = r.section("functions", |r| r.ty(0))?;
+        let locals = r.section("functions", |r| r.opt())?;
+        Ok((id, entry, params, locals))
+    })?;
+    let code = r.section("code", read_instr)?;
+    let lines = r.section("lines", |r| Ok((r.usize()?, r.usize()?)))?;
+    if !r.data.is_empty() {
+        return Err(LoadError::TrailingBytes);
+    }
+    let m = Module {
+        text,
+        spans,
+        code,
+        consts,
+        types,
+        strings,
+        errors,
+        fns,
+        lines,
+    };
+    m.check_spans()?;
+    verify(&m.program())?;
+    Ok(m)
+}
+
+impl Module {
+    // span indices, before building the program
+    fn check_spans(&self) -> Result<(), LoadError> {
+        let n = self.spans.len();
+        let mut fn_spans = (self.fns.iter()).flat_map(|(id, _, _, locals)| {
+            std::iter::once(*id).chain(locals.iter().filter_map(|l| *l))
+        });
+        if let Some(s) = fn_spans.find(|s| *s >= n) {
+            return Err(LoadError::Invalid(
+                "functions",
+                format!("invalid span {}", s),
+            ));
+        }
+        if let Some((_, s)) = self.lines.iter().find(|(_, s)| *s >= n) {
+            return Err(LoadError::Invalid("lines", format!("invalid span {}", s)));
+        }
+        Ok(())
+    }
+
+    pub fn program(&self) -> Program<'_> {
+        let span = |i: usize| {
+            let (offset, len, line) = self.spans[i];
+            Span {
+                offset,
+                line,
+                fragment: &self.text[offset..offset + len],
+                extra: (),
+            }
+        };
+        Program {
+            code: self.code.clone(),
+            consts: self.consts.clone(),
+            types: self.types.clone(),
+            strings: self.strings.clone(),
+            errors: self.errors.clone(),
+            fns: (self.fns.iter())
+                .map(|(id, entry, params, locals)| Function {
+                    id: span(*id),
+                    entry: *entry,
+                    params: params.clone(),
+                    locals: locals.iter().map(|l| l.map(span)).collect(),
+                })
+                .collect(),
+            lines: self.lines.iter().map(|(pc, s)| (*pc, span(*s))).collect(),
+        }
+    }
+}
+
+// checks the indices of the instructions and that the operand stack has
+// a unique depth at each instruction, never below the operands of it
+pub fn verify(p: &Program) -> Result<(), LoadError> {
+    let invalid = |section, msg: String| Err(LoadError::Invalid(section, msg));
+    match p.lines.first() {
+        None if p.code.is_empty() => (),
+        Some((0, _)) if p.lines.windows(2).all(|w| w[0].0 < w[1].0) => (),
+        _ => return invalid("lines", "instructions without span".to_string()),
+    }
+    if let Some((pc, _)) = p.lines.iter().find(|(pc, _)| *pc >= p.code.len()) {
+        return invalid("lines", format!("invalid instruction {}", pc));
+    }
+    let mut start = 0;
+    for (n, f) in p.fns.iter().enumerate() {
+        let end = p.fns.get(n + 1).map_or(p.code.len(), |g| g.entry);
+        if f.entry != start || end <= start {
+            return invalid("functions", format!("invalid entry {}", f.entry));
+        }
+        verify_fn(p, f, end).map_err(|msg| LoadError::Invalid("code", msg))?;
+        start = end;
+    }
+    if start != p.code.len() {
+        return invalid("code", "instructions outside of functions".to_string());
+    }
+    Ok(())
+}
+
+fn verify_fn(p: &Program, f: &Function, end: usize) -> Result<(), String> {
+    let nlocals = f.locals.len();
+    if nlocals < f.params.len() {
+        return Err(format!(
+            "`{}` has fewer locals than parameters",
+            f.id.fragment
+        ));
+    }
+    let index = |i: usize, n: usize, what: &str, pc: usize| match i < n {
+        true => Ok(()),
+        false => Err(format!("invalid {} {} at {}", what, i, pc)),
+    };
+    // the stack depth and whether the frame was exited on every path,
+    // paths that may not have exited are merged into the state once more
+    let mut state: Vec<Option<(usize, bool)>> = vec![None; end - f.entry];
+    let mut work = vec![(f.entry, 0, false)];
+    while let Some((pc, d, exited)) = work.pop() {
+        if pc < f.entry || pc >= end {
+            return Err(format!("control leaves `{}` at {}", f.id.fragment, pc));
+        }
+        match state[pc - f.entry] {
+            Some((e, _)) if e != d => return Err(format!("stack depth {} and {} at {}", e, d, pc)),
+            Some((_, x)) if !x || exited => continue,
+            _ => state[pc - f.entry] = Some((d, exited)),
+        }
+        let i = p.code[pc];
+        match i {
+            Instr::Const(c) => index(c, p.consts.len(), "constant", pc)?,
+            Instr::Load(l) | Instr::Store(l) | Instr::Let(l) | Instr::Borrow(l, _) => {
+                index(l, nlocals, "local", pc)?
+            }
+            Instr::Check(t) => index(t, p.types.len(), "type", pc)?,
+            Instr::Call(g, _) => index(g, p.fns.len(), "function", pc)?,
+            Instr::Exit(s, e) if s > e || e > nlocals => {
+                return Err(format!("invalid locals {}..{} at {}", s, e, pc))
+            }
+            Instr::Macro(m, n, fmt, cond) => {
+                index(m.fixed(), n + 1, "argument count", pc)?;
+                for s in fmt.iter().chain(cond.iter()) {
+                    index(*s, p.strings.len(), "string", pc)?;
+                }
+            }
+            Instr::Fail(e) => index(e, p.errors.len(), "error", pc)?,
+            _ => (),
+        }
+        // operands, results
+        let (pops, pushes) = match i {
+            Instr::Const(_) | Instr::Load(_) | Instr::Borrow(..) => (0, 1),
+            Instr::Store(_) | Instr::Let(_) | Instr::Pop | Instr::JumpIfNot(_) => (1, 0),
+            Instr::StoreInd => (2, 0),
+            Instr::Bin(_) => (2, 1),
+            Instr::Call(_, n) | Instr::Macro(_, n, ..) => (n, 1),
+            Instr::Jump(_) | Instr::Exit(..) | Instr::Fail(_) => (0, 0),
+            _ => (1, 1),
+        };
+        if d < pops {
+            return Err(format!("stack underflow at {}", pc));
+        }
+        let d = d - pops + pushes;
+        // borrowed or live locals of the frame
+        let exited = match i {
+            Instr::Exit(0, e) => exited || e == nlocals,
+            Instr::Borrow(..) | Instr::Let(_) => false,
+            _ => exited,
+        };
+        match i {
+            Instr::Jump(t) => work.push((t, d, exited)),
+            Instr::JumpIfNot(t) | Instr::Short(_, t) => {
+                work.push((t, d, exited));
+                work.push((pc + 1, d, exited));
+            }
+            Instr::Ret if !exited => {
+                return Err(format!("return without exiting the frame at {}", pc))
+            }
+            Instr::Ret | Instr::Fail(_) => (),
+            _ => work.push((pc + 1, d, exited)),
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "cannot read module: {}", e),
+            LoadError::Magic => write!(f, "not a crust module"),
+            LoadError::Version(v) => {
+                write!(f, "unsupported module version {} (expected {})", v, VERSION)
+            }
+            LoadError::Truncated => write!(f, "unexpected end of module"),
+            LoadError::TrailingBytes => write!(f, "unexpected data after the end of module"),
+            LoadError::Invalid(section, msg) => write!(f, "malformed {}: {}", section, msg),
+        }
+    }
+}
+
+#[test]
+fn test_module() {
+    let src = "
+fn f(x: &mut i32) {
+    *x = *x + 1;
+}
+
+fn main() -> i32 {
+    let mut y: i32 = 1;
+    f(&mut y);
+    assert!(y == 2, \"{}\", y);
+    let r: &i32 = &y;
+    return *r * 10 / (y - 2);
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let prog = crate::bytecode::compile(&p);
+    verify(&prog).unwrap();
+    let bytes = to_bytes(&prog).unwrap();
+    let m = from_bytes(&bytes).unwrap();
+    let loaded = m.program();
+    assert_eq!(loaded, prog);
+    assert_eq!(loaded.to_string(), prog.to_string());
+    let e = crate::vm::run(&loaded, "main", vec![]).unwrap_err();
+    assert_eq!(Err(e), crate::vm::run(&prog, "main", vec![]));
+    assert_eq!(to_bytes(&loaded).unwrap(), bytes);
+
+    // every truncation is detected
+    for n in 0..bytes.len() {
+        assert!(from_bytes(&bytes[..n]).is_err());
+    }
+    assert_eq!(from_bytes(b"\x7fELF"), Err(LoadError::Magic));
+    let mut b = bytes.clone();
+    b[4] = 9;
+    assert_eq!(from_bytes(&b), Err(LoadError::Version(9)));
+    b = bytes.clone();
+    b.push(0);
+    assert_eq!(from_bytes(&b), Err(LoadError::TrailingBytes));
+
+    // altered code
+    let mut bad = prog.clone();
+    bad.code[1] = Instr::Jump(1000);
+    let e = from_bytes(&to_bytes(&bad).unwrap()).unwrap_err();
+    assert!(matches!(e, LoadError::Invalid("code", _)), "{}", e);
+    bad.code[1] = prog.code[1];
+    bad.code[0] = Instr::Pop;
+    assert_eq!(
+        from_bytes(&to_bytes(&bad).unwrap()),
+        Err(LoadError::Invalid(
+            "code",
+            "stack underflow at 0".to_string()
+        ))
+    );
+    bad.code[0] = Instr::Load(1000);
+    assert!(from_bytes(&to_bytes(&bad).unwrap()).is_err());
+}
+
+#[test]
+fn test_escaping_reference() {
+    use crate::bytecode::Function;
+
+    // `g` jumps over the exit of its frame, returning a reference to its
+    // local, which `main` dereferences after the return
+    let f = |id, entry| Function {
+        id: Span::new(id),
+        entry,
+        params: vec![],
+        locals: vec![None],
+    };
+    let mut prog = Program {
+        code: vec![
+            Instr::Borrow(0, false),
+            Instr::Jump(3),
+            Instr::Exit(0, 1),
+            Instr::Ret,
+            Instr::Call(0, 0),
+            Instr::Deref,
+            Instr::Exit(0, 1),
+            Instr::Ret,
+        ],
+        fns: vec![f("g", 0), f("main", 4)],
+        lines: vec![(0, Span::new("g"))],
+        ..Program::default()
+    };
+    let e = from_bytes(&to_bytes(&prog).unwrap()).unwrap_err();
+    assert_eq!(
+        e,
+        LoadError::Invalid("code", "return without exiting the frame at 3".to_string())
+    );
+    // the VM reports the dangling reference of unverified code
+    let e = crate::vm::run(&prog, "main", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Dangling(None));
+
+    // through the exit, the local is not live and cannot be borrowed
+    prog.code[1] = Instr::Jump(2);
+    verify(&prog).unwrap();
+    let e = crate::vm::run(&prog, "main", vec![]).unwrap_err();
+    assert_eq!((e.kind, e.backtrace.len()), (ErrorKind::Dangling(None), 2));
+
+    // a local borrowed after the exit
+    prog.code.splice(
+        0..3,
+        vec![
+            Instr::Const(0),
+            Instr::Let(0),
+            Instr::Exit(0, 1),
+            Instr::Borrow(0, false),
+        ],
+    );
+    prog.consts = vec![Value::Num(1)];
+    prog.fns[1].entry = 5;
+    assert_eq!(
+        verify(&prog),
+        Err(LoadError::Invalid(
+            "code",
+            "return without exiting the frame at 4".to_string()
+        ))
+    );
+}
+
+#[test]
+fn test_malformed() {
+    // one entry per section, at known offsets
+    let prog = Program {
+        code: vec![Instr::Const(0), Instr::Exit(0, 0), Instr::Ret],
+        consts: vec![Value::Num(7)],
+        types: vec![Type::I32],
+        strings: vec!["{}".to_string()],
+        errors: vec![ErrorKind::InvalidAssign],
+        fns: vec![Function {
+            id: Span::new("main"),
+            entry: 0,
+            params: vec![],
+            locals: vec![],
+        }],
+        lines: vec![(0, Span::new("main"))],
+    };
+    let Writer {
+        buf: bytes,
+        sections,
+    } = encode(&prog).unwrap();
+    assert_eq!(from_bytes(&bytes).unwrap().program(), prog);
+    // the start of a section, its length or count, and the offset of a
+    // field of its entries
+    let start = |section: &str| sections.iter().find(|(s, _)| *s == section).unwrap().1;
+    let at = |section: &str, field: usize| start(section) + 4 + field;
+    let patch = |at: usize, v: &[u8]| {
+        let mut b = bytes.clone();
+        b[at..at + v.len()].copy_from_slice(v);
+        from_bytes(&b)
+    };
+    let u32 = |v: u32| v.to_le_bytes();
+    let invalid = |section, msg: &str| Err(LoadError::Invalid(section, msg.to_string()));
+
+    // truncated: lengths and counts past the end
+    assert_eq!(
+        patch(start("text"), &u32(NONE - 1)),
+        Err(LoadError::Truncated)
+    );
+    assert_eq!(
+        patch(start("strings"), &u32(1000)),
+        Err(LoadError::Truncated)
+    );
+    assert_eq!(patch(start("code"), &u32(1000)), Err(LoadError::Truncated));
+
+    // corrupted tags and bytes, those of the first entry
+    assert_eq!(
+        patch(at("constants", 0), &[7]),
+        invalid("constants", "invalid constant tag 7")
+    );
+    assert_eq!(
+        patch(at("types", 0), &[9]),
+        invalid("types", "invalid type tag 9")
+    );
+    // the string after its length
+    assert_eq!(
+        patch(at("strings", 4), &[0xff]),
+        invalid("strings", "invalid UTF-8")
+    );
+    assert_eq!(
+        patch(at("errors", 0), &[6]),
+        invalid("errors", "invalid error tag 6")
+    );
+    assert_eq!(
+        patch(at("code", 0), &[23]),
+        invalid("code", "invalid opcode 23")
+    );
+
+    // out-of-range offsets and indices: the offset of the span, the
+    // identifier and entry of `main`, the operands of `Const` and the
+    // second of `Exit` following it, the instruction and span of the line
+    assert_eq!(
+        patch(at("spans", 0), &u32(3)),
+        invalid("spans", "span 3+4 outside of the text")
+    );
+    assert_eq!(
+        patch(at("functions", 0), &u32(1)),
+        invalid("functions", "invalid span 1")
+    );
+    assert_eq!(
+        patch(at("functions", 4), &u32(1)),
+        invalid("functions", "invalid entry 1")
+    );
+    assert_eq!(
+        patch(at("code", 1), &u32(1)),
+        invalid("code", "invalid constant 1 at 0")
+    );
+    assert_eq!(
+        patch(at("code", 5 + 5), &u32(1)),
+        invalid("code", "invalid locals 0..1 at 1")
+    );
+    assert_eq!(
+        patch(at("lines", 0), &u32(2)),
+        invalid("lines", "instructions without span")
+    );
+    assert_eq!(
+        patch(at("lines", 4), &u32(5)),
+        invalid("lines", "invalid span 5")
+    );
+}
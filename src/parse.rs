@@ -1,35 +1,97 @@
 extern crate nom;
 
+use std::cell::Cell;
 use std::iter::Peekable;
 use std::slice::Iter;
 
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::char,
-    character::complete::{digit1, multispace0},
-    combinator::{cut, map},
-    error::ParseError,
-    multi::many1,
-    sequence::{delimited, preceded},
-    IResult,
+    bytes::complete::{tag, take_while, take_while1},
+    character::complete::{alpha1, char, digit1, multispace0},
+    combinator::{all_consuming, cut, map, map_res, not, opt, recognize, verify},
+    error::{ErrorKind, ParseError},
+    multi::{many0, many1, separated_list},
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    Err, IResult, Offset, Slice,
 };
 
-use crate::ast::{Expr, Op, Span, SpanExpr};
+use crate::ast::{
+    get_prec, Ass, Expr, FnDecl, Macro, Op, Param, Prog, Span, SpanBlock, SpanExpr, SpanStmt,
+    SpanType, Stmt, Type,
+};
+use crate::diagnostic::Diagnostic;
+
+// maximum nesting of parentheses, blocks, prefix and right-associative
+// operators, see `parse_with_limit`, parsing uses up to 10 KB of host
+// stack per level (debug build)
+pub const MAX_NESTING: usize = 64;
+
+// maximum depth of an expression tree, e.g., the length of a chain of
+// left-associative operators, bounding the recursion of the passes over
+// the AST
+pub const MAX_EXPR_DEPTH: usize = 256;
+
+// the parser state, current nesting and its limit, passed to all parsers
+// that may nest
+pub struct Nesting {
+    depth: Cell<usize>,
+    max: usize,
+}
+
+impl Nesting {
+    pub fn new(max: usize) -> Self {
+        Nesting {
+            depth: Cell::new(0),
+            max,
+        }
+    }
+}
+
+impl Default for Nesting {
+    fn default() -> Self {
+        Nesting::new(MAX_NESTING)
+    }
+}
+
+const KEYWORDS: [&str; 11] = [
+    "let", "mut", "fn", "if", "else", "while", "return", "true", "false", "i32", "bool",
+];
 
 pub fn parse_i32(i: Span) -> IResult<Span, (Span, i32)> {
-    map(digit1, |digit_str: Span| {
-        (digit_str, digit_str.fragment.parse::<i32>().unwrap())
+    map_res(digit1, |digit_str: Span| {
+        digit_str.fragment.parse::<i32>().map(|v| (digit_str, v))
     })(i)
 }
 
+fn is_id_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+pub fn parse_id(i: Span) -> IResult<Span, Span> {
+    verify(
+        recognize(pair(alt((alpha1, tag("_"))), take_while(is_id_char))),
+        |s: &Span| !KEYWORDS.contains(&s.fragment),
+    )(i)
+}
+
+// matches the keyword only if not followed by an identifier character
+pub(crate) fn keyword<'a>(kw: &'static str) -> impl Fn(Span<'a>) -> IResult<Span<'a>, Span<'a>> {
+    terminated(tag(kw), not(take_while1(is_id_char)))
+}
+
 fn parse_op(i: Span) -> IResult<Span, (Span, Op)> {
     alt((
         map(tag("=="), |s| (s, Op::Eq)),
         map(tag("!="), |s| (s, Op::Neq)),
         map(tag("**"), |s| (s, Op::Pow)),
         map(tag("&&"), |s| (s, Op::And)),
+        map(recognize(pair(char('&'), ms(keyword("mut")))), |s| {
+            (s, Op::RefMut)
+        }),
+        map(tag("&"), |s| (s, Op::Ref)),
         map(tag("||"), |s| (s, Op::Or)),
+        map(tag("<"), |s| (s, Op::Lt)),
+        map(tag(">"), |s| (s, Op::Gt)),
         map(tag("+"), |s| (s, Op::Add)),
         map(tag("-"), |s| (s, Op::Sub)),
         map(tag("*"), |s| (s, Op::Mul)),
@@ -41,143 +103,665 @@ fn parse_op(i: Span) -> IResult<Span, (Span, Op)> {
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token<'a> {
     Num(i32),
+    Bool(bool),
+    Unit,
+    Id(&'a str),
+    Call(&'a str, Vec<(Span<'a>, Vec<SpanToken<'a>>)>),
     Par(Vec<SpanToken<'a>>),
     Op(Op),
+    // string literal, the content between the quotes
+    Str(Span<'a>),
+    Macro(Macro, Vec<(Span<'a>, Vec<SpanToken<'a>>)>),
 }
 
 type SpanToken<'a> = (Span<'a>, Token<'a>);
 
-fn parse_terminal(i: Span) -> IResult<Span, SpanToken> {
+// string literals have no escapes and do not span lines
+fn parse_str(i: Span) -> IResult<Span, SpanToken> {
+    map(
+        spanned(delimited(
+            char('"'),
+            take_while(|c| c != '"' && c != '\n'),
+            char('"'),
+        )),
+        |(s, content)| (s, Token::Str(content)),
+    )(i)
+}
+
+// the name of a builtin macro, followed by `!`
+fn parse_macro(i: Span) -> IResult<Span, (Span, Macro)> {
+    terminated(
+        alt((
+            map(keyword("println"), |s| (s, Macro::Println)),
+            map(keyword("assert_eq"), |s| (s, Macro::AssertEq)),
+            map(keyword("assert"), |s| (s, Macro::Assert)),
+            map(keyword("panic"), |s| (s, Macro::Panic)),
+        )),
+        char('!'),
+    )(i)
+}
+
+// comma separated arguments in parentheses
+fn parse_args<'a>(
+    n: &Nesting,
+    i: Span<'a>,
+) -> IResult<Span<'a>, Vec<(Span<'a>, Vec<SpanToken<'a>>)>> {
+    parse_par(
+        n,
+        separated_list(preceded(multispace0, char(',')), |i| parse_tokens(n, i)),
+        i,
+    )
+}
+
+fn parse_terminal<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, SpanToken<'a>> {
     alt((
         map(parse_i32, |(s, v)| (s, Token::Num(v))),
-        map(parse_par(parse_tokens), |(s, tokens)| {
-            (s, Token::Par(tokens))
+        map(keyword("true"), |s| (s, Token::Bool(true))),
+        map(keyword("false"), |s| (s, Token::Bool(false))),
+        map(tag("()"), |s| (s, Token::Unit)),
+        parse_str,
+        map(pair(parse_macro, |i| parse_args(n, i)), |((s, m), args)| {
+            (s, Token::Macro(m, args))
+        }),
+        map(pair(parse_id, |i| parse_args(n, i)), |(s, args)| {
+            (s, Token::Call(s.fragment, args))
         }),
+        map(parse_id, |s| (s, Token::Id(s.fragment))),
+        map(
+            |i| parse_par(n, |i| parse_tokens(n, i), i),
+            |(s, tokens)| (s, Token::Par(tokens)),
+        ),
     ))(i)
 }
 
-fn parse_token(i: Span) -> IResult<Span, SpanToken> {
+fn parse_token<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, SpanToken<'a>> {
     preceded(
         multispace0,
-        alt((map(parse_op, |(s, op)| (s, Token::Op(op))), parse_terminal)),
+        alt((map(parse_op, |(s, op)| (s, Token::Op(op))), |i| {
+            parse_terminal(n, i)
+        })),
     )(i)
 }
 
 // I think the outer span is wrong
-fn parse_tokens(i: Span) -> IResult<Span, (Span, Vec<SpanToken>)> {
-    map(many1(parse_token), |tokens| (i, tokens))(i)
+pub(crate) fn parse_tokens<'a>(
+    n: &Nesting,
+    i: Span<'a>,
+) -> IResult<Span<'a>, (Span<'a>, Vec<SpanToken<'a>>)> {
+    map(many1(|i| parse_token(n, i)), |tokens| (i, tokens))(i)
 }
 
-fn compute_atom<'a>(t: &mut Peekable<Iter<SpanToken<'a>>>) -> SpanExpr<'a> {
-    match t.next() {
-        Some((s, Token::Num(i))) => (*s, Expr::Num(*i)),
-        Some((_, Token::Par(v))) => climb(&mut v.iter().peekable(), 0),
-        Some((s, Token::Op(op))) => (*s, Expr::UnaryOp(*op, Box::new(climb(t, 4)))), // assume highest precedence
-        _ => panic!("error in compute atom"),
+pub(crate) type ClimbError<'a> = (Span<'a>, ErrorKind);
+
+// an expression and its depth, see `MAX_EXPR_DEPTH`
+type Climbed<'a> = Result<(SpanExpr<'a>, usize), ClimbError<'a>>;
+
+// the depth of a node over children of depth `d`, expressions deeper than
+// `MAX_EXPR_DEPTH` are reported as `LengthValue` at the node
+fn node<'a>(s: Span<'a>, d: usize) -> Result<usize, ClimbError<'a>> {
+    match d + 1 {
+        d if d > MAX_EXPR_DEPTH => Err((s, ErrorKind::LengthValue)),
+        d => Ok(d),
     }
 }
 
-fn climb<'a>(
+// On error, the span of the offending token is returned,
+// or `prev` (the span of the preceding token) if the tokens ran out.
+fn compute_atom<'a>(
+    n: &Nesting,
     t: &mut Peekable<Iter<SpanToken<'a>>>,
-    min_prec: u8,
-) -> SpanExpr<'a> {
-    let mut result: SpanExpr = compute_atom(t);
-
-    loop {
-        match t.peek() {
-            Some((s, Token::Op(op))) => {
-                let (prec, ass) = get_prec(op);
-                if prec < min_prec {
-                    break;
-                };
-                let next_prec = prec
-                    + match ass {
-                        Ass::Left => 1,
-                        _ => 0,
-                    };
-                t.next();
-                let rhs = climb(t, next_prec);
-                result = (*s, Expr::BinOp(*op, Box::new(result), Box::new(rhs)))
+    prev: Span<'a>,
+) -> Climbed<'a> {
+    match t.next() {
+        Some((s, Token::Num(i))) => Ok(((*s, Expr::Num(*i)), 1)),
+        Some((s, Token::Bool(b))) => Ok(((*s, Expr::Bool(*b)), 1)),
+        Some((s, Token::Unit)) => Ok(((*s, Expr::Unit), 1)),
+        Some((s, Token::Id(id))) => Ok(((*s, Expr::Id(id)), 1)),
+        Some((s, Token::Call(id, args))) => {
+            let (args, d) = climb_args(n, args.iter())?;
+            Ok(((*s, Expr::Call(id, args)), node(*s, d)?))
+        }
+        Some((s, Token::Par(v))) => deeper(n, *s, 1, || climb_seq(n, v, *s)),
+        Some((s, Token::Macro(m, args))) => compute_macro(n, *s, *m, args),
+        // strings are only allowed as format strings
+        Some((s, Token::Str(_))) => Err((*s, ErrorKind::Verify)),
+        Some((s, Token::Op(op))) => match op {
+            // assume highest precedence
+            Op::Add | Op::Sub | Op::Not | Op::Ref | Op::RefMut => {
+                let (e, d) = deeper(n, *s, 1, || climb(n, t, 7, *s))?;
+                Ok(((*s, Expr::UnaryOp(*op, Box::new(e))), node(*s, d)?))
             }
-            _ => {
-                break;
+            // prefix `*` dereferences
+            Op::Mul => {
+                let (e, d) = deeper(n, *s, 1, || climb(n, t, 7, *s))?;
+                Ok(((*s, Expr::UnaryOp(Op::Deref, Box::new(e))), node(*s, d)?))
             }
-        }
+            // `&&e` and `**e` are two prefix operators
+            Op::And | Op::Pow => {
+                let op = if *op == Op::And { Op::Ref } else { Op::Deref };
+                let (e, d) = deeper(n, *s, 2, || climb(n, t, 7, *s))?;
+                let e = (*s, Expr::UnaryOp(op, Box::new(e)));
+                let d = node(*s, node(*s, d)?)?;
+                Ok(((*s, Expr::UnaryOp(op, Box::new(e))), d))
+            }
+            _ => Err((*s, ErrorKind::Verify)),
+        },
+        None => Err((prev, ErrorKind::Verify)),
+    }
+}
+
+// the arguments of a call or macro and their greatest depth
+fn climb_args<'a, 'b>(
+    n: &Nesting,
+    args: impl Iterator<Item = &'b (Span<'a>, Vec<SpanToken<'a>>)>,
+) -> Result<(Vec<SpanExpr<'a>>, usize), ClimbError<'a>>
+where
+    'a: 'b,
+{
+    let mut depth = 0;
+    let mut es = vec![];
+    for (s, v) in args {
+        let (e, d) = deeper(n, *s, 1, || climb_seq(n, v, *s))?;
+        depth = depth.max(d);
+        es.push(e);
     }
-    result
+    Ok((es, depth))
 }
 
-pub fn test(s: &str, v: i32) {
-    match parse_tokens(Span::new(s)) {
-        Ok((Span { fragment: "", .. }, (_, t))) => {
-            let mut t = t.iter().peekable();
-            println!("{:?}", &t);
-            let e = climb(&mut t, 0);
-            println!("{:?}", &e);
-            println!("eval {} {}", math_eval(&e), v);
-            assert_eq!(math_eval(&e), v);
+// A macro takes its fixed arguments, optionally followed by a format
+// string and one argument per `{}`. Wrong argument counts are reported
+// as `ManyMN` at the macro, mismatching format strings as `Count`.
+fn compute_macro<'a>(
+    n: &Nesting,
+    s: Span<'a>,
+    m: Macro,
+    args: &[(Span<'a>, Vec<SpanToken<'a>>)],
+) -> Climbed<'a> {
+    if args.len() < m.fixed() {
+        return Err((s, ErrorKind::ManyMN));
+    }
+    let (fixed, rest) = args.split_at(m.fixed());
+    let fmt = match rest.first() {
+        None => None,
+        Some((_, t)) => match t.as_slice() {
+            [(fs, Token::Str(f))] => match placeholders(f.fragment) {
+                Some(n) if n == rest.len() - 1 => Some(*f),
+                _ => return Err((*fs, ErrorKind::Count)),
+            },
+            // the format string must be a literal
+            _ => return Err((t[0].0, ErrorKind::Verify)),
+        },
+    };
+    let (args, d) = climb_args(n, fixed.iter().chain(rest.iter().skip(1)))?;
+    Ok(((s, Expr::Macro(m, args, fmt)), node(s, d)?))
+}
+
+// the number of `{}` in a format string, None for unmatched braces
+fn placeholders(fmt: &str) -> Option<usize> {
+    let mut n = 0;
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('}')) => n += 1,
+            ('{', Some('{')) | ('}', Some('}')) => (),
+            ('{', _) | ('}', _) => return None,
+            _ => continue,
         }
-        Ok((s, t)) => println!(
-            "parse incomplete, \n parsed tokens \t{:?}, \n remaining \t{:?}",
-            t, s
+        chars.next();
+    }
+    Some(n)
+}
+
+// runs `f` `k` levels deeper, failing with `TooLarge` beyond the limit
+fn deeper<'a, T, F>(n: &Nesting, s: Span<'a>, k: usize, f: F) -> Result<T, ClimbError<'a>>
+where
+    F: FnOnce() -> Result<T, ClimbError<'a>>,
+{
+    let depth = n.depth.get();
+    if depth + k > n.max {
+        return Err((s, ErrorKind::TooLarge));
+    }
+    n.depth.set(depth + k);
+    let r = f();
+    n.depth.set(depth);
+    r
+}
+
+fn climb<'a>(
+    n: &Nesting,
+    t: &mut Peekable<Iter<SpanToken<'a>>>,
+    min_prec: u8,
+    prev: Span<'a>,
+) -> Climbed<'a> {
+    let (mut result, mut depth) = compute_atom(n, t, prev)?;
+    while let Some((s, Token::Op(op))) = t.peek() {
+        let (prec, ass) = match get_prec(op) {
+            Some(p) => p,
+            None => break,
+        };
+        if prec < min_prec {
+            break;
+        };
+        let next_prec = prec
+            + match ass {
+                Ass::Left => 1,
+                _ => 0,
+            };
+        t.next();
+        // the right operand of a right-associative operator takes the rest
+        // of the chain, one level deeper, that of a left-associative one
+        // ends at the next operator binding no tighter
+        let k = match ass {
+            Ass::Left => 0,
+            Ass::Right => 1,
+        };
+        let (rhs, d) = deeper(n, *s, k, || climb(n, t, next_prec, *s))?;
+        depth = node(*s, depth.max(d))?;
+        result = (*s, Expr::BinOp(*op, Box::new(result), Box::new(rhs)))
+    }
+    Ok((result, depth))
+}
+
+// climbs the complete token sequence, left over tokens are rejected
+pub(crate) fn climb_all<'a>(
+    n: &Nesting,
+    v: &[SpanToken<'a>],
+    s: Span<'a>,
+) -> Result<SpanExpr<'a>, ClimbError<'a>> {
+    climb_seq(n, v, s).map(|(e, _)| e)
+}
+
+// as `climb_all`, with the depth of the expression
+fn climb_seq<'a>(n: &Nesting, v: &[SpanToken<'a>], s: Span<'a>) -> Climbed<'a> {
+    let mut t = v.iter().peekable();
+    let e = climb(n, &mut t, 0, s)?;
+    match t.next() {
+        Some((s, _)) => Err((*s, ErrorKind::Verify)),
+        None => Ok(e),
+    }
+}
+
+pub fn parse_expr<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, SpanExpr<'a>> {
+    let (rest, (s, t)) = parse_tokens(n, i)?;
+    match climb_all(n, &t, s) {
+        Ok(e) => Ok((rest, e)),
+        Err(e) => Err(Err::Failure(e)),
+    }
+}
+
+fn parse_type<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, SpanType<'a>> {
+    alt((
+        map(keyword("i32"), |s| (s, Type::I32)),
+        map(keyword("bool"), |s| (s, Type::Bool)),
+        map(tag("()"), |s| (s, Type::Unit)),
+        map(
+            spanned(|i| {
+                nested(
+                    n,
+                    preceded(
+                        char('&'),
+                        pair(opt(ms(keyword("mut"))), cut(ms(|i| parse_type(n, i)))),
+                    ),
+                    i,
+                )
+            }),
+            |(s, (m, (_, t)))| (s, Type::Ref(m.is_some(), Box::new(t))),
+        ),
+    ))(i)
+}
+
+fn parse_let<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, Stmt<'a>> {
+    map(
+        preceded(
+            keyword("let"),
+            cut(tuple((
+                opt(ms(keyword("mut"))),
+                ms(parse_id),
+                preceded(ms(char(':')), ms(|i| parse_type(n, i))),
+                preceded(ms(char('=')), |i| parse_expr(n, i)),
+                ms(char(';')),
+            ))),
+        ),
+        |(m, id, ty, e, _)| Stmt::Let(m.is_some(), id, ty, e),
+    )(i)
+}
+
+fn parse_if<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, Stmt<'a>> {
+    map(
+        preceded(
+            keyword("if"),
+            cut(tuple((
+                |i| parse_expr(n, i),
+                ms(|i| parse_block(n, i)),
+                opt(preceded(
+                    ms(keyword("else")),
+                    cut(ms(alt((
+                        |i| parse_block(n, i),
+                        // else if, wrapped in a block of its own
+                        map(
+                            |i| nested(n, spanned(|i| parse_if(n, i)), i),
+                            |(s, stmt)| (s, vec![(s, stmt)]),
+                        ),
+                    )))),
+                )),
+            ))),
+        ),
+        |(c, t, e)| Stmt::If(c, t, e),
+    )(i)
+}
+
+fn parse_while<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, Stmt<'a>> {
+    map(
+        preceded(
+            keyword("while"),
+            cut(pair(|i| parse_expr(n, i), ms(|i| parse_block(n, i)))),
+        ),
+        |(c, b)| Stmt::While(c, b),
+    )(i)
+}
+
+fn parse_return<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, Stmt<'a>> {
+    map(
+        preceded(
+            keyword("return"),
+            cut(terminated(opt(|i| parse_expr(n, i)), ms(char(';')))),
         ),
-        Err(err) => println!("{:?}", err),
+        Stmt::Return,
+    )(i)
+}
+
+pub fn parse_stmt<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, SpanStmt<'a>> {
+    ms(spanned(alt((
+        |i| parse_let(n, i),
+        |i| parse_if(n, i),
+        |i| parse_while(n, i),
+        |i| parse_return(n, i),
+        map(|i| parse_block(n, i), Stmt::Block),
+        map(
+            tuple((
+                |i| parse_expr(n, i),
+                ms(char('=')),
+                |i| parse_expr(n, i),
+                ms(char(';')),
+            )),
+            |(l, _, r, _)| Stmt::Assign(l, r),
+        ),
+        map(terminated(|i| parse_expr(n, i), ms(char(';'))), Stmt::Expr),
+    ))))(i)
+}
+
+pub fn parse_block<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, SpanBlock<'a>> {
+    spanned(|i| {
+        nested(
+            n,
+            delimited(char('{'), many0(|i| parse_stmt(n, i)), cut(ms(char('}')))),
+            i,
+        )
+    })(i)
+}
+
+fn parse_param<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, Param<'a>> {
+    map(
+        tuple((
+            opt(keyword("mut")),
+            ms(parse_id),
+            preceded(ms(char(':')), ms(|i| parse_type(n, i))),
+        )),
+        |(m, id, ty)| Param {
+            mutable: m.is_some(),
+            id,
+            ty,
+        },
+    )(i)
+}
+
+pub fn parse_fn<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, FnDecl<'a>> {
+    map(
+        preceded(
+            keyword("fn"),
+            cut(tuple((
+                ms(parse_id),
+                delimited(
+                    ms(char('(')),
+                    separated_list(ms(char(',')), ms(|i| parse_param(n, i))),
+                    ms(char(')')),
+                ),
+                opt(preceded(ms(tag("->")), ms(|i| parse_type(n, i)))),
+                ms(|i| parse_block(n, i)),
+            ))),
+        ),
+        |(id, params, ret, body)| FnDecl {
+            id,
+            params,
+            ret,
+            body,
+        },
+    )(i)
+}
+
+pub fn parse_prog<'a>(n: &Nesting, i: Span<'a>) -> IResult<Span<'a>, Prog<'a>> {
+    all_consuming(terminated(many0(ms(|i| parse_fn(n, i))), multispace0))(i)
+}
+
+// parses a complete program, the first syntax error is reported as a diagnostic
+pub fn parse<'a>(src: &'a str) -> Result<Prog<'a>, Diagnostic<'a>> {
+    parse_with_limit(src, MAX_NESTING)
+}
+
+// as `parse`, rejecting programs nested deeper than `max_nesting`
+pub fn parse_with_limit<'a>(src: &'a str, max_nesting: usize) -> Result<Prog<'a>, Diagnostic<'a>> {
+    let n = Nesting::new(max_nesting);
+    parse_prog(&n, Span::new(src))
+        .map(|(_, prog)| prog)
+        .map_err(|e| parse_error(&n, e))
+}
+
+// a single expression, e.g., entered in a debugger
+pub fn parse_expr_all<'a>(src: &'a str) -> Result<SpanExpr<'a>, Diagnostic<'a>> {
+    let n = Nesting::default();
+    let r = all_consuming(terminated(|i| parse_expr(&n, i), multispace0))(Span::new(src));
+    r.map(|(_, e)| e).map_err(|e| parse_error(&n, e))
+}
+
+// the diagnostic of a syntax error, `n` gives the nesting limit
+pub fn parse_error<'a>(n: &Nesting, e: Err<(Span<'a>, ErrorKind)>) -> Diagnostic<'a> {
+    match e {
+        Err::Error((s, ErrorKind::TooLarge)) | Err::Failure((s, ErrorKind::TooLarge)) => {
+            Diagnostic::error(s, format!("nesting exceeds the limit of {} levels", n.max))
+        }
+        Err::Error((s, ErrorKind::LengthValue)) | Err::Failure((s, ErrorKind::LengthValue)) => {
+            Diagnostic::error(
+                s,
+                format!("expression exceeds the depth limit of {}", MAX_EXPR_DEPTH),
+            )
+        }
+        Err::Error((s, ErrorKind::ManyMN)) | Err::Failure((s, ErrorKind::ManyMN)) => {
+            Diagnostic::error(s, format!("wrong number of arguments to `{}!`", s.fragment))
+        }
+        Err::Error((s, ErrorKind::Count)) | Err::Failure((s, ErrorKind::Count)) => {
+            Diagnostic::error(
+                s,
+                "format string does not match the number of arguments".to_string(),
+            )
+        }
+        Err::Error((s, _)) | Err::Failure((s, _)) => syntax_error(s),
+        Err::Incomplete(_) => unreachable!(), // only complete parsers are used
+    }
+}
+
+pub fn syntax_error(s: Span) -> Diagnostic {
+    match s.fragment.split_whitespace().next() {
+        None => Diagnostic::error(s, "unexpected end of input".to_string()),
+        Some(t) => Diagnostic::error(s, format!("unexpected `{}`", t)),
     }
 }
 
 // helpers
-fn parse_par<'a, O, F, E>(
-    inner: F,
-) -> impl Fn(Span<'a>) -> IResult<Span<'a>, O, E>
+fn parse_par<'a, O, F>(n: &Nesting, inner: F, i: Span<'a>) -> IResult<Span<'a>, O>
 where
-    F: Fn(Span<'a>) -> IResult<Span<'a>, O, E>,
-    E: ParseError<Span<'a>>,
+    F: Fn(Span<'a>) -> IResult<Span<'a>, O>,
 {
     // delimited allows us to split up the input
     // cut allwos us to consume the input (and prevent backtracking)
-    delimited(char('('), preceded(multispace0, inner), cut(char(')')))
-}
-
-fn math_eval(e: &SpanExpr) -> i32 {
-    match e.clone().1 {
-        Expr::Num(i) => i,
-        Expr::BinOp(op, l, r) => {
-            let lv = math_eval(&l);
-            let rv = math_eval(&r);
-            match op {
-                Op::Add => lv + rv,
-                Op::Sub => lv - rv,
-                Op::Mul => lv * rv,
-                Op::Div => lv / rv,
-                Op::Pow => lv.pow(rv as u32),
-                _ => unimplemented!(),
-            }
+    nested(
+        n,
+        delimited(char('('), preceded(multispace0, inner), cut(ms(char(')')))),
+        i,
+    )
+}
+
+// applies `inner` to `i` one level deeper, see `deeper`
+fn nested<'a, O, F>(n: &Nesting, inner: F, i: Span<'a>) -> IResult<Span<'a>, O>
+where
+    F: Fn(Span<'a>) -> IResult<Span<'a>, O>,
+{
+    match deeper(n, i, 1, || Ok(inner(i))) {
+        Ok(r) => r,
+        Err(e) => Err(Err::Failure(e)),
+    }
+}
+
+// consumes leading white spaces
+fn ms<'a, O, F, E>(inner: F) -> impl Fn(Span<'a>) -> IResult<Span<'a>, O, E>
+where
+    F: Fn(Span<'a>) -> IResult<Span<'a>, O, E>,
+    E: ParseError<Span<'a>>,
+{
+    preceded(multispace0, inner)
+}
+
+// pairs the result with the span of the consumed input
+fn spanned<'a, O, F, E>(inner: F) -> impl Fn(Span<'a>) -> IResult<Span<'a>, (Span<'a>, O), E>
+where
+    F: Fn(Span<'a>) -> IResult<Span<'a>, O, E>,
+    E: ParseError<Span<'a>>,
+{
+    move |i: Span<'a>| {
+        let (rest, o) = inner(i)?;
+        Ok((rest, (i.slice(..i.offset(&rest)), o)))
+    }
+}
+
+#[test]
+fn test_parse_expr_prec() {
+    let e = parse_expr_all("a || 1 + 2 * 3 == 7 && !b").unwrap();
+    match e {
+        (s, Expr::BinOp(Op::Or, _, r)) => {
+            assert_eq!(s.fragment, "||");
+            assert!(matches!(r.1, Expr::BinOp(Op::And, _, _)));
         }
-        Expr::UnaryOp(op, e) => {
-            let e = math_eval(&e);
-            match op {
-                Op::Add => e,
-                Op::Sub => -e,
-                _ => unimplemented!(),
-            }
+        _ => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn test_parse_prog() {
+    let src = "
+fn f(x: i32, mut y: bool) -> i32 {
+    let mut a: i32 = g(x, 2) ** 2;
+    while a > 0 {
+        a = a - 1;
+    }
+    if y { return a; } else if !y { return 2; } else { y = true; }
+    return (a + 1) * x;
+}
+
+fn g(a: i32, b: i32) -> i32 {
+    return a;
+}
+";
+    let p = parse(src).unwrap();
+    assert_eq!(p.len(), 2);
+    assert_eq!(p[0].id.fragment, "f");
+    assert!(p[0].params[1].mutable);
+    assert_eq!(p[0].body.1.len(), 4);
+    match &p[0].body.1[2] {
+        (s, Stmt::If(_, _, Some((_, e)))) => {
+            assert!(s.fragment.starts_with("if y"));
+            assert!(s.fragment.ends_with("y = true; }"));
+            assert!(matches!(e[0].1, Stmt::If(..)));
         }
-        _ => unimplemented!(),
+        s => panic!("{:?}", s),
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum Ass {
-    Left,
-    Right,
+#[test]
+fn test_parse_error() {
+    let d = parse("fn f() {\n    let x: i32 = 1 +;\n}").unwrap_err();
+    assert_eq!(d.span.line, 2);
+    assert_eq!(d.span.fragment, "+");
+    let d = parse("fn f() {\n    x = 1\n}").unwrap_err();
+    assert_eq!(d.span.line, 2);
 }
 
-fn get_prec(op: &Op) -> (u8, Ass) {
-    match op {
-        Op::Add => (1, Ass::Left),
-        Op::Sub => (1, Ass::Left),
-        Op::Mul => (2, Ass::Left),
-        Op::Div => (2, Ass::Left),
-        Op::Pow => (3, Ass::Right),
-        _ => unimplemented!(),
+#[test]
+fn test_parse_nesting() {
+    let src = format!(
+        "fn main() -> i32 {{ return {}1{}; }}",
+        "(".repeat(70),
+        ")".repeat(70)
+    );
+    let d = parse(&src).unwrap_err();
+    assert_eq!(d.msg, "nesting exceeds the limit of 64 levels");
+    // the function body is the first level
+    assert_eq!(d.span.offset, 26 + 63);
+    assert!(parse_with_limit(&src, 80).is_ok());
+
+    let src = format!("fn main() -> i32 {{ return 1{}; }}", " ** 1".repeat(70));
+    assert_eq!(parse(&src).unwrap_err().span.fragment, "**");
+    let src = format!("fn main() {{ {}{} }}", "{".repeat(70), "}".repeat(70));
+    assert!(parse(&src).is_err());
+    let src = format!("fn main() {{ {} }}", "if true {} else ".repeat(70) + "{}");
+    assert!(parse(&src).is_err());
+
+    // flat chains of left-associative operators do not nest
+    let src = format!("fn main() -> i32 {{ return 1{}; }}", " + 1".repeat(69));
+    assert!(parse(&src).is_ok());
+    let src = format!("fn f(x: i32) -> i32 {{ return x{}; }}", " * x".repeat(99));
+    assert!(parse(&src).is_ok());
+    let src = format!(
+        "fn main() -> bool {{ return true{}; }}",
+        " && true".repeat(79)
+    );
+    assert!(parse(&src).is_ok());
+    // but their depth is bounded
+    let src = format!(
+        "fn main() -> i32 {{ return 1{}; }}",
+        " + 1".repeat(MAX_EXPR_DEPTH)
+    );
+    let d = parse(&src).unwrap_err();
+    assert_eq!(d.msg, "expression exceeds the depth limit of 256");
+    assert_eq!(d.span.fragment, "+");
+}
+
+#[test]
+fn test_parse_macros() {
+    let e = parse_expr_all("println!(\"{} + {{}} = {}\", 1, f(2))").unwrap();
+    assert_eq!(e.1.to_string(), "println!(\"{} + {{}} = {}\", 1, f(2))");
+    match e.1 {
+        Expr::Macro(Macro::Println, args, Some(f)) => {
+            assert_eq!(args.len(), 2);
+            assert_eq!(f.fragment, "{} + {{}} = {}");
+        }
+        e => panic!("{:?}", e),
     }
+    let e = parse_expr_all("assert_eq!(a, 1, \"a\")").unwrap();
+    assert!(matches!(e.1, Expr::Macro(Macro::AssertEq, ref a, Some(_)) if a.len() == 2));
+
+    let err = |s| parse_expr_all(s).unwrap_err();
+    let d = err("println!(\"{}\")");
+    assert_eq!(
+        (d.span.fragment, d.msg.as_str()),
+        (
+            "\"{}\"",
+            "format string does not match the number of arguments"
+        )
+    );
+    assert_eq!(err("println!(\"{\", 1)").span.fragment, "\"{\"");
+    assert_eq!(
+        err("assert!()").msg,
+        "wrong number of arguments to `assert!`"
+    );
+    assert_eq!(err("panic!(x)").span.fragment, "x");
+    assert_eq!(err("1 + \"a\"").span.fragment, "\"a\"");
 }
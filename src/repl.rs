@@ -0,0 +1,234 @@
+// Read-eval-print loop
+//
+// Inputs are function declarations, statements (e.g., `let`), or a single
+// expression whose value is printed. Functions and variables persist
+// between inputs; a function declared again replaces the earlier one.
+// Lines are collected until parentheses and braces are balanced.
+//
+// Each input is parsed together with the declarations so far, as the
+// source `input \n declarations`, so that diagnostics can point into both.
+
+use std::io::{self, BufRead, Write};
+
+use nom::{
+    character::complete::multispace0,
+    combinator::all_consuming,
+    multi::many1,
+    sequence::{preceded, terminated},
+    Err,
+};
+
+use crate::ast::{FnDecl, Prog, Span};
+use crate::diagnostic::{Diagnostic, Level};
+use crate::interp::{eval_in, exec_in, Value, Vars};
+use crate::parse::{keyword, parse_error, parse_expr, parse_fn, parse_prog, parse_stmt, Nesting};
+
+#[derive(Default)]
+pub struct Repl {
+    // declared functions, by name, and their source
+    fns: Vec<(String, String)>,
+    vars: Vars,
+}
+
+// true if parentheses or braces are left open, those in string
+// literals are not counted. Strings end at the line, as in the parser,
+// an unterminated one is a syntax error rather than more input.
+pub fn incomplete(src: &str) -> bool {
+    let (mut par, mut brace) = (0, 0);
+    let mut chars = src.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // skips the string, unless it is unterminated
+            '"' if chars.find(|&c| c == '"' || c == '\n') != Some('"') => return false,
+            '(' => par += 1,
+            ')' => par -= 1,
+            '{' => brace += 1,
+            '}' => brace -= 1,
+            _ => (),
+        }
+    }
+    par > 0 || brace > 0
+}
+
+// the furthest of two syntax errors
+fn furthest<'a>(
+    n: &Nesting,
+    e1: Err<(Span<'a>, nom::error::ErrorKind)>,
+    e2: Err<(Span<'a>, nom::error::ErrorKind)>,
+) -> Diagnostic<'a> {
+    let offset = |e: &Err<(Span, _)>| match e {
+        Err::Error((s, _)) | Err::Failure((s, _)) => s.offset,
+        Err::Incomplete(_) => unreachable!(),
+    };
+    parse_error(n, if offset(&e1) >= offset(&e2) { e1 } else { e2 })
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl::default()
+    }
+
+    // the variables defined so far
+    pub fn vars(&self) -> &Vars {
+        &self.vars
+    }
+
+    // evaluates a complete input, returns the text to print
+    pub fn eval(&mut self, input: &str) -> String {
+        if input.trim().is_empty() {
+            return String::new();
+        }
+        let defs: String = self.fns.iter().map(|(_, f)| format!("{}\n", f)).collect();
+        let src = format!("{}\n{}", input, defs);
+        let (i, d) = src.split_at(input.len() + 1);
+        let defs = Span {
+            offset: i.len(),
+            line: i.matches('\n').count() as u32 + 1,
+            fragment: d,
+            extra: (),
+        };
+        let prog = parse_prog(&Nesting::default(), defs).unwrap().1;
+        match self.input(&src[..input.len()], &src, &prog) {
+            Ok(Some(v)) => format!("{}\n", v),
+            Ok(None) => String::new(),
+            Err(e) => e,
+        }
+    }
+
+    // the error is rendered against the full source `all`
+    fn input<'a>(
+        &mut self,
+        src: &'a str,
+        all: &str,
+        prog: &Prog<'a>,
+    ) -> Result<Option<Value>, String> {
+        let i = Span::new(src);
+        let n = Nesting::default();
+        if preceded(multispace0, keyword("fn"))(i).is_ok() {
+            let fns = all_consuming(terminated(many1(|i| parse_fn(&n, i)), multispace0))(i);
+            return match fns {
+                Ok((_, fns)) => match self.declare(src, fns) {
+                    Ok(()) => Ok(None),
+                    Err(d) => Err(d.render(all)),
+                },
+                Err(e) => {
+                    Err(furthest(&n, e, Err::Error((i, nom::error::ErrorKind::Many1))).render(all))
+                }
+            };
+        }
+        let e = match all_consuming(terminated(|i| parse_expr(&n, i), multispace0))(i) {
+            Ok((_, e)) => {
+                return eval_in(prog, &self.vars, &e)
+                    .map(Some)
+                    .map_err(|e| e.diagnostic().render(all))
+            }
+            Err(e) => e,
+        };
+        let stmts = match all_consuming(terminated(many1(|i| parse_stmt(&n, i)), multispace0))(i) {
+            Ok((_, stmts)) => stmts,
+            Err(e2) => return Err(furthest(&n, e, e2).render(all)),
+        };
+        for s in &stmts {
+            let vars = self.vars.clone();
+            let (vars, r) = exec_in(prog, &vars, s).map_err(|e| e.diagnostic().render(all))?;
+            self.vars = vars;
+            if r.is_some() {
+                return Ok(r);
+            }
+        }
+        Ok(None)
+    }
+
+    // adds (or replaces) functions after checking their bodies
+    fn declare<'a>(&mut self, src: &'a str, fns: Vec<FnDecl<'a>>) -> Result<(), Diagnostic<'a>> {
+        for f in &fns {
+            let errors = crate::flow::check_fn(f);
+            if let Some(d) = errors.into_iter().find(|d| d.level == Level::Error) {
+                return Err(d);
+            }
+        }
+        let mut start = 0;
+        for f in fns {
+            let end = f.body.0.offset + f.body.0.fragment.len();
+            let name = f.id.fragment.to_string();
+            self.fns.retain(|(n, _)| *n != name);
+            self.fns.push((name, src[start..end].trim().to_string()));
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+// prompts for input until the end of `input`
+pub fn run<R: BufRead, W: Write>(mut input: R, mut output: W) -> io::Result<()> {
+    let mut repl = Repl::new();
+    let mut buf = String::new();
+    loop {
+        write!(output, "{}", if buf.is_empty() { ">> " } else { ".. " })?;
+        output.flush()?;
+        if input.read_line(&mut buf)? == 0 {
+            return Ok(());
+        }
+        if incomplete(&buf) {
+            continue;
+        }
+        write!(output, "{}", repl.eval(buf.trim_end()))?;
+        buf.clear();
+    }
+}
+
+#[test]
+fn test_repl() {
+    let mut r = Repl::new();
+    assert_eq!(r.eval("1 + 2 * 3"), "7\n");
+    assert_eq!(r.eval("let x: i32 = 4;"), "");
+    assert_eq!(r.eval("fn sq(x: i32) -> i32 {\n  return x * x;\n}"), "");
+    assert_eq!(r.eval("sq(x) + 1"), "17\n");
+    assert_eq!(r.eval("let mut y: i32 = sq(3); y = y + x;"), "");
+    assert_eq!(r.eval("y"), "13\n");
+    assert_eq!(r.eval("fn sq(x: i32) -> i32 { return x; }"), "");
+    assert_eq!(r.eval("sq(y) == y"), "true\n");
+    assert_eq!(
+        r.eval("fn f(b: bool) -> i32 { if b { return 1; } }"),
+        "error: function `f` does not return a value on every path\n \
+         --> 1:4\n  |\n1 | fn f(b: bool) -> i32 { if b { return 1; } }\n  |    ^\n\
+         note: this `if` lacks an `else` branch that returns\n \
+         --> 1:24\n  |\n1 | fn f(b: bool) -> i32 { if b { return 1; } }\n  |                        \
+         ^^^^^^^^^^^^^^^^^^\n"
+    );
+    assert_eq!(
+        r.eval("let z: i32 = x +;"),
+        "error: unexpected `+`\n --> 1:16\n  |\n1 | let z: i32 = x +;\n  |                ^\n"
+    );
+    assert!(r
+        .eval("sq(1 / 0)")
+        .starts_with("error: attempt to divide by zero\n --> 1:6"));
+    assert!(r
+        .eval("x = 5;")
+        .starts_with("error: cannot assign twice to immutable variable `x`"));
+    assert_eq!(r.eval("y = y + 1;"), "");
+    assert_eq!(r.eval("y"), "14\n");
+    assert_eq!(r.eval("let fnx: i32 = 3;"), "");
+    assert_eq!(r.eval("fnx + 1"), "4\n");
+    assert_eq!(
+        r.vars()
+            .iter()
+            .map(|(x, ..)| x.as_str())
+            .collect::<Vec<_>>(),
+        vec!["fnx", "x", "y"]
+    );
+}
+
+#[test]
+fn test_repl_run() {
+    assert!(incomplete("fn f() {\n  if (1 + 2"));
+    assert!(!incomplete("fn f() { }"));
+    assert!(!incomplete("println!(\"{ (\")"));
+    assert!(!incomplete("println!(\"abc);\n"));
+    assert!(!incomplete("println!(\"C:\\\");\n"));
+    assert!(incomplete("println!(\"}\", {"));
+    let input = "fn twice(x: i32) -> i32 {\n  return 2 * x;\n}\ntwice(\n  21\n)\n";
+    let mut out = vec![];
+    run(input.as_bytes(), &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), ">> .. .. >> .. .. 42\n>> ");
+}
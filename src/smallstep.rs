@@ -0,0 +1,587 @@
+// Small-step semantics
+//
+// Configurations are rewritten one step at a time. Values are embedded in
+// the rewritten terms as literals, reduction is leftmost-innermost:
+//
+// (1 + 2) * 3 → 3 * 3 → 9
+//
+// Programs run on a stack of frames, each holding the statements still to
+// execute and a stack of scopes. Loops unfold into conditionals,
+// `while c { b }` → `if c { { b } while c { b } }`, and blocks declaring
+// variables push a scope, popped once their statements are done. A call
+// whose arguments are values pushes a frame; its return value replaces the
+// call in the statement of the caller. Variables hold values directly,
+// there is no store, so references are not supported. Builtin macros
+// reduce their arguments, then print or fail as in `interp`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::ast::{Expr, FnDecl, Op, Prog, Span, SpanExpr, SpanStmt, Stmt, Type};
+use crate::interp::{
+    binop, builtin, short_circuit, unop, variable, ErrorKind, RuntimeError, Value,
+};
+
+type Result<'a, T> = std::result::Result<T, RuntimeError<'a>>;
+
+// variables, whether they are mutable, and their values
+type Scope<'a> = Vec<(&'a str, bool, Value)>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item<'a> {
+    Stmt(Box<SpanStmt<'a>>),
+    // end of a block declaring variables
+    PopScope,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame<'a> {
+    pub id: Span<'a>,
+    pub call: Option<Span<'a>>,
+    // remaining code, the next item last
+    pub code: Vec<Item<'a>>,
+    pub scopes: Vec<Scope<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config<'a> {
+    pub frames: Vec<Frame<'a>>,
+    // the value of the entry call, once returned
+    pub result: Option<Value>,
+}
+
+pub struct SmallStep<'p, 'a> {
+    fns: HashMap<&'a str, &'p FnDecl<'a>>,
+    config: Config<'a>,
+    failed: bool,
+    // output of `println!`
+    out: Box<dyn Write + 'p>,
+}
+
+// runs the function `id` of the program to completion
+pub fn run<'a>(p: &Prog<'a>, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+    let mut m = SmallStep::new(p, id, args)?;
+    while m.step()? {}
+    Ok(m.config.result.unwrap())
+}
+
+fn is_value(e: &SpanExpr) -> bool {
+    matches!(e.1, Expr::Num(_) | Expr::Bool(_) | Expr::Unit)
+}
+
+fn value(e: &SpanExpr) -> Value {
+    match &e.1 {
+        Expr::Num(i) => Value::Num(*i),
+        Expr::Bool(b) => Value::Bool(*b),
+        Expr::Unit => Value::Unit,
+        _ => unreachable!(),
+    }
+}
+
+// references are never produced, see `enter`
+fn literal<'a>(v: Value) -> Expr<'a> {
+    match v {
+        Value::Num(i) => Expr::Num(i),
+        Value::Bool(b) => Expr::Bool(b),
+        Value::Unit => Expr::Unit,
+        Value::Ref(_) => unreachable!(),
+    }
+}
+
+fn lookup<'s, 'a>(scopes: &'s mut [Scope<'a>], x: &str) -> Option<(bool, &'s mut Value)> {
+    scopes
+        .iter_mut()
+        .rev()
+        .flat_map(|sc| sc.iter_mut().rev())
+        .find(|(id, ..)| *id == x)
+        .map(|(_, m, v)| (*m, v))
+}
+
+// a call with evaluated arguments, to be entered
+type Enter<'a> = Option<(Span<'a>, &'a str, Vec<Value>)>;
+
+// rewrites the leftmost-innermost redex of `e`, which must not be a value
+fn step_expr<'a>(
+    e: &mut SpanExpr<'a>,
+    scopes: &mut [Scope<'a>],
+    is_fn: &dyn Fn(&str) -> bool,
+    out: &mut dyn Write,
+) -> std::result::Result<Enter<'a>, (Span<'a>, ErrorKind)> {
+    let (s, e) = e;
+    let v = match e {
+        Expr::Id(x) => match lookup(scopes, x) {
+            Some((_, v)) => v.clone(),
+            None => return Err((*s, ErrorKind::UnboundVariable(x.to_string()))),
+        },
+        // parentheses vanish with the step producing their value
+        Expr::Par(inner) if is_value(inner) => value(inner),
+        Expr::Par(inner) => {
+            let r = step_expr(inner, scopes, is_fn, out)?;
+            if is_value(inner) {
+                *e = literal(value(inner));
+            }
+            return Ok(r);
+        }
+        Expr::BinOp(_, l, _) if !is_value(l) => return step_expr(l, scopes, is_fn, out),
+        // the right operand is dropped unevaluated
+        Expr::BinOp(op, l, _) if short_circuit(*op, &value(l)) => value(l),
+        Expr::BinOp(_, _, r) if !is_value(r) => return step_expr(r, scopes, is_fn, out),
+        Expr::BinOp(op, l, r) => binop(*op, value(l), value(r)).map_err(|k| (*s, k))?,
+        Expr::UnaryOp(_, e) if !is_value(e) => return step_expr(e, scopes, is_fn, out),
+        Expr::UnaryOp(op, e) => unop(*op, value(e)).map_err(|k| (*s, k))?,
+        Expr::Call(id, _) if !is_fn(id) => {
+            return Err((*s, ErrorKind::UnboundFunction(id.to_string())))
+        }
+        Expr::Call(id, args) => {
+            return match args.iter_mut().find(|a| !is_value(a)) {
+                Some(a) => step_expr(a, scopes, is_fn, out),
+                None => Ok(Some((*s, *id, args.iter().map(value).collect()))),
+            }
+        }
+        Expr::Macro(_, args, _) if !args.iter().all(is_value) => {
+            let a = args.iter_mut().find(|a| !is_value(a)).unwrap();
+            return step_expr(a, scopes, is_fn, out);
+        }
+        Expr::Macro(m, args, fmt) => {
+            let vals: Vec<_> = args.iter().map(value).collect();
+            let cond = || args[0].1.to_string();
+            let fmt = fmt.map(|f| f.fragment);
+            builtin(*m, fmt, cond, &vals, out).map_err(|k| (*s, k))?
+        }
+        Expr::Num(_) | Expr::Bool(_) | Expr::Unit => unreachable!(),
+    };
+    *e = literal(v);
+    Ok(None)
+}
+
+// replaces the entered call (the redex) of `e` by its value
+fn plug(e: &mut SpanExpr, v: Value) {
+    match &mut e.1 {
+        Expr::Par(e) | Expr::UnaryOp(_, e) => plug(e, v),
+        Expr::BinOp(_, l, _) if !is_value(l) => plug(l, v),
+        Expr::BinOp(_, _, r) => plug(r, v),
+        Expr::Call(_, args) => match args.iter_mut().find(|a| !is_value(a)) {
+            Some(a) => plug(a, v),
+            None => e.1 = literal(v),
+        },
+        Expr::Macro(_, args, _) => plug(args.iter_mut().find(|a| !is_value(a)).unwrap(), v),
+        _ => unreachable!(),
+    }
+}
+
+// the expression under evaluation in a statement, if any
+fn focus<'s, 'a>(stmt: &'s mut Stmt<'a>) -> Option<&'s mut SpanExpr<'a>> {
+    match stmt {
+        Stmt::Let(_, _, _, e) | Stmt::If(e, ..) | Stmt::Return(Some(e)) | Stmt::Expr(e) => Some(e),
+        Stmt::Assign(l, e) if variable(l).is_some() => Some(e),
+        _ => None,
+    }
+}
+
+impl<'p, 'a> SmallStep<'p, 'a> {
+    // the initial configuration for calling `id`
+    pub fn new(p: &'p Prog<'a>, id: &str, args: Vec<Value>) -> Result<'a, Self> {
+        let mut m = SmallStep {
+            fns: p.iter().map(|f| (f.id.fragment, f)).collect(),
+            config: Config {
+                frames: vec![],
+                result: None,
+            },
+            failed: false,
+            out: Box::new(io::stdout()),
+        };
+        let f = match m.fns.get(id) {
+            Some(f) => *f,
+            None => return Err(m.error(Span::new(""), ErrorKind::UnboundFunction(id.to_string()))),
+        };
+        m.enter(f, args, None)?;
+        Ok(m)
+    }
+
+    // redirects the output of `println!`
+    pub fn set_output(&mut self, out: Box<dyn Write + 'p>) {
+        self.out = out;
+    }
+
+    pub fn config(&self) -> &Config<'a> {
+        &self.config
+    }
+
+    fn error(&self, span: Span<'a>, kind: ErrorKind) -> RuntimeError<'a> {
+        RuntimeError {
+            span,
+            kind,
+            backtrace: self
+                .config
+                .frames
+                .iter()
+                .rev()
+                .map(|f| (f.id, f.call))
+                .collect(),
+            notes: vec![],
+        }
+    }
+
+    fn check_type(&self, span: Span<'a>, expected: &Type, v: Value) -> Result<'a, Value> {
+        if v.ty() == *expected {
+            Ok(v)
+        } else {
+            Err(self.error(span, ErrorKind::TypeMismatch(expected.clone(), v.ty())))
+        }
+    }
+
+    fn enter(
+        &mut self,
+        f: &'p FnDecl<'a>,
+        args: Vec<Value>,
+        call: Option<Span<'a>>,
+    ) -> Result<'a, ()> {
+        let span = call.unwrap_or(f.id);
+        if args.len() != f.params.len() {
+            return Err(self.error(span, ErrorKind::ArgCount(f.params.len(), args.len())));
+        }
+        let mut scope = vec![];
+        for (p, v) in f.params.iter().zip(args) {
+            let v = self.check_type(span, &p.ty.1, v)?;
+            // as for `&e`, there is no store to refer to
+            if let Value::Ref(_) = v {
+                return Err(self.error(span, ErrorKind::Operands(Op::Ref, vec![v.ty()])));
+            }
+            scope.push((p.id.fragment, p.mutable, v));
+        }
+        self.config.frames.push(Frame {
+            id: f.id,
+            call,
+            code: vec![Item::Stmt(Box::new((
+                f.body.0,
+                Stmt::Block(f.body.clone()),
+            )))],
+            scopes: vec![scope],
+        });
+        Ok(())
+    }
+
+    // returns from the current frame, `None` when falling off its end
+    fn ret(&mut self, v: Option<Value>) -> Result<'a, ()> {
+        let f = self.fns[self.config.frames.last().unwrap().id.fragment];
+        let v = match v {
+            Some(v) => {
+                self.check_type(f.ret.as_ref().map_or(f.id, |(s, _)| *s), &f.ret_type(), v)?
+            }
+            None if f.ret_type() == Type::Unit => Value::Unit,
+            None => {
+                return Err(self.error(
+                    f.body.0,
+                    ErrorKind::MissingReturn(f.id.fragment.to_string()),
+                ))
+            }
+        };
+        self.config.frames.pop();
+        match self.config.frames.last_mut() {
+            Some(caller) => match caller.code.last_mut() {
+                Some(Item::Stmt(stmt)) => plug(focus(&mut stmt.1).unwrap(), v),
+                _ => unreachable!(),
+            },
+            None => self.config.result = Some(v),
+        }
+        Ok(())
+    }
+
+    // performs a single step, false if the entry call has returned
+    pub fn step(&mut self) -> Result<'a, bool> {
+        if self.config.result.is_some() {
+            return Ok(false);
+        }
+        let frame = self.config.frames.last_mut().unwrap();
+        let (s, mut stmt) = match frame.code.pop() {
+            None => return self.ret(None).map(|_| true),
+            Some(Item::PopScope) => {
+                frame.scopes.pop();
+                return Ok(true);
+            }
+            Some(Item::Stmt(stmt)) => *stmt,
+        };
+        let fns = &self.fns;
+        if let Some(e) = focus(&mut stmt).filter(|e| !is_value(e)) {
+            let is_fn = |id: &str| fns.contains_key(id);
+            let enter = step_expr(e, &mut frame.scopes, &is_fn, &mut self.out);
+            frame.code.push(Item::Stmt(Box::new((s, stmt))));
+            return match enter {
+                Ok(None) => Ok(true),
+                Ok(Some((call, id, args))) => {
+                    self.enter(self.fns[id], args, Some(call)).map(|_| true)
+                }
+                Err((span, kind)) => Err(self.error(span, kind)),
+            };
+        }
+        match stmt {
+            Stmt::Let(m, id, (_, ty), e) => {
+                let v = self.check_type(s, &ty, value(&e))?;
+                let frame = self.config.frames.last_mut().unwrap();
+                frame.scopes.last_mut().unwrap().push((id.fragment, m, v));
+            }
+            Stmt::Assign(l, e) if variable(&l).is_some() => {
+                let (ls, x) = (l.0, variable(&l).unwrap());
+                let scopes = &mut self.config.frames.last_mut().unwrap().scopes;
+                let ty = match lookup(scopes, x) {
+                    Some((false, _)) => {
+                        let kind = ErrorKind::AssignImmutable(x.to_string());
+                        return Err(self.error(ls, kind));
+                    }
+                    Some((_, v)) => v.ty(),
+                    None => return Err(self.error(ls, ErrorKind::UnboundVariable(x.to_string()))),
+                };
+                let v = self.check_type(s, &ty, value(&e))?;
+                let scopes = &mut self.config.frames.last_mut().unwrap().scopes;
+                *lookup(scopes, x).unwrap().1 = v;
+            }
+            Stmt::Assign((ls, _), _) => return Err(self.error(ls, ErrorKind::InvalidAssign)),
+            Stmt::If(c, t, e) => {
+                let b = match value(&c) {
+                    Value::Bool(b) => b,
+                    v => return Err(self.error(c.0, ErrorKind::TypeMismatch(Type::Bool, v.ty()))),
+                };
+                let branch = if b { Some(t) } else { e };
+                if let Some(b) = branch {
+                    let frame = self.config.frames.last_mut().unwrap();
+                    frame.code.push(Item::Stmt(Box::new((b.0, Stmt::Block(b)))));
+                }
+            }
+            Stmt::While(c, b) => {
+                let body = vec![
+                    (b.0, Stmt::Block(b.clone())),
+                    (s, Stmt::While(c.clone(), b)),
+                ];
+                let frame = self.config.frames.last_mut().unwrap();
+                frame
+                    .code
+                    .push(Item::Stmt(Box::new((s, Stmt::If(c, (s, body), None)))));
+            }
+            Stmt::Return(e) => self.ret(Some(e.map_or(Value::Unit, |e| value(&e))))?,
+            Stmt::Expr(_) => (),
+            Stmt::Block((_, b)) => {
+                // blocks without declarations need no scope
+                let frame = self.config.frames.last_mut().unwrap();
+                if b.iter().any(|(_, s)| matches!(s, Stmt::Let(..))) {
+                    frame.scopes.push(vec![]);
+                    frame.code.push(Item::PopScope);
+                }
+                frame
+                    .code
+                    .extend(b.into_iter().rev().map(|s| Item::Stmt(Box::new(s))));
+            }
+        }
+        Ok(true)
+    }
+}
+
+// yields the configuration after each step, ending after an error
+impl<'p, 'a> Iterator for SmallStep<'p, 'a> {
+    type Item = Result<'a, Config<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.step() {
+            Ok(true) => Some(Ok(self.config.clone())),
+            Ok(false) => None,
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// successive rewrites of a closed expression, ending in a value or error
+pub struct ExprSteps<'a> {
+    e: Option<SpanExpr<'a>>,
+    // output of `println!`
+    out: Box<dyn Write + 'a>,
+}
+
+pub fn expr_steps(e: SpanExpr) -> ExprSteps {
+    ExprSteps {
+        e: Some(e),
+        out: Box::new(io::stdout()),
+    }
+}
+
+impl<'a> ExprSteps<'a> {
+    pub fn set_output(&mut self, out: Box<dyn Write + 'a>) {
+        self.out = out;
+    }
+}
+
+impl<'a> Iterator for ExprSteps<'a> {
+    type Item = Result<'a, SpanExpr<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut e = self.e.take().filter(|e| !is_value(e))?;
+        match step_expr(&mut e, &mut [], &|_| false, &mut self.out) {
+            Ok(_) => {
+                self.e = Some(e.clone());
+                Some(Ok(e))
+            }
+            Err((span, kind)) => Some(Err(RuntimeError {
+                span,
+                kind,
+                backtrace: vec![],
+                notes: vec![],
+            })),
+        }
+    }
+}
+
+// the reduction sequence of a closed expression, e.g.,
+// `(1 + 2) * 3 → 3 * 3 → 9`
+pub fn trace_expr(e: SpanExpr) -> String {
+    let mut t = e.1.to_string();
+    for e in expr_steps(e) {
+        match e {
+            Ok((_, e)) => t.push_str(&format!(" → {}", e)),
+            Err(e) => t.push_str(&format!(" → error: {}", e.kind)),
+        }
+    }
+    t
+}
+
+// `f: ⟨next statement, {x ↦ 1}⟩` for the innermost frame
+impl<'a> fmt::Display for Config<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(v) = &self.result {
+            return write!(f, "{}", v);
+        }
+        let frame = self.frames.last().unwrap();
+        let mut env = BTreeMap::new();
+        for scope in &frame.scopes {
+            env.extend(scope.iter().map(|(x, _, v)| (x.to_string(), v.clone())));
+        }
+        let next = frame.code.iter().rev().find_map(|i| match i {
+            Item::Stmt(s) => Some(s.1.to_string()),
+            Item::PopScope => None,
+        });
+        write!(
+            f,
+            "{}: ⟨{}, {}⟩",
+            frame.id.fragment,
+            next.unwrap_or_else(|| "{}".to_string()),
+            crate::sos::env_text(&env.into_iter().collect())
+        )
+    }
+}
+
+#[test]
+fn test_expr_steps() {
+    use crate::parse::parse_expr_all;
+
+    let e = parse_expr_all("(1 + 2) * 3").unwrap();
+    assert_eq!(trace_expr(e), "(1 + 2) * 3 → 3 * 3 → 9");
+    let e = parse_expr_all("!(1 > 2) && 2 ** 3 < 4 / (1 - 1)").unwrap();
+    assert_eq!(
+        trace_expr(e),
+        "!(1 > 2) && 2 ** 3 < 4 / (1 - 1) → !false && 2 ** 3 < 4 / (1 - 1) \
+         → true && 2 ** 3 < 4 / (1 - 1) → true && 8 < 4 / (1 - 1) \
+         → true && 8 < 4 / 0 → error: attempt to divide by zero"
+    );
+}
+
+#[test]
+fn test_steps() {
+    let src = "
+fn inc(x: i32) -> i32 {
+    return x + 1;
+}
+
+fn main() -> i32 {
+    let mut x: i32 = 0;
+    while x < 2 {
+        x = inc(x);
+    }
+    return x;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let m = SmallStep::new(&p, "main", vec![]).unwrap();
+    assert_eq!(
+        m.config().to_string(),
+        "main: ⟨{ let mut x: i32 = 0; while x < 2 { x = inc(x); } return x; }, {}⟩"
+    );
+    let t: Vec<_> = m.map(|c| c.unwrap().to_string()).collect();
+    assert_eq!(t[1], "main: ⟨while x < 2 { x = inc(x); }, {x ↦ 0}⟩");
+    assert_eq!(
+        t[2],
+        "main: ⟨if x < 2 { { x = inc(x); } while x < 2 { x = inc(x); } }, {x ↦ 0}⟩"
+    );
+    assert!(t.contains(&"main: ⟨x = inc(0);, {x ↦ 0}⟩".to_string()));
+    assert!(t.contains(&"inc: ⟨return 0 + 1;, {x ↦ 0}⟩".to_string()));
+    assert!(t.contains(&"main: ⟨x = 1;, {x ↦ 0}⟩".to_string()));
+    assert_eq!(t.last().unwrap(), "2");
+
+    // assigned places may be parenthesized, the parser drops the
+    // parentheses so they are put back here
+    let src = "fn main() -> i32 { let mut x: i32 = 0; (x) = 1 + 1; return x; }";
+    let mut p = crate::parse::parse(src).unwrap();
+    if let Stmt::Assign(l, _) = &mut p[0].body.1[1].1 {
+        *l = (l.0, Expr::Par(Box::new(l.clone())));
+    }
+    let m = SmallStep::new(&p, "main", vec![]).unwrap();
+    let t: Vec<_> = m.map(|c| c.unwrap().to_string()).collect();
+    assert!(t.contains(&"main: ⟨(x) = 2;, {x ↦ 0}⟩".to_string()));
+    assert_eq!(t.last().unwrap(), "2");
+}
+
+// small-step and big-step semantics agree on every test program
+#[test]
+fn test_programs() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/programs");
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+    for path in paths {
+        let src = std::fs::read_to_string(&path).unwrap();
+        let p = crate::parse::parse(&src).unwrap();
+        let big = crate::interp::run(&p, "main", vec![]);
+        let small = run(&p, "main", vec![]);
+        assert_eq!(small, big, "{}", path.display());
+    }
+}
+
+#[test]
+fn test_builtins() {
+    let src = "
+fn main() -> i32 {
+    let x: i32 = 2;
+    println!(\"{} {}\", x, x * x);
+    assert_eq!(x, 3);
+    return x;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let mut out = vec![];
+    let mut m = SmallStep::new(&p, "main", vec![]).unwrap();
+    m.set_output(Box::new(&mut out));
+    let e = loop {
+        if let Err(e) = m.step() {
+            break e;
+        }
+    };
+    drop(m);
+    assert_eq!(String::from_utf8(out).unwrap(), "2 4\n");
+    let mut i = crate::interp::Interp::new(&p);
+    i.set_output(Box::new(io::sink()));
+    assert_eq!(Err(e), i.call("main", vec![]).map(|_| ()));
+
+    let e = crate::parse::parse_expr_all("2 * (1 + 2) == 6 && println!(\"{}\", 1) == ()").unwrap();
+    let mut out = vec![];
+    let mut steps = expr_steps(e);
+    steps.set_output(Box::new(&mut out));
+    let last = steps.last().unwrap().unwrap();
+    assert_eq!(last.1, Expr::Bool(true));
+    assert_eq!(String::from_utf8(out).unwrap(), "1\n");
+}
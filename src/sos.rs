@@ -0,0 +1,239 @@
+// SOS derivation trees
+//
+// The interpreter records a derivation when tracing is enabled, one node
+// per rule application (big-step). A judgement relates a term in a state,
+// the environment (the addresses of the variables of the current frame)
+// and the store (the values of the live cells), to its outcome:
+// expressions conclude in a value and a store, statements in an updated
+// state or an executed `return`:
+//
+// [E-Add] ⟨x + 1, {x ↦ @0}, {@0 ↦ 1}⟩ ⇓ 2, {@0 ↦ 1}
+//   [E-Var] ⟨x, {x ↦ @0}, {@0 ↦ 1}⟩ ⇓ 1, {@0 ↦ 1}
+//   [E-Num] ⟨1, {x ↦ @0}, {@0 ↦ 1}⟩ ⇓ 1, {@0 ↦ 1}
+//
+// Derivations are exported as indented plain text and as LaTeX, using
+// `\inferrule*` from the `mathpartir` package.
+
+use crate::ast::Prog;
+use crate::interp::{Interp, RuntimeError, Value};
+
+// visible variables of the current frame and their values, sorted by name
+pub type Env = Vec<(String, Value)>;
+
+// environment and store, sorted by name and by address
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct State {
+    pub env: Vec<(String, usize)>,
+    pub store: Vec<(usize, Value)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conclusion {
+    Value(Value),
+    Env,
+    Return(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derivation {
+    pub rule: &'static str,
+    pub term: String,
+    pub before: State,
+    pub after: State,
+    pub conclusion: Conclusion,
+    pub premises: Vec<Derivation>,
+}
+
+// runs the function `id` recording the derivation of the call
+pub fn derive<'a>(
+    p: &Prog<'a>,
+    id: &str,
+    args: Vec<Value>,
+) -> Result<Derivation, RuntimeError<'a>> {
+    let mut i = Interp::new(p);
+    i.set_trace(true);
+    i.call(id, args)?;
+    Ok(i.derivation().unwrap())
+}
+
+// single line, abbreviated term
+fn abbrev(t: &str) -> String {
+    let t = t.split_whitespace().collect::<Vec<_>>().join(" ");
+    if t.chars().count() > 40 {
+        format!("{}...", t.chars().take(37).collect::<String>())
+    } else {
+        t
+    }
+}
+
+pub(crate) fn env_text(env: &Env) -> String {
+    let b: Vec<_> = env.iter().map(|(x, v)| format!("{} ↦ {}", x, v)).collect();
+    format!("{{{}}}", b.join(", "))
+}
+
+fn store_text(store: &[(usize, Value)]) -> String {
+    let b: Vec<_> = store
+        .iter()
+        .map(|(a, v)| format!("@{} ↦ {}", a, v))
+        .collect();
+    format!("{{{}}}", b.join(", "))
+}
+
+fn state_text(s: &State) -> String {
+    let env: Vec<_> = s
+        .env
+        .iter()
+        .map(|(x, a)| format!("{} ↦ @{}", x, a))
+        .collect();
+    format!("{{{}}}, {}", env.join(", "), store_text(&s.store))
+}
+
+fn store_latex(store: &[(usize, Value)]) -> String {
+    let b: Vec<_> = store
+        .iter()
+        .map(|(a, v)| format!("@{} \\mapsto {}", a, latex_escape(&v.to_string())))
+        .collect();
+    format!("\\{{{}\\}}", b.join(", "))
+}
+
+fn state_latex(s: &State) -> String {
+    let env: Vec<_> = s
+        .env
+        .iter()
+        .map(|(x, a)| format!("\\mathit{{{}}} \\mapsto @{}", latex_escape(x), a))
+        .collect();
+    format!("\\{{{}\\}}, {}", env.join(", "), store_latex(&s.store))
+}
+
+fn latex_escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '\\' => "\\textbackslash{}".to_string(),
+            '{' | '}' | '_' | '&' | '%' | '#' | '$' => format!("\\{}", c),
+            '^' => "\\^{}".to_string(),
+            '~' => "\\~{}".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+impl Derivation {
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        self.text(0, &mut out);
+        out
+    }
+
+    fn text(&self, indent: usize, out: &mut String) {
+        let result = match &self.conclusion {
+            Conclusion::Value(v) => format!("{}, {}", v, store_text(&self.after.store)),
+            Conclusion::Env => state_text(&self.after),
+            Conclusion::Return(v) => format!("return {}, {}", v, state_text(&self.after)),
+        };
+        out.push_str(&format!(
+            "{:indent$}[{}] ⟨{}, {}⟩ ⇓ {}\n",
+            "",
+            self.rule,
+            abbrev(&self.term),
+            state_text(&self.before),
+            result,
+            indent = indent
+        ));
+        for p in &self.premises {
+            p.text(indent + 2, out);
+        }
+    }
+
+    pub fn to_latex(&self) -> String {
+        let result = match &self.conclusion {
+            Conclusion::Value(v) => format!(
+                "{}, {}",
+                latex_escape(&v.to_string()),
+                store_latex(&self.after.store)
+            ),
+            Conclusion::Env => state_latex(&self.after),
+            Conclusion::Return(v) => format!(
+                "\\mathbf{{return}}\\ {}, {}",
+                latex_escape(&v.to_string()),
+                state_latex(&self.after)
+            ),
+        };
+        let premises: Vec<_> = self.premises.iter().map(|p| p.to_latex()).collect();
+        format!(
+            "\\inferrule*[right={}]\n{{{}}}\n{{\\langle \\texttt{{{}}}, {} \\rangle \\Downarrow {}}}",
+            self.rule,
+            if premises.is_empty() {
+                " ".to_string()
+            } else {
+                premises.join("\n\\and\n")
+            },
+            latex_escape(&abbrev(&self.term)),
+            state_latex(&self.before),
+            result
+        )
+    }
+}
+
+#[test]
+fn test_derive() {
+    let src = "
+fn main() -> i32 {
+    let mut x: i32 = 1;
+    while x < 3 {
+        x = x * 2;
+    }
+    return x + 1;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let d = derive(&p, "main", vec![]).unwrap();
+    assert_eq!(d.rule, "E-Call");
+    assert_eq!(d.conclusion, Conclusion::Value(Value::Num(5)));
+    let text = d.to_text();
+    let lines: Vec<_> = text.lines().collect();
+    assert_eq!(lines[0], "[E-Call] ⟨main(), {}, {}⟩ ⇓ 5, {}");
+    assert_eq!(
+        lines[1],
+        "  [S-Block] ⟨{ let mut x: i32 = 1; while x < 3 { x..., {}, {}⟩ ⇓ return 5, {}, {}"
+    );
+    assert_eq!(
+        lines[2],
+        "    [S-Let] ⟨let mut x: i32 = 1;, {}, {}⟩ ⇓ {x ↦ @0}, {@0 ↦ 1}"
+    );
+    assert!(text.contains(
+        "\n      [S-While-True] ⟨while x < 3 { x = x * 2; }, {x ↦ @0}, {@0 ↦ 2}⟩ \
+         ⇓ {x ↦ @0}, {@0 ↦ 4}\n"
+    ));
+    assert!(text.contains(
+        "\n        [S-While-False] ⟨while x < 3 { x = x * 2; }, {x ↦ @0}, {@0 ↦ 4}⟩ \
+         ⇓ {x ↦ @0}, {@0 ↦ 4}\n"
+    ));
+    assert!(text.ends_with("      [E-Num] ⟨1, {x ↦ @0}, {@0 ↦ 4}⟩ ⇓ 1, {@0 ↦ 4}\n"));
+
+    let latex = d.to_latex();
+    assert!(latex.starts_with("\\inferrule*[right=E-Call]\n{\\inferrule*[right=S-Block]"));
+    assert!(latex.contains(
+        "{\\langle \\texttt{x < 3}, \\{\\mathit{x} \\mapsto @0\\}, \\{@0 \\mapsto 4\\} \\rangle \
+         \\Downarrow false, \\{@0 \\mapsto 4\\}}"
+    ));
+    assert_eq!(latex.matches("\\inferrule*").count(), text.lines().count());
+}
+
+#[test]
+fn test_derive_store() {
+    let src = "
+fn main() -> i32 {
+    let mut x: i32 = 1;
+    let r: &mut i32 = &mut x;
+    *r = 2;
+    return x;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let text = derive(&p, "main", vec![]).unwrap().to_text();
+    // the reference and the cell it points to
+    assert!(text.contains(
+        "\n    [S-Assign] ⟨*r = 2;, {r ↦ @1, x ↦ @0}, {@0 ↦ 1, @1 ↦ &mut @0}⟩ \
+         ⇓ {r ↦ @1, x ↦ @0}, {@0 ↦ 2, @1 ↦ &mut @0}\n"
+    ));
+}
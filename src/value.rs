@@ -0,0 +1,89 @@
+// Runtime values
+//
+// Shared by all evaluators (`math_eval`, the interpreters and the folder).
+// Values print like literals, references as the address of the cell they
+// point to, e.g., `&mut @3`. References are equal when pointing to the
+// same cell, regardless of the borrow (tag) and mutability; comparing
+// references with `==` in programs compares the referenced values.
+
+use std::fmt;
+
+use crate::ast::Type;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Num(i32),
+    Bool(bool),
+    Unit,
+    Ref(Ref),
+}
+
+// a reference to the store cell `addr`
+#[derive(Debug, Clone)]
+pub struct Ref {
+    pub addr: usize,
+    pub mutable: bool,
+    // the borrow that created the reference, a tag
+    pub tag: usize,
+    // type of the referenced value
+    pub ty: Box<Type>,
+}
+
+impl Value {
+    pub fn ty(&self) -> Type {
+        match self {
+            Value::Num(_) => Type::I32,
+            Value::Bool(_) => Type::Bool,
+            Value::Unit => Type::Unit,
+            Value::Ref(r) => Type::Ref(r.mutable, r.ty.clone()),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Num(l), Value::Num(r)) => l == r,
+            (Value::Bool(l), Value::Bool(r)) => l == r,
+            (Value::Unit, Value::Unit) => true,
+            (Value::Ref(l), Value::Ref(r)) => l.addr == r.addr,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Num(i) => write!(f, "{}", i),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Unit => write!(f, "()"),
+            Value::Ref(r) if r.mutable => write!(f, "&mut @{}", r.addr),
+            Value::Ref(r) => write!(f, "&@{}", r.addr),
+        }
+    }
+}
+
+#[test]
+fn test_value() {
+    let r = |tag, mutable| {
+        Value::Ref(Ref {
+            addr: 3,
+            mutable,
+            tag,
+            ty: Box::new(Type::I32),
+        })
+    };
+    assert_eq!(r(1, true), r(2, false));
+    assert_ne!(Value::Num(0), Value::Bool(false));
+    assert_eq!(r(1, true).ty().to_string(), "&mut i32");
+    let vs = [
+        Value::Num(-1),
+        Value::Bool(true),
+        Value::Unit,
+        r(1, true),
+        r(1, false),
+    ];
+    let s: Vec<_> = vs.iter().map(Value::to_string).collect();
+    assert_eq!(s, vec!["-1", "true", "()", "&mut @3", "&@3"]);
+}
@@ -0,0 +1,533 @@
+// Virtual machine
+//
+// Executes compiled programs (see `bytecode`) on an operand stack, with
+// the locals of the active calls in a single vector, each frame owning a
+// range of it. References are indices into this vector. Results and
+// runtime errors are those of the tree-walking interpreter, except that
+// fuel counts executed instructions and accesses are not checked against
+// borrow stacks.
+
+use std::io::{self, Write};
+
+use crate::ast::{Op, Span, Type};
+use crate::bytecode::{Instr, Program};
+use crate::interp::{binop, builtin, short_circuit, unop, ErrorKind, Limits, RuntimeError};
+use crate::value::{Ref, Value};
+
+type Result<'a, T> = std::result::Result<T, RuntimeError<'a>>;
+
+#[derive(Debug, Clone)]
+struct Local {
+    value: Value,
+    live: bool,
+    borrowed: bool,
+}
+
+const DEAD: Local = Local {
+    value: Value::Unit,
+    live: false,
+    borrowed: false,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    fun: usize,
+    // the calling instruction, None for the entry call
+    call: Option<usize>,
+    // first local
+    base: usize,
+}
+
+pub struct Vm<'p, 'a> {
+    prog: &'p Program<'a>,
+    stack: Vec<Value>,
+    locals: Vec<Local>,
+    frames: Vec<Frame>,
+    // live locals, see `Limits::max_store`
+    live: usize,
+    limits: Limits,
+    // executed instructions
+    steps: u64,
+    // output of `println!`
+    out: Box<dyn Write + 'p>,
+}
+
+// runs the function `id` of the compiled program with the given arguments
+pub fn run<'a>(p: &Program<'a>, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+    Vm::new(p).call(id, args)
+}
+
+impl<'p, 'a> Vm<'p, 'a> {
+    pub fn new(prog: &'p Program<'a>) -> Self {
+        Vm {
+            prog,
+            stack: vec![],
+            locals: vec![],
+            frames: vec![],
+            live: 0,
+            limits: Limits::default(),
+            steps: 0,
+            out: Box::new(io::stdout()),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // redirects the output of `println!`
+    pub fn set_output(&mut self, out: Box<dyn Write + 'p>) {
+        self.out = out;
+    }
+
+    pub fn call(&mut self, id: &str, args: Vec<Value>) -> Result<'a, Value> {
+        let f = match self.prog.function(id) {
+            Some(f) => f,
+            None => {
+                let kind = ErrorKind::UnboundFunction(id.to_string());
+                return Err(self.error(Span::new(""), kind));
+            }
+        };
+        self.stack = args;
+        self.locals.clear();
+        self.frames.clear();
+        self.live = 0;
+        let n = self.stack.len();
+        let pc = self.enter(f, n, None)?;
+        self.exec(pc)
+    }
+
+    fn error(&self, span: Span<'a>, kind: ErrorKind) -> RuntimeError<'a> {
+        RuntimeError {
+            span,
+            kind,
+            backtrace: (self.frames.iter().rev())
+                .map(|f| (self.prog.fns[f.fun].id, f.call.map(|pc| self.prog.span(pc))))
+                .collect(),
+            notes: vec![],
+        }
+    }
+
+    fn check_type(&self, span: Span<'a>, expected: &Type, v: Value) -> Result<'a, Value> {
+        match (expected, v) {
+            (e, v) if v.ty() == *e => Ok(v),
+            (Type::Ref(false, t), Value::Ref(r)) if r.mutable && r.ty == *t => {
+                Ok(Value::Ref(Ref {
+                    mutable: false,
+                    ..r
+                }))
+            }
+            (e, v) => Err(self.error(span, ErrorKind::TypeMismatch(e.clone(), v.ty()))),
+        }
+    }
+
+    // pushes a frame for `f`, its `n` arguments on top of the stack,
+    // returning the entry point
+    fn enter(&mut self, f: usize, n: usize, call: Option<usize>) -> Result<'a, usize> {
+        let fun = &self.prog.fns[f];
+        let span = call.map_or(fun.id, |pc| self.prog.span(pc));
+        if n != fun.params.len() {
+            return Err(self.error(span, ErrorKind::ArgCount(fun.params.len(), n)));
+        }
+        if self.frames.len() >= self.limits.max_depth {
+            return Err(self.error(span, ErrorKind::CallDepth(self.limits.max_depth)));
+        }
+        if self.live + n > self.limits.max_store {
+            return Err(self.error(span, ErrorKind::StoreSize(self.limits.max_store)));
+        }
+        let args = self.stack.split_off(self.stack.len() - n);
+        let base = self.locals.len();
+        for (t, v) in fun.params.iter().zip(args) {
+            let value = self.check_type(span, t, v)?;
+            self.locals.push(Local {
+                value,
+                live: true,
+                borrowed: false,
+            });
+        }
+        self.locals.resize(base + fun.locals.len(), DEAD);
+        self.live += n;
+        self.frames.push(Frame { fun: f, call, base });
+        Ok(fun.entry)
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    fn local(&mut self, slot: usize) -> &mut Local {
+        let base = self.frames.last().unwrap().base;
+        &mut self.locals[base + slot]
+    }
+
+    // the cell a reference points to, see `Interp::deref`
+    fn deref(&self, pc: usize, v: &Value) -> Result<'a, usize> {
+        match v {
+            Value::Ref(r) if self.locals.get(r.addr).is_some_and(|c| c.live) => Ok(r.addr),
+            Value::Ref(_) => Err(self.error(self.prog.span(pc), ErrorKind::Dangling(None))),
+            v => {
+                let kind = ErrorKind::Operands(Op::Deref, vec![v.ty()]);
+                Err(self.error(self.prog.span(pc), kind))
+            }
+        }
+    }
+
+    fn borrow(&mut self, pc: usize, addr: usize, mutable: bool) -> Value {
+        let cell = &mut self.locals[addr];
+        cell.borrowed = true;
+        Value::Ref(Ref {
+            addr,
+            mutable,
+            tag: pc,
+            ty: Box::new(cell.value.ty()),
+        })
+    }
+
+    // frees the live locals of the slot range, no remaining value may
+    // refer to them, see `Interp::pop_scope`
+    fn exit(&mut self, start: usize, end: usize) -> Result<'a, ()> {
+        let frame = *self.frames.last().unwrap();
+        let mut freed = vec![];
+        for slot in start..end {
+            let cell = &mut self.locals[frame.base + slot];
+            if cell.live {
+                cell.live = false;
+                self.live -= 1;
+                freed.push(slot);
+            }
+        }
+        if !freed.iter().any(|s| self.locals[frame.base + s].borrowed) {
+            return Ok(());
+        }
+        let dangling = |v: &Value| match v {
+            Value::Ref(r) if r.addr >= frame.base => (freed.iter())
+                .find(|s| frame.base + **s == r.addr)
+                .map(|s| (r.tag, *s)),
+            _ => None,
+        };
+        let found = (self.stack.iter().find_map(&dangling)).or_else(|| {
+            (self.locals.iter())
+                .filter(|c| c.live)
+                .find_map(|c| dangling(&c.value))
+        });
+        match found {
+            Some((tag, slot)) => {
+                let id = self.prog.fns[frame.fun].locals[slot];
+                let kind = ErrorKind::Dangling(id.map(|id| id.fragment.to_string()));
+                Err(self.error(self.prog.span(tag), kind))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn exec(&mut self, mut pc: usize) -> Result<'a, Value> {
+        loop {
+            if let Some(fuel) = self.limits.fuel {
+                if self.steps >= fuel {
+                    return Err(self.error(self.prog.span(pc), ErrorKind::OutOfFuel(fuel)));
+                }
+            }
+            self.steps += 1;
+            let prog = self.prog;
+            let span = move || prog.span(pc);
+            match prog.code[pc] {
+                Instr::Const(c) => self.stack.push(prog.consts[c].clone()),
+                Instr::Load(slot) => {
+                    let v = self.local(slot).value.clone();
+                    self.stack.push(v);
+                }
+                Instr::Store(slot) => {
+                    let v = self.pop();
+                    let ty = self.local(slot).value.ty();
+                    self.local(slot).value = self.check_type(span(), &ty, v)?;
+                }
+                Instr::Let(slot) => {
+                    if self.live >= self.limits.max_store {
+                        let kind = ErrorKind::StoreSize(self.limits.max_store);
+                        return Err(self.error(span(), kind));
+                    }
+                    let value = self.pop();
+                    *self.local(slot) = Local {
+                        value,
+                        live: true,
+                        borrowed: false,
+                    };
+                    self.live += 1;
+                }
+                Instr::Check(t) => {
+                    let v = self.pop();
+                    let v = self.check_type(span(), &prog.types[t], v)?;
+                    self.stack.push(v);
+                }
+                Instr::Borrow(slot, mutable) => {
+                    // only live locals are borrowed, such that `exit` finds
+                    // the references
+                    let addr = self.frames.last().unwrap().base + slot;
+                    if !self.locals[addr].live {
+                        return Err(self.error(span(), ErrorKind::Dangling(None)));
+                    }
+                    let r = self.borrow(pc, addr, mutable);
+                    self.stack.push(r);
+                }
+                Instr::Reborrow(mutable) => match self.pop() {
+                    Value::Ref(r) if mutable && !r.mutable => {
+                        return Err(self.error(span(), ErrorKind::BorrowMut(None)))
+                    }
+                    r => {
+                        let addr = self.deref(pc, &r)?;
+                        let r = self.borrow(pc, addr, mutable);
+                        self.stack.push(r);
+                    }
+                },
+                Instr::Place => {
+                    self.deref(pc, self.stack.last().unwrap())?;
+                }
+                Instr::PlaceMut => {
+                    if let Some(Value::Ref(r)) = self.stack.last() {
+                        if !r.mutable {
+                            return Err(self.error(span(), ErrorKind::AssignShared));
+                        }
+                    }
+                }
+                Instr::StoreInd => {
+                    let r = self.pop();
+                    let v = self.pop();
+                    let addr = self.deref(pc, &r)?;
+                    let ty = self.locals[addr].value.ty();
+                    self.locals[addr].value = self.check_type(span(), &ty, v)?;
+                }
+                Instr::Deref => {
+                    let r = self.pop();
+                    let addr = self.deref(pc, &r)?;
+                    self.stack.push(self.locals[addr].value.clone());
+                }
+                Instr::DerefAll => {
+                    while let Some(Value::Ref(_)) = self.stack.last() {
+                        let r = self.pop();
+                        let addr = self.deref(pc, &r)?;
+                        self.stack.push(self.locals[addr].value.clone());
+                    }
+                }
+                Instr::Bin(op) => {
+                    let rv = self.pop();
+                    let lv = self.pop();
+                    let v = binop(op, lv, rv).map_err(|kind| self.error(span(), kind))?;
+                    self.stack.push(v);
+                }
+                Instr::Un(op) => {
+                    let v = self.pop();
+                    let v = unop(op, v).map_err(|kind| self.error(span(), kind))?;
+                    self.stack.push(v);
+                }
+                Instr::Short(op, target) => {
+                    if short_circuit(op, self.stack.last().unwrap()) {
+                        pc = target;
+                        continue;
+                    }
+                }
+                Instr::Jump(target) => {
+                    pc = target;
+                    continue;
+                }
+                Instr::JumpIfNot(target) => match self.pop() {
+                    Value::Bool(true) => (),
+                    Value::Bool(false) => {
+                        pc = target;
+                        continue;
+                    }
+                    v => {
+                        let kind = ErrorKind::TypeMismatch(Type::Bool, v.ty());
+                        return Err(self.error(span(), kind));
+                    }
+                },
+                Instr::Pop => {
+                    self.pop();
+                }
+                Instr::Call(f, n) => {
+                    pc = self.enter(f, n, Some(pc))?;
+                    continue;
+                }
+                Instr::Ret => {
+                    let frame = self.frames.pop().unwrap();
+                    self.locals.truncate(frame.base);
+                    match frame.call {
+                        Some(call) => {
+                            pc = call + 1;
+                            continue;
+                        }
+                        None => return Ok(self.pop()),
+                    }
+                }
+                Instr::Exit(start, end) => self.exit(start, end)?,
+                Instr::Macro(m, n, fmt, cond) => {
+                    let vals = self.stack.split_off(self.stack.len() - n);
+                    let fmt = fmt.map(|i| prog.strings[i].as_str());
+                    let cond = || cond.map_or(String::new(), |i| prog.strings[i].clone());
+                    let v = builtin(m, fmt, cond, &vals, &mut self.out)
+                        .map_err(|kind| self.error(span(), kind))?;
+                    self.stack.push(v);
+                }
+                Instr::Fail(e) => return Err(self.error(span(), prog.errors[e].clone())),
+            }
+            pc += 1;
+        }
+    }
+}
+
+// every test program gives the same result as in the interpreter
+#[test]
+fn test_programs() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/programs");
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    paths.sort();
+    for path in paths {
+        let src = std::fs::read_to_string(&path).unwrap();
+        let p = crate::parse::parse(&src).unwrap();
+        let prog = crate::bytecode::compile(&p);
+        let vm = run(&prog, "main", vec![]);
+        let m = crate::module::from_bytes(&crate::module::to_bytes(&prog).unwrap()).unwrap();
+        assert_eq!(run(&m.program(), "main", vec![]), vm);
+        assert_eq!(
+            vm,
+            crate::interp::run(&p, "main", vec![]),
+            "{}",
+            path.display()
+        );
+    }
+}
+
+#[test]
+fn test_errors() {
+    let src = "
+fn f(n: i32) -> i32 {
+    if n == 0 { return 0; }
+    return f(n - 1) + 1;
+}
+
+fn dangle() -> &i32 {
+    let x: i32 = 1;
+    return &x;
+}
+
+fn escape(r: &mut &i32) {
+    let y: i32 = 2;
+    *r = &y;
+}
+
+fn outer() -> i32 {
+    let mut r: &i32 = &0;
+    escape(&mut r);
+    return *r;
+}
+
+fn shared(mut x: i32) {
+    let r: &i32 = &x;
+    *r = 1;
+}
+
+fn immutable(x: i32) {
+    let r: &mut i32 = &mut *&x;
+}
+
+fn mismatch() -> bool {
+    let mut x: i32 = 1;
+    x = true;
+    return x;
+}
+
+fn assign(x: i32) -> i32 {
+    let y: i32 = x;
+    (y) = 2;
+    return y;
+}
+
+fn missing(x: bool) -> i32 {
+    if x { return 1; }
+}
+
+fn unbound() {
+    let y: i32 = 1;
+    { let z: i32 = y + w; }
+}
+
+fn args() -> i32 {
+    return f(1, 2) + g();
+}
+
+fn checks() {
+    assert!(1 + 1 == 2);
+    assert_eq!(f(3), 4, \"f({})\", 3);
+    assert!(f(2) > 2 && true);
+}
+
+fn cond(x: i32) -> i32 {
+    while x {}
+    return 1 / 0;
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let prog = crate::bytecode::compile(&p);
+    let calls = [
+        ("f", vec![Value::Num(10)]),
+        ("f", vec![Value::Num(100)]),
+        ("f", vec![]),
+        ("dangle", vec![]),
+        ("outer", vec![]),
+        ("shared", vec![Value::Num(1)]),
+        ("immutable", vec![Value::Num(1)]),
+        ("mismatch", vec![]),
+        ("assign", vec![Value::Num(1)]),
+        ("missing", vec![Value::Bool(true)]),
+        ("missing", vec![Value::Bool(false)]),
+        ("unbound", vec![]),
+        ("args", vec![]),
+        ("checks", vec![]),
+        ("cond", vec![Value::Num(0)]),
+        ("none", vec![]),
+    ];
+    for (id, args) in calls.iter() {
+        let vm = run(&prog, id, args.clone());
+        assert_eq!(vm, crate::interp::run(&p, id, args.clone()), "{}", id);
+    }
+    let e = run(&prog, "outer", vec![]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::Dangling(Some("y".to_string())));
+    let e = run(&prog, "assign", vec![Value::Num(1)]).unwrap_err();
+    assert_eq!(e.kind, ErrorKind::AssignImmutable("y".to_string()));
+
+    let mut vm = Vm::new(&prog);
+    vm.set_limits(Limits {
+        max_store: 20,
+        ..Limits::default()
+    });
+    let mut i = crate::interp::Interp::new(&p);
+    i.set_limits(Limits {
+        max_store: 20,
+        ..Limits::default()
+    });
+    let args = vec![Value::Num(50)];
+    assert_eq!(vm.call("f", args.clone()), i.call("f", args));
+}
+
+#[test]
+fn test_output() {
+    let src = "
+fn main() {
+    let mut i: i32 = 0;
+    while i < 3 {
+        println!(\"{} {}\", i, i * i);
+        i = i + 1;
+    }
+}
+";
+    let p = crate::parse::parse(src).unwrap();
+    let prog = crate::bytecode::compile(&p);
+    let mut out = vec![];
+    let mut vm = Vm::new(&prog);
+    vm.set_output(Box::new(&mut out));
+    assert_eq!(vm.call("main", vec![]), Ok(Value::Unit));
+    drop(vm);
+    assert_eq!(String::from_utf8(out).unwrap(), "0 0\n1 1\n2 4\n");
+}